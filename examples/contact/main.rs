@@ -1,6 +1,6 @@
 use std::{path::Path, time::Duration};
 
-use ud3tn_aap::{config::{ConfigBundle, Contact, ContactDataRate}, Agent};
+use ud3tn_aap::{config::{ClaAddress, ConfigBundle, Contact, ContactDataRate}, Agent};
 
 fn main(){
     let mut agent = Agent::connect_unix(
@@ -11,7 +11,7 @@ fn main(){
     agent.send_config(ConfigBundle::AddContact{
         eid: "dtn://example.org/".into(),
         reliability: None,
-        cla_address: "file:/home/epickiwi/Documents/DTN-research/data/".into(),
+        cla_address: ClaAddress::File("/home/epickiwi/Documents/DTN-research/data/".into()),
         reaches_eid: Vec::new(),
         contacts: vec![
             Contact::from_now_during(Duration::from_secs(60), ContactDataRate::Unlimited)
@@ -0,0 +1,48 @@
+use std::{env, path::Path};
+
+use ud3tn_aap::{Agent, transfer::{TransferAgent, TransferEvent}};
+
+fn main(){
+    let args: Vec<String> = env::args().collect();
+
+    let agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket"))
+            .expect("Failed to connect to DTN node")
+        .register("file-transfer".to_owned())
+            .expect("Failed to register agent");
+
+    let mut agent = TransferAgent::new(
+        agent,
+        Path::new("file-transfer-state"),
+        Path::new("received")
+    ).expect("Failed to load transfer state");
+
+    agent.on_progress(|progress| {
+        println!("{:?} {} {}/{} bytes", progress.direction, progress.name, progress.done_bytes, progress.total_bytes)
+    });
+
+    // Announce unfinished transfers again after a restart
+    agent.resume().expect("Failed to resume transfers");
+
+    match args.get(1).map(String::as_str) {
+        Some("send") => {
+            let (Some(destination), Some(file)) = (args.get(2), args.get(3)) else {
+                return eprintln!("Usage: file-transfer send <destination EID> <file>")
+            };
+            let transfer_id = agent.send_file(destination.clone(), Path::new(file), 64 * 1024)
+                .expect("Failed to send file");
+            println!("Sending {} as transfer {:016x}", file, transfer_id);
+        }
+        Some("receive") => println!("Waiting for files in ./received"),
+        _ => return eprintln!("Usage: file-transfer (send <destination EID> <file> | receive)")
+    }
+
+    loop {
+        match agent.process_next().expect("Failed to process transfer bundle") {
+            Some(TransferEvent::Received { path, .. }) => println!("Received {}", path.display()),
+            Some(TransferEvent::Delivered { transfer_id }) => println!("Transfer {:016x} delivered", transfer_id),
+            Some(TransferEvent::ChecksumMismatch { transfer_id }) => println!("Transfer {:016x} corrupted, receiving again", transfer_id),
+            Some(TransferEvent::Announced(manifest)) => println!("Receiving {} ({} bytes)", manifest.name, manifest.size),
+            None => {}
+        }
+    }
+}
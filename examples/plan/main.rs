@@ -0,0 +1,57 @@
+use std::{env, path::Path, process::ExitCode, time::SystemTime};
+
+use ud3tn_aap::plan::{ContactPlan, lint::{self, Severity}};
+
+const USAGE: &str = "Usage: plan (lint <contact plan file> | export (dot | csv | svg) <contact plan file>)";
+
+fn load(file: &str) -> Option<ContactPlan> {
+    match ContactPlan::load(Path::new(file)) {
+        Ok(plan) => Some(plan),
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", file, e);
+            None
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.get(1..) {
+        Some(["lint", file]) => {
+            let Some(plan) = load(file) else { return ExitCode::FAILURE };
+
+            let issues = plan.lint(SystemTime::now());
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            println!("{}", lint::summary(&issues));
+
+            if issues.iter().any(|it| it.severity == Severity::Error) {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Some(["export", format, file]) => {
+            let Some(plan) = load(file) else { return ExitCode::FAILURE };
+
+            let now = SystemTime::now();
+            match *format {
+                "dot" => print!("{}", plan.to_dot(now)),
+                "csv" => print!("{}", plan.to_timeline_csv(now)),
+                "svg" => print!("{}", plan.to_timeline_svg(now)),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    return ExitCode::FAILURE
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,563 @@
+//! Legacy Bundle Protocol version 6 ([RFC 5050](https://www.rfc-editor.org/rfc/rfc5050)) encoding
+//!
+//! Used to read bundles produced by ud3tn nodes running with `-b 6`, e.g. BIBE payloads or debug captures.
+//! A parsed [Bundle] can be turned into the same [ReceivedBundle] view returned by [crate::RegisteredAgent::recv_bundle].
+
+use std::string::FromUtf8Error;
+
+use thiserror::Error;
+
+use crate::{DtnTime, ReceivedBundle};
+
+/// Bundle protocol version handled by this module
+pub const VERSION: u8 = 6;
+
+/// Block type of the payload block
+pub const PAYLOAD_BLOCK_TYPE: u8 = 1;
+
+/// Bundle processing control flags (RFC 5050 section 4.2)
+pub mod bundle_flags {
+    /// Bundle is a fragment
+    pub const IS_FRAGMENT: u64 = 0x01;
+    /// Application data unit is an administrative record
+    pub const ADMIN_RECORD: u64 = 0x02;
+    /// Bundle must not be fragmented
+    pub const MUST_NOT_FRAGMENT: u64 = 0x04;
+    /// Custody transfer is requested
+    pub const CUSTODY_TRANSFER: u64 = 0x08;
+    /// Destination endpoint is a singleton
+    pub const SINGLETON: u64 = 0x10;
+    /// Acknowledgement by application is requested
+    pub const APP_ACK_REQUESTED: u64 = 0x20;
+}
+
+/// Block processing control flags (RFC 5050 section 4.3)
+pub mod block_flags {
+    /// Block must be replicated in every fragment
+    pub const REPLICATE_IN_FRAGMENTS: u64 = 0x01;
+    /// Transmit status report if block can't be processed
+    pub const REPORT_IF_UNPROCESSED: u64 = 0x02;
+    /// Delete bundle if block can't be processed
+    pub const DELETE_IF_UNPROCESSED: u64 = 0x04;
+    /// Last block of the bundle
+    pub const LAST_BLOCK: u64 = 0x08;
+    /// Discard block if it can't be processed
+    pub const DISCARD_IF_UNPROCESSED: u64 = 0x10;
+    /// Block was forwarded without being processed
+    pub const FORWARDED_UNPROCESSED: u64 = 0x20;
+    /// Block contains an EID-reference field
+    pub const HAS_EID_REFERENCES: u64 = 0x40;
+}
+
+/// A RFC 5050 bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    /// Primary block of this bundle
+    pub primary: PrimaryBlock,
+
+    /// Canonical blocks following the primary block, including the payload block
+    pub blocks: Vec<CanonicalBlock>,
+}
+
+/// Primary bundle block
+///
+/// EIDs are stored in their textual form (`scheme:ssp`), the dictionary is rebuilt on serialization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrimaryBlock {
+    /// Bundle processing control flags, see [bundle_flags]
+    ///
+    /// [bundle_flags::IS_FRAGMENT] is managed on serialization from [PrimaryBlock::fragment].
+    pub flags: u64,
+
+    /// Destination EID
+    pub destination: String,
+
+    /// Source EID
+    pub source: String,
+
+    /// Report-to EID
+    pub report_to: String,
+
+    /// Current custodian EID
+    pub custodian: String,
+
+    /// Creation time in seconds since DTN epoch
+    pub creation_time: u64,
+
+    /// Creation timestamp sequence number
+    pub sequence_number: u64,
+
+    /// Lifetime in seconds
+    pub lifetime: u64,
+
+    /// Fragment offset and total application data unit length, only present for fragments
+    pub fragment: Option<FragmentInfo>,
+}
+
+/// Fragmentation information of a bundle fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentInfo {
+    /// Offset of this fragment payload in the original payload
+    pub offset: u64,
+
+    /// Length of the original payload
+    pub total_length: u64,
+}
+
+/// A non-primary bundle block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalBlock {
+    /// Block type code, [PAYLOAD_BLOCK_TYPE] for the payload block
+    pub block_type: u8,
+
+    /// Block processing control flags, see [block_flags]
+    ///
+    /// [block_flags::LAST_BLOCK] and [block_flags::HAS_EID_REFERENCES] are managed on serialization.
+    pub flags: u64,
+
+    /// EIDs referenced by this block
+    pub eid_references: Vec<String>,
+
+    /// Block-type-specific data
+    pub data: Vec<u8>,
+}
+
+impl Bundle {
+    /// Create a new bundle with a single payload block
+    pub fn new(source: String, destination: String, creation_time: u64, sequence_number: u64, lifetime: u64, payload: Vec<u8>) -> Self {
+        Self {
+            primary: PrimaryBlock {
+                flags: bundle_flags::SINGLETON,
+                destination,
+                report_to: source.clone(),
+                source,
+                custodian: "dtn:none".into(),
+                creation_time,
+                sequence_number,
+                lifetime,
+                fragment: None,
+            },
+            blocks: vec![CanonicalBlock {
+                block_type: PAYLOAD_BLOCK_TYPE,
+                flags: 0,
+                eid_references: Vec::new(),
+                data: payload,
+            }],
+        }
+    }
+
+    /// Parse a bundle from bytes
+    pub fn parse(bytes: &[u8]) -> Result<Self, Bpv6Error> {
+        Self::parse_buffer(bytes).map(|it| it.0)
+    }
+
+    /// Parse a bundle from bytes and return consumed bytes
+    ///
+    /// Returns a tuple of (Parsed bundle, number of bytes consumed in buffer)
+    pub fn parse_buffer(bytes: &[u8]) -> Result<(Self, usize), Bpv6Error> {
+        let mut reader = Reader { bytes, offset: 0 };
+
+        let version = reader.byte()?;
+        if version != VERSION {
+            return Err(Bpv6Error::VersionNotSupported(version));
+        }
+
+        let flags = reader.sdnv()?;
+        let block_length = reader.sdnv()? as usize;
+        let block_start = reader.offset;
+
+        let mut eid_offsets = [0_u64; 8];
+        for offset in eid_offsets.iter_mut() {
+            *offset = reader.sdnv()?;
+        }
+
+        let creation_time = reader.sdnv()?;
+        let sequence_number = reader.sdnv()?;
+        let lifetime = reader.sdnv()?;
+        let dictionary_length = reader.sdnv()? as usize;
+        let dictionary = reader.take(dictionary_length)?;
+
+        let fragment = if flags & bundle_flags::IS_FRAGMENT != 0 {
+            Some(FragmentInfo {
+                offset: reader.sdnv()?,
+                total_length: reader.sdnv()?,
+            })
+        } else {
+            None
+        };
+
+        if reader.offset - block_start != block_length {
+            return Err(Bpv6Error::InvalidBlockLength);
+        }
+
+        let primary = PrimaryBlock {
+            flags: flags & !bundle_flags::IS_FRAGMENT,
+            destination: dictionary_eid(dictionary, eid_offsets[0], eid_offsets[1])?,
+            source: dictionary_eid(dictionary, eid_offsets[2], eid_offsets[3])?,
+            report_to: dictionary_eid(dictionary, eid_offsets[4], eid_offsets[5])?,
+            custodian: dictionary_eid(dictionary, eid_offsets[6], eid_offsets[7])?,
+            creation_time,
+            sequence_number,
+            lifetime,
+            fragment,
+        };
+
+        let mut blocks = Vec::new();
+        loop {
+            let block_type = reader.byte()?;
+            let flags = reader.sdnv()?;
+
+            let mut eid_references = Vec::new();
+            if flags & block_flags::HAS_EID_REFERENCES != 0 {
+                let count = reader.sdnv()?;
+                for _ in 0..count {
+                    let scheme = reader.sdnv()?;
+                    let ssp = reader.sdnv()?;
+                    eid_references.push(dictionary_eid(dictionary, scheme, ssp)?);
+                }
+            }
+
+            let data_length = reader.sdnv()? as usize;
+            let data = reader.take(data_length)?.to_vec();
+
+            blocks.push(CanonicalBlock {
+                block_type,
+                flags: flags & !(block_flags::LAST_BLOCK | block_flags::HAS_EID_REFERENCES),
+                eid_references,
+                data,
+            });
+
+            if flags & block_flags::LAST_BLOCK != 0 {
+                break;
+            }
+        }
+
+        Ok((Self { primary, blocks }, reader.offset))
+    }
+
+    /// Serialize this bundle to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dictionary = Dictionary::default();
+        let primary_offsets: Vec<(u64, u64)> = [
+            &self.primary.destination,
+            &self.primary.source,
+            &self.primary.report_to,
+            &self.primary.custodian,
+        ].into_iter().map(|eid| dictionary.insert_eid(eid)).collect();
+        let block_offsets: Vec<Vec<(u64, u64)>> = self.blocks.iter()
+            .map(|block| block.eid_references.iter().map(|eid| dictionary.insert_eid(eid)).collect())
+            .collect();
+
+        let mut primary = Vec::new();
+        for (scheme, ssp) in primary_offsets {
+            encode_sdnv(scheme, &mut primary);
+            encode_sdnv(ssp, &mut primary);
+        }
+        encode_sdnv(self.primary.creation_time, &mut primary);
+        encode_sdnv(self.primary.sequence_number, &mut primary);
+        encode_sdnv(self.primary.lifetime, &mut primary);
+        encode_sdnv(dictionary.bytes.len() as u64, &mut primary);
+        primary.extend_from_slice(&dictionary.bytes);
+
+        let mut flags = self.primary.flags & !bundle_flags::IS_FRAGMENT;
+        if let Some(fragment) = self.primary.fragment {
+            flags |= bundle_flags::IS_FRAGMENT;
+            encode_sdnv(fragment.offset, &mut primary);
+            encode_sdnv(fragment.total_length, &mut primary);
+        }
+
+        let mut result = vec![VERSION];
+        encode_sdnv(flags, &mut result);
+        encode_sdnv(primary.len() as u64, &mut result);
+        result.append(&mut primary);
+
+        for (index, (block, offsets)) in self.blocks.iter().zip(block_offsets).enumerate() {
+            let mut flags = block.flags & !(block_flags::LAST_BLOCK | block_flags::HAS_EID_REFERENCES);
+            if index == self.blocks.len() - 1 {
+                flags |= block_flags::LAST_BLOCK;
+            }
+            if !offsets.is_empty() {
+                flags |= block_flags::HAS_EID_REFERENCES;
+            }
+
+            result.push(block.block_type);
+            encode_sdnv(flags, &mut result);
+            if !offsets.is_empty() {
+                encode_sdnv(offsets.len() as u64, &mut result);
+                for (scheme, ssp) in offsets {
+                    encode_sdnv(scheme, &mut result);
+                    encode_sdnv(ssp, &mut result);
+                }
+            }
+            encode_sdnv(block.data.len() as u64, &mut result);
+            result.extend_from_slice(&block.data);
+        }
+
+        result
+    }
+
+    /// Payload block of this bundle
+    pub fn payload_block(&self) -> Option<&CanonicalBlock> {
+        self.blocks.iter().find(|it| it.block_type == PAYLOAD_BLOCK_TYPE)
+    }
+
+    /// Payload of this bundle
+    ///
+    /// [None] if this bundle doesn't have a payload block
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.payload_block().map(|it| it.data.as_slice())
+    }
+
+    /// Bundle creation time
+    ///
+    /// [None] if the timestamp in seconds doesn't fit a [DtnTime] in milliseconds
+    pub fn creation_time(&self) -> Option<DtnTime> {
+        self.primary.creation_time.checked_mul(1000).map(DtnTime::from)
+    }
+
+    /// Convert this bundle to the high-level bundle view
+    ///
+    /// Fails with [Bpv6Error::MissingPayload] if this bundle doesn't have a payload block
+    pub fn into_received_bundle(self) -> Result<ReceivedBundle, Bpv6Error> {
+        let source = match self.primary.source.as_str() {
+            "dtn:none" => None,
+            _ => Some(self.primary.source),
+        };
+
+        let payload = self.blocks.into_iter()
+            .find(|it| it.block_type == PAYLOAD_BLOCK_TYPE)
+            .ok_or(Bpv6Error::MissingPayload)?
+            .data;
+
+        Ok(ReceivedBundle { source, payload })
+    }
+}
+
+impl TryFrom<Bundle> for ReceivedBundle {
+    type Error = Bpv6Error;
+
+    fn try_from(value: Bundle) -> Result<Self, Self::Error> {
+        value.into_received_bundle()
+    }
+}
+
+/// Append a SDNV encoded value to a buffer
+pub fn encode_sdnv(value: u64, target: &mut Vec<u8>) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut remaining = value >> 7;
+    while remaining > 0 {
+        groups.push((remaining & 0x7F) as u8 | 0x80);
+        remaining >>= 7;
+    }
+    target.extend(groups.into_iter().rev());
+}
+
+/// Decode a SDNV value at the start of a buffer
+///
+/// Returns a tuple of (Decoded value, number of bytes consumed in buffer)
+pub fn decode_sdnv(bytes: &[u8]) -> Result<(u64, usize), Bpv6Error> {
+    let mut value: u64 = 0;
+    for (index, byte) in bytes.iter().enumerate() {
+        if value > (u64::MAX >> 7) {
+            return Err(Bpv6Error::SdnvOverflow);
+        }
+        value = (value << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return Ok((value, index + 1));
+        }
+    }
+    Err(Bpv6Error::UnexpectedEnd)
+}
+
+/// Cursor over a bundle buffer
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, Bpv6Error> {
+        let byte = *self.bytes.get(self.offset).ok_or(Bpv6Error::UnexpectedEnd)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn sdnv(&mut self) -> Result<u64, Bpv6Error> {
+        let (value, consumed) = decode_sdnv(&self.bytes[self.offset..])?;
+        self.offset += consumed;
+        Ok(value)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Bpv6Error> {
+        let end = self.offset.checked_add(length).ok_or(Bpv6Error::UnexpectedEnd)?;
+        let slice = self.bytes.get(self.offset..end).ok_or(Bpv6Error::UnexpectedEnd)?;
+        self.offset = end;
+        Ok(slice)
+    }
+}
+
+/// Read an EID made of a scheme and a SSP from a dictionary
+fn dictionary_eid(dictionary: &[u8], scheme_offset: u64, ssp_offset: u64) -> Result<String, Bpv6Error> {
+    let scheme = dictionary_string(dictionary, scheme_offset)?;
+    let ssp = dictionary_string(dictionary, ssp_offset)?;
+    Ok(format!("{}:{}", scheme, ssp))
+}
+
+/// Read a null-terminated string from a dictionary
+fn dictionary_string(dictionary: &[u8], offset: u64) -> Result<String, Bpv6Error> {
+    let start = offset as usize;
+    if start >= dictionary.len() {
+        return Err(Bpv6Error::InvalidDictionaryOffset(offset));
+    }
+    let length = dictionary[start..].iter()
+        .position(|it| *it == 0)
+        .ok_or(Bpv6Error::InvalidDictionaryOffset(offset))?;
+    Ok(String::from_utf8(dictionary[start..start + length].to_vec())?)
+}
+
+/// Dictionary being built during serialization
+#[derive(Default)]
+struct Dictionary {
+    bytes: Vec<u8>,
+    entries: Vec<(String, u64)>,
+}
+
+impl Dictionary {
+    /// Insert an EID and return its (scheme offset, SSP offset)
+    fn insert_eid(&mut self, eid: &str) -> (u64, u64) {
+        let (scheme, ssp) = eid.split_once(':').unwrap_or((eid, ""));
+        (self.insert(scheme), self.insert(ssp))
+    }
+
+    fn insert(&mut self, value: &str) -> u64 {
+        if let Some((_, offset)) = self.entries.iter().find(|(it, _)| it == value) {
+            return *offset;
+        }
+        let offset = self.bytes.len() as u64;
+        self.bytes.extend_from_slice(value.as_bytes());
+        self.bytes.push(0);
+        self.entries.push((value.to_owned(), offset));
+        offset
+    }
+}
+
+/// Parsing error of a BPv6 bundle
+#[derive(Debug, Error, Clone)]
+pub enum Bpv6Error {
+    /// Bundle protocol version isn't 6
+    #[error("Bundle protocol version {0} not supported")]
+    VersionNotSupported(u8),
+
+    /// No more bytes to read but bundle wasn't finished
+    #[error("Unexpected end of bundle")]
+    UnexpectedEnd,
+
+    /// A SDNV value doesn't fit in 64 bits
+    #[error("SDNV value overflow")]
+    SdnvOverflow,
+
+    /// Primary block length doesn't match its content
+    #[error("Invalid primary block length")]
+    InvalidBlockLength,
+
+    /// An EID references an offset outside of the dictionary
+    #[error("Invalid dictionary offset {0}")]
+    InvalidDictionaryOffset(u64),
+
+    /// Bundle doesn't contain a payload block
+    #[error("Missing payload block")]
+    MissingPayload,
+
+    /// A dictionary string isn't a valid utf8 string
+    #[error("Invalid utf8 string {0}")]
+    Utf8Error(#[from] FromUtf8Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdnv_roundtrip() {
+        for (value, encoded) in [
+            (0_u64, vec![0x00]),
+            (0x7F, vec![0x7F]),
+            (0xABC, vec![0x95, 0x3C]),
+            (0x1234, vec![0xA4, 0x34]),
+            (0x4234, vec![0x81, 0x84, 0x34]),
+        ] {
+            let mut buffer = Vec::new();
+            encode_sdnv(value, &mut buffer);
+            assert_eq!(buffer, encoded);
+            assert_eq!(decode_sdnv(&buffer).unwrap(), (value, encoded.len()));
+        }
+
+        let mut buffer = Vec::new();
+        encode_sdnv(u64::MAX, &mut buffer);
+        assert_eq!(decode_sdnv(&buffer).unwrap(), (u64::MAX, 10));
+    }
+
+    #[test]
+    fn sdnv_errors() {
+        assert!(matches!(decode_sdnv(&[0x81, 0x80]), Err(Bpv6Error::UnexpectedEnd)));
+        assert!(matches!(decode_sdnv(&[0xFF; 11]), Err(Bpv6Error::SdnvOverflow)));
+    }
+
+    #[test]
+    fn parse_bundle() {
+        let bytes = vec![
+            0x06, // Version
+            0x10, // Flags (singleton)
+            0x2D, // Block length
+            0x00, 0x04, // Destination
+            0x00, 0x0F, // Source
+            0x00, 0x0F, // Report-to
+            0x00, 0x1C, // Custodian
+            0x05, 0x01, 0x3C, // Timestamp, sequence number, lifetime
+            0x21, // Dictionary length
+            b'd', b't', b'n', 0, b'/', b'/', b'b', b'o', b'b', b'.', b'd', b't', b'n', b'/', 0,
+            b'/', b'/', b'a', b'l', b'i', b'c', b'e', b'.', b'd', b't', b'n', b'/', 0,
+            b'n', b'o', b'n', b'e', 0,
+            0x01, 0x08, 0x02, b'h', b'i', // Payload block
+        ];
+
+        let (bundle, consumed) = Bundle::parse_buffer(&bytes).unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_eq!(bundle.primary.destination, "dtn://bob.dtn/");
+        assert_eq!(bundle.primary.source, "dtn://alice.dtn/");
+        assert_eq!(bundle.primary.custodian, "dtn:none");
+        assert_eq!(bundle.primary.lifetime, 60);
+        assert_eq!(bundle.creation_time(), Some(DtnTime::from(5000)));
+        assert_eq!(bundle.payload(), Some(b"hi".as_slice()));
+        assert_eq!(bundle.to_bytes(), bytes);
+
+        let mut overflowing = bundle.clone();
+        overflowing.primary.creation_time = u64::MAX;
+        assert_eq!(overflowing.creation_time(), None);
+
+        let received = bundle.into_received_bundle().unwrap();
+        assert_eq!(received.source, Some("dtn://alice.dtn/".into()));
+        assert_eq!(received.payload, b"hi");
+    }
+
+    #[test]
+    fn serialize_roundtrip() {
+        let mut bundle = Bundle::new("ipn:1.1".into(), "ipn:2.1".into(), 742772140, 3, 86400, vec![0xAB; 300]);
+        bundle.primary.fragment = Some(FragmentInfo { offset: 300, total_length: 900 });
+        bundle.blocks.insert(0, CanonicalBlock {
+            block_type: 20,
+            flags: block_flags::REPLICATE_IN_FRAGMENTS,
+            eid_references: vec!["ipn:3.0".into()],
+            data: vec![1, 2, 3],
+        });
+
+        let bytes = bundle.to_bytes();
+        assert_eq!(Bundle::parse(&bytes).unwrap(), bundle);
+        assert!(matches!(Bundle::parse(&bytes[..bytes.len() - 1]), Err(Bpv6Error::UnexpectedEnd)));
+    }
+
+    #[test]
+    fn reject_other_versions() {
+        assert!(matches!(Bundle::parse(&[0x07, 0x00]), Err(Bpv6Error::VersionNotSupported(7))));
+    }
+}
@@ -2,14 +2,38 @@
 
 use std::time::{SystemTime, Duration};
 
+mod batch;
+mod builder;
+mod cla;
+mod parse;
+mod validate;
+
+pub use batch::{ConfigBatch, DEFAULT_MAX_PAYLOAD_SIZE};
+pub use builder::ContactBuilder;
+pub use cla::{ClaAddress, ClaAddressError, HostPort};
+pub use parse::{ConfigParseError, ConfigParseErrorKind};
+pub use validate::{ConfigError, RELIABILITY_RANGE, RESERVED_CHARACTERS};
+
+/// Data rate sent for [ContactDataRate::Unlimited]
+const UNLIMITED_DATA_RATE: u64 = 4_294_967_200;
+
 /// Return a ud3tn timestamp based on custom offset 1st of january 2000
 /// See [ud3tn_utils/config.py line 15](https://gitlab.com/d3tn/ud3tn/-/blob/master/python-ud3tn-utils/ud3tn_utils/config.py#L15)
-fn dtn_timestamp(time:SystemTime) -> u64{
-    time.duration_since(SystemTime::UNIX_EPOCH + Duration::from_secs(946684800)).unwrap().as_secs()
+fn dtn_timestamp(time:SystemTime) -> Result<u64, ConfigError>{
+    time.duration_since(SystemTime::UNIX_EPOCH + Duration::from_secs(946684800))
+        .map(|it| it.as_secs())
+        .map_err(|_| ConfigError::BeforeDtnEpoch(time))
+}
+
+/// Inverse of [dtn_timestamp], [None] if time can't be represented
+fn from_dtn_timestamp(timestamp: u64) -> Option<SystemTime> {
+    (SystemTime::UNIX_EPOCH + Duration::from_secs(946684800)).checked_add(Duration::from_secs(timestamp))
 }
 
 /// ud3tn config bundle
-#[derive(Debug, Clone)]
+///
+/// Parsed back from its textual form with [str::parse] or [ConfigBundle::parse_all]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ConfigBundle {
     /// Add a new available contact
     AddContact {
@@ -20,9 +44,9 @@ pub enum ConfigBundle {
         reliability: Option<i32>,
 
         /// CLA address used in this contact
-        /// Uses the same string representation as ud3tn and consists of the convergence layer adapter and the node address
+        /// Serialized with the same string representation as ud3tn and consists of the convergence layer adapter and the node address
         /// e.g., `(tcpclv3:127.0.0.1:1234)`
-        cla_address: String,
+        cla_address: ClaAddress,
 
         /// Reachable EID through this contact
         reaches_eid: Vec<String>,
@@ -40,9 +64,9 @@ pub enum ConfigBundle {
         reliability: Option<i32>,
 
         /// CLA address used in this contact
-        /// Uses the same string representation as ud3tn and consists of the convergence layer adapter and the node address
+        /// Serialized with the same string representation as ud3tn and consists of the convergence layer adapter and the node address
         /// e.g., `(tcpclv3:127.0.0.1:1234)`
-        cla_address: Option<String>,
+        cla_address: Option<ClaAddress>,
 
         /// Reachable EID through this contact
         reaches_eid: Vec<String>,
@@ -57,6 +81,8 @@ pub enum ConfigBundle {
 
 impl ConfigBundle {
     /// Serialize this config bundle as string
+    ///
+    /// Bundle isn't checked, times before DTN epoch are written as 0. See [ConfigBundle::validate]
     pub fn to_string(&self) -> String {
         let result: String = match self {
             ConfigBundle::AddContact {
@@ -93,11 +119,11 @@ impl ConfigBundle {
                         .map(|it| {
                             format!(
                                 "{{{},{},{}}}",
-                                dtn_timestamp(it.start),
-                                dtn_timestamp(it.end),
+                                dtn_timestamp(it.start).unwrap_or(0),
+                                dtn_timestamp(it.end).unwrap_or(0),
                                 match it.data_rate {
                                     ContactDataRate::Limited(i) => format!("{}", i),
-                                    ContactDataRate::Unlimited => format!("{}", 4_294_967_200_i64),
+                                    ContactDataRate::Unlimited => format!("{}", UNLIMITED_DATA_RATE),
                                 }
                             )
                         })
@@ -148,11 +174,11 @@ impl ConfigBundle {
                         .map(|it| {
                             format!(
                                 "{{{},{},{}}}",
-                                dtn_timestamp(it.start),
-                                dtn_timestamp(it.end),
+                                dtn_timestamp(it.start).unwrap_or(0),
+                                dtn_timestamp(it.end).unwrap_or(0),
                                 match it.data_rate {
                                     ContactDataRate::Limited(i) => format!("{}", i),
-                                    ContactDataRate::Unlimited => format!("{}", 4_294_967_200_i64),
+                                    ContactDataRate::Unlimited => format!("{}", UNLIMITED_DATA_RATE),
                                 }
                             )
                         })
@@ -181,7 +207,7 @@ impl ConfigBundle {
 }
 
 /// Describes when a contact is available
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Contact {
     /// When this contact will start
     pub start: SystemTime,
@@ -206,7 +232,7 @@ impl Contact {
 }
 
 /// Contact expected transmission rate
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ContactDataRate {
     /// Unlimited transmission rate
     Unlimited,
@@ -229,7 +255,7 @@ mod tests {
         let config_1 = ConfigBundle::AddContact{
             eid: "dtn://ud3tn2.dtn/".into(),
             reliability: None,
-            cla_address: "mtcp:127.0.0.1:4223".into(),
+            cla_address: ClaAddress::Mtcp(HostPort::new("127.0.0.1", 4223)),
             reaches_eid: Vec::new(),
             contacts: vec![
                 Contact {
@@ -250,7 +276,7 @@ mod tests {
         let config_2 = ConfigBundle::AddContact{
             eid: "dtn://13714/".into(),
             reliability: Some(333),
-            cla_address: "tcpspp:".into(),
+            cla_address: ClaAddress::TcpSpp(None),
             reaches_eid: vec!["dtn://18471/".into(), "dtn://81491/".into()],
             contacts: Vec::new(),
         };
@@ -263,7 +289,7 @@ mod tests {
         let config_3 = ConfigBundle::AddContact{
             eid: "dtn://example.org/".into(),
             reliability: None,
-            cla_address: "file:/home/epickiwi/Documents/Dev/archipel-core/data".into(),
+            cla_address: ClaAddress::File("/home/epickiwi/Documents/Dev/archipel-core/data".into()),
             reaches_eid: Vec::new(),
             contacts: vec![
                 Contact { 
@@ -284,7 +310,7 @@ mod tests {
         let config_1 = ConfigBundle::ReplaceContact{
             eid: "dtn://ud3tn2.dtn/".into(),
             reliability: None,
-            cla_address: Some("mtcp:127.0.0.1:4223".into()),
+            cla_address: Some(ClaAddress::Mtcp(HostPort::new("127.0.0.1", 4223))),
             reaches_eid: vec!["dtn://89326/".into(), "dtn://12349/".into()],
             contacts: Vec::new(),
         };
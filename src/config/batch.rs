@@ -0,0 +1,162 @@
+//! Several config commands sent in a single config bundle
+
+use super::{ConfigBundle, ConfigError};
+
+/// Default maximum size of a batch config bundle payload
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// Config commands sent in as few config bundles as possible
+///
+/// ud3tn config agent accepts several `;`-terminated commands in one payload. Commands are packed in order into
+/// payloads of at most `max_payload_size` bytes, a command is never split across payloads.
+///
+/// The node applies each payload on its own: a batch split in several payloads isn't atomic, and a failure
+/// while sending leaves the node with only the first payloads applied. Use [ConfigBatch::with_atomic] to refuse
+/// batches that don't fit in a single payload instead.
+///
+/// ```rust
+/// use ud3tn_aap::config::{ConfigBatch, ConfigBundle};
+///
+/// let batch = ConfigBatch::new()
+///     .with_max_payload_size(32)
+///     .with_command(ConfigBundle::DeleteContact("dtn://ud3tn2.dtn/".into()))
+///     .with_command(ConfigBundle::DeleteContact("dtn://ud3tn3.dtn/".into()));
+///
+/// assert_eq!(batch.payloads().unwrap(), vec![b"3(dtn://ud3tn2.dtn/);".to_vec(), b"3(dtn://ud3tn3.dtn/);".to_vec()]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigBatch {
+    commands: Vec<ConfigBundle>,
+    max_payload_size: usize,
+    atomic: bool,
+}
+
+impl Default for ConfigBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBatch {
+    /// Empty batch using [DEFAULT_MAX_PAYLOAD_SIZE]
+    pub fn new() -> Self {
+        Self { commands: Vec::new(), max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE, atomic: false }
+    }
+
+    /// Limit size of each config bundle payload
+    pub fn with_max_payload_size(mut self, size: usize) -> Self {
+        self.max_payload_size = size;
+        self
+    }
+
+    /// Fail with [ConfigError::NotAtomic] instead of splitting commands across several payloads
+    pub fn with_atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Append a command
+    pub fn with_command(mut self, command: ConfigBundle) -> Self {
+        self.push(command);
+        self
+    }
+
+    /// Append a command
+    pub fn push(&mut self, command: ConfigBundle) {
+        self.commands.push(command);
+    }
+
+    /// Commands in sending order
+    pub fn commands(&self) -> &[ConfigBundle] {
+        &self.commands
+    }
+
+    /// Commands grouped by config bundle, in sending order
+    ///
+    /// Fails if a command is invalid or doesn't fit in a payload, or if an atomic batch needs several payloads
+    pub fn chunks(&self) -> Result<Vec<&[ConfigBundle]>, ConfigError> {
+        let mut result = Vec::new();
+        let mut start = 0;
+        let mut size = 0;
+        let mut total = 0;
+
+        for (index, command) in self.commands.iter().enumerate() {
+            command.validate()?;
+            let length = command.to_string().len();
+            if length > self.max_payload_size {
+                return Err(ConfigError::TooLarge { size: length, max: self.max_payload_size });
+            }
+
+            if size + length > self.max_payload_size {
+                result.push(&self.commands[start..index]);
+                start = index;
+                size = 0;
+            }
+            size += length;
+            total += length;
+        }
+
+        if self.atomic && !result.is_empty() {
+            return Err(ConfigError::NotAtomic { size: total, max: self.max_payload_size });
+        }
+        if start < self.commands.len() {
+            result.push(&self.commands[start..]);
+        }
+        Ok(result)
+    }
+
+    /// Payloads of config bundles, in sending order
+    pub fn payloads(&self) -> Result<Vec<Vec<u8>>, ConfigError> {
+        Ok(self.chunks()?.into_iter().map(to_payload).collect())
+    }
+}
+
+impl FromIterator<ConfigBundle> for ConfigBatch {
+    fn from_iter<T: IntoIterator<Item = ConfigBundle>>(iter: T) -> Self {
+        Self { commands: iter.into_iter().collect(), ..Self::new() }
+    }
+}
+
+/// Payload of a config bundle carrying `commands`
+fn to_payload(commands: &[ConfigBundle]) -> Vec<u8> {
+    commands.iter().flat_map(ConfigBundle::to_bytes).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_in_order() {
+        let delete = |n: u32| ConfigBundle::DeleteContact(format!("dtn://node{}.dtn/", n));
+        let batch: ConfigBatch = (1..=5).map(delete).collect();
+        let batch = batch.with_max_payload_size(50);
+
+        let chunks = batch.chunks().unwrap();
+        assert_eq!(chunks, vec![&[delete(1), delete(2)][..], &[delete(3), delete(4)][..], &[delete(5)][..]]);
+        assert_eq!(batch.payloads().unwrap()[0], b"3(dtn://node1.dtn/);3(dtn://node2.dtn/);".to_vec());
+
+        assert_eq!(
+            batch.clone().with_max_payload_size(10).payloads(),
+            Err(ConfigError::TooLarge { size: 20, max: 10 })
+        );
+        assert_eq!(
+            batch.with_command(ConfigBundle::DeleteContact("".into())).payloads(),
+            Err(ConfigError::Empty("EID"))
+        );
+        assert!(ConfigBatch::new().payloads().unwrap().is_empty());
+    }
+
+    #[test]
+    fn atomic_batch() {
+        let delete = |n: u32| ConfigBundle::DeleteContact(format!("dtn://node{}.dtn/", n));
+        let batch: ConfigBatch = (1..=2).map(delete).collect();
+        let batch = batch.with_atomic(true);
+
+        assert_eq!(batch.clone().with_max_payload_size(40).payloads().unwrap().len(), 1);
+        assert_eq!(
+            batch.with_max_payload_size(30).payloads(),
+            Err(ConfigError::NotAtomic { size: 40, max: 30 })
+        );
+    }
+}
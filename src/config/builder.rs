@@ -0,0 +1,144 @@
+//! Builder of validated contact config bundles
+
+use super::{ClaAddress, ConfigBundle, ConfigError, Contact};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Add,
+    Replace,
+}
+
+/// Builder of [ConfigBundle::AddContact] and [ConfigBundle::ReplaceContact], validated on [ContactBuilder::build]
+///
+/// ```rust
+/// use std::time::Duration;
+/// use ud3tn_aap::config::{ClaAddress, ConfigBundle, Contact, ContactDataRate, HostPort};
+///
+/// let config = ConfigBundle::add_contact("dtn://ud3tn2.dtn/", ClaAddress::Mtcp(HostPort::new("127.0.0.1", 4223)))
+///     .with_reliability(900)
+///     .with_reach("dtn://ud3tn3.dtn/")
+///     .with_contact(Contact::from_now_during(Duration::from_secs(3600), ContactDataRate::Unlimited))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ContactBuilder {
+    command: Command,
+    eid: String,
+    reliability: Option<i32>,
+    cla_address: Option<ClaAddress>,
+    reaches_eid: Vec<String>,
+    contacts: Vec<Contact>,
+}
+
+impl ContactBuilder {
+    fn new(command: Command, eid: String, cla_address: Option<ClaAddress>) -> Self {
+        Self { command, eid, reliability: None, cla_address, reaches_eid: Vec::new(), contacts: Vec::new() }
+    }
+
+    /// Set expected likelihood of future contacts, between 100 and 1000
+    pub fn with_reliability(mut self, reliability: i32) -> Self {
+        self.reliability = Some(reliability);
+        self
+    }
+
+    /// Set CLA address used in contact
+    pub fn with_cla_address(mut self, cla_address: ClaAddress) -> Self {
+        self.cla_address = Some(cla_address);
+        self
+    }
+
+    /// Add an EID reachable through contact
+    pub fn with_reach(mut self, eid: impl Into<String>) -> Self {
+        self.reaches_eid.push(eid.into());
+        self
+    }
+
+    /// Add a future contact
+    pub fn with_contact(mut self, contact: Contact) -> Self {
+        self.contacts.push(contact);
+        self
+    }
+
+    /// Add several future contacts
+    pub fn with_contacts(mut self, contacts: impl IntoIterator<Item = Contact>) -> Self {
+        self.contacts.extend(contacts);
+        self
+    }
+
+    /// Build config bundle, checking it with [ConfigBundle::validate]
+    pub fn build(self) -> Result<ConfigBundle, ConfigError> {
+        let result = match self.command {
+            Command::Add => ConfigBundle::AddContact {
+                eid: self.eid,
+                reliability: self.reliability,
+                cla_address: self.cla_address.ok_or(ConfigError::Empty("CLA address"))?,
+                reaches_eid: self.reaches_eid,
+                contacts: self.contacts,
+            },
+            Command::Replace => ConfigBundle::ReplaceContact {
+                eid: self.eid,
+                reliability: self.reliability,
+                cla_address: self.cla_address,
+                reaches_eid: self.reaches_eid,
+                contacts: self.contacts,
+            },
+        };
+
+        result.validate()?;
+        Ok(result)
+    }
+}
+
+impl ConfigBundle {
+    /// Start building a [ConfigBundle::AddContact]
+    pub fn add_contact(eid: impl Into<String>, cla_address: ClaAddress) -> ContactBuilder {
+        ContactBuilder::new(Command::Add, eid.into(), Some(cla_address))
+    }
+
+    /// Start building a [ConfigBundle::ReplaceContact]
+    pub fn replace_contact(eid: impl Into<String>) -> ContactBuilder {
+        ContactBuilder::new(Command::Replace, eid.into(), None)
+    }
+
+    /// Build a validated [ConfigBundle::DeleteContact]
+    pub fn delete_contact(eid: impl Into<String>) -> Result<ConfigBundle, ConfigError> {
+        let result = ConfigBundle::DeleteContact(eid.into());
+        result.validate()?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::config::ContactDataRate;
+
+    use super::*;
+
+    #[test]
+    fn build_contacts() {
+        let start = SystemTime::now();
+        let config = ConfigBundle::replace_contact("dtn://ud3tn2.dtn/")
+            .with_reliability(1000)
+            .with_reach("dtn://ud3tn3.dtn/")
+            .with_contact(Contact::from_during(start, Duration::from_secs(60), ContactDataRate::Limited(1200)))
+            .build()
+            .unwrap();
+
+        assert_eq!(config, ConfigBundle::ReplaceContact {
+            eid: "dtn://ud3tn2.dtn/".into(),
+            reliability: Some(1000),
+            cla_address: None,
+            reaches_eid: vec!["dtn://ud3tn3.dtn/".into()],
+            contacts: vec![Contact::from_during(start, Duration::from_secs(60), ContactDataRate::Limited(1200))],
+        });
+
+        assert_eq!(
+            ConfigBundle::add_contact("dtn://ud3tn2.dtn/", "mtcp:127.0.0.1:4223".parse().unwrap()).with_reliability(10).build(),
+            Err(ConfigError::InvalidReliability(10))
+        );
+        assert_eq!(ConfigBundle::delete_contact(""), Err(ConfigError::Empty("EID")));
+    }
+}
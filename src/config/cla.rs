@@ -0,0 +1,240 @@
+//! Typed CLA addresses of ud3tn convergence layer adapters
+
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use thiserror::Error;
+
+/// Host and port of a TCP-based CLA
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HostPort {
+    /// Host name or IP address, IPv6 addresses between brackets
+    pub host: String,
+
+    /// TCP port
+    pub port: u16,
+}
+
+impl HostPort {
+    /// Create a new host and port
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self { host: host.into(), port }
+    }
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl FromStr for HostPort {
+    type Err = ClaAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((host, port)) = s.rsplit_once(':') else {
+            return Err(ClaAddressError::MissingPort(s.to_owned()));
+        };
+        if host.is_empty() {
+            return Err(ClaAddressError::MissingHost(s.to_owned()));
+        }
+        let port = port.parse().map_err(|_| ClaAddressError::InvalidPort(port.to_owned()))?;
+        Ok(Self { host: host.to_owned(), port })
+    }
+}
+
+/// Address of a peer through one of ud3tn's convergence layer adapters
+///
+/// Written as `<cla>:<address>`, e.g. `mtcp:127.0.0.1:4223` or `file:/var/lib/dtn/outbox`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "String", into = "String"))]
+pub enum ClaAddress {
+    /// TCP convergence layer version 3 (RFC 7242)
+    TcpClV3(HostPort),
+
+    /// TCP convergence layer version 4 (RFC 9174)
+    TcpClV4(HostPort),
+
+    /// Minimal TCP convergence layer
+    Mtcp(HostPort),
+
+    /// Minimal TCP convergence layer with a single outgoing connection
+    Smtcp(HostPort),
+
+    /// Space Packet Protocol over TCP, the address is optional as the CLA uses a single configured connection
+    TcpSpp(Option<HostPort>),
+
+    /// Bundle-in-bundle encapsulation, addressed by the EID of the encapsulating peer
+    Bibe(String),
+
+    /// Bundles stored as files in a directory
+    File(PathBuf),
+
+    /// Bundles stored in an SQLite database
+    Sqlite(PathBuf),
+
+    /// Any other convergence layer adapter, address is kept as written
+    ///
+    /// Only built explicitly or by [ClaAddress::parse_lenient], [ClaAddress::from_str] refuses CLAs unknown to ud3tn.
+    Other {
+        /// Name of convergence layer adapter
+        cla: String,
+        /// Address in CLA-specific syntax
+        address: String,
+    },
+}
+
+impl ClaAddress {
+    /// Name of convergence layer adapter, as used by ud3tn
+    pub fn cla_name(&self) -> &str {
+        match self {
+            ClaAddress::TcpClV3(_) => "tcpclv3",
+            ClaAddress::TcpClV4(_) => "tcpclv4",
+            ClaAddress::Mtcp(_) => "mtcp",
+            ClaAddress::Smtcp(_) => "smtcp",
+            ClaAddress::TcpSpp(_) => "tcpspp",
+            ClaAddress::Bibe(_) => "bibe",
+            ClaAddress::File(_) => "file",
+            ClaAddress::Sqlite(_) => "sqlite",
+            ClaAddress::Other { cla, .. } => cla,
+        }
+    }
+
+    /// Parse an address as [ClaAddress::from_str], keeping addresses of other CLAs as [ClaAddress::Other]
+    pub fn parse_lenient(s: &str) -> Result<Self, ClaAddressError> {
+        match s.parse() {
+            Err(ClaAddressError::UnknownCla(cla)) if is_cla_name(&cla) => {
+                let address = &s[cla.len() + 1..];
+                Ok(ClaAddress::Other { cla, address: address.to_owned() })
+            }
+            result => result,
+        }
+    }
+}
+
+fn is_cla_name(cla: &str) -> bool {
+    !cla.is_empty() && cla.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl fmt::Display for ClaAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.cla_name())?;
+        match self {
+            ClaAddress::TcpClV3(address)
+            | ClaAddress::TcpClV4(address)
+            | ClaAddress::Mtcp(address)
+            | ClaAddress::Smtcp(address) => write!(f, "{}", address),
+            ClaAddress::TcpSpp(Some(address)) => write!(f, "{}", address),
+            ClaAddress::TcpSpp(None) => Ok(()),
+            ClaAddress::Bibe(eid) => write!(f, "{}", eid),
+            ClaAddress::File(path) | ClaAddress::Sqlite(path) => write!(f, "{}", path.display()),
+            ClaAddress::Other { address, .. } => write!(f, "{}", address),
+        }
+    }
+}
+
+impl FromStr for ClaAddress {
+    type Err = ClaAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((cla, address)) = s.split_once(':') else {
+            return Err(ClaAddressError::UnknownCla(s.to_owned()));
+        };
+        Ok(match cla {
+            "tcpclv3" => ClaAddress::TcpClV3(address.parse()?),
+            "tcpclv4" => ClaAddress::TcpClV4(address.parse()?),
+            "mtcp" => ClaAddress::Mtcp(address.parse()?),
+            "smtcp" => ClaAddress::Smtcp(address.parse()?),
+            "tcpspp" if address.is_empty() => ClaAddress::TcpSpp(None),
+            "tcpspp" => ClaAddress::TcpSpp(Some(address.parse()?)),
+            "bibe" if address.is_empty() => return Err(ClaAddressError::EmptyAddress(s.to_owned())),
+            "bibe" => ClaAddress::Bibe(address.to_owned()),
+            "file" | "sqlite" if address.is_empty() => return Err(ClaAddressError::EmptyAddress(s.to_owned())),
+            "file" => ClaAddress::File(address.into()),
+            "sqlite" => ClaAddress::Sqlite(address.into()),
+            _ => return Err(ClaAddressError::UnknownCla(cla.to_owned())),
+        })
+    }
+}
+
+impl TryFrom<String> for ClaAddress {
+    type Error = ClaAddressError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ClaAddress> for String {
+    fn from(value: ClaAddress) -> Self {
+        value.to_string()
+    }
+}
+
+/// An error parsing a [ClaAddress]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ClaAddressError {
+    /// Address doesn't start with the name of one of ud3tn's convergence layer adapters
+    #[error("Unknown CLA {0:?}")]
+    UnknownCla(String),
+
+    /// Address has no port
+    #[error("Missing port in {0:?}")]
+    MissingPort(String),
+
+    /// Address has no host
+    #[error("Missing host in {0:?}")]
+    MissingHost(String),
+
+    /// Port isn't a number between 0 and 65535
+    #[error("Invalid port {0:?}")]
+    InvalidPort(String),
+
+    /// CLA requires an address
+    #[error("Empty address in {0:?}")]
+    EmptyAddress(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for address in [
+            "tcpclv3:127.0.0.1:4556",
+            "tcpclv4:[::1]:4556",
+            "mtcp:ud3tn2.dtn:4224",
+            "smtcp:10.0.0.2:4222",
+            "tcpspp:",
+            "tcpspp:localhost:4223",
+            "bibe:dtn://gateway.dtn/",
+            "file:/home/epickiwi/Documents/Dev/archipel-core/data",
+            "sqlite:/var/lib/ud3tn/bundles.db",
+        ] {
+            let parsed: ClaAddress = address.parse().unwrap();
+            assert_eq!(parsed.to_string(), address);
+            assert_eq!(ClaAddress::parse_lenient(address), Ok(parsed));
+        }
+
+        let other = ClaAddress::parse_lenient("usbotg:/dev/ttyACM0").unwrap();
+        assert_eq!(other, ClaAddress::Other { cla: "usbotg".into(), address: "/dev/ttyACM0".into() });
+        assert_eq!(other.to_string(), "usbotg:/dev/ttyACM0");
+
+        assert_eq!(
+            "mtcp:127.0.0.1:4223".parse(),
+            Ok(ClaAddress::Mtcp(HostPort::new("127.0.0.1", 4223)))
+        );
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        assert_eq!("mctp:127.0.0.1:4223".parse::<ClaAddress>(), Err(ClaAddressError::UnknownCla("mctp".into())));
+        assert_eq!(ClaAddress::parse_lenient("m tcp:127.0.0.1:4223"), Err(ClaAddressError::UnknownCla("m tcp".into())));
+        assert_eq!(ClaAddress::parse_lenient("mtcp:127.0.0.1"), Err(ClaAddressError::MissingPort("127.0.0.1".into())));
+        assert_eq!("mtcp:127.0.0.1".parse::<ClaAddress>(), Err(ClaAddressError::MissingPort("127.0.0.1".into())));
+        assert_eq!("tcpclv3::4556".parse::<ClaAddress>(), Err(ClaAddressError::MissingHost(":4556".into())));
+        assert_eq!("tcpclv4:localhost:99999".parse::<ClaAddress>(), Err(ClaAddressError::InvalidPort("99999".into())));
+        assert_eq!("file:".parse::<ClaAddress>(), Err(ClaAddressError::EmptyAddress("file:".into())));
+        assert_eq!("localhost".parse::<ClaAddress>(), Err(ClaAddressError::UnknownCla("localhost".into())));
+    }
+}
@@ -0,0 +1,412 @@
+//! Parser of ud3tn textual config commands
+//!
+//! Grammar, as produced by [ConfigBundle::to_string] and the python `ud3tn_utils` tooling:
+//!
+//! ```text
+//! 1(<eid>)[,<reliability>]:(<cla>)[:[(<eid>),...][:[{<start>,<end>,<rate>},...]]];
+//! 2(<eid>)[,<reliability>]:[(<cla>)][:[(<eid>),...][:[{<start>,<end>,<rate>},...]]];
+//! 3(<eid>);
+//! ```
+
+use std::{fmt, str::FromStr, time::SystemTime};
+
+use thiserror::Error;
+
+use super::{from_dtn_timestamp, ClaAddress, ClaAddressError, ConfigBundle, Contact, ContactDataRate, UNLIMITED_DATA_RATE};
+
+/// An error while parsing config commands, with its position in input
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{kind} at line {line}, column {column}")]
+pub struct ConfigParseError {
+    /// What went wrong
+    pub kind: ConfigParseErrorKind,
+
+    /// Byte offset in input
+    pub position: usize,
+
+    /// Line in input, starting at 1
+    pub line: usize,
+
+    /// Character in line, starting at 1
+    pub column: usize,
+}
+
+impl ConfigParseError {
+    fn new(input: &str, position: usize, kind: ConfigParseErrorKind) -> Self {
+        let before = &input[..position];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or(before).chars().count() + 1;
+        Self { kind, position, line, column }
+    }
+}
+
+/// Kind of [ConfigParseError]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigParseErrorKind {
+    /// Input ended in the middle of a command
+    UnexpectedEnd,
+
+    /// A character other than expected was found
+    Unexpected {
+        /// What was expected
+        expected: &'static str,
+        /// Character found
+        found: char,
+    },
+
+    /// Command number isn't 1, 2 or 3
+    UnknownCommand(char),
+
+    /// A number is malformed or out of range
+    InvalidNumber(String),
+
+    /// An add contact command has no CLA address
+    MissingClaAddress,
+
+    /// CLA address isn't a valid [ClaAddress]
+    InvalidClaAddress(ClaAddressError),
+}
+
+impl fmt::Display for ConfigParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigParseErrorKind::UnexpectedEnd => write!(f, "Unexpected end of input"),
+            ConfigParseErrorKind::Unexpected { expected, found } => write!(f, "Expected {}, found {:?}", expected, found),
+            ConfigParseErrorKind::UnknownCommand(command) => write!(f, "Unknown command {:?}", command),
+            ConfigParseErrorKind::InvalidNumber(number) => write!(f, "Invalid number {:?}", number),
+            ConfigParseErrorKind::MissingClaAddress => write!(f, "Missing CLA address"),
+            ConfigParseErrorKind::InvalidClaAddress(e) => write!(f, "Invalid CLA address: {}", e),
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, position: usize, kind: ConfigParseErrorKind) -> ConfigParseError {
+        ConfigParseError::new(self.input, position, kind)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn at_end(&self) -> bool {
+        self.position == self.input.len()
+    }
+
+    fn next_if(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += expected.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char, description: &'static str) -> Result<(), ConfigParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += c.len_utf8();
+                Ok(())
+            }
+            Some(found) => Err(self.error(self.position, ConfigParseErrorKind::Unexpected { expected: description, found })),
+            None => Err(self.error(self.position, ConfigParseErrorKind::UnexpectedEnd)),
+        }
+    }
+
+    /// Content between parentheses
+    fn parenthesized(&mut self) -> Result<&'a str, ConfigParseError> {
+        self.expect('(', "'('")?;
+        let rest = &self.input[self.position..];
+        let Some(length) = rest.find(')') else {
+            return Err(self.error(self.input.len(), ConfigParseErrorKind::UnexpectedEnd));
+        };
+
+        self.position += length + 1;
+        Ok(&rest[..length])
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, ConfigParseError> {
+        let start = self.position;
+        let rest = &self.input[start..];
+        let length = rest.find(|c: char| !c.is_ascii_digit() && c != '-').unwrap_or(rest.len());
+        if length == 0 {
+            return match self.peek() {
+                Some(found) => Err(self.error(start, ConfigParseErrorKind::Unexpected { expected: "a number", found })),
+                None => Err(self.error(start, ConfigParseErrorKind::UnexpectedEnd)),
+            };
+        }
+
+        self.position += length;
+        rest[..length].parse()
+            .map_err(|_| self.error(start, ConfigParseErrorKind::InvalidNumber(rest[..length].to_owned())))
+    }
+
+    /// Comma separated items between brackets
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T, ConfigParseError>) -> Result<Vec<T>, ConfigParseError> {
+        let mut result = Vec::new();
+        self.expect('[', "'['")?;
+        if self.next_if(']') {
+            return Ok(result);
+        }
+
+        loop {
+            result.push(item(self)?);
+            if self.next_if(']') {
+                return Ok(result);
+            }
+            self.expect(',', "',' or end of list")?;
+        }
+    }
+
+    fn contact(&mut self) -> Result<Contact, ConfigParseError> {
+        self.expect('{', "'{'")?;
+        let start = self.timestamp()?;
+        self.expect(',', "','")?;
+        let end = self.timestamp()?;
+        self.expect(',', "','")?;
+        let rate_position = self.position;
+        let rate: u64 = self.number()?;
+        self.expect('}', "'}'")?;
+
+        let data_rate = if rate == UNLIMITED_DATA_RATE {
+            ContactDataRate::Unlimited
+        } else {
+            ContactDataRate::Limited(rate.try_into()
+                .map_err(|_| self.error(rate_position, ConfigParseErrorKind::InvalidNumber(rate.to_string())))?)
+        };
+
+        Ok(Contact { start, end, data_rate })
+    }
+
+    fn timestamp(&mut self) -> Result<SystemTime, ConfigParseError> {
+        let position = self.position;
+        let timestamp = self.number()?;
+        from_dtn_timestamp(timestamp)
+            .ok_or_else(|| self.error(position, ConfigParseErrorKind::InvalidNumber(timestamp.to_string())))
+    }
+
+    fn command(&mut self) -> Result<ConfigBundle, ConfigParseError> {
+        let command_position = self.position;
+        let command = match self.peek() {
+            Some(c @ ('1' | '2' | '3')) => {
+                self.position += 1;
+                c
+            }
+            Some(c) => return Err(self.error(command_position, ConfigParseErrorKind::UnknownCommand(c))),
+            None => return Err(self.error(command_position, ConfigParseErrorKind::UnexpectedEnd)),
+        };
+
+        let eid = self.parenthesized()?.to_owned();
+        if command == '3' {
+            self.expect(';', "';'")?;
+            return Ok(ConfigBundle::DeleteContact(eid));
+        }
+
+        let reliability = if self.next_if(',') { Some(self.number()?) } else { None };
+
+        self.expect(':', "':'")?;
+        let cla_position = self.position;
+        let cla_address = match self.peek() {
+            Some('(') => Some(ClaAddress::parse_lenient(self.parenthesized()?)
+                .map_err(|e| self.error(cla_position + 1, ConfigParseErrorKind::InvalidClaAddress(e)))?),
+            _ => None,
+        };
+
+        let mut reaches_eid = Vec::new();
+        let mut contacts = Vec::new();
+        if self.next_if(':') {
+            if self.peek() == Some('[') {
+                reaches_eid = self.list(|parser| Ok(parser.parenthesized()?.to_owned()))?;
+            }
+            if self.next_if(':') && self.peek() == Some('[') {
+                contacts = self.list(Self::contact)?;
+            }
+        }
+        self.expect(';', "';'")?;
+
+        Ok(match command {
+            '1' => ConfigBundle::AddContact {
+                eid,
+                reliability,
+                cla_address: cla_address
+                    .ok_or_else(|| self.error(cla_position, ConfigParseErrorKind::MissingClaAddress))?,
+                reaches_eid,
+                contacts,
+            },
+            _ => ConfigBundle::ReplaceContact { eid, reliability, cla_address, reaches_eid, contacts },
+        })
+    }
+}
+
+impl ConfigBundle {
+    /// Parse a sequence of config commands, separated by optional whitespace
+    pub fn parse_all(input: &str) -> Result<Vec<ConfigBundle>, ConfigParseError> {
+        let mut parser = Parser { input, position: 0 };
+        let mut result = Vec::new();
+
+        parser.skip_whitespace();
+        while !parser.at_end() {
+            result.push(parser.command()?);
+            parser.skip_whitespace();
+        }
+
+        Ok(result)
+    }
+}
+
+impl FromStr for ConfigBundle {
+    type Err = ConfigParseError;
+
+    /// Parse a single config command
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { input: s, position: 0 };
+        parser.skip_whitespace();
+        let result = parser.command()?;
+        parser.skip_whitespace();
+
+        match parser.peek() {
+            None => Ok(result),
+            Some(found) => Err(parser.error(parser.position, ConfigParseErrorKind::Unexpected { expected: "end of input", found })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    fn ts(timestamp: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp)
+    }
+
+    fn assert_round_trip(config: ConfigBundle) {
+        let parsed: ConfigBundle = config.to_string().parse().unwrap();
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn round_trip_add() {
+        assert_round_trip(ConfigBundle::AddContact {
+            eid: "dtn://ud3tn2.dtn/".into(),
+            reliability: None,
+            cla_address: "mtcp:127.0.0.1:4223".parse().unwrap(),
+            reaches_eid: Vec::new(),
+            contacts: vec![
+                Contact { start: ts(1689456940), end: ts(1689457000), data_rate: ContactDataRate::Limited(1200) },
+                Contact { start: ts(1689457060), end: ts(1689457120), data_rate: ContactDataRate::Unlimited },
+            ],
+        });
+        assert_round_trip(ConfigBundle::AddContact {
+            eid: "dtn://13714/".into(),
+            reliability: Some(333),
+            cla_address: ClaAddress::TcpSpp(None),
+            reaches_eid: vec!["dtn://18471/".into(), "dtn://81491/".into()],
+            contacts: Vec::new(),
+        });
+        assert_round_trip(ConfigBundle::AddContact {
+            eid: "ipn:2.0".into(),
+            reliability: None,
+            cla_address: "tcpclv3:[::1]:4556".parse().unwrap(),
+            reaches_eid: Vec::new(),
+            contacts: Vec::new(),
+        });
+        assert_round_trip(ConfigBundle::AddContact {
+            eid: "dtn://rover.dtn/".into(),
+            reliability: None,
+            cla_address: ClaAddress::Other { cla: "usbotg".into(), address: "/dev/ttyACM0".into() },
+            reaches_eid: Vec::new(),
+            contacts: Vec::new(),
+        });
+    }
+
+    #[test]
+    fn round_trip_replace() {
+        assert_round_trip(ConfigBundle::ReplaceContact {
+            eid: "dtn://ud3tn2.dtn/".into(),
+            reliability: Some(1000),
+            cla_address: Some("mtcp:127.0.0.1:4223".parse().unwrap()),
+            reaches_eid: vec!["dtn://89326/".into(), "dtn://12349/".into()],
+            contacts: vec![Contact { start: ts(1689456940), end: ts(1689457000), data_rate: ContactDataRate::Limited(9600) }],
+        });
+        assert_round_trip(ConfigBundle::ReplaceContact {
+            eid: "dtn://ud3tn2.dtn/".into(),
+            reliability: None,
+            cla_address: None,
+            reaches_eid: Vec::new(),
+            contacts: vec![Contact { start: ts(1689456940), end: ts(1689457000), data_rate: ContactDataRate::Unlimited }],
+        });
+    }
+
+    #[test]
+    fn round_trip_delete() {
+        assert_round_trip(ConfigBundle::DeleteContact("dtn://ud3tn2.dtn/".into()));
+    }
+
+    #[test]
+    fn parse_multiple_commands() {
+        let input = "1(dtn://a.dtn/):(mtcp:10.0.0.1:4224):[]:[];\n  2(dtn://b.dtn/),500::[(dtn://c.dtn/)];\r\n3(dtn://d.dtn/);\n";
+        let configs = ConfigBundle::parse_all(input).unwrap();
+
+        assert_eq!(configs, vec![
+            ConfigBundle::AddContact {
+                eid: "dtn://a.dtn/".into(),
+                reliability: None,
+                cla_address: "mtcp:10.0.0.1:4224".parse().unwrap(),
+                reaches_eid: Vec::new(),
+                contacts: Vec::new(),
+            },
+            ConfigBundle::ReplaceContact {
+                eid: "dtn://b.dtn/".into(),
+                reliability: Some(500),
+                cla_address: None,
+                reaches_eid: vec!["dtn://c.dtn/".into()],
+                contacts: Vec::new(),
+            },
+            ConfigBundle::DeleteContact("dtn://d.dtn/".into()),
+        ]);
+    }
+
+    #[test]
+    fn error_positions() {
+        let error = ConfigBundle::parse_all("3(dtn://a.dtn/);\n1(dtn://b.dtn/):(mtcp:x:1):[(dtn://c.dtn/)}];").unwrap_err();
+        assert_eq!(error.kind, ConfigParseErrorKind::Unexpected { expected: "',' or end of list", found: '}' });
+        assert_eq!((error.position, error.line, error.column), (59, 2, 43));
+
+        let error = "1(dtn://a.dtn/):(mtcp:127.0.0.1);".parse::<ConfigBundle>().unwrap_err();
+        assert_eq!(error.kind, ConfigParseErrorKind::InvalidClaAddress(ClaAddressError::MissingPort("127.0.0.1".into())));
+        assert_eq!(error.column, 18);
+
+        let error = "1(dtn://a.dtn/)::;".parse::<ConfigBundle>().unwrap_err();
+        assert_eq!(error.kind, ConfigParseErrorKind::MissingClaAddress);
+        assert_eq!(error.column, 17);
+
+        let error = "2(dtn://a.dtn/):::[{1,2,99999999999}];".parse::<ConfigBundle>().unwrap_err();
+        assert_eq!(error.kind, ConfigParseErrorKind::InvalidNumber("99999999999".into()));
+        assert_eq!(error.column, 25);
+
+        let error = "1(dtn://a/):(mtcp:1.2.3.4:1)::[{18446744073709551615,1,1}];".parse::<ConfigBundle>().unwrap_err();
+        assert_eq!(error.kind, ConfigParseErrorKind::InvalidNumber("18446744073709551615".into()));
+        assert_eq!(error.column, 33);
+
+        let error = "4(dtn://a.dtn/);".parse::<ConfigBundle>().unwrap_err();
+        assert_eq!(error.kind, ConfigParseErrorKind::UnknownCommand('4'));
+
+        let error = "3(dtn://a.dtn/);3(dtn://b.dtn/);".parse::<ConfigBundle>().unwrap_err();
+        assert_eq!(error.position, 16);
+
+        let error = "1(dtn://a.dtn/".parse::<ConfigBundle>().unwrap_err();
+        assert_eq!(error.kind, ConfigParseErrorKind::UnexpectedEnd);
+    }
+}
@@ -0,0 +1,183 @@
+//! Validation of config bundles before they are sent
+
+use std::time::SystemTime;
+
+use thiserror::Error;
+
+use super::{dtn_timestamp, ConfigBundle, Contact, ContactDataRate};
+
+/// Characters delimiting fields in ud3tn config syntax, forbidden inside EIDs and CLA addresses
+pub const RESERVED_CHARACTERS: [char; 3] = ['(', ')', ';'];
+
+/// Accepted reliability values
+pub const RELIABILITY_RANGE: std::ops::RangeInclusive<i32> = 100..=1000;
+
+/// A reason a [ConfigBundle] can't be sent to ud3tn
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConfigError {
+    /// Reliability isn't within [RELIABILITY_RANGE]
+    #[error("Reliability {0} out of range 100..=1000")]
+    InvalidReliability(i32),
+
+    /// Contact doesn't end after it starts
+    #[error("Contact ends before it starts")]
+    InvalidContactWindow {
+        /// Start of contact
+        start: SystemTime,
+        /// End of contact
+        end: SystemTime,
+    },
+
+    /// Time can't be represented as a DTN timestamp
+    #[error("Time is before DTN epoch (2000-01-01)")]
+    BeforeDtnEpoch(SystemTime),
+
+    /// Limited data rate is negative
+    #[error("Invalid data rate {0}")]
+    InvalidDataRate(i32),
+
+    /// A required field is empty
+    #[error("Empty {0}")]
+    Empty(&'static str),
+
+    /// A command doesn't fit in a config bundle of a [super::ConfigBatch]
+    #[error("Command of {size} bytes exceeds maximum payload size of {max} bytes")]
+    TooLarge {
+        /// Size of command
+        size: usize,
+        /// Maximum payload size
+        max: usize,
+    },
+
+    /// An atomic [super::ConfigBatch] doesn't fit in a single config bundle
+    #[error("Atomic batch of {size} bytes exceeds maximum payload size of {max} bytes")]
+    NotAtomic {
+        /// Size of all commands
+        size: usize,
+        /// Maximum payload size
+        max: usize,
+    },
+
+    /// A field contains one of [RESERVED_CHARACTERS]
+    #[error("{field} contains reserved character {character:?}")]
+    ReservedCharacter {
+        /// Name of field
+        field: &'static str,
+        /// Forbidden character found
+        character: char,
+    },
+}
+
+fn check_text(field: &'static str, value: &str) -> Result<(), ConfigError> {
+    if value.is_empty() {
+        return Err(ConfigError::Empty(field));
+    }
+    match value.chars().find(|it| RESERVED_CHARACTERS.contains(it)) {
+        Some(character) => Err(ConfigError::ReservedCharacter { field, character }),
+        None => Ok(()),
+    }
+}
+
+fn check_common(eid: &str, reliability: Option<i32>, reaches_eid: &[String], contacts: &[Contact]) -> Result<(), ConfigError> {
+    check_text("EID", eid)?;
+
+    if let Some(reliability) = reliability {
+        if !RELIABILITY_RANGE.contains(&reliability) {
+            return Err(ConfigError::InvalidReliability(reliability));
+        }
+    }
+
+    for reach in reaches_eid {
+        check_text("reachable EID", reach)?;
+    }
+
+    contacts.iter().try_for_each(Contact::validate)
+}
+
+impl Contact {
+    /// Check this contact can be sent to ud3tn
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        dtn_timestamp(self.start)?;
+        dtn_timestamp(self.end)?;
+
+        if self.end <= self.start {
+            return Err(ConfigError::InvalidContactWindow { start: self.start, end: self.end });
+        }
+
+        match self.data_rate {
+            ContactDataRate::Limited(rate) if rate < 0 => Err(ConfigError::InvalidDataRate(rate)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ConfigBundle {
+    /// Check this config bundle can be sent to ud3tn
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self {
+            ConfigBundle::AddContact { eid, reliability, cla_address, reaches_eid, contacts } => {
+                check_text("CLA address", &cla_address.to_string())?;
+                check_common(eid, *reliability, reaches_eid, contacts)
+            }
+            ConfigBundle::ReplaceContact { eid, reliability, cla_address, reaches_eid, contacts } => {
+                if let Some(cla_address) = cla_address {
+                    check_text("CLA address", &cla_address.to_string())?;
+                }
+                check_common(eid, *reliability, reaches_eid, contacts)
+            }
+            ConfigBundle::DeleteContact(eid) => check_text("EID", eid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::ClaAddress;
+
+    use super::*;
+
+    fn add(eid: &str, reliability: Option<i32>, cla_address: ClaAddress, contacts: Vec<Contact>) -> ConfigBundle {
+        ConfigBundle::AddContact {
+            eid: eid.into(),
+            reliability,
+            cla_address,
+            reaches_eid: Vec::new(),
+            contacts,
+        }
+    }
+
+    #[test]
+    fn invalid_bundles() {
+        let now = SystemTime::now();
+        let contact = Contact::from_during(now, Duration::from_secs(60), ContactDataRate::Unlimited);
+
+        assert_eq!(add("dtn://a.dtn/", Some(500), "mtcp:127.0.0.1:4224".parse().unwrap(), vec![contact.clone()]).validate(), Ok(()));
+        assert_eq!(
+            add("dtn://a.dtn/", Some(50), "mtcp:127.0.0.1:4224".parse().unwrap(), Vec::new()).validate(),
+            Err(ConfigError::InvalidReliability(50))
+        );
+        assert_eq!(
+            add("dtn://a.dtn/)", None, "mtcp:127.0.0.1:4224".parse().unwrap(), Vec::new()).validate(),
+            Err(ConfigError::ReservedCharacter { field: "EID", character: ')' })
+        );
+        assert_eq!(
+            add("dtn://a.dtn/", None, ClaAddress::File("/tmp/a;b".into()), Vec::new()).validate(),
+            Err(ConfigError::ReservedCharacter { field: "CLA address", character: ';' })
+        );
+        assert_eq!(
+            ConfigBundle::DeleteContact("dtn://a.dtn/;3(dtn://b.dtn/)".into()).validate(),
+            Err(ConfigError::ReservedCharacter { field: "EID", character: ';' })
+        );
+
+        let reversed = Contact { start: now, end: now - Duration::from_secs(1), data_rate: ContactDataRate::Unlimited };
+        assert!(matches!(reversed.validate(), Err(ConfigError::InvalidContactWindow { .. })));
+
+        let ancient = Contact::from_during(SystemTime::UNIX_EPOCH, Duration::from_secs(60), ContactDataRate::Unlimited);
+        assert_eq!(ancient.validate(), Err(ConfigError::BeforeDtnEpoch(SystemTime::UNIX_EPOCH)));
+
+        let negative = Contact::from_during(now, Duration::from_secs(60), ContactDataRate::Limited(-1));
+        assert_eq!(negative.validate(), Err(ConfigError::InvalidDataRate(-1)));
+    }
+}
@@ -0,0 +1,127 @@
+//! In-memory byte pipes used as in-process AAP streams
+
+use std::{collections::VecDeque, io, sync::{Condvar, Mutex, MutexGuard, PoisonError}, time::{Duration, Instant}};
+#[cfg(feature = "testing")]
+use std::{io::{Read, Write}, sync::Arc};
+
+#[derive(Debug, Default)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+/// A one way byte channel, reads block until bytes are written or pipe is closed
+#[derive(Debug, Default)]
+pub(crate) struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Append bytes for reader, fails with [io::ErrorKind::BrokenPipe] once closed
+    pub(crate) fn push(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buffer.extend(bytes);
+        self.readable.notify_all();
+        Ok(())
+    }
+
+    /// Read available bytes, 0 once closed and empty
+    ///
+    /// Fails with [io::ErrorKind::TimedOut] if nothing was written within `timeout`
+    pub(crate) fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let deadline = timeout.map(|it| Instant::now() + it);
+        let mut state = self.lock();
+        while state.buffer.is_empty() && !state.closed {
+            state = match deadline {
+                None => self.readable.wait(state).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    self.readable.wait_timeout(state, deadline - now).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+
+        let count = buf.len().min(state.buffer.len());
+        for (target, byte) in buf.iter_mut().zip(state.buffer.drain(..count)) {
+            *target = byte;
+        }
+        Ok(count)
+    }
+
+    /// Close pipe, waking up blocked readers
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+/// One end of an in-memory bidirectional byte stream, see [DuplexStream::pair]
+///
+/// Dropping an end closes both directions: reads on the other end return 0 and writes fail.
+#[cfg(feature = "testing")]
+#[derive(Debug)]
+pub struct DuplexStream {
+    inbound: Arc<Pipe>,
+    outbound: Arc<Pipe>,
+    read_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "testing")]
+impl DuplexStream {
+    /// Two connected ends, bytes written on one are read on the other
+    pub fn pair() -> (Self, Self) {
+        let (left, right) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        (
+            Self { inbound: left.clone(), outbound: right.clone(), read_timeout: None },
+            Self { inbound: right, outbound: left, read_timeout: None },
+        )
+    }
+
+    /// Fail reads with [io::ErrorKind::TimedOut] when nothing is received within `timeout`
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Pipe written by this end, to write from another thread while this end is being read
+    pub(crate) fn outbound(&self) -> Arc<Pipe> {
+        self.outbound.clone()
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.read(buf, self.read_timeout)
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.push(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.inbound.close();
+        self.outbound.close();
+    }
+}
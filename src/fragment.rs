@@ -0,0 +1,500 @@
+//! Application-level fragmentation of oversized messages
+//!
+//! A message is split into numbered fragments, each sent as its own bundle with a small header.
+//! On the receiving side, fragments are collected until the message is complete, whatever their arrival order.
+//!
+//! ```rust,no_run
+//! use std::path::Path;
+//! use ud3tn_aap::{Agent, fragment::FragmentingAgent};
+//!
+//! let agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("big-data".to_owned()).unwrap();
+//! let mut agent = FragmentingAgent::new(agent, 64 * 1024);
+//!
+//! agent.send_message("dtn://bob.dtn/big-data".into(), &vec![0; 1_000_000]).unwrap();
+//! let message = agent.recv_message().unwrap();
+//! ```
+
+use std::{collections::{BTreeMap, HashMap, VecDeque}, time::{Duration, SystemTime}};
+
+use thiserror::Error;
+
+use crate::{message::ParseError, wire, AapStream, BundleIdentifier, ReceivedBundle, RegisteredAgent};
+
+/// First byte of every fragment
+const MAGIC: u8 = 0xF7;
+
+/// Size of the header prepended to every fragment payload
+pub const HEADER_SIZE: usize = 17;
+
+/// Default time after which an incomplete message is dropped
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Default maximum number of fragments of a received message
+pub const DEFAULT_MAX_FRAGMENTS: u32 = 1 << 16;
+
+/// Default maximum size of a reassembled message
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Default maximum number of messages reassembled at once
+pub const DEFAULT_MAX_PARTIALS: usize = 64;
+
+/// Maximum number of received bundles kept when they aren't fragments, oldest are dropped first
+pub const MAX_UNHANDLED_BUNDLES: usize = 1024;
+
+/// A fragment of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    /// Identifier of the fragmented message, chosen by the sender
+    pub message_id: u64,
+
+    /// Position of this fragment in message, starting at 0
+    pub index: u32,
+
+    /// Total number of fragments in message
+    pub count: u32,
+
+    /// Part of message carried by this fragment
+    pub data: Vec<u8>,
+}
+
+impl Fragment {
+    /// Split a payload in fragments carrying at most `max_data_size` bytes each
+    ///
+    /// An empty payload produces a single empty fragment.
+    pub fn split(message_id: u64, payload: &[u8], max_data_size: usize) -> Result<Vec<Fragment>, FragmentError> {
+        let max_data_size = max_data_size.max(1);
+        let count = payload.len().div_ceil(max_data_size).max(1);
+        let count = u32::try_from(count).map_err(|_| FragmentError::TooManyFragments)?;
+
+        if payload.is_empty() {
+            return Ok(vec![Fragment { message_id, index: 0, count, data: Vec::new() }]);
+        }
+
+        Ok(payload.chunks(max_data_size)
+            .enumerate()
+            .map(|(index, data)| Fragment {
+                message_id,
+                index: index as u32,
+                count,
+                data: data.to_vec(),
+            })
+            .collect())
+    }
+
+    /// Serialize this fragment to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        result.push(MAGIC);
+        wire::put_u64(&mut result, self.message_id);
+        wire::put_u32(&mut result, self.index);
+        wire::put_u32(&mut result, self.count);
+        result.extend_from_slice(&self.data);
+        result
+    }
+
+    /// Parse a fragment from a bundle payload
+    pub fn parse(bytes: &[u8]) -> Result<Self, FragmentError> {
+        let mut reader = wire::Reader::new(bytes);
+        if reader.u8()? != MAGIC {
+            return Err(FragmentError::NotAFragment);
+        }
+
+        let message_id = reader.u64()?;
+        let index = reader.u32()?;
+        let count = reader.u32()?;
+        if count == 0 || index >= count {
+            return Err(FragmentError::InvalidIndex { index, count });
+        }
+
+        Ok(Self { message_id, index, count, data: reader.rest().to_vec() })
+    }
+}
+
+/// A message being reassembled
+#[derive(Debug)]
+struct PartialMessage {
+    count: u32,
+    fragments: BTreeMap<u32, Vec<u8>>,
+    size: usize,
+    first_seen: SystemTime,
+}
+
+/// Collects fragments until messages are complete
+///
+/// Fragments are indexed by message source and identifier.
+/// Duplicated fragments are ignored, as well as fragments of messages completed less than `timeout` ago.
+/// Messages announcing more than [DEFAULT_MAX_FRAGMENTS] fragments or growing over [DEFAULT_MAX_MESSAGE_SIZE] bytes
+/// are refused, see [Reassembler::with_max_fragments] and [Reassembler::with_max_message_size].
+/// At most [DEFAULT_MAX_PARTIALS] messages are reassembled at once, the oldest is dropped to make room for a new one,
+/// see [Reassembler::with_max_partials].
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    max_fragments: u32,
+    max_message_size: usize,
+    max_partials: usize,
+    partials: HashMap<(Option<String>, u64), PartialMessage>,
+    completed: HashMap<(Option<String>, u64), SystemTime>,
+}
+
+impl Reassembler {
+    /// Create a reassembler dropping incomplete messages after `timeout`
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_partials: DEFAULT_MAX_PARTIALS,
+            partials: HashMap::new(),
+            completed: HashMap::new(),
+        }
+    }
+
+    /// Refuse messages announcing more than `max_fragments` fragments
+    pub fn with_max_fragments(mut self, max_fragments: u32) -> Self {
+        self.max_fragments = max_fragments;
+        self
+    }
+
+    /// Refuse messages bigger than `max_message_size` bytes, dropping their received fragments
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Reassemble at most `max_partials` messages at once, dropping the oldest one when a new one starts
+    pub fn with_max_partials(mut self, max_partials: usize) -> Self {
+        self.max_partials = max_partials.max(1);
+        self
+    }
+
+    /// Add a received fragment
+    ///
+    /// Returns the whole message if this fragment completes it
+    pub fn push(&mut self, source: Option<&str>, fragment: Fragment, now: SystemTime) -> Result<Option<Vec<u8>>, FragmentError> {
+        let Fragment { message_id, index, count, data } = fragment;
+        if count == 0 || index >= count {
+            return Err(FragmentError::InvalidIndex { index, count });
+        }
+        if count > self.max_fragments {
+            return Err(FragmentError::TooManyFragments);
+        }
+
+        let key = (source.map(str::to_owned), message_id);
+        if self.completed.contains_key(&key) {
+            return Ok(None);
+        }
+        if !self.partials.contains_key(&key) && self.partials.len() >= self.max_partials {
+            let oldest = self.partials.iter()
+                .min_by_key(|(_, partial)| partial.first_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.partials.remove(&oldest);
+            }
+        }
+
+        let partial = self.partials.entry(key.clone()).or_insert_with(|| PartialMessage {
+            count,
+            fragments: BTreeMap::new(),
+            size: 0,
+            first_seen: now,
+        });
+
+        if partial.count != count {
+            return Err(FragmentError::InvalidIndex { index, count });
+        }
+        if partial.fragments.contains_key(&index) {
+            return Ok(None);
+        }
+
+        let size = partial.size.saturating_add(data.len());
+        if size > self.max_message_size {
+            self.partials.remove(&key);
+            return Err(FragmentError::MessageTooLarge { max: self.max_message_size });
+        }
+        partial.size = size;
+        partial.fragments.insert(index, data);
+
+        if partial.fragments.len() != count as usize {
+            return Ok(None);
+        }
+
+        let partial = self.partials.remove(&key).expect("partial message exists");
+        self.completed.insert(key, now);
+        Ok(Some(partial.fragments.into_values().flatten().collect()))
+    }
+
+    /// Drop incomplete messages older than timeout
+    ///
+    /// Returns (source, message identifier) of dropped messages
+    pub fn expire(&mut self, now: SystemTime) -> Vec<(Option<String>, u64)> {
+        let timeout = self.timeout;
+        let is_expired = |since: &SystemTime| now.duration_since(*since).map(|it| it >= timeout).unwrap_or(false);
+
+        self.completed.retain(|_, completed_at| !is_expired(completed_at));
+
+        let expired: Vec<(Option<String>, u64)> = self.partials.iter()
+            .filter(|(_, partial)| is_expired(&partial.first_seen))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired.iter() {
+            self.partials.remove(key);
+        }
+        expired
+    }
+
+    /// Number of messages waiting for missing fragments
+    pub fn pending(&self) -> usize {
+        self.partials.len()
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_TIMEOUT)
+    }
+}
+
+/// A registered agent sending and receiving fragmented messages
+pub struct FragmentingAgent<S: AapStream> {
+    agent: RegisteredAgent<S>,
+    max_fragment_size: usize,
+    reassembler: Reassembler,
+    unhandled: VecDeque<ReceivedBundle>,
+}
+
+impl<S: AapStream> FragmentingAgent<S> {
+    /// Wrap a registered agent, sending bundles of at most `max_fragment_size` bytes of payload (header included)
+    pub fn new(agent: RegisteredAgent<S>, max_fragment_size: usize) -> Self {
+        Self { agent, max_fragment_size, reassembler: Reassembler::default(), unhandled: VecDeque::new() }
+    }
+
+    /// Drop incomplete received messages after `timeout` instead of [DEFAULT_TIMEOUT]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.reassembler.timeout = timeout;
+        self
+    }
+
+    /// Refuse received messages announcing more than `max_fragments` fragments, see [Reassembler::with_max_fragments]
+    pub fn with_max_fragments(mut self, max_fragments: u32) -> Self {
+        self.reassembler.max_fragments = max_fragments;
+        self
+    }
+
+    /// Refuse received messages bigger than `max_message_size` bytes, see [Reassembler::with_max_message_size]
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.reassembler.max_message_size = max_message_size;
+        self
+    }
+
+    /// Reassemble at most `max_partials` received messages at once, see [Reassembler::with_max_partials]
+    pub fn with_max_partials(mut self, max_partials: usize) -> Self {
+        self.reassembler.max_partials = max_partials.max(1);
+        self
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// Unwrap the underlying registered agent
+    pub fn into_inner(self) -> RegisteredAgent<S> {
+        self.agent
+    }
+
+    /// Send a message split in as many bundles as needed
+    ///
+    /// Returns identifiers of sent bundles in fragment order
+    pub fn send_message(&mut self, destination_eid: String, payload: &[u8]) -> Result<Vec<BundleIdentifier>, FragmentError> {
+        let max_data_size = self.max_fragment_size.saturating_sub(HEADER_SIZE);
+        let fragments = Fragment::split(wire::new_message_id(), payload, max_data_size)?;
+
+        let mut identifiers = Vec::with_capacity(fragments.len());
+        for fragment in fragments {
+            identifiers.push(self.agent.send_bundle(destination_eid.clone(), &fragment.to_bytes())?);
+        }
+        Ok(identifiers)
+    }
+
+    /// Block until a whole message is received
+    ///
+    /// Incomplete messages older than timeout are dropped while waiting.
+    /// Bundles that aren't fragments are kept for [FragmentingAgent::take_unhandled], up to [MAX_UNHANDLED_BUNDLES].
+    pub fn recv_message(&mut self) -> Result<ReceivedBundle, FragmentError> {
+        loop {
+            let bundle = self.agent.recv_bundle()?;
+            let now = SystemTime::now();
+            self.reassembler.expire(now);
+
+            let fragment = match Fragment::parse(&bundle.payload) {
+                Ok(fragment) => fragment,
+                Err(FragmentError::NotAFragment | FragmentError::Malformed(_)) => {
+                    if self.unhandled.len() >= MAX_UNHANDLED_BUNDLES {
+                        self.unhandled.pop_front();
+                    }
+                    self.unhandled.push_back(bundle);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(payload) = self.reassembler.push(bundle.source.as_deref(), fragment, now)? {
+                return Ok(ReceivedBundle { source: bundle.source, payload });
+            }
+        }
+    }
+
+    /// Take the oldest received bundle that wasn't a fragment
+    pub fn take_unhandled(&mut self) -> Option<ReceivedBundle> {
+        self.unhandled.pop_front()
+    }
+}
+
+/// An error during fragmentation or reassembly
+#[derive(Debug, Error)]
+pub enum FragmentError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Received bundle doesn't start with a fragment header
+    #[error("Bundle is not a fragment")]
+    NotAFragment,
+
+    /// Fragment header is truncated
+    #[error("Malformed fragment")]
+    Malformed(#[from] ParseError),
+
+    /// Fragment index is out of its message bounds
+    #[error("Invalid fragment {index} of {count}")]
+    InvalidIndex {
+        /// Index of fragment
+        index: u32,
+        /// Number of fragments announced
+        count: u32,
+    },
+
+    /// Message would need more than [u32::MAX] fragments, or more than allowed by [Reassembler::with_max_fragments]
+    #[error("Too many fragments")]
+    TooManyFragments,
+
+    /// Reassembled message would be bigger than allowed by [Reassembler::with_max_message_size]
+    #[error("Message bigger than {max} bytes")]
+    MessageTooLarge {
+        /// Maximum message size in bytes
+        max: usize,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_and_parse() {
+        let payload: Vec<u8> = (0..=255).collect();
+        let fragments = Fragment::split(42, &payload, 100).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[2].data.len(), 56);
+
+        let bytes = fragments[1].to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 100);
+        assert_eq!(Fragment::parse(&bytes).unwrap(), fragments[1]);
+
+        assert_eq!(Fragment::split(1, &[], 100).unwrap().len(), 1);
+        assert!(matches!(Fragment::parse(b"hello"), Err(FragmentError::NotAFragment)));
+        assert!(matches!(Fragment::parse(&bytes[..10]), Err(FragmentError::Malformed(_))));
+    }
+
+    #[test]
+    fn reassemble_out_of_order_with_duplicates() {
+        let payload: Vec<u8> = (0..250).collect();
+        let mut fragments = Fragment::split(7, &payload, 60).unwrap();
+        fragments.reverse();
+        let now = SystemTime::now();
+
+        let mut reassembler = Reassembler::default();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments.iter() {
+            assert_eq!(reassembler.push(Some("dtn://a/"), fragment.clone(), now).unwrap(), None);
+            assert_eq!(reassembler.push(Some("dtn://a/"), fragment.clone(), now).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(Some("dtn://b/"), last.clone(), now).unwrap(), None);
+        assert_eq!(reassembler.push(Some("dtn://a/"), last.clone(), now).unwrap(), Some(payload));
+        assert_eq!(reassembler.pending(), 1);
+
+        // Late duplicate of a completed message
+        assert_eq!(reassembler.push(Some("dtn://a/"), last, now).unwrap(), None);
+        assert_eq!(reassembler.pending(), 1);
+    }
+
+    #[test]
+    fn refuse_oversized_messages() {
+        let now = SystemTime::now();
+        let mut reassembler = Reassembler::default().with_max_fragments(4).with_max_message_size(10);
+
+        let huge = Fragment { message_id: 1, index: 0, count: u32::MAX, data: vec![1] };
+        assert!(matches!(reassembler.push(None, huge, now), Err(FragmentError::TooManyFragments)));
+        let outside = Fragment { message_id: 1, index: 2, count: 2, data: vec![1] };
+        assert!(matches!(reassembler.push(None, outside, now), Err(FragmentError::InvalidIndex { index: 2, count: 2 })));
+        let empty = Fragment { message_id: 1, index: 0, count: 0, data: vec![] };
+        assert!(matches!(reassembler.push(None, empty, now), Err(FragmentError::InvalidIndex { .. })));
+
+        let fragments = Fragment::split(2, &[0; 12], 4).unwrap();
+        assert_eq!(reassembler.push(None, fragments[0].clone(), now).unwrap(), None);
+        assert_eq!(reassembler.push(None, fragments[1].clone(), now).unwrap(), None);
+        assert!(matches!(
+            reassembler.push(None, fragments[2].clone(), now),
+            Err(FragmentError::MessageTooLarge { max: 10 })
+        ));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn oldest_partial_evicted() {
+        let start = SystemTime::now();
+        let mut reassembler = Reassembler::default().with_max_partials(2);
+
+        for (id, delay) in [(1, 0), (2, 1), (3, 2)] {
+            let fragments = Fragment::split(id, &[1, 2], 1).unwrap();
+            reassembler.push(None, fragments[0].clone(), start + Duration::from_secs(delay)).unwrap();
+        }
+        assert_eq!(reassembler.pending(), 2);
+
+        let first = Fragment::split(1, &[1, 2], 1).unwrap();
+        let second = Fragment::split(2, &[1, 2], 1).unwrap();
+        assert_eq!(reassembler.push(None, second[1].clone(), start).unwrap(), Some(vec![1, 2]));
+        assert_eq!(reassembler.push(None, first[1].clone(), start).unwrap(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn plain_bundles_kept() {
+        use crate::{testing::MockNode, Agent};
+
+        let node = MockNode::new("dtn://a.dtn/");
+        let mut sender = FragmentingAgent::new(Agent::new(node.connect()).unwrap().register("sender".into()).unwrap(), 20);
+        let mut receiver = FragmentingAgent::new(Agent::new(node.connect()).unwrap().register("receiver".into()).unwrap(), 20);
+
+        assert!(node.deliver("dtn://a.dtn/receiver", "dtn://b.dtn/app", b"plain"));
+        sender.send_message("dtn://a.dtn/receiver".into(), b"fragmented message").unwrap();
+
+        assert_eq!(receiver.recv_message().unwrap().payload, b"fragmented message");
+        assert_eq!(receiver.take_unhandled().unwrap().payload, b"plain");
+        assert!(receiver.take_unhandled().is_none());
+    }
+
+    #[test]
+    fn expire_incomplete_messages() {
+        let fragments = Fragment::split(3, &[1, 2, 3, 4], 2).unwrap();
+        let start = SystemTime::now();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        reassembler.push(None, fragments[0].clone(), start).unwrap();
+
+        assert!(reassembler.expire(start + Duration::from_secs(30)).is_empty());
+        assert_eq!(reassembler.expire(start + Duration::from_secs(60)), vec![(None, 3)]);
+        assert_eq!(reassembler.push(None, fragments[1].clone(), start + Duration::from_secs(61)).unwrap(), None);
+    }
+}
@@ -0,0 +1,330 @@
+//! Durable inbox with duplicate detection and acknowledged consumption
+//!
+//! Received bundles are written to an append-only log before being handed out.
+//! Consumers acknowledge processed bundles with [Inbox::ack], unacknowledged bundles are handed out again
+//! after a restart.
+//! Bundles with the same source and payload hash as a bundle received within the duplicate window are dropped.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::Duration};
+//! use ud3tn_aap::{Agent, inbox::Inbox};
+//!
+//! let mut agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("reports".to_owned()).unwrap();
+//! let mut inbox = Inbox::open(Path::new("/var/lib/app/inbox.log"), Duration::from_secs(24 * 60 * 60)).unwrap();
+//!
+//! loop {
+//!     let entry = inbox.receive(&mut agent).unwrap();
+//!     println!("{} bytes from {:?}", entry.payload.len(), entry.source);
+//!     inbox.ack(entry.sequence).unwrap();
+//! }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{journal::Journal, message::ParseError, wire, AapStream, ReceivedBundle, RegisteredAgent};
+
+const RECORD_RECEIVED: u8 = 0;
+const RECORD_ACKED: u8 = 1;
+
+/// Fingerprint of an acknowledged bundle, written on compaction to keep detecting its duplicates
+const RECORD_SEEN: u8 = 2;
+
+/// Next sequence number, written on compaction so sequence numbers are never reused
+const RECORD_NEXT_SEQUENCE: u8 = 3;
+
+/// A bundle stored in the inbox
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboxEntry {
+    /// Sequence number of entry, increasing in reception order
+    pub sequence: u64,
+
+    /// Source endpoint ID of bundle
+    pub source: Option<String>,
+
+    /// Content of bundle
+    pub payload: Vec<u8>,
+
+    /// When bundle was stored in inbox
+    pub received_at: SystemTime,
+}
+
+/// Source and payload hash identifying duplicated bundles
+type Fingerprint = (Option<String>, [u8; 32]);
+
+/// A file-backed store of received bundles
+#[derive(Debug)]
+pub struct Inbox {
+    journal: Journal,
+    window: Duration,
+    entries: BTreeMap<u64, InboxEntry>,
+    seen: HashMap<Fingerprint, SystemTime>,
+
+    /// Unacknowledged entries already handed out by this process
+    delivered: HashSet<u64>,
+    next_sequence: u64,
+}
+
+impl Inbox {
+    /// Open or create an inbox log, detecting duplicates received within `window`
+    pub fn open(path: &Path, window: Duration) -> Result<Self, InboxError> {
+        let (journal, records) = Journal::open(path)?;
+        let mut new_self = Self {
+            journal,
+            window,
+            entries: BTreeMap::new(),
+            seen: HashMap::new(),
+            delivered: HashSet::new(),
+            next_sequence: 0,
+        };
+
+        for record in records {
+            let mut reader = wire::Reader::new(&record);
+            match reader.u8()? {
+                RECORD_RECEIVED => {
+                    let sequence = reader.u64()?;
+                    let received_at = from_timestamp(reader.u64()?);
+                    let source = read_source(&mut reader)?;
+                    let payload = reader.bytes()?.to_vec();
+
+                    new_self.seen.insert((source.clone(), fingerprint(&payload)), received_at);
+                    new_self.next_sequence = new_self.next_sequence.max(sequence + 1);
+                    new_self.entries.insert(sequence, InboxEntry { sequence, source, payload, received_at });
+                }
+                RECORD_ACKED => {
+                    new_self.entries.remove(&reader.u64()?);
+                }
+                RECORD_SEEN => {
+                    let received_at = from_timestamp(reader.u64()?);
+                    let source = read_source(&mut reader)?;
+                    let hash: [u8; 32] = reader.take(32)?.try_into()?;
+                    new_self.seen.insert((source, hash), received_at);
+                }
+                RECORD_NEXT_SEQUENCE => {
+                    new_self.next_sequence = new_self.next_sequence.max(reader.u64()?);
+                }
+                kind => return Err(InboxError::Malformed(ParseError::UnknownType(kind))),
+            }
+        }
+
+        Ok(new_self)
+    }
+
+    /// Hand out the next bundle
+    ///
+    /// Unacknowledged bundles not yet handed out by this process are returned first,
+    /// then blocks until a bundle that isn't a duplicate is received and stored.
+    pub fn receive<S: AapStream>(&mut self, agent: &mut RegisteredAgent<S>) -> Result<InboxEntry, InboxError> {
+        if let Some(entry) = self.entries.values().find(|it| !self.delivered.contains(&it.sequence)) {
+            self.delivered.insert(entry.sequence);
+            return Ok(entry.clone());
+        }
+
+        loop {
+            let bundle = agent.recv_bundle()?;
+            if let Some(sequence) = self.store(bundle, SystemTime::now())? {
+                self.delivered.insert(sequence);
+                return Ok(self.entries[&sequence].clone());
+            }
+        }
+    }
+
+    /// Store a received bundle
+    ///
+    /// Returns its sequence number, [None] if it is a duplicate
+    pub fn store(&mut self, bundle: ReceivedBundle, now: SystemTime) -> Result<Option<u64>, InboxError> {
+        let window = self.window;
+        self.seen.retain(|_, at| now.duration_since(*at).unwrap_or(Duration::ZERO) < window);
+
+        let key = (bundle.source.clone(), fingerprint(&bundle.payload));
+        if self.seen.contains_key(&key) {
+            return Ok(None);
+        }
+
+        let sequence = self.next_sequence;
+        let mut record = vec![RECORD_RECEIVED];
+        wire::put_u64(&mut record, sequence);
+        wire::put_u64(&mut record, to_timestamp(now));
+        put_source(&mut record, &bundle.source);
+        wire::put_bytes(&mut record, &bundle.payload);
+        self.journal.append(&record)?;
+
+        self.next_sequence += 1;
+        self.seen.insert(key, now);
+        self.entries.insert(sequence, InboxEntry {
+            sequence,
+            source: bundle.source,
+            payload: bundle.payload,
+            received_at: now,
+        });
+        Ok(Some(sequence))
+    }
+
+    /// Acknowledge a processed entry, it won't be handed out again
+    pub fn ack(&mut self, sequence: u64) -> Result<(), InboxError> {
+        if !self.entries.contains_key(&sequence) {
+            return Err(InboxError::UnknownEntry(sequence));
+        }
+
+        let mut record = vec![RECORD_ACKED];
+        wire::put_u64(&mut record, sequence);
+        self.journal.append(&record)?;
+
+        self.entries.remove(&sequence);
+        self.delivered.remove(&sequence);
+        Ok(())
+    }
+
+    /// Entries not acknowledged yet, in reception order
+    pub fn unacked(&self) -> impl Iterator<Item = &InboxEntry> {
+        self.entries.values()
+    }
+
+    /// Rewrite the log without acknowledged entries
+    ///
+    /// Fingerprints of entries received within the duplicate window are kept
+    pub fn compact(&mut self) -> Result<(), InboxError> {
+        let now = SystemTime::now();
+        let window = self.window;
+        self.seen.retain(|_, at| now.duration_since(*at).unwrap_or(Duration::ZERO) < window);
+
+        let mut records = Vec::new();
+        let mut record = vec![RECORD_NEXT_SEQUENCE];
+        wire::put_u64(&mut record, self.next_sequence);
+        records.push(record);
+
+        for ((source, hash), at) in self.seen.iter() {
+            let mut record = vec![RECORD_SEEN];
+            wire::put_u64(&mut record, to_timestamp(*at));
+            put_source(&mut record, source);
+            record.extend_from_slice(hash);
+            records.push(record);
+        }
+
+        for entry in self.entries.values() {
+            let mut record = vec![RECORD_RECEIVED];
+            wire::put_u64(&mut record, entry.sequence);
+            wire::put_u64(&mut record, to_timestamp(entry.received_at));
+            put_source(&mut record, &entry.source);
+            wire::put_bytes(&mut record, &entry.payload);
+            records.push(record);
+        }
+
+        self.journal.rewrite(records)?;
+        Ok(())
+    }
+}
+
+fn fingerprint(payload: &[u8]) -> [u8; 32] {
+    Sha256::digest(payload).into()
+}
+
+fn to_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|it| it.as_millis() as u64).unwrap_or(0)
+}
+
+fn from_timestamp(millis: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+fn put_source(target: &mut Vec<u8>, source: &Option<String>) {
+    match source {
+        Some(source) => {
+            target.push(1);
+            wire::put_string(target, source);
+        }
+        None => target.push(0),
+    }
+}
+
+fn read_source(reader: &mut wire::Reader) -> Result<Option<String>, ParseError> {
+    match reader.u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(reader.string()?)),
+    }
+}
+
+/// An error of the inbox
+#[derive(Debug, Error)]
+pub enum InboxError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Error reading or writing the log
+    #[error("io Error")]
+    IOError(#[from] io::Error),
+
+    /// A log record is malformed
+    #[error("Malformed inbox record")]
+    Malformed(#[from] ParseError),
+
+    /// Entry doesn't exist or is already acknowledged
+    #[error("Unknown entry {0}")]
+    UnknownEntry(u64),
+}
+
+impl From<std::array::TryFromSliceError> for InboxError {
+    fn from(value: std::array::TryFromSliceError) -> Self {
+        Self::Malformed(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::journal::temp_log_path;
+
+    use super::*;
+
+    fn bundle(source: &str, payload: &[u8]) -> ReceivedBundle {
+        ReceivedBundle { source: Some(source.into()), payload: payload.to_vec() }
+    }
+
+    #[test]
+    fn unacked_entries_survive_restart() {
+        let path = temp_log_path("inbox-restart");
+        let now = SystemTime::now();
+        let mut inbox = Inbox::open(&path, Duration::from_secs(60)).unwrap();
+        assert_eq!(inbox.store(bundle("dtn://a/app", b"one"), now).unwrap(), Some(0));
+        assert_eq!(inbox.store(bundle("dtn://a/app", b"two"), now).unwrap(), Some(1));
+        inbox.ack(0).unwrap();
+        assert!(matches!(inbox.ack(0), Err(InboxError::UnknownEntry(0))));
+        drop(inbox);
+
+        let inbox = Inbox::open(&path, Duration::from_secs(60)).unwrap();
+        let unacked: Vec<&InboxEntry> = inbox.unacked().collect();
+        assert_eq!(unacked.len(), 1);
+        assert_eq!(unacked[0].payload, b"two");
+        assert_eq!(unacked[0].source.as_deref(), Some("dtn://a/app"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn duplicates_within_window() {
+        let path = temp_log_path("inbox-duplicates");
+        let now = SystemTime::now();
+        let mut inbox = Inbox::open(&path, Duration::from_secs(60)).unwrap();
+        assert!(inbox.store(bundle("dtn://a/app", b"same"), now).unwrap().is_some());
+        assert!(inbox.store(bundle("dtn://b/app", b"same"), now).unwrap().is_some());
+        assert!(inbox.store(bundle("dtn://a/app", b"same"), now + Duration::from_secs(30)).unwrap().is_none());
+        inbox.ack(0).unwrap();
+        inbox.compact().unwrap();
+        drop(inbox);
+
+        let mut inbox = Inbox::open(&path, Duration::from_secs(60)).unwrap();
+        assert_eq!(inbox.unacked().count(), 1);
+        assert!(inbox.store(bundle("dtn://a/app", b"same"), now + Duration::from_secs(30)).unwrap().is_none());
+        assert_eq!(inbox.store(bundle("dtn://a/app", b"same"), now + Duration::from_secs(61)).unwrap(), Some(2));
+        fs::remove_file(path).unwrap();
+    }
+}
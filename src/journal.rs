@@ -0,0 +1,92 @@
+//! Append-only record log used by durable queues
+//!
+//! Each record is framed as (length, content, checksum). A torn record at the end of the log,
+//! left by a crash during an append, is dropped when the log is opened.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// FNV-1a hash of a record, detecting torn or corrupted records
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5_u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+fn frame(record: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(record.len() + 8);
+    result.extend_from_slice(&(record.len() as u32).to_be_bytes());
+    result.extend_from_slice(record);
+    result.extend_from_slice(&checksum(record).to_be_bytes());
+    result
+}
+
+/// An open record log
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Open or create a log and read its valid records
+    pub(crate) fn open(path: &Path) -> io::Result<(Self, Vec<Vec<u8>>)> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some(header) = content.get(offset..offset + 4) {
+            let length = u32::from_be_bytes(header.try_into().expect("header is 4 bytes")) as usize;
+            let Some(record) = content.get(offset + 4..offset + 4 + length) else { break };
+            let Some(sum) = content.get(offset + 4 + length..offset + 8 + length) else { break };
+            if u32::from_be_bytes(sum.try_into().expect("checksum is 4 bytes")) != checksum(record) {
+                break;
+            }
+
+            records.push(record.to_vec());
+            offset += length + 8;
+        }
+
+        if offset != content.len() {
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+
+        Ok((Self { path: path.to_owned(), file }, records))
+    }
+
+    /// Append a record and wait until it is written to disk
+    pub(crate) fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all(&frame(record))?;
+        self.file.sync_data()
+    }
+
+    /// Atomically replace the content of the log
+    pub(crate) fn rewrite<I: IntoIterator<Item = Vec<u8>>>(&mut self, records: I) -> io::Result<()> {
+        let temporary_path = self.path.with_extension("compact");
+        let mut temporary = File::create(&temporary_path)?;
+        for record in records {
+            temporary.write_all(&frame(&record))?;
+        }
+        temporary.sync_all()?;
+        fs::rename(&temporary_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Path of a new log in the temporary directory, removed if a previous test run left it
+#[cfg(test)]
+pub(crate) fn temp_log_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ud3tn-aap-{}-{}.log", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
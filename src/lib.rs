@@ -1,16 +1,51 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
-use std::{fmt::Debug, io::{Read, Write}, os::unix::net::UnixStream};
+use std::{collections::VecDeque, fmt::Debug, io::{Read, Write}, os::unix::net::UnixStream};
 use std::path::Path;
 
-use config::ConfigBundle;
+use config::{ConfigBatch, ConfigBundle};
 use message::ParseError;
 pub use message::{ReceivedBundle, BundleIdentifier, Message, DtnTime};
 use thiserror::Error;
 
 pub mod message;
 pub mod config;
+pub mod fragment;
+pub mod reliable;
+pub mod rpc;
+pub mod pubsub;
+pub mod outbox;
+pub mod ratelimit;
+pub mod priority;
+pub mod schedule;
+pub mod plan;
+pub mod routing;
+#[cfg(feature = "bpv6")]
+pub mod bpv6;
+#[cfg(feature = "serde")]
+pub mod typed;
+#[cfg(feature = "transfer")]
+pub mod transfer;
+#[cfg(feature = "inbox")]
+pub mod inbox;
+#[cfg(feature = "sim")]
+pub mod sim;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(any(feature = "sim", feature = "testing"))]
+mod duplex;
+mod journal;
+mod wire;
+
+/// Endpoint ID of an agent registered on a node, `dtn://node/agent` or `ipn:node.agent`
+pub fn endpoint_id(node_eid: &str, agent_id: &str) -> String {
+    match node_eid.strip_suffix(".0") {
+        Some(node) if node.starts_with("ipn:") => format!("{}.{}", node, agent_id),
+        _ => format!("{}{}", node_eid, agent_id),
+    }
+}
 
 /// Any stream matching requirements to be used as an ud3tn aap source
 /// 
@@ -83,7 +118,8 @@ impl<S: AapStream> Agent<S> {
         self.send_request(Message::Register(agent_id.clone()))?;
         Ok(RegisteredAgent {
             inner: self,
-            agent_id
+            agent_id,
+            pending_bundles: VecDeque::new()
         })
     }
 
@@ -102,20 +138,16 @@ impl<S: AapStream> Agent<S> {
     fn recv_message(&mut self) -> Result<Message, Error> {
         let mut buffer = [0;1024];
         loop {
-            let byte_red = self.stream.read(&mut buffer)?;
-
-            if byte_red > 0 {
-                self.recv_buffer.extend_from_slice(&buffer[0..byte_red]);
-            }
-
+            // A previous read may already have buffered a whole message
             let (mess, consumed_bytes) = match Message::parse_buffer(&self.recv_buffer) {
                 Ok(it) => it,
                 Err(message::ParseError::UnexpectedEnd) => {
+                    let byte_red = self.stream.read(&mut buffer)?;
                     if byte_red == 0 {
                         return Err(Error::UnexpectedEnd)
-                    } else {
-                        continue;
                     }
+                    self.recv_buffer.extend_from_slice(&buffer[0..byte_red]);
+                    continue;
                 },
                 Err(e) => return Err(Error::MalformedMessage(e))
             };
@@ -142,7 +174,10 @@ impl<S:AapStream> BaseAgent<S> for Agent<S> {
 /// AAn agent that was registered and abto to send and receive bundles
 pub struct RegisteredAgent<S: AapStream> {
     inner: Agent<S>,
-    agent_id: String
+    agent_id: String,
+
+    /// Bundles received while waiting for a [Message::SendConfirm]
+    pending_bundles: VecDeque<ReceivedBundle>
 }
 
 impl<S: AapStream> RegisteredAgent<S> {
@@ -152,24 +187,54 @@ impl<S: AapStream> RegisteredAgent<S> {
         &self.agent_id
     }
 
+    /// Endpoint ID of this agent, see [endpoint_id]
+    pub fn endpoint_id(&self) -> String {
+        endpoint_id(self.node_id(), &self.agent_id)
+    }
+
     /// Send a bundle to ud3tn node to route it
     /// 
     /// Bundle is sent with this agent as source.
     /// 
     /// Returns bundle identifier as [`u64`]
     pub fn send_bundle(&mut self, destination_eid: String, payload:&[u8]) -> Result<BundleIdentifier, Error>{
+        self.write_bundle(destination_eid, payload)?;
+        self.recv_send_confirm()
+    }
+
+    /// Write a [Message::SendBundle] without waiting for its confirmation
+    ///
+    /// The node doesn't accept the bundle if this fails, as it only handles whole messages
+    pub(crate) fn write_bundle(&mut self, destination_eid: String, payload:&[u8]) -> Result<(), Error> {
         let message = Message::SendBundle(destination_eid, std::borrow::Cow::Borrowed(payload));
         self.inner.stream.write_all(&message.to_bytes())?;
-        match self.inner.recv_message()? {
-            Message::SendConfirm(identifier) => Ok(identifier),
-            _ => Err(Error::UnexpectedMessage)
+        Ok(())
+    }
+
+    /// Wait for the [Message::SendConfirm] of a written bundle, keeping bundles received meanwhile
+    pub(crate) fn recv_send_confirm(&mut self) -> Result<BundleIdentifier, Error> {
+        loop {
+            match self.inner.recv_message()? {
+                Message::SendConfirm(identifier) => return Ok(identifier),
+                Message::RecvBundle(source, content) => self.pending_bundles.push_back(ReceivedBundle {
+                    source: Some(source),
+                    payload: content.into_owned()
+                }),
+                _ => return Err(Error::UnexpectedMessage)
+            }
         }
     }
 
     /// Block until a bundle is received from ud3tn node adressed to this agent
     /// 
     /// If something other than a bundle is received [`Err(Error::UnexpectedMessage)`] is returned
+    /// 
+    /// Bundles received while [RegisteredAgent::send_bundle] was waiting for its confirmation are returned first
     pub fn recv_bundle(&mut self) -> Result<ReceivedBundle, Error> {
+        if let Some(bundle) = self.pending_bundles.pop_front() {
+            return Ok(bundle)
+        }
+
         match self.inner.recv_message()? {
             Message::RecvBundle(source, content) => Ok(ReceivedBundle {
                 source: Some(source),
@@ -180,12 +245,33 @@ impl<S: AapStream> RegisteredAgent<S> {
     }
 
     /// Send a configuration bundle to ud3tn node
+    ///
+    /// Fails with [Error::InvalidConfig] without sending anything if bundle doesn't pass [ConfigBundle::validate]
     pub fn send_config(&mut self, config:ConfigBundle) -> Result<(), Error> {
+        config.validate()?;
         match self.send_bundle(format!("{0}config", self.inner.node_eid), &config.to_bytes()) {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
         }
     }
+
+    /// Send several configuration commands using as few bundles as possible
+    ///
+    /// See [ConfigBatch]. Nothing is sent if a command is invalid, commands may be split across bundles
+    pub fn send_config_batch(&mut self, configs: &[ConfigBundle]) -> Result<(), Error> {
+        self.send_batch(&configs.iter().cloned().collect())
+    }
+
+    /// Send a batch of configuration commands, one bundle per payload in order
+    ///
+    /// Nothing is sent if a command is invalid. The batch is only applied atomically by the node if it fits in
+    /// a single payload, see [ConfigBatch::with_atomic]
+    pub fn send_batch(&mut self, batch: &ConfigBatch) -> Result<(), Error> {
+        for payload in batch.payloads()? {
+            self.send_bundle(format!("{0}config", self.inner.node_eid), &payload)?;
+        }
+        Ok(())
+    }
 }
 
 impl<S:AapStream> BaseAgent<S> for RegisteredAgent<S> {
@@ -221,5 +307,101 @@ pub enum Error {
 
     /// Stream ended before a message was fully received
     #[error("Unexpected end")]
-    UnexpectedEnd
-}
\ No newline at end of file
+    UnexpectedEnd,
+
+    /// Config bundle refused before sending
+    #[error("Invalid config bundle")]
+    InvalidConfig(#[from] config::ConfigError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, io::{self, Cursor}};
+
+    use super::*;
+
+    /// Stream replying one scripted message per read, then failing instead of blocking
+    struct Replies(VecDeque<Vec<u8>>);
+
+    impl Read for Replies {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let reply = self.0.pop_front().ok_or(io::ErrorKind::WouldBlock)?;
+            buf[..reply.len()].copy_from_slice(&reply);
+            Ok(reply.len())
+        }
+    }
+
+    impl Write for Replies {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Stream replying with scripted bytes in a single read, then failing instead of blocking
+    struct Scripted(Cursor<Vec<u8>>);
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                count => Ok(count),
+            }
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn agent_endpoint_id() {
+        assert_eq!(endpoint_id("ipn:42.0", "7"), "ipn:42.7");
+        assert_eq!(endpoint_id("dtn://a.dtn/", "sink"), "dtn://a.dtn/sink");
+    }
+
+    #[test]
+    fn bundles_received_before_send_confirm() {
+        let script = [
+            Message::Welcome("dtn://a.dtn/".into()),
+            Message::Ack,
+            Message::RecvBundle("dtn://b.dtn/app".into(), b"early".into()),
+            Message::RecvBundle("dtn://c.dtn/app".into(), b"later".into()),
+            Message::SendConfirm(BundleIdentifier::from(42)),
+        ];
+        let stream = Replies(script.iter().map(Message::to_bytes).collect());
+
+        let mut agent = Agent::new(stream).unwrap().register("app".into()).unwrap();
+        assert_eq!(agent.send_bundle("dtn://b.dtn/app".into(), b"hi").unwrap(), BundleIdentifier::from(42));
+
+        let early = agent.recv_bundle().unwrap();
+        assert_eq!((early.source.as_deref(), early.payload.as_slice()), (Some("dtn://b.dtn/app"), &b"early"[..]));
+        assert_eq!(agent.recv_bundle().unwrap().payload, b"later");
+        assert!(matches!(agent.recv_bundle(), Err(Error::IOError(_))));
+    }
+
+    #[test]
+    fn messages_received_in_one_read() {
+        let script = [
+            Message::Welcome("dtn://a.dtn/".into()),
+            Message::Ack,
+            Message::RecvBundle("dtn://b.dtn/app".into(), b"hello".into()),
+            Message::SendConfirm(BundleIdentifier::from(42)),
+        ];
+        let stream = Scripted(Cursor::new(script.iter().flat_map(Message::to_bytes).collect()));
+
+        let mut agent = Agent::new(stream).unwrap().register("app".into()).unwrap();
+        assert_eq!(agent.send_bundle("dtn://b.dtn/app".into(), b"hi").unwrap(), BundleIdentifier::from(42));
+        assert_eq!(agent.recv_bundle().unwrap().payload, b"hello");
+        assert!(matches!(agent.recv_bundle(), Err(Error::IOError(_))));
+    }
+}
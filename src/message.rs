@@ -221,13 +221,14 @@ impl<'a> Message<'a> {
     /// 
     /// Returns a tuple of (Parsed message, number of bytes consumed in buffer)
     pub fn parse_buffer(bytes: &[u8]) -> Result<(Self, usize), ParseError> {
-        let version = (bytes[0] & 0b11110000) >> 4;
+        let first = *bytes.first().ok_or(ParseError::UnexpectedEnd)?;
+        let version = (first & 0b11110000) >> 4;
 
         if version != 0x1 {
             return Err(ParseError::VersionNotSupported);
         }
 
-        let message_type = bytes[0] & 0b00001111;
+        let message_type = first & 0b00001111;
         let mut offset = 1;
 
         let message = match message_type {
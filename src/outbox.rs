@@ -0,0 +1,416 @@
+//! Durable outbox queuing bundles while the node is unreachable, with effectively exactly-once handoff
+//!
+//! Sends are accepted at any time and persisted in an append-only log, then handed to the node by
+//! [Outbox::drain] when an agent is connected. The [BundleIdentifier] returned by the node is recorded for each entry.
+//!
+//! AAP has no way to ask the node whether it accepted a bundle. Before an entry is handed to the node, an intent
+//! record is written to disk. If writing the bundle to the node fails, the node didn't accept it and the entry
+//! is queued again. If the process crashes after the intent record, or the connection fails after the bundle was
+//! written but before the node confirmation is recorded, the entry is [EntryState::Uncertain] and the next
+//! [Outbox::drain] hands it to the node again, so no entry is lost.
+//!
+//! A bundle handed twice is sent again by the same agent with the same payload, which an `inbox::Inbox` (`inbox`
+//! feature) at the destination detects as a duplicate. With an inbox whose duplicate window is longer than the
+//! outbox takes to resend, each entry is processed once. Entries with the same payload and destination are
+//! duplicates to the inbox too, include a unique identifier in payloads that may repeat.
+//!
+//! ```rust,no_run
+//! use std::path::Path;
+//! use ud3tn_aap::{Agent, outbox::Outbox};
+//!
+//! let mut outbox = Outbox::open(Path::new("/var/lib/app/outbox.log")).unwrap();
+//! outbox.enqueue("dtn://hq.dtn/reports".into(), b"report").unwrap();
+//!
+//! if let Ok(agent) = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")) {
+//!     let mut agent = agent.register("reports".to_owned()).unwrap();
+//!     outbox.drain(&mut agent).unwrap();
+//!     outbox.compact().unwrap();
+//! }
+//! ```
+
+use std::{collections::BTreeMap, io, path::Path};
+
+use thiserror::Error;
+
+use crate::{journal::Journal, message::ParseError, wire, AapStream, BundleIdentifier, RegisteredAgent};
+
+const RECORD_ENQUEUED: u8 = 0;
+const RECORD_SENDING: u8 = 1;
+const RECORD_HANDED_OFF: u8 = 2;
+const RECORD_REQUEUED: u8 = 3;
+const RECORD_DISCARDED: u8 = 4;
+
+/// Next sequence number, written on compaction so sequence numbers are never reused
+const RECORD_NEXT_SEQUENCE: u8 = 5;
+
+/// State of an outbox entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryState {
+    /// Waiting to be handed to the node
+    Queued,
+
+    /// Handing to the node was interrupted, the node may or may not have accepted it, handed again by next drain
+    Uncertain,
+
+    /// Accepted by the node
+    HandedOff(BundleIdentifier),
+}
+
+/// A bundle stored in the outbox
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    /// Sequence number of entry, increasing in enqueue order
+    pub sequence: u64,
+
+    /// Destination EID of bundle
+    pub destination: String,
+
+    /// Payload of bundle
+    pub payload: Vec<u8>,
+
+    /// Current state of entry
+    pub state: EntryState,
+}
+
+/// A file-backed queue of bundles to send
+#[derive(Debug)]
+pub struct Outbox {
+    journal: Journal,
+    entries: BTreeMap<u64, OutboxEntry>,
+    next_sequence: u64,
+}
+
+impl Outbox {
+    /// Open or create an outbox log
+    ///
+    /// Entries interrupted while being handed to the node by a previous process become [EntryState::Uncertain]
+    pub fn open(path: &Path) -> Result<Self, OutboxError> {
+        let (journal, records) = Journal::open(path)?;
+        let mut entries: BTreeMap<u64, OutboxEntry> = BTreeMap::new();
+        let mut next_sequence = 0;
+
+        for record in records {
+            let mut reader = wire::Reader::new(&record);
+            let kind = reader.u8()?;
+            let sequence = reader.u64()?;
+
+            if kind == RECORD_NEXT_SEQUENCE {
+                next_sequence = next_sequence.max(sequence);
+                continue;
+            }
+            next_sequence = next_sequence.max(sequence + 1);
+
+            if kind == RECORD_ENQUEUED {
+                entries.insert(sequence, OutboxEntry {
+                    sequence,
+                    destination: reader.string()?,
+                    payload: reader.bytes()?.to_vec(),
+                    state: EntryState::Queued,
+                });
+                continue;
+            }
+
+            let Some(entry) = entries.get_mut(&sequence) else { continue };
+            match kind {
+                RECORD_SENDING => entry.state = EntryState::Uncertain,
+                RECORD_HANDED_OFF => entry.state = EntryState::HandedOff(BundleIdentifier(reader.take(8)?.try_into()?)),
+                RECORD_REQUEUED => entry.state = EntryState::Queued,
+                RECORD_DISCARDED => {
+                    entries.remove(&sequence);
+                }
+                _ => return Err(OutboxError::Malformed(ParseError::UnknownType(kind))),
+            }
+        }
+
+        Ok(Self { journal, entries, next_sequence })
+    }
+
+    /// Persist a bundle to send
+    ///
+    /// Returns the sequence number of the new entry
+    pub fn enqueue(&mut self, destination_eid: String, payload: &[u8]) -> Result<u64, OutboxError> {
+        let sequence = self.next_sequence;
+        let mut record = vec![RECORD_ENQUEUED];
+        wire::put_u64(&mut record, sequence);
+        wire::put_string(&mut record, &destination_eid);
+        wire::put_bytes(&mut record, payload);
+        self.journal.append(&record)?;
+
+        self.next_sequence += 1;
+        self.entries.insert(sequence, OutboxEntry {
+            sequence,
+            destination: destination_eid,
+            payload: payload.to_vec(),
+            state: EntryState::Queued,
+        });
+        Ok(sequence)
+    }
+
+    /// Hand queued and uncertain entries to the node in sequence order
+    ///
+    /// Stops at the first error. The entry being handed when it happened stays [EntryState::Queued] if the bundle
+    /// couldn't be written to the node, and becomes [EntryState::Uncertain] if its confirmation wasn't received.
+    /// Returns (sequence number, bundle identifier) of entries handed off by this call.
+    pub fn drain<S: AapStream>(&mut self, agent: &mut RegisteredAgent<S>) -> Result<Vec<(u64, BundleIdentifier)>, OutboxError> {
+        let queued: Vec<u64> = self.entries.values()
+            .filter(|it| matches!(it.state, EntryState::Queued | EntryState::Uncertain))
+            .map(|it| it.sequence)
+            .collect();
+
+        let mut handed_off = Vec::new();
+        for sequence in queued {
+            self.append_state(RECORD_SENDING, sequence, &[])?;
+            let entry = self.entries.get_mut(&sequence).expect("queued entry exists");
+            entry.state = EntryState::Uncertain;
+
+            if let Err(e) = agent.write_bundle(entry.destination.clone(), &entry.payload) {
+                entry.state = EntryState::Queued;
+                self.append_state(RECORD_REQUEUED, sequence, &[])?;
+                return Err(e.into());
+            }
+
+            let bundle_id = agent.recv_send_confirm()?;
+            let entry = self.entries.get_mut(&sequence).expect("queued entry exists");
+            entry.state = EntryState::HandedOff(bundle_id);
+            self.append_state(RECORD_HANDED_OFF, sequence, &bundle_id.0)?;
+            handed_off.push((sequence, bundle_id));
+        }
+        Ok(handed_off)
+    }
+
+    /// Remove an entry that isn't handed off, it won't be sent
+    pub fn discard(&mut self, sequence: u64) -> Result<(), OutboxError> {
+        match self.entries.get(&sequence).map(|it| it.state) {
+            Some(EntryState::Queued) | Some(EntryState::Uncertain) => {}
+            _ => return Err(OutboxError::InvalidState(sequence)),
+        }
+        self.append_state(RECORD_DISCARDED, sequence, &[])?;
+        self.entries.remove(&sequence);
+        Ok(())
+    }
+
+    /// Get an entry by sequence number
+    pub fn get(&self, sequence: u64) -> Option<&OutboxEntry> {
+        self.entries.get(&sequence)
+    }
+
+    /// Entries in sequence order
+    pub fn entries(&self) -> impl Iterator<Item = &OutboxEntry> {
+        self.entries.values()
+    }
+
+    /// Number of entries waiting to be handed to the node, uncertain entries excluded
+    pub fn queued(&self) -> usize {
+        self.entries.values().filter(|it| it.state == EntryState::Queued).count()
+    }
+
+    /// Entries whose handing to the node was interrupted, to be handed again
+    pub fn uncertain(&self) -> Vec<&OutboxEntry> {
+        self.entries.values().filter(|it| it.state == EntryState::Uncertain).collect()
+    }
+
+    /// Rewrite the log without handed off entries
+    pub fn compact(&mut self) -> Result<(), OutboxError> {
+        self.entries.retain(|_, it| !matches!(it.state, EntryState::HandedOff(_)));
+
+        let mut records = Vec::new();
+        let mut record = vec![RECORD_NEXT_SEQUENCE];
+        wire::put_u64(&mut record, self.next_sequence);
+        records.push(record);
+
+        for entry in self.entries.values() {
+            let mut record = vec![RECORD_ENQUEUED];
+            wire::put_u64(&mut record, entry.sequence);
+            wire::put_string(&mut record, &entry.destination);
+            wire::put_bytes(&mut record, &entry.payload);
+            records.push(record);
+
+            if entry.state == EntryState::Uncertain {
+                let mut record = vec![RECORD_SENDING];
+                wire::put_u64(&mut record, entry.sequence);
+                records.push(record);
+            }
+        }
+        self.journal.rewrite(records)?;
+        Ok(())
+    }
+
+    fn append_state(&mut self, kind: u8, sequence: u64, extra: &[u8]) -> Result<(), OutboxError> {
+        let mut record = vec![kind];
+        wire::put_u64(&mut record, sequence);
+        record.extend_from_slice(extra);
+        self.journal.append(&record)?;
+        Ok(())
+    }
+}
+
+/// An error of the outbox
+#[derive(Debug, Error)]
+pub enum OutboxError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Error reading or writing the log
+    #[error("io Error")]
+    IOError(#[from] io::Error),
+
+    /// A log record is malformed
+    #[error("Malformed outbox record")]
+    Malformed(#[from] ParseError),
+
+    /// Entry doesn't exist or its state doesn't allow this operation
+    #[error("Invalid state of entry {0}")]
+    InvalidState(u64),
+}
+
+impl From<std::array::TryFromSliceError> for OutboxError {
+    fn from(value: std::array::TryFromSliceError) -> Self {
+        Self::Malformed(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::journal::temp_log_path;
+
+    use super::*;
+
+    #[test]
+    fn persist_across_reopen() {
+        let path = temp_log_path("outbox-reopen");
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.enqueue("dtn://a/app".into(), b"first").unwrap(), 0);
+        assert_eq!(outbox.enqueue("dtn://b/app".into(), b"second").unwrap(), 1);
+        outbox.discard(0).unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.queued(), 1);
+        assert_eq!(outbox.get(1).unwrap().payload, b"second");
+        assert_eq!(outbox.enqueue("dtn://c/app".into(), b"third").unwrap(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn interrupted_handoff_is_uncertain() {
+        let path = temp_log_path("outbox-uncertain");
+        let mut outbox = Outbox::open(&path).unwrap();
+        outbox.enqueue("dtn://a/app".into(), b"first").unwrap();
+        outbox.enqueue("dtn://a/app".into(), b"second").unwrap();
+        outbox.append_state(RECORD_SENDING, 0, &[]).unwrap();
+        outbox.append_state(RECORD_HANDED_OFF, 0, &[0, 0, 0, 0, 0, 0, 0, 9]).unwrap();
+        outbox.append_state(RECORD_SENDING, 1, &[]).unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.get(0).unwrap().state, EntryState::HandedOff(BundleIdentifier::from(9)));
+        assert_eq!(outbox.get(1).unwrap().state, EntryState::Uncertain);
+        assert_eq!(outbox.queued(), 0);
+        assert!(matches!(outbox.discard(0), Err(OutboxError::InvalidState(0))));
+
+        outbox.compact().unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert!(outbox.get(0).is_none());
+        assert_eq!(outbox.uncertain().len(), 1);
+        assert_eq!(outbox.enqueue("dtn://a/app".into(), b"third").unwrap(), 2);
+        outbox.discard(1).unwrap();
+        assert_eq!(outbox.queued(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn failed_write_is_requeued() {
+        use crate::{testing::{Fault, MockNode}, Agent};
+
+        let path = temp_log_path("outbox-requeued");
+        let mut outbox = Outbox::open(&path).unwrap();
+        for payload in [&b"first"[..], b"second", b"third"] {
+            outbox.enqueue("dtn://b.dtn/app".into(), payload).unwrap();
+        }
+
+        let node = MockNode::new("dtn://a.dtn/");
+        let mut agent = Agent::new(node.connect()).unwrap().register("app".into()).unwrap();
+        node.inject(Fault::Disconnect);
+        assert!(outbox.drain(&mut agent).is_err());
+        assert_eq!(outbox.get(0).unwrap().state, EntryState::Uncertain);
+        assert!(outbox.drain(&mut agent).is_err());
+        assert_eq!(outbox.get(0).unwrap().state, EntryState::Queued);
+        assert_eq!(outbox.get(1).unwrap().state, EntryState::Queued);
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.queued(), 3);
+        let mut agent = Agent::new(node.connect()).unwrap().register("app".into()).unwrap();
+        assert_eq!(outbox.drain(&mut agent).unwrap().len(), 3);
+        assert_eq!(node.sent_bundles().len(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "testing", feature = "inbox"))]
+    fn resent_entry_received_once() {
+        use std::time::Duration;
+
+        use crate::{inbox::Inbox, testing::MockNode, Agent};
+
+        let path = temp_log_path("outbox-resent");
+        let node = MockNode::new("dtn://a.dtn/");
+        let mut outbox = Outbox::open(&path).unwrap();
+        outbox.enqueue("dtn://a.dtn/reports".into(), b"report").unwrap();
+
+        // Crash after the node accepted the bundle, before its confirmation was recorded
+        let mut agent = Agent::new(node.connect()).unwrap().register("app".into()).unwrap();
+        outbox.append_state(RECORD_SENDING, 0, &[]).unwrap();
+        agent.send_bundle("dtn://a.dtn/reports".into(), b"report").unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.uncertain().len(), 1);
+        assert_eq!(outbox.drain(&mut agent).unwrap().len(), 1);
+        assert!(matches!(outbox.get(0).unwrap().state, EntryState::HandedOff(_)));
+
+        let sent = node.sent_bundles();
+        assert_eq!(sent.len(), 2);
+        let inbox_path = temp_log_path("outbox-resent-inbox");
+        let mut inbox = Inbox::open(&inbox_path, Duration::from_secs(60)).unwrap();
+        let mut stream = node.connect();
+        stream.set_read_timeout(Some(Duration::from_millis(200)));
+        let mut receiver = Agent::new(stream).unwrap().register("reports".into()).unwrap();
+        for bundle in &sent {
+            assert!(node.deliver(&bundle.destination, &bundle.source, &bundle.payload));
+        }
+
+        let entry = inbox.receive(&mut receiver).unwrap();
+        assert_eq!(entry.payload, b"report");
+        inbox.ack(entry.sequence).unwrap();
+        assert!(inbox.receive(&mut receiver).is_err());
+        fs::remove_file(path).unwrap();
+        fs::remove_file(inbox_path).unwrap();
+    }
+
+    #[test]
+    fn torn_record_is_dropped() {
+        let path = temp_log_path("outbox-torn");
+        let mut outbox = Outbox::open(&path).unwrap();
+        outbox.enqueue("dtn://a/app".into(), b"kept").unwrap();
+        outbox.enqueue("dtn://a/app".into(), b"torn").unwrap();
+        drop(outbox);
+
+        let length = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 3).unwrap();
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.entries().count(), 1);
+        assert_eq!(outbox.enqueue("dtn://a/app".into(), b"new").unwrap(), 1);
+        drop(outbox);
+
+        assert_eq!(Outbox::open(&path).unwrap().entries().count(), 2);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+//! Contact plans describing the contacts of several nodes
+//!
+//! A [ContactPlan] lists, for each configured node, its peers with their CLA address, reachable EIDs and
+//! contact windows. Windows use relative times resolved when the plan is applied, see [Window].
+//! Plans are loaded and saved as TOML, JSON or YAML with the `toml`, `json` and `yaml` features, and exported for
+//! review with [ContactPlan::to_dot], [ContactPlan::to_timeline_csv] and [ContactPlan::to_timeline_svg].
+//!
+//! ```toml
+//! [[nodes]]
+//! eid = "dtn://ud3tn1.dtn/"
+//!
+//! [[nodes.peers]]
+//! eid = "dtn://ud3tn2.dtn/"
+//! cla = "mtcp:127.0.0.1:4223"
+//! reliability = 900
+//! reaches = ["dtn://ud3tn3.dtn/"]
+//! windows = ["+10m for 5m", "2024-05-01T10:00:00Z until 2024-05-01T11:00:00Z at 1200"]
+//! ```
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::SystemTime};
+//! use ud3tn_aap::{Agent, BaseAgent, plan::ContactPlan};
+//!
+//! let mut agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("planner".to_owned()).unwrap();
+//! let plan = ContactPlan::load(Path::new("contacts.toml")).unwrap();
+//!
+//! let node = agent.node_id().to_owned();
+//! for config in plan.to_config_bundles(&node, SystemTime::now()).unwrap() {
+//!     agent.send_config(config).unwrap();
+//! }
+//! ```
+
+use std::{path::Path, time::SystemTime};
+
+use thiserror::Error;
+
+use crate::config::{ClaAddress, ConfigBundle, ConfigError, ConfigParseError, Contact};
+
+pub mod ion;
+pub mod lint;
+pub mod recurrence;
+pub mod sync;
+mod export;
+mod time;
+
+pub use time::{
+    format_datetime, format_duration, parse_datetime, parse_duration, TimeExpr, TimeParseError, Window, WindowEnd,
+};
+
+/// Contacts of several nodes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ContactPlan {
+    /// Configured nodes
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub nodes: Vec<PlanNode>,
+}
+
+/// A node and its contacts with peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PlanNode {
+    /// EID of configured node
+    pub eid: String,
+
+    /// Nodes in contact with this node
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub peers: Vec<Peer>,
+}
+
+/// A peer of a node, sent as a [ConfigBundle::AddContact]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Peer {
+    /// EID of peer node
+    pub eid: String,
+
+    /// CLA address of peer
+    pub cla: ClaAddress,
+
+    /// Expected likelihood of future contacts, between 100 and 1000
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub reliability: Option<i32>,
+
+    /// EIDs reachable through peer
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub reaches: Vec<String>,
+
+    /// Contact windows with peer
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub windows: Vec<Window>,
+}
+
+impl Peer {
+    /// Contacts of windows when plan is applied at `now`
+    ///
+    /// Fails if a window ends beyond what [SystemTime] can represent, see [Window::resolve]
+    pub fn contacts(&self, now: SystemTime) -> Result<Vec<Contact>, TimeParseError> {
+        self.windows.iter().map(|it| it.resolve(now)).collect()
+    }
+
+    /// Contacts of windows when plan is applied at `now`, skipping windows [Window::resolve] refuses
+    ///
+    /// [ContactPlan::lint] reports skipped windows
+    pub fn resolvable_contacts(&self, now: SystemTime) -> Vec<Contact> {
+        self.windows.iter().filter_map(|it| it.resolve(now).ok()).collect()
+    }
+
+    /// Validated [ConfigBundle::AddContact] of this peer when plan is applied at `now`
+    pub fn to_config_bundle(&self, now: SystemTime) -> Result<ConfigBundle, PlanError> {
+        let mut builder = ConfigBundle::add_contact(self.eid.clone(), self.cla.clone())
+            .with_contacts(self.contacts(now)?);
+        if let Some(reliability) = self.reliability {
+            builder = builder.with_reliability(reliability);
+        }
+        for eid in &self.reaches {
+            builder = builder.with_reach(eid.clone());
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// A file format of contact plans
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// TOML, requires `toml` feature
+    Toml,
+    /// JSON, requires `json` feature
+    Json,
+    /// YAML, requires `yaml` feature
+    Yaml,
+}
+
+impl PlanFormat {
+    /// Format of a file by its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(PlanFormat::Toml),
+            "json" => Some(PlanFormat::Json),
+            "yaml" | "yml" => Some(PlanFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl ContactPlan {
+    /// Node of plan by EID
+    pub fn node(&self, eid: &str) -> Option<&PlanNode> {
+        self.nodes.iter().find(|it| it.eid == eid)
+    }
+
+    /// Mutable node of plan by EID
+    pub fn node_mut(&mut self, eid: &str) -> Option<&mut PlanNode> {
+        self.nodes.iter_mut().find(|it| it.eid == eid)
+    }
+
+    /// Config bundles to send to node `node_eid` when plan is applied at `now`, one per peer
+    pub fn to_config_bundles(&self, node_eid: &str, now: SystemTime) -> Result<Vec<ConfigBundle>, PlanError> {
+        let node = self.node(node_eid).ok_or_else(|| PlanError::UnknownNode(node_eid.to_owned()))?;
+        node.peers.iter().map(|peer| peer.to_config_bundle(now)).collect()
+    }
+
+    /// Commands turning node `node_eid` configured with this plan into `target`, both applied at `now`
+    ///
+    /// See [sync::NodeConfig::diff]
+    pub fn diff(&self, target: &ContactPlan, node_eid: &str, now: SystemTime) -> Result<Vec<ConfigBundle>, PlanError> {
+        let configs = |plan: &ContactPlan| match plan.to_config_bundles(node_eid, now) {
+            Err(PlanError::UnknownNode(_)) => Ok(Vec::new()),
+            result => result,
+        };
+        let old = sync::NodeConfig::from_config_bundles(&configs(self)?);
+        let new = sync::NodeConfig::from_config_bundles(&configs(target)?);
+        Ok(old.diff(&new))
+    }
+
+    /// Parse a plan
+    pub fn from_str_format(s: &str, format: PlanFormat) -> Result<Self, PlanError> {
+        match format {
+            #[cfg(feature = "toml")]
+            PlanFormat::Toml => toml::from_str(s).map_err(|e| PlanError::Decode(e.to_string())),
+            #[cfg(feature = "json")]
+            PlanFormat::Json => serde_json::from_str(s).map_err(|e| PlanError::Decode(e.to_string())),
+            #[cfg(feature = "yaml")]
+            PlanFormat::Yaml => serde_yaml::from_str(s).map_err(|e| PlanError::Decode(e.to_string())),
+            #[allow(unreachable_patterns)]
+            format => {
+                let _ = s;
+                Err(PlanError::FormatNotEnabled(format))
+            }
+        }
+    }
+
+    /// Serialize plan
+    pub fn to_string_format(&self, format: PlanFormat) -> Result<String, PlanError> {
+        match format {
+            #[cfg(feature = "toml")]
+            PlanFormat::Toml => toml::to_string_pretty(self).map_err(|e| PlanError::Encode(e.to_string())),
+            #[cfg(feature = "json")]
+            PlanFormat::Json => serde_json::to_string_pretty(self).map_err(|e| PlanError::Encode(e.to_string())),
+            #[cfg(feature = "yaml")]
+            PlanFormat::Yaml => serde_yaml::to_string(self).map_err(|e| PlanError::Encode(e.to_string())),
+            #[allow(unreachable_patterns)]
+            format => Err(PlanError::FormatNotEnabled(format)),
+        }
+    }
+
+    /// Load a plan from a file, format chosen by extension
+    pub fn load(path: &Path) -> Result<Self, PlanError> {
+        let format = PlanFormat::from_path(path).ok_or_else(|| PlanError::UnknownFormat(path.display().to_string()))?;
+        Self::from_str_format(&std::fs::read_to_string(path)?, format)
+    }
+
+    /// Save plan to a file, format chosen by extension
+    pub fn save(&self, path: &Path) -> Result<(), PlanError> {
+        let format = PlanFormat::from_path(path).ok_or_else(|| PlanError::UnknownFormat(path.display().to_string()))?;
+        std::fs::write(path, self.to_string_format(format)?)?;
+        Ok(())
+    }
+}
+
+/// An error of contact plan handling
+#[derive(Debug, Error)]
+pub enum PlanError {
+    /// Node isn't part of plan
+    #[error("Unknown node {0}")]
+    UnknownNode(String),
+
+    /// ION contact plan couldn't be imported
+    #[error("ION import failed")]
+    Ion(#[from] ion::IonError),
+
+    /// Plan produced an invalid config bundle
+    #[error("Invalid config bundle")]
+    InvalidConfig(#[from] ConfigError),
+
+    /// Error reading or writing plan file
+    #[error("io Error")]
+    IOError(#[from] std::io::Error),
+
+    /// File extension isn't a known plan format
+    #[error("Unknown plan format for {0}")]
+    UnknownFormat(String),
+
+    /// Format feature isn't enabled in this build
+    #[error("Format {0:?} not enabled")]
+    FormatNotEnabled(PlanFormat),
+
+    /// Error sending config bundles to node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Stored configuration is malformed
+    #[error("Malformed stored configuration")]
+    MalformedState(#[from] ConfigParseError),
+
+    /// Plan window can't be applied at the requested time
+    #[error("Invalid time")]
+    Time(#[from] TimeParseError),
+
+    /// Plan couldn't be serialized
+    #[error("Encoding failed: {0}")]
+    Encode(String),
+
+    /// Plan couldn't be deserialized
+    #[error("Decoding failed: {0}")]
+    Decode(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::{ContactDataRate, HostPort};
+
+    use super::*;
+
+    fn plan() -> ContactPlan {
+        ContactPlan {
+            nodes: vec![PlanNode {
+                eid: "dtn://ud3tn1.dtn/".into(),
+                peers: vec![
+                    Peer {
+                        eid: "dtn://ud3tn2.dtn/".into(),
+                        cla: ClaAddress::Mtcp(HostPort::new("127.0.0.1", 4223)),
+                        reliability: Some(900),
+                        reaches: vec!["dtn://ud3tn3.dtn/".into()],
+                        windows: vec!["+10m for 5m".parse().unwrap(), "+1h until +2h at 1200".parse().unwrap()],
+                    },
+                    Peer {
+                        eid: "dtn://ud3tn4.dtn/".into(),
+                        cla: ClaAddress::TcpClV4(HostPort::new("10.0.0.4", 4556)),
+                        reliability: None,
+                        reaches: Vec::new(),
+                        windows: Vec::new(),
+                    },
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn config_bundles_of_node() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let configs = plan().to_config_bundles("dtn://ud3tn1.dtn/", now).unwrap();
+
+        assert_eq!(configs[0], ConfigBundle::AddContact {
+            eid: "dtn://ud3tn2.dtn/".into(),
+            reliability: Some(900),
+            cla_address: ClaAddress::Mtcp(HostPort::new("127.0.0.1", 4223)),
+            reaches_eid: vec!["dtn://ud3tn3.dtn/".into()],
+            contacts: vec![
+                Contact::from_during(now + Duration::from_secs(600), Duration::from_secs(300), ContactDataRate::Unlimited),
+                Contact::from_during(now + Duration::from_secs(3600), Duration::from_secs(3600), ContactDataRate::Limited(1200)),
+            ],
+        });
+        assert_eq!(configs.len(), 2);
+        assert!(matches!(plan().to_config_bundles("dtn://other.dtn/", now), Err(PlanError::UnknownNode(_))));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_round_trip() {
+        let text = plan().to_string_format(PlanFormat::Toml).unwrap();
+        assert!(text.contains("windows = [\n    \"+10m for 5m\",\n    \"+1h until +2h at 1200\",\n]"));
+        assert_eq!(ContactPlan::from_str_format(&text, PlanFormat::Toml).unwrap(), plan());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        let text = plan().to_string_format(PlanFormat::Json).unwrap();
+        assert!(text.contains("\"cla\": \"tcpclv4:10.0.0.4:4556\""));
+        assert_eq!(ContactPlan::from_str_format(&text, PlanFormat::Json).unwrap(), plan());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_round_trip() {
+        let text = plan().to_string_format(PlanFormat::Yaml).unwrap();
+        assert_eq!(ContactPlan::from_str_format(&text, PlanFormat::Yaml).unwrap(), plan());
+    }
+}
@@ -0,0 +1,205 @@
+//! Rendering of contact plans for review
+
+use std::{fmt::Write, time::{Duration, SystemTime}};
+
+use crate::config::{Contact, ContactDataRate};
+
+use super::{format_datetime, ContactPlan};
+
+const SVG_WIDTH: f64 = 1200.0;
+const SVG_LABEL_WIDTH: f64 = 360.0;
+const SVG_ROW_HEIGHT: f64 = 24.0;
+const SVG_AXIS_HEIGHT: f64 = 40.0;
+const SVG_TICKS: u32 = 4;
+
+fn rate(data_rate: &ContactDataRate) -> String {
+    match data_rate {
+        ContactDataRate::Limited(rate) => rate.to_string(),
+        ContactDataRate::Unlimited => "unlimited".to_owned(),
+    }
+}
+
+fn rate_label(data_rate: &ContactDataRate) -> String {
+    match data_rate {
+        ContactDataRate::Limited(rate) => format!("{} B/s", rate),
+        ContactDataRate::Unlimited => "unlimited".to_owned(),
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+impl ContactPlan {
+    /// Contacts of each link when plan is applied at `now`, as (node, peer, CLA address, contacts)
+    fn links(&self, now: SystemTime) -> Vec<(&str, &str, String, Vec<Contact>)> {
+        self.nodes.iter()
+            .flat_map(|node| node.peers.iter().map(move |peer| (node.eid.as_str(), peer, peer.resolvable_contacts(now))))
+            .map(|(node, peer, contacts)| (node, peer.eid.as_str(), peer.cla.to_string(), contacts))
+            .collect()
+    }
+
+    /// Graphviz DOT graph of nodes and links, links labelled with CLA address and data rates in bytes per second
+    pub fn to_dot(&self, now: SystemTime) -> String {
+        let mut result = String::from("digraph \"contact plan\" {\n    rankdir=LR;\n");
+
+        let mut nodes: Vec<&str> = self.nodes.iter()
+            .flat_map(|node| std::iter::once(node.eid.as_str()).chain(node.peers.iter().map(|it| it.eid.as_str())))
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        for node in nodes {
+            let _ = writeln!(result, "    \"{}\";", dot_escape(node));
+        }
+
+        for (node, peer, cla, contacts) in self.links(now) {
+            let mut rates: Vec<String> = contacts.iter().map(|it| rate_label(&it.data_rate)).collect();
+            rates.dedup();
+            let rates = if rates.is_empty() { "no window".to_owned() } else { rates.join(", ") };
+            let _ = writeln!(
+                result,
+                "    \"{}\" -> \"{}\" [label=\"{}\\n{}\"];",
+                dot_escape(node), dot_escape(peer), dot_escape(&cla), rates
+            );
+        }
+
+        result + "}\n"
+    }
+
+    /// CSV timeline with one row per contact window
+    ///
+    /// Columns are `node,peer,cla,start,end,duration,data_rate`, times in RFC 3339 and duration in seconds
+    pub fn to_timeline_csv(&self, now: SystemTime) -> String {
+        let mut result = String::from("node,peer,cla,start,end,duration,data_rate\n");
+        for (node, peer, cla, contacts) in self.links(now) {
+            for contact in contacts {
+                let duration = contact.end.duration_since(contact.start).unwrap_or(Duration::ZERO);
+                let _ = writeln!(
+                    result,
+                    "{},{},{},{},{},{},{}",
+                    csv_escape(node), csv_escape(peer), csv_escape(&cla),
+                    format_datetime(contact.start), format_datetime(contact.end),
+                    duration.as_secs(), rate(&contact.data_rate)
+                );
+            }
+        }
+        result
+    }
+
+    /// Self-contained SVG timeline with one row per link and one bar per contact window
+    pub fn to_timeline_svg(&self, now: SystemTime) -> String {
+        let links = self.links(now);
+        let contacts = links.iter().flat_map(|(_, _, _, contacts)| contacts);
+        let first = contacts.clone().map(|it| it.start).min().unwrap_or(now);
+        let last = contacts.map(|it| it.end).max().unwrap_or(now).max(first + Duration::from_secs(1));
+        let span = last.duration_since(first).unwrap_or(Duration::ZERO).as_secs_f64();
+
+        let chart_width = SVG_WIDTH - SVG_LABEL_WIDTH - 10.0;
+        let x = |time: SystemTime| {
+            let offset = time.duration_since(first).unwrap_or(Duration::ZERO).as_secs_f64();
+            SVG_LABEL_WIDTH + offset.min(span) / span * chart_width
+        };
+        let height = SVG_AXIS_HEIGHT + links.len() as f64 * SVG_ROW_HEIGHT + 10.0;
+
+        let mut result = String::new();
+        let _ = writeln!(
+            result,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\" \
+             font-family=\"sans-serif\" font-size=\"12\">",
+            SVG_WIDTH, height
+        );
+        let _ = writeln!(result, "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>");
+
+        for tick in 0..=SVG_TICKS {
+            let time = first + Duration::from_secs_f64(span * tick as f64 / SVG_TICKS as f64);
+            let tick_x = x(time);
+            let anchor = match tick {
+                0 => "start",
+                SVG_TICKS => "end",
+                _ => "middle",
+            };
+            let _ = writeln!(
+                result,
+                "<line x1=\"{0:.1}\" y1=\"{1}\" x2=\"{0:.1}\" y2=\"{2}\" stroke=\"#ccc\"/>\
+                 <text x=\"{0:.1}\" y=\"20\" text-anchor=\"{3}\">{4}</text>",
+                tick_x, SVG_AXIS_HEIGHT - 10.0, height - 10.0, anchor, format_datetime(time)
+            );
+        }
+
+        for (row, (node, peer, cla, contacts)) in links.iter().enumerate() {
+            let y = SVG_AXIS_HEIGHT + row as f64 * SVG_ROW_HEIGHT;
+            let _ = writeln!(
+                result,
+                "<text x=\"4\" y=\"{:.1}\">{} → {}</text>",
+                y + SVG_ROW_HEIGHT * 0.65, xml_escape(node), xml_escape(peer)
+            );
+            for contact in contacts {
+                let _ = writeln!(
+                    result,
+                    "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#4a90d9\">\
+                     <title>{} {} to {} at {}</title></rect>",
+                    x(contact.start), y + 4.0, (x(contact.end) - x(contact.start)).max(1.0), SVG_ROW_HEIGHT - 8.0,
+                    xml_escape(cla), format_datetime(contact.start), format_datetime(contact.end), rate_label(&contact.data_rate)
+                );
+            }
+        }
+
+        result + "</svg>\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::{ClaAddress, HostPort}, plan::{Peer, PlanNode}};
+
+    use super::*;
+
+    #[test]
+    fn plan_exported() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let plan = ContactPlan {
+            nodes: vec![PlanNode {
+                eid: "dtn://a.dtn/".into(),
+                peers: vec![Peer {
+                    eid: "dtn://b.dtn/".into(),
+                    cla: ClaAddress::Mtcp(HostPort::new("127.0.0.1", 4223)),
+                    reliability: None,
+                    reaches: Vec::new(),
+                    windows: vec!["+0s for 10m at 1200".parse().unwrap(), "+1h for 5m".parse().unwrap()],
+                }],
+            }],
+        };
+
+        assert_eq!(plan.to_dot(now), concat!(
+            "digraph \"contact plan\" {\n",
+            "    rankdir=LR;\n",
+            "    \"dtn://a.dtn/\";\n",
+            "    \"dtn://b.dtn/\";\n",
+            "    \"dtn://a.dtn/\" -> \"dtn://b.dtn/\" [label=\"mtcp:127.0.0.1:4223\\n1200 B/s, unlimited\"];\n",
+            "}\n",
+        ));
+
+        assert_eq!(plan.to_timeline_csv(now), concat!(
+            "node,peer,cla,start,end,duration,data_rate\n",
+            "dtn://a.dtn/,dtn://b.dtn/,mtcp:127.0.0.1:4223,2024-05-01T10:00:00Z,2024-05-01T10:10:00Z,600,1200\n",
+            "dtn://a.dtn/,dtn://b.dtn/,mtcp:127.0.0.1:4223,2024-05-01T11:00:00Z,2024-05-01T11:05:00Z,300,unlimited\n",
+        ));
+
+        let svg = plan.to_timeline_svg(now);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert_eq!(svg.matches("<title>").count(), 2);
+        assert!(svg.contains("dtn://a.dtn/ → dtn://b.dtn/"));
+    }
+}
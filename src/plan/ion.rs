@@ -0,0 +1,365 @@
+//! Import of ION contact plans (`ionadmin` `.rc` files)
+//!
+//! Supported commands, other `ionadmin` commands are ignored:
+//!
+//! ```text
+//! @ 2024/05/01-10:00:00                                  reference time of relative times
+//! a contact <start> <end> <from> <to> <rate> [confidence]  rate in bytes per second, confidence in 0.0..=1.0
+//! a range <start> <end> <from> <to> <one way light time>   light time in seconds
+//! ```
+//!
+//! Times are `+<seconds>`, relative to the reference time or to plan application, or `yyyy/mm/dd-hh:mm:ss` in UTC.
+//!
+//! Ranges are parsed but not part of the imported [ContactPlan], ud3tn contacts have no light time.
+//!
+//! ```rust,no_run
+//! use ud3tn_aap::{config::ClaAddress, plan::ion::{IonNodeMap, IonPlan}};
+//!
+//! let ion = IonPlan::parse(&std::fs::read_to_string("partner.rc").unwrap()).unwrap();
+//! let mapping = IonNodeMap::new()
+//!     .with_node(1, "dtn://ud3tn1.dtn/", "mtcp:10.0.0.1:4224".parse::<ClaAddress>().unwrap())
+//!     .with_node(2, "dtn://ud3tn2.dtn/", "mtcp:10.0.0.2:4224".parse::<ClaAddress>().unwrap());
+//! let plan = ion.to_contact_plan(&mapping).unwrap();
+//! ```
+
+use std::{collections::BTreeMap, time::{Duration, SystemTime}};
+
+use thiserror::Error;
+
+use crate::config::{ClaAddress, ConfigBundle, ContactDataRate};
+
+use super::{time::days_from_civil, ContactPlan, Peer, PlanError, PlanNode, TimeExpr, Window, WindowEnd};
+
+/// An `a contact` command
+#[derive(Debug, Clone, PartialEq)]
+pub struct IonContact {
+    /// Start of contact
+    pub start: TimeExpr,
+
+    /// End of contact
+    pub end: TimeExpr,
+
+    /// Transmitting node number
+    pub from: u64,
+
+    /// Receiving node number
+    pub to: u64,
+
+    /// Transmission rate in bytes per second
+    pub rate: u64,
+
+    /// Likelihood of contact, between 0 and 1
+    pub confidence: Option<f64>,
+}
+
+impl IonContact {
+    /// Contact window of this contact
+    pub fn window(&self) -> Window {
+        Window {
+            start: self.start,
+            end: WindowEnd::Until(self.end),
+            data_rate: match i32::try_from(self.rate) {
+                Ok(rate) => ContactDataRate::Limited(rate),
+                Err(_) => ContactDataRate::Unlimited,
+            },
+        }
+    }
+}
+
+/// An `a range` command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IonRange {
+    /// Start of range validity
+    pub start: TimeExpr,
+
+    /// End of range validity
+    pub end: TimeExpr,
+
+    /// First node number
+    pub from: u64,
+
+    /// Second node number
+    pub to: u64,
+
+    /// One way light time between nodes
+    pub light_time: Duration,
+}
+
+/// EID and CLA address of ION node numbers
+#[derive(Debug, Clone, Default)]
+pub struct IonNodeMap {
+    nodes: BTreeMap<u64, (String, ClaAddress)>,
+    ignore_unmapped: bool,
+}
+
+impl IonNodeMap {
+    /// Empty mapping
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map an ION node number to an EID reached through a CLA address
+    pub fn with_node(mut self, number: u64, eid: impl Into<String>, cla: ClaAddress) -> Self {
+        self.nodes.insert(number, (eid.into(), cla));
+        self
+    }
+
+    /// Skip contacts of unmapped nodes instead of failing with [IonError::UnmappedNode]
+    pub fn ignore_unmapped(mut self) -> Self {
+        self.ignore_unmapped = true;
+        self
+    }
+
+    /// EID and CLA address of a node number
+    pub fn get(&self, number: u64) -> Option<&(String, ClaAddress)> {
+        self.nodes.get(&number)
+    }
+}
+
+/// Content of an ION contact plan
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IonPlan {
+    /// Contacts in file order
+    pub contacts: Vec<IonContact>,
+
+    /// Ranges in file order
+    pub ranges: Vec<IonRange>,
+}
+
+/// Parse an ION time, `+<seconds>` or `yyyy/mm/dd-hh:mm:ss`
+pub fn parse_ion_time(s: &str, reference: Option<SystemTime>) -> Option<TimeExpr> {
+    if let Some(seconds) = s.strip_prefix('+') {
+        let delay = Duration::from_secs(seconds.parse().ok()?);
+        return Some(match reference {
+            Some(reference) => TimeExpr::Absolute(reference.checked_add(delay)?),
+            None => TimeExpr::Relative(delay),
+        });
+    }
+
+    let (date, clock) = s.split_once('-')?;
+    let date: Vec<u32> = date.split('/').map(str::parse).collect::<Result<_, _>>().ok()?;
+    let clock: Vec<u32> = clock.split(':').map(str::parse).collect::<Result<_, _>>().ok()?;
+    let ([year, month, day], [hour, minute, second]) = (date.as_slice(), clock.as_slice()) else { return None };
+    if !(1..=12).contains(month) || !(1..=31).contains(day) || *hour > 23 || *minute > 59 || *second > 60 {
+        return None;
+    }
+
+    let seconds = days_from_civil(*year as i64, *month, *day) * 86400
+        + (*hour as i64) * 3600 + (*minute as i64) * 60 + *second as i64;
+    Some(TimeExpr::Absolute(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(seconds).ok()?)))
+}
+
+impl IonPlan {
+    /// Parse `ionadmin` commands
+    pub fn parse(input: &str) -> Result<Self, IonError> {
+        let mut result = Self::default();
+        let mut reference = None;
+
+        for (index, line) in input.lines().enumerate() {
+            let error = |message: &str| IonError::Syntax { line: index + 1, message: message.to_owned() };
+            let words: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+            let time = |word: &str| parse_ion_time(word, reference).ok_or_else(|| error(&format!("invalid time {:?}", word)));
+            let number = |word: &str| word.parse::<u64>().map_err(|_| error(&format!("invalid number {:?}", word)));
+
+            match words.as_slice() {
+                ["@", time] => match parse_ion_time(time, None) {
+                    Some(TimeExpr::Absolute(time)) => reference = Some(time),
+                    _ => return Err(error(&format!("invalid reference time {:?}", time))),
+                },
+                ["a", "contact", start, end, from, to, rate, rest @ ..] if rest.len() <= 1 => {
+                    let confidence = match rest.first() {
+                        Some(word) => Some(word.parse::<f64>().ok()
+                            .filter(|it| (0.0..=1.0).contains(it))
+                            .ok_or_else(|| error(&format!("invalid confidence {:?}", word)))?),
+                        None => None,
+                    };
+                    result.contacts.push(IonContact {
+                        start: time(start)?,
+                        end: time(end)?,
+                        from: number(from)?,
+                        to: number(to)?,
+                        rate: number(rate)?,
+                        confidence,
+                    });
+                }
+                ["a", "contact", ..] => return Err(error("expected a contact <start> <end> <from> <to> <rate> [confidence]")),
+                ["a", "range", start, end, from, to, light_time] => result.ranges.push(IonRange {
+                    start: time(start)?,
+                    end: time(end)?,
+                    from: number(from)?,
+                    to: number(to)?,
+                    light_time: Duration::from_secs(number(light_time)?),
+                }),
+                ["a", "range", ..] => return Err(error("expected a range <start> <end> <from> <to> <light time>")),
+                _ => {}
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Contact plan with a node for each mapped transmitting node
+    ///
+    /// Reliability of a peer is the lowest confidence of its contacts, scaled to 100..=1000. Contacts of a node with
+    /// itself, which ION plans usually list for loopback, are skipped. Ranges are dropped, light time is not taken into
+    /// account.
+    pub fn to_contact_plan(&self, mapping: &IonNodeMap) -> Result<ContactPlan, IonError> {
+        let mut plan = ContactPlan::default();
+
+        for contact in self.contacts.iter().filter(|it| it.from != it.to) {
+            let (Some((from_eid, _)), Some((to_eid, to_cla))) = (mapping.get(contact.from), mapping.get(contact.to)) else {
+                if mapping.ignore_unmapped {
+                    continue;
+                }
+                let unmapped = if mapping.get(contact.from).is_none() { contact.from } else { contact.to };
+                return Err(IonError::UnmappedNode(unmapped));
+            };
+            if from_eid == to_eid {
+                continue;
+            }
+
+            if plan.node(from_eid).is_none() {
+                plan.nodes.push(PlanNode { eid: from_eid.clone(), peers: Vec::new() });
+            }
+            let node = plan.node_mut(from_eid).expect("node was just added");
+
+            let peer = match node.peers.iter().position(|it| it.eid == *to_eid) {
+                Some(index) => &mut node.peers[index],
+                None => {
+                    node.peers.push(Peer {
+                        eid: to_eid.clone(),
+                        cla: to_cla.clone(),
+                        reliability: None,
+                        reaches: Vec::new(),
+                        windows: Vec::new(),
+                    });
+                    node.peers.last_mut().expect("peer was just added")
+                }
+            };
+
+            peer.windows.push(contact.window());
+            if let Some(confidence) = contact.confidence {
+                let reliability = ((confidence * 1000.0).round() as i32).clamp(100, 1000);
+                peer.reliability = Some(peer.reliability.map_or(reliability, |it| it.min(reliability)));
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// [ConfigBundle::AddContact]s of node `node_number` when plan is applied at `now`
+    pub fn to_config_bundles(&self, mapping: &IonNodeMap, node_number: u64, now: SystemTime) -> Result<Vec<ConfigBundle>, PlanError> {
+        let (eid, _) = mapping.get(node_number).ok_or(IonError::UnmappedNode(node_number))?;
+        self.to_contact_plan(mapping)?.to_config_bundles(eid, now)
+    }
+}
+
+/// An error importing an ION contact plan
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum IonError {
+    /// A command is malformed
+    #[error("Line {line}: {message}")]
+    Syntax {
+        /// Line of command, starting at 1
+        line: usize,
+        /// Description of problem
+        message: String,
+    },
+
+    /// A contact involves a node number without EID and CLA address
+    #[error("ION node {0} is not mapped")]
+    UnmappedNode(u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Contact, HostPort};
+
+    use super::*;
+
+    const PLAN: &str = "
+# Partner schedule
+m horizon +0
+a contact +60 +660 1 2 125000 0.9
+a contact 2024/05/01-10:00:00 2024/05/01-11:00:00 1 2 10000000000
+a contact +0 +86400 2 1 1000
+a range +0 +86400 1 2 1
+a contact +0 +3600 1 3 1000
+a contact +0 +86400 1 1 1000
+a contact +0 +86400 4 4 1000
+";
+
+    fn mapping() -> IonNodeMap {
+        IonNodeMap::new()
+            .with_node(1, "dtn://ud3tn1.dtn/", ClaAddress::Mtcp(HostPort::new("10.0.0.1", 4224)))
+            .with_node(2, "dtn://ud3tn2.dtn/", ClaAddress::Mtcp(HostPort::new("10.0.0.2", 4224)))
+    }
+
+    #[test]
+    fn parse_contacts_and_ranges() {
+        let plan = IonPlan::parse(PLAN).unwrap();
+        assert_eq!(plan.contacts.len(), 6);
+        assert_eq!(plan.contacts[0], IonContact {
+            start: TimeExpr::Relative(Duration::from_secs(60)),
+            end: TimeExpr::Relative(Duration::from_secs(660)),
+            from: 1,
+            to: 2,
+            rate: 125000,
+            confidence: Some(0.9),
+        });
+        assert_eq!(plan.contacts[1].start, TimeExpr::Absolute(SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600)));
+        assert_eq!(plan.ranges, vec![IonRange {
+            start: TimeExpr::Relative(Duration::ZERO),
+            end: TimeExpr::Relative(Duration::from_secs(86400)),
+            from: 1,
+            to: 2,
+            light_time: Duration::from_secs(1),
+        }]);
+
+        let referenced = IonPlan::parse("@ 2024/05/01-10:00:00\na contact +60 +120 1 2 100").unwrap();
+        assert_eq!(referenced.contacts[0].start, TimeExpr::Absolute(SystemTime::UNIX_EPOCH + Duration::from_secs(1714557660)));
+
+        assert_eq!(
+            IonPlan::parse("a contact +0 +60 1 2").unwrap_err(),
+            IonError::Syntax { line: 1, message: "expected a contact <start> <end> <from> <to> <rate> [confidence]".into() }
+        );
+        assert!(matches!(IonPlan::parse("\na contact +0 tomorrow 1 2 100"), Err(IonError::Syntax { line: 2, .. })));
+        assert!(matches!(
+            IonPlan::parse("@ 2024/05/01-10:00:00\na contact +18446744073709551615 +18446744073709551615 1 2 100"),
+            Err(IonError::Syntax { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn contact_plan_of_mapped_nodes() {
+        let ion = IonPlan::parse(PLAN).unwrap();
+        assert_eq!(ion.to_contact_plan(&mapping()).unwrap_err(), IonError::UnmappedNode(3));
+
+        let plan = ion.to_contact_plan(&mapping().ignore_unmapped()).unwrap();
+        assert_eq!(plan.nodes.len(), 2);
+        assert!(plan.nodes.iter().all(|node| node.peers.iter().all(|it| it.eid != node.eid)));
+        assert_eq!(plan.node("dtn://ud3tn1.dtn/").unwrap().peers.len(), 1);
+
+        // Two node numbers mapped to the same node are a loopback too
+        let aliased = mapping().with_node(3, "dtn://ud3tn1.dtn/", ClaAddress::Mtcp(HostPort::new("10.0.0.1", 4224)));
+        assert_eq!(ion.to_contact_plan(&aliased).unwrap(), plan);
+
+        let peer = &plan.node("dtn://ud3tn1.dtn/").unwrap().peers[0];
+        assert_eq!(peer.eid, "dtn://ud3tn2.dtn/");
+        assert_eq!(peer.cla, ClaAddress::Mtcp(HostPort::new("10.0.0.2", 4224)));
+        assert_eq!(peer.reliability, Some(900));
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714550000);
+        let configs = ion.to_config_bundles(&mapping().ignore_unmapped(), 2, now).unwrap();
+        assert!(matches!(&configs[..], [ConfigBundle::AddContact { eid, .. }] if eid == "dtn://ud3tn1.dtn/"));
+
+        assert_eq!(peer.contacts(now).unwrap(), vec![
+            Contact::from_during(now + Duration::from_secs(60), Duration::from_secs(600), ContactDataRate::Limited(125000)),
+            Contact::from_during(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600),
+                Duration::from_secs(3600),
+                ContactDataRate::Unlimited
+            ),
+        ]);
+    }
+}
@@ -0,0 +1,397 @@
+//! Static checks of contact plans
+
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt, time::{Duration, SystemTime}};
+
+use crate::config::{ContactDataRate, RELIABILITY_RANGE};
+
+use super::ContactPlan;
+
+/// Peers of a link whose capacities differ by more than this factor are reported as [LintKind::AsymmetricCapacity]
+pub const ASYMMETRY_FACTOR: u64 = 10;
+
+/// Importance of a [LintIssue]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Plan works but is likely not what was meant
+    Warning,
+
+    /// Node would reject plan or never use part of it
+    Error,
+}
+
+/// Problem found in a contact plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintKind {
+    /// Two windows with the same peer overlap
+    OverlappingWindows {
+        /// Index of first window in peer windows
+        first: usize,
+        /// Index of second window in peer windows
+        second: usize,
+    },
+
+    /// Window ends before or when it starts
+    EmptyWindow(usize),
+
+    /// Window ended before plan is applied
+    PastWindow(usize),
+
+    /// Window transmits nothing
+    ZeroCapacity(usize),
+
+    /// Window ends beyond the representable time range when plan is applied
+    UnresolvableWindow(usize),
+
+    /// Reliability isn't within [RELIABILITY_RANGE]
+    ReliabilityOutOfRange(i32),
+
+    /// Peer reaches the configured node itself, bundles would loop back
+    ReachesSelf,
+
+    /// Peer lists itself as reachable EID
+    ReachesPeer,
+
+    /// Bundles to this reachable EID are forwarded around a loop of plan nodes and never reach it
+    ReachesLoop(String),
+
+    /// Peer has reachable EIDs but no window, they are never reachable
+    UnreachableEids,
+
+    /// The same peer is listed twice
+    DuplicatePeer,
+
+    /// Capacity from node to peer and from peer to node differ a lot
+    AsymmetricCapacity {
+        /// Bytes node can send to peer in plan windows
+        sent: u64,
+        /// Bytes peer can send to node in plan windows
+        received: u64,
+    },
+}
+
+/// A problem found by [ContactPlan::lint]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintIssue {
+    /// Importance of problem
+    pub severity: Severity,
+
+    /// Node configured with faulty peer
+    pub node: String,
+
+    /// Faulty peer
+    pub peer: String,
+
+    /// Problem found
+    pub kind: LintKind,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {} -> {}: ", severity, self.node, self.peer)?;
+        match &self.kind {
+            LintKind::OverlappingWindows { first, second } => write!(f, "windows {} and {} overlap", first, second),
+            LintKind::EmptyWindow(index) => write!(f, "window {} ends before it starts", index),
+            LintKind::PastWindow(index) => write!(f, "window {} is entirely in the past", index),
+            LintKind::ZeroCapacity(index) => write!(f, "window {} has a data rate of 0", index),
+            LintKind::UnresolvableWindow(index) => write!(f, "window {} ends too far in the future", index),
+            LintKind::ReliabilityOutOfRange(reliability) => write!(f, "reliability {} out of range 100..=1000", reliability),
+            LintKind::ReachesSelf => write!(f, "reachable EIDs loop back to {}", self.node),
+            LintKind::ReachesPeer => write!(f, "peer lists itself as reachable EID"),
+            LintKind::ReachesLoop(eid) => write!(f, "reachable EID {} loops back through plan nodes", eid),
+            LintKind::UnreachableEids => write!(f, "reachable EIDs but no contact window"),
+            LintKind::DuplicatePeer => write!(f, "peer listed more than once"),
+            LintKind::AsymmetricCapacity { sent, received } => {
+                write!(f, "capacity {} bytes sent but {} bytes received", sent, received)
+            }
+        }
+    }
+}
+
+fn capacity(rate: &ContactDataRate, duration: Duration) -> Option<u64> {
+    match rate {
+        ContactDataRate::Limited(rate) => Some(((*rate).max(0) as u64).saturating_mul(duration.as_secs())),
+        ContactDataRate::Unlimited => None,
+    }
+}
+
+impl ContactPlan {
+    /// Plan nodes forwarding bundles for `eid` around loops of plan nodes, never reaching it
+    ///
+    /// A node delivers bundles for `eid` if it is `eid`, has it as peer or forwards them out of the plan. Nodes
+    /// which can't forward to a delivering node loop if a cycle is reachable from them, they are dead ends otherwise.
+    fn looping_nodes(&self, eid: &str) -> HashSet<&str> {
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        for (position, node) in self.nodes.iter().enumerate() {
+            index.entry(node.eid.as_str()).or_insert(position);
+        }
+
+        let mut delivered = vec![false; self.nodes.len()];
+        let mut next: Vec<Vec<usize>> = vec![Vec::new(); self.nodes.len()];
+        let mut previous: Vec<Vec<usize>> = vec![Vec::new(); self.nodes.len()];
+        for (&node_eid, &position) in &index {
+            let node = &self.nodes[position];
+            if node_eid == eid || node.peers.iter().any(|it| it.eid == eid) {
+                delivered[position] = true;
+                continue;
+            }
+            for peer in node.peers.iter().filter(|it| it.reaches.iter().any(|it| it == eid)) {
+                match index.get(peer.eid.as_str()) {
+                    Some(&peer) => {
+                        next[position].push(peer);
+                        previous[peer].push(position);
+                    }
+                    None => delivered[position] = true,
+                }
+            }
+        }
+
+        let mut queue: VecDeque<usize> = (0..self.nodes.len()).filter(|it| delivered[*it]).collect();
+        while let Some(position) = queue.pop_front() {
+            for &it in &previous[position] {
+                if !delivered[it] {
+                    delivered[it] = true;
+                    queue.push_back(it);
+                }
+            }
+        }
+
+        // Peel dead ends off the undelivered nodes, those left reach a cycle
+        let mut remaining: Vec<usize> = next.iter().map(Vec::len).collect();
+        let mut queue: VecDeque<usize> = index.values().copied().filter(|it| !delivered[*it] && remaining[*it] == 0).collect();
+        let mut dead_end = vec![false; self.nodes.len()];
+        while let Some(position) = queue.pop_front() {
+            dead_end[position] = true;
+            for &it in previous[position].iter().filter(|it| !delivered[**it]) {
+                remaining[it] -= 1;
+                if remaining[it] == 0 {
+                    queue.push_back(it);
+                }
+            }
+        }
+
+        index.into_iter()
+            .filter(|(_, position)| !delivered[*position] && !dead_end[*position])
+            .map(|(eid, _)| eid)
+            .collect()
+    }
+}
+
+impl ContactPlan {
+    /// Check plan applied at `now`, issues sorted by node then peer, errors first
+    pub fn lint(&self, now: SystemTime) -> Vec<LintIssue> {
+        let mut result = Vec::new();
+        let mut loops: HashMap<&str, HashSet<&str>> = HashMap::new();
+        for eid in self.nodes.iter().flat_map(|it| &it.peers).flat_map(|it| &it.reaches) {
+            if !loops.contains_key(eid.as_str()) {
+                loops.insert(eid, self.looping_nodes(eid));
+            }
+        }
+
+        for node in &self.nodes {
+            for (index, peer) in node.peers.iter().enumerate() {
+                let mut issue = |severity, kind| result.push(LintIssue {
+                    severity,
+                    node: node.eid.clone(),
+                    peer: peer.eid.clone(),
+                    kind,
+                });
+
+                if node.peers[..index].iter().any(|it| it.eid == peer.eid) {
+                    issue(Severity::Error, LintKind::DuplicatePeer);
+                }
+                if let Some(reliability) = peer.reliability.filter(|it| !RELIABILITY_RANGE.contains(it)) {
+                    issue(Severity::Error, LintKind::ReliabilityOutOfRange(reliability));
+                }
+                if peer.reaches.contains(&node.eid) {
+                    issue(Severity::Error, LintKind::ReachesSelf);
+                }
+                if peer.reaches.contains(&peer.eid) {
+                    issue(Severity::Warning, LintKind::ReachesPeer);
+                }
+                for eid in peer.reaches.iter().filter(|it| **it != node.eid && **it != peer.eid) {
+                    if loops[eid.as_str()].contains(peer.eid.as_str()) {
+                        issue(Severity::Error, LintKind::ReachesLoop(eid.clone()));
+                    }
+                }
+                if !peer.reaches.is_empty() && peer.windows.is_empty() {
+                    issue(Severity::Warning, LintKind::UnreachableEids);
+                }
+
+                let contacts: Vec<_> = peer.windows.iter().map(|it| it.resolve(now)).collect();
+                for (index, contact) in contacts.iter().enumerate() {
+                    let Ok(contact) = contact else {
+                        issue(Severity::Error, LintKind::UnresolvableWindow(index));
+                        continue;
+                    };
+                    if contact.end <= contact.start {
+                        issue(Severity::Error, LintKind::EmptyWindow(index));
+                    } else if contact.end <= now {
+                        issue(Severity::Warning, LintKind::PastWindow(index));
+                    }
+                    if contact.data_rate == ContactDataRate::Limited(0) {
+                        issue(Severity::Warning, LintKind::ZeroCapacity(index));
+                    }
+
+                    for (other_index, other) in contacts.iter().enumerate().skip(index + 1) {
+                        let Ok(other) = other else { continue };
+                        if contact.start < other.end && other.start < contact.end {
+                            issue(Severity::Error, LintKind::OverlappingWindows { first: index, second: other_index });
+                        }
+                    }
+                }
+
+                let total = |contacts: &[Result<crate::config::Contact, _>]| contacts.iter().flatten()
+                    .map(|it| capacity(&it.data_rate, it.end.duration_since(it.start).unwrap_or(Duration::ZERO)))
+                    .try_fold(0u64, |sum, it| Some(sum.saturating_add(it?)));
+                let reverse = self.node(&peer.eid)
+                    .and_then(|it| it.peers.iter().find(|it| it.eid == node.eid))
+                    .map(|it| it.windows.iter().map(|it| it.resolve(now)).collect::<Vec<_>>());
+                if let (Some(sent), Some(Some(received))) = (total(&contacts), reverse.as_deref().map(total)) {
+                    if sent.max(received) > sent.min(received).saturating_mul(ASYMMETRY_FACTOR) {
+                        issue(Severity::Warning, LintKind::AsymmetricCapacity { sent, received });
+                    }
+                }
+            }
+        }
+
+        result.sort_by(|a, b| (&a.node, &a.peer, b.severity).cmp(&(&b.node, &b.peer, a.severity)));
+        result
+    }
+}
+
+/// Summary line of lint issues, e.g. `2 errors, 1 warning`
+pub fn summary(issues: &[LintIssue]) -> String {
+    let errors = issues.iter().filter(|it| it.severity == Severity::Error).count();
+    let warnings = issues.len() - errors;
+    format!(
+        "{} error{}, {} warning{}",
+        errors, if errors == 1 { "" } else { "s" },
+        warnings, if warnings == 1 { "" } else { "s" },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::{ClaAddress, HostPort}, plan::{Peer, PlanNode}};
+
+    use super::*;
+
+    fn peer(eid: &str, reaches: &[&str], windows: &[&str]) -> Peer {
+        Peer {
+            eid: eid.into(),
+            cla: ClaAddress::Mtcp(HostPort::new("10.0.0.1", 4224)),
+            reliability: None,
+            reaches: reaches.iter().map(|it| it.to_string()).collect(),
+            windows: windows.iter().map(|it| it.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn issues_found() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let mut unreliable = peer("dtn://c.dtn/", &["dtn://c.dtn/"], &[]);
+        unreliable.reliability = Some(5000);
+
+        let plan = ContactPlan {
+            nodes: vec![
+                PlanNode {
+                    eid: "dtn://a.dtn/".into(),
+                    peers: vec![
+                        peer("dtn://b.dtn/", &["dtn://a.dtn/"], &[
+                            "+0s for 10m at 1000",
+                            "+5m for 10m at 1000",
+                            "2024-01-01T00:00:00Z for 1h at 1000",
+                            "+1h until +30m at 0",
+                            "+213503982334601d for 1s",
+                        ]),
+                        unreliable,
+                    ],
+                },
+                PlanNode {
+                    eid: "dtn://b.dtn/".into(),
+                    peers: vec![peer("dtn://a.dtn/", &[], &["+0s for 10m at 100"])],
+                },
+            ],
+        };
+
+        let kinds: Vec<(Severity, &str, LintKind)> = plan.lint(now).into_iter()
+            .map(|it| (it.severity, if it.node == "dtn://a.dtn/" { "a" } else { "b" }, it.kind))
+            .collect();
+
+        assert_eq!(kinds, vec![
+            (Severity::Error, "a", LintKind::ReachesSelf),
+            (Severity::Error, "a", LintKind::OverlappingWindows { first: 0, second: 1 }),
+            (Severity::Error, "a", LintKind::EmptyWindow(3)),
+            (Severity::Error, "a", LintKind::UnresolvableWindow(4)),
+            (Severity::Warning, "a", LintKind::PastWindow(2)),
+            (Severity::Warning, "a", LintKind::ZeroCapacity(3)),
+            (Severity::Warning, "a", LintKind::AsymmetricCapacity { sent: 4_800_000, received: 60_000 }),
+            (Severity::Error, "a", LintKind::ReliabilityOutOfRange(5000)),
+            (Severity::Warning, "a", LintKind::ReachesPeer),
+            (Severity::Warning, "a", LintKind::UnreachableEids),
+            (Severity::Warning, "b", LintKind::AsymmetricCapacity { sent: 60_000, received: 4_800_000 }),
+        ]);
+    }
+
+    #[test]
+    fn reaches_loops() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let node = |eid: &str, peers| PlanNode { eid: eid.into(), peers };
+        let plan = ContactPlan {
+            nodes: vec![
+                node("dtn://a.dtn/", vec![
+                    peer("dtn://b.dtn/", &["dtn://d.dtn/", "dtn://e.dtn/"], &["+0s for 10m"]),
+                    peer("dtn://c.dtn/", &["dtn://f.dtn/"], &["+0s for 10m"]),
+                ]),
+                node("dtn://b.dtn/", vec![
+                    peer("dtn://c.dtn/", &["dtn://d.dtn/"], &["+0s for 10m"]),
+                    peer("dtn://e.dtn/", &[], &["+0s for 10m"]),
+                ]),
+                node("dtn://c.dtn/", vec![peer("dtn://a.dtn/", &["dtn://d.dtn/"], &["+0s for 10m"])]),
+            ],
+        };
+
+        let loops: Vec<(String, String, LintKind)> = plan.lint(now).into_iter()
+            .filter(|it| matches!(it.kind, LintKind::ReachesLoop(_)))
+            .map(|it| (it.node, it.peer, it.kind))
+            .collect();
+        let issue = |node: &str, peer: &str| (node.to_owned(), peer.to_owned(), LintKind::ReachesLoop("dtn://d.dtn/".into()));
+        assert_eq!(loops, vec![
+            issue("dtn://a.dtn/", "dtn://b.dtn/"),
+            issue("dtn://b.dtn/", "dtn://c.dtn/"),
+            issue("dtn://c.dtn/", "dtn://a.dtn/"),
+        ]);
+    }
+
+    #[test]
+    fn full_mesh_linted() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let eids: Vec<String> = (0..16).map(|it| format!("dtn://n{}.dtn/", it)).collect();
+        let mut plan = ContactPlan {
+            nodes: eids.iter()
+                .map(|eid| PlanNode {
+                    eid: eid.clone(),
+                    peers: eids.iter()
+                        .filter(|it| *it != eid)
+                        .map(|it| peer(it, &["dtn://x.dtn/"], &["+0s for 10m"]))
+                        .collect(),
+                })
+                .collect(),
+        };
+        let loops = |plan: &ContactPlan| plan.lint(now).into_iter().filter(|it| matches!(it.kind, LintKind::ReachesLoop(_))).count();
+        assert_eq!(loops(&plan), 16 * 15);
+
+        // A single node with the EID as peer delivers for the whole mesh
+        plan.nodes[7].peers.push(peer("dtn://x.dtn/", &[], &["+0s for 10m"]));
+        assert_eq!(loops(&plan), 0);
+    }
+
+    #[test]
+    fn huge_capacity_saturates() {
+        assert_eq!(capacity(&ContactDataRate::Limited(i32::MAX), Duration::MAX), Some(u64::MAX));
+    }
+}
@@ -0,0 +1,787 @@
+//! Periodic contact windows
+//!
+//! A [RecurringWindow] is a [Recurrence] of window starts with a fixed duration, expanded into [Contact] lists
+//! up to a horizon. Times are UTC.
+//!
+//! ```text
+//! every:  every <duration> [from <date and time>]   every 90m from 2024-05-01T00:12:00Z
+//! weekly: weekly <weekdays> at <HH:MM[:SS]>         weekly mon-fri at 07:45
+//! cron:   cron <minute> <hour> <day> <month> <weekday>  cron */30 6-22 * * sat,sun
+//! window: <recurrence> for <duration> [at <bytes per second>]
+//! ```
+//!
+//! [ContactRefresher] keeps a node configured with the windows of the next horizon, sending a
+//! [ConfigBundle::ReplaceContact] for each peer before the windows last sent run out.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::Duration};
+//! use ud3tn_aap::{Agent, config::{ClaAddress, HostPort}, plan::recurrence::{ContactRefresher, RecurringContact}};
+//!
+//! let mut agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("contacts".to_owned()).unwrap();
+//!
+//! let mut refresher = ContactRefresher::new(Duration::from_secs(24 * 60 * 60));
+//! refresher.add(RecurringContact {
+//!     eid: "dtn://bus.dtn/".into(),
+//!     cla_address: Some(ClaAddress::Mtcp(HostPort::new("10.0.0.2", 4224))),
+//!     reliability: Some(900),
+//!     reaches_eid: Vec::new(),
+//!     windows: vec!["weekly mon-fri at 07:45 for 10m at 100000".parse().unwrap()],
+//! });
+//! refresher.run(&mut agent).unwrap();
+//! ```
+
+use std::{convert::Infallible, fmt, ops::BitOr, str::FromStr, thread, time::{Duration, SystemTime}};
+
+use thiserror::Error;
+
+use crate::{config::{ClaAddress, ConfigBundle, Contact, ContactDataRate}, AapStream, RegisteredAgent};
+
+use super::{format_datetime, format_duration, parse_datetime, parse_duration, time::civil_from_days, TimeParseError};
+
+/// Most window starts expanded at once, to bound the size of config bundles
+pub const MAX_OCCURRENCES: usize = 4096;
+
+const DAY: u64 = 24 * 60 * 60;
+
+const WEEKDAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+/// An error parsing a recurrence
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RecurrenceError {
+    /// Recurrence isn't `every`, `weekly` or `cron`
+    #[error("Invalid recurrence {0:?}")]
+    InvalidRecurrence(String),
+
+    /// Weekdays aren't a list of day names or ranges
+    #[error("Invalid weekdays {0:?}")]
+    InvalidWeekdays(String),
+
+    /// Cron expression field is malformed or out of range
+    #[error("Invalid cron field {0:?}")]
+    InvalidCron(String),
+
+    /// Period of zero would repeat forever
+    #[error("Recurrence period is zero")]
+    ZeroPeriod,
+
+    /// Duration, time or data rate is malformed
+    #[error(transparent)]
+    Time(#[from] TimeParseError),
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|it| it.as_secs()).unwrap_or(0)
+}
+
+fn time(seconds: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+/// `time + duration`, or the latest representable time past it
+fn saturating_add(time: SystemTime, duration: Duration) -> SystemTime {
+    time.checked_add(duration).unwrap_or_else(|| {
+        let (mut result, mut step) = (time, duration);
+        while !step.is_zero() {
+            step /= 2;
+            if let Some(it) = result.checked_add(step) {
+                result = it;
+            }
+        }
+        result
+    })
+}
+
+/// Day of week of a number of days since 1970-01-01, 0 is monday
+fn weekday(days: u64) -> u32 {
+    ((days + 3) % 7) as u32
+}
+
+/// A set of days of the week
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Weekdays(u8);
+
+impl Weekdays {
+    /// Monday
+    pub const MONDAY: Self = Self(1);
+    /// Tuesday
+    pub const TUESDAY: Self = Self(1 << 1);
+    /// Wednesday
+    pub const WEDNESDAY: Self = Self(1 << 2);
+    /// Thursday
+    pub const THURSDAY: Self = Self(1 << 3);
+    /// Friday
+    pub const FRIDAY: Self = Self(1 << 4);
+    /// Saturday
+    pub const SATURDAY: Self = Self(1 << 5);
+    /// Sunday
+    pub const SUNDAY: Self = Self(1 << 6);
+    /// Monday to friday
+    pub const WORKDAYS: Self = Self(0b0011111);
+    /// Saturday and sunday
+    pub const WEEKEND: Self = Self(0b1100000);
+    /// Every day
+    pub const ALL: Self = Self(0b1111111);
+
+    /// Whether a day is in the set, 0 is monday and 6 sunday
+    pub fn contains(self, weekday: u32) -> bool {
+        weekday < 7 && self.0 & (1 << weekday) != 0
+    }
+
+    /// Whether no day is in the set
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    fn with(self, weekday: u32) -> Self {
+        Self(self.0 | 1 << weekday)
+    }
+}
+
+impl BitOr for Weekdays {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = (0..7).filter(|it| self.contains(*it)).map(|it| WEEKDAY_NAMES[it as usize]).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl FromStr for Weekdays {
+    type Err = RecurrenceError;
+
+    /// Parse day names and wrapping ranges such as `mon-fri,sun` or `sat-mon`, `*` is every day
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || RecurrenceError::InvalidWeekdays(s.to_owned());
+        if s == "*" {
+            return Ok(Self::ALL);
+        }
+
+        let day = |name: &str| {
+            WEEKDAY_NAMES.iter().position(|it| it.eq_ignore_ascii_case(name)).map(|it| it as u32).ok_or_else(error)
+        };
+
+        let mut result = Self::default();
+        for part in s.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (day(first)?, day(last)?),
+                None => (day(part)?, day(part)?),
+            };
+            let mut current = first;
+            result = result.with(current);
+            while current != last {
+                current = (current + 1) % 7;
+                result = result.with(current);
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// A cron expression `<minute> <hour> <day of month> <month> <day of week>` in UTC
+///
+/// Fields accept `*`, numbers, names (`jan`, `mon`), ranges `a-b`, steps `*/n` or `a-b/n` and comma separated lists.
+/// Day of week is 0 to 7, both 0 and 7 being sunday. As with usual cron implementations, when both day fields are
+/// restricted a day matching either one matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: Weekdays,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, RecurrenceError> {
+    let error = || RecurrenceError::InvalidCron(field.to_owned());
+    let value = |s: &str| match names.iter().position(|it| it.eq_ignore_ascii_case(s)) {
+        Some(index) => Ok(index as u32 + min),
+        None => s.parse::<u32>().ok().filter(|it| (min..=max).contains(it)).ok_or_else(error),
+    };
+
+    let mut result = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|it| *it > 0).ok_or_else(error)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(error());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            result |= 1 << value;
+        }
+    }
+    Ok(result)
+}
+
+impl Cron {
+    fn matches_day(&self, days: u64) -> bool {
+        let (_, month, day) = civil_from_days(days as i64);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays.contains(weekday(days));
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        }
+    }
+
+    /// Matching times from `from` included to `until` excluded, at most [MAX_OCCURRENCES]
+    pub fn times(&self, from: SystemTime, until: SystemTime) -> Vec<SystemTime> {
+        let (from, until) = (seconds(from), seconds(until));
+        let mut result = Vec::new();
+        if from >= until {
+            return result;
+        }
+
+        for days in from / DAY..=(until - 1) / DAY {
+            if !self.matches_day(days) {
+                continue;
+            }
+            for hour in (0..24).filter(|it| self.hours & (1 << it) != 0) {
+                for minute in (0..60).filter(|it| self.minutes & (1 << it) != 0) {
+                    let start = days * DAY + hour * 3600 + minute * 60;
+                    if start >= until || result.len() == MAX_OCCURRENCES {
+                        return result;
+                    }
+                    if start >= from {
+                        result.push(time(start));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl FromStr for Cron {
+    type Err = RecurrenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            return Err(RecurrenceError::InvalidCron(s.to_owned()));
+        };
+
+        let sunday_first = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+        let cron_weekdays = parse_field(weekdays, 0, 7, &sunday_first)?;
+        let weekdays = (0..=7)
+            .filter(|it| cron_weekdays & (1 << it) != 0)
+            .fold(Weekdays::default(), |result, it| result.with((it + 6) % 7));
+
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59, &[])?,
+            hours: parse_field(hours, 0, 23, &[])?,
+            days: parse_field(days, 1, 31, &[])?,
+            months: parse_field(months, 1, 12, &MONTH_NAMES)?,
+            weekdays,
+            any_day: days.starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+}
+
+/// When windows start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recurrence {
+    /// Every period from a first start, written `every 90m from 2024-05-01T00:12:00Z`
+    ///
+    /// Without `from`, starts are aligned on multiples of period since 1970-01-01
+    Every {
+        /// A start of window
+        first: SystemTime,
+        /// Time between window starts
+        period: Duration,
+    },
+
+    /// Once a day on some days of week, written `weekly mon-fri at 07:45`
+    Weekly {
+        /// Days with a window
+        days: Weekdays,
+        /// Time since midnight UTC of window start
+        time_of_day: Duration,
+    },
+
+    /// Times matching a cron expression, written `cron 0 8 * * 1-5`
+    Cron(Cron),
+}
+
+impl Recurrence {
+    /// Window starts from `from` included to `until` excluded, at most [MAX_OCCURRENCES]
+    pub fn starts(&self, from: SystemTime, until: SystemTime) -> Vec<SystemTime> {
+        match self {
+            Recurrence::Every { first, period } => {
+                if period.is_zero() {
+                    return Vec::new();
+                }
+                let skipped = match from.duration_since(*first) {
+                    Ok(elapsed) => elapsed.as_nanos().div_ceil(period.as_nanos()),
+                    Err(_) => 0,
+                };
+                (skipped..)
+                    .map_while(|it| u32::try_from(it).ok().and_then(|it| period.checked_mul(it)))
+                    .map_while(|it| first.checked_add(it))
+                    .take_while(|it| *it < until)
+                    .take(MAX_OCCURRENCES)
+                    .collect()
+            }
+            Recurrence::Weekly { days, time_of_day } => {
+                let offset = time_of_day.as_secs();
+                let first_day = seconds(from).saturating_sub(offset) / DAY;
+                (first_day..)
+                    .map_while(|day| Some((day, SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(day.checked_mul(DAY)?.checked_add(offset)?))?)))
+                    .take_while(|(_, start)| *start < until)
+                    .filter(|(day, start)| *start >= from && days.contains(weekday(*day)))
+                    .map(|(_, start)| start)
+                    .take(MAX_OCCURRENCES)
+                    .collect()
+            }
+            Recurrence::Cron(cron) => cron.times(from, until),
+        }
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recurrence::Every { first, period } => {
+                write!(f, "every {} from {}", format_duration(*period), format_datetime(*first))
+            }
+            Recurrence::Weekly { days, time_of_day } => {
+                let seconds = time_of_day.as_secs();
+                write!(f, "weekly {} at {:02}:{:02}", days, seconds / 3600, seconds % 3600 / 60)?;
+                match seconds % 60 {
+                    0 => Ok(()),
+                    second => write!(f, ":{:02}", second),
+                }
+            }
+            Recurrence::Cron(cron) => write!(f, "cron {}", cron),
+        }
+    }
+}
+
+fn parse_time_of_day(s: &str) -> Option<Duration> {
+    let parts: Vec<u64> = s.split(':').map(|it| it.parse().ok()).collect::<Option<_>>()?;
+    let (hour, minute, second) = match parts.as_slice() {
+        [hour, minute] => (*hour, *minute, 0),
+        [hour, minute, second] => (*hour, *minute, *second),
+        _ => return None,
+    };
+    (hour < 24 && minute < 60 && second < 60).then(|| Duration::from_secs(hour * 3600 + minute * 60 + second))
+}
+
+impl FromStr for Recurrence {
+    type Err = RecurrenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || RecurrenceError::InvalidRecurrence(s.to_owned());
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words.as_slice() {
+            ["every", period, rest @ ..] => {
+                let text = period;
+                let period = parse_duration(period)?;
+                if period.is_zero() {
+                    return Err(RecurrenceError::ZeroPeriod);
+                }
+                let first = match rest {
+                    [] => SystemTime::UNIX_EPOCH,
+                    ["from", first] => parse_datetime(first).ok_or_else(|| TimeParseError::InvalidTime(first.to_string()))?,
+                    _ => return Err(error()),
+                };
+                if first.checked_add(period).is_none() {
+                    return Err(TimeParseError::InvalidDuration(text.to_string()).into());
+                }
+                Ok(Recurrence::Every { first, period })
+            }
+            ["weekly", days, "at", time_of_day] => Ok(Recurrence::Weekly {
+                days: days.parse()?,
+                time_of_day: parse_time_of_day(time_of_day).ok_or_else(|| TimeParseError::InvalidTime(time_of_day.to_string()))?,
+            }),
+            ["cron", fields @ ..] => Ok(Recurrence::Cron(fields.join(" ").parse()?)),
+            _ => Err(error()),
+        }
+    }
+}
+
+/// Windows of a fixed duration starting at each occurrence of a recurrence, written `<recurrence> for 10m at 1200`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "String", into = "String"))]
+pub struct RecurringWindow {
+    /// When windows start
+    pub recurrence: Recurrence,
+
+    /// How long windows last
+    pub duration: Duration,
+
+    /// Expected transmission rate, unlimited if not written
+    pub data_rate: ContactDataRate,
+}
+
+impl RecurringWindow {
+    /// Contacts overlapping `from` to `until`, including a window already open at `from`
+    ///
+    /// Windows ending past the latest representable time are left out.
+    pub fn contacts(&self, from: SystemTime, until: SystemTime) -> Vec<Contact> {
+        let open_since = from.checked_sub(self.duration).map(|it| it + Duration::from_secs(1)).unwrap_or(from);
+        self.recurrence.starts(open_since.min(from), until)
+            .into_iter()
+            .map_while(|start| Some(Contact { start, end: start.checked_add(self.duration)?, data_rate: self.data_rate.clone() }))
+            .collect()
+    }
+}
+
+impl fmt::Display for RecurringWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} for {}", self.recurrence, format_duration(self.duration))?;
+        match self.data_rate {
+            ContactDataRate::Limited(rate) => write!(f, " at {}", rate),
+            ContactDataRate::Unlimited => Ok(()),
+        }
+    }
+}
+
+impl FromStr for RecurringWindow {
+    type Err = RecurrenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (recurrence, rest) = s.rsplit_once(" for ").ok_or_else(|| RecurrenceError::InvalidRecurrence(s.to_owned()))?;
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let (duration, rate) = match words.as_slice() {
+            [duration] => (*duration, None),
+            [duration, "at", rate] => (*duration, Some(*rate)),
+            _ => return Err(TimeParseError::InvalidWindow(s.to_owned()).into()),
+        };
+
+        let data_rate = match rate {
+            None | Some("unlimited") => ContactDataRate::Unlimited,
+            Some(rate) => ContactDataRate::Limited(rate.parse().map_err(|_| TimeParseError::InvalidDataRate(rate.to_owned()))?),
+        };
+
+        let text = duration;
+        let duration = parse_duration(duration)?;
+        if SystemTime::UNIX_EPOCH.checked_add(duration).is_none() {
+            return Err(TimeParseError::InvalidDuration(text.to_owned()).into());
+        }
+        Ok(Self { recurrence: recurrence.parse()?, duration, data_rate })
+    }
+}
+
+impl TryFrom<String> for RecurringWindow {
+    type Error = RecurrenceError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RecurringWindow> for String {
+    fn from(value: RecurringWindow) -> Self {
+        value.to_string()
+    }
+}
+
+/// A peer with periodic contacts, sent as a [ConfigBundle::ReplaceContact]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecurringContact {
+    /// EID of peer node
+    pub eid: String,
+
+    /// CLA address of peer, kept as configured on the node if [None]
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub cla_address: Option<ClaAddress>,
+
+    /// Expected likelihood of future contacts, between 100 and 1000
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub reliability: Option<i32>,
+
+    /// EIDs reachable through peer
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub reaches_eid: Vec<String>,
+
+    /// Periodic windows with peer
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub windows: Vec<RecurringWindow>,
+}
+
+impl RecurringContact {
+    /// Contacts of all windows overlapping `from` to `until`, sorted by start
+    pub fn contacts(&self, from: SystemTime, until: SystemTime) -> Vec<Contact> {
+        let mut result: Vec<Contact> = self.windows.iter().flat_map(|it| it.contacts(from, until)).collect();
+        result.sort_by_key(|it| (it.start, it.end));
+        result
+    }
+
+    /// [ConfigBundle::ReplaceContact] with contacts overlapping `from` to `until`
+    pub fn to_config_bundle(&self, from: SystemTime, until: SystemTime) -> ConfigBundle {
+        ConfigBundle::ReplaceContact {
+            eid: self.eid.clone(),
+            reliability: self.reliability,
+            cla_address: self.cla_address.clone(),
+            reaches_eid: self.reaches_eid.clone(),
+            contacts: self.contacts(from, until),
+        }
+    }
+}
+
+/// Keeps the windows of the next horizon configured on a node
+///
+/// Each peer is sent again once the windows last sent cover less than the lead time, half the horizon by default.
+#[derive(Debug, Clone)]
+pub struct ContactRefresher {
+    horizon: Duration,
+    lead: Duration,
+    contacts: Vec<(RecurringContact, Option<SystemTime>)>,
+}
+
+impl ContactRefresher {
+    /// Refresher sending windows starting within `horizon`
+    pub fn new(horizon: Duration) -> Self {
+        Self { horizon, lead: horizon / 2, contacts: Vec::new() }
+    }
+
+    /// Send windows again when those sent cover less than `lead`, clamped to the horizon
+    pub fn with_lead(mut self, lead: Duration) -> Self {
+        self.lead = lead.min(self.horizon);
+        self
+    }
+
+    /// Add a peer, sent on next refresh
+    pub fn add(&mut self, contact: RecurringContact) {
+        self.remove(&contact.eid);
+        self.contacts.push((contact, None));
+    }
+
+    /// Stop refreshing a peer, windows already sent stay configured
+    pub fn remove(&mut self, eid: &str) -> Option<RecurringContact> {
+        let index = self.contacts.iter().position(|(it, _)| it.eid == eid)?;
+        Some(self.contacts.remove(index).0)
+    }
+
+    /// Refreshed peers
+    pub fn contacts(&self) -> impl Iterator<Item = &RecurringContact> {
+        self.contacts.iter().map(|(it, _)| it)
+    }
+
+    /// Time of next refresh, [None] without peers
+    pub fn next_refresh(&self) -> Option<SystemTime> {
+        self.contacts.iter()
+            .map(|(_, sent_until)| sent_until.and_then(|it| it.checked_sub(self.lead)).unwrap_or(SystemTime::UNIX_EPOCH))
+            .min()
+    }
+
+    /// Indexes of peers to refresh at `now`
+    fn due(&self, now: SystemTime) -> Vec<usize> {
+        self.contacts.iter().enumerate()
+            .filter(|(_, (_, sent_until))| sent_until.is_none_or(|it| it <= saturating_add(now, self.lead)))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Config bundles of peers to refresh at `now`, considered sent
+    pub fn take_due(&mut self, now: SystemTime) -> Vec<ConfigBundle> {
+        let until = saturating_add(now, self.horizon);
+        self.due(now).into_iter()
+            .map(|index| {
+                let (contact, sent_until) = &mut self.contacts[index];
+                *sent_until = Some(until);
+                contact.to_config_bundle(now, until)
+            })
+            .collect()
+    }
+
+    /// Send config bundles of peers to refresh at `now`, returns the number of peers sent
+    ///
+    /// Peers are only considered sent once the node confirmed them, they are sent again on next refresh otherwise
+    pub fn refresh<S: AapStream>(&mut self, agent: &mut RegisteredAgent<S>, now: SystemTime) -> Result<usize, crate::Error> {
+        let until = saturating_add(now, self.horizon);
+        let due = self.due(now);
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        let configs: Vec<ConfigBundle> = due.iter().map(|index| self.contacts[*index].0.to_config_bundle(now, until)).collect();
+        agent.send_config_batch(&configs)?;
+        for index in &due {
+            self.contacts[*index].1 = Some(until);
+        }
+        Ok(due.len())
+    }
+
+    /// Refresh forever, sleeping until next refresh
+    pub fn run<S: AapStream>(&mut self, agent: &mut RegisteredAgent<S>) -> Result<Infallible, crate::Error> {
+        loop {
+            self.refresh(agent, SystemTime::now())?;
+            let sleep = match self.next_refresh() {
+                Some(next) => next.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO),
+                None => self.lead.max(Duration::from_secs(1)),
+            };
+            thread::sleep(sleep.max(Duration::from_secs(1)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> SystemTime {
+        parse_datetime(s).unwrap()
+    }
+
+    fn starts(recurrence: &str, from: &str, until: &str) -> Vec<String> {
+        recurrence.parse::<Recurrence>().unwrap()
+            .starts(at(from), at(until))
+            .into_iter()
+            .map(format_datetime)
+            .collect()
+    }
+
+    #[test]
+    fn recurrences_expanded() {
+        assert_eq!(
+            starts("every 90m from 2024-05-01T00:12:00Z", "2024-05-01T01:00:00Z", "2024-05-01T05:00:00Z"),
+            vec!["2024-05-01T01:42:00Z", "2024-05-01T03:12:00Z", "2024-05-01T04:42:00Z"],
+        );
+        // 2024-05-03 is a friday
+        assert_eq!(
+            starts("weekly mon-fri at 07:45", "2024-05-03T07:45:00Z", "2024-05-07T00:00:00Z"),
+            vec!["2024-05-03T07:45:00Z", "2024-05-06T07:45:00Z"],
+        );
+        assert_eq!(
+            starts("cron */30 6-7 * * sat,sun", "2024-05-03T00:00:00Z", "2024-05-05T00:00:00Z"),
+            vec!["2024-05-04T06:00:00Z", "2024-05-04T06:30:00Z", "2024-05-04T07:00:00Z", "2024-05-04T07:30:00Z"],
+        );
+        // Either the 1st of month or a sunday
+        assert_eq!(
+            starts("cron 0 12 1 * 0", "2024-05-01T00:00:00Z", "2024-05-13T00:00:00Z"),
+            vec!["2024-05-01T12:00:00Z", "2024-05-05T12:00:00Z", "2024-05-12T12:00:00Z"],
+        );
+
+        for s in ["every 1h30m from 2024-05-01T00:12:00Z", "weekly mon,wed,sat at 07:45:30", "cron 0 8 * jan-mar 1-5"] {
+            assert_eq!(s.parse::<Recurrence>().unwrap().to_string(), s);
+        }
+        assert_eq!("every 0s".parse::<Recurrence>(), Err(RecurrenceError::ZeroPeriod));
+        assert!("cron 60 * * * *".parse::<Recurrence>().is_err());
+        assert!("weekly mon-xyz at 07:00".parse::<Recurrence>().is_err());
+    }
+
+    #[test]
+    fn windows_refreshed() {
+        let window: RecurringWindow = "every 1h for 10m at 1200".parse().unwrap();
+        assert_eq!(window.to_string(), "every 1h from 1970-01-01T00:00:00Z for 10m at 1200");
+
+        // Window open at start of horizon is kept
+        let contacts = window.contacts(at("2024-05-01T00:05:00Z"), at("2024-05-01T02:00:00Z"));
+        assert_eq!(contacts, vec![
+            Contact::from_during(at("2024-05-01T00:00:00Z"), Duration::from_secs(600), ContactDataRate::Limited(1200)),
+            Contact::from_during(at("2024-05-01T01:00:00Z"), Duration::from_secs(600), ContactDataRate::Limited(1200)),
+        ]);
+
+        let mut refresher = ContactRefresher::new(Duration::from_secs(4 * 3600));
+        refresher.add(RecurringContact {
+            eid: "dtn://bus.dtn/".into(),
+            cla_address: None,
+            reliability: None,
+            reaches_eid: Vec::new(),
+            windows: vec![window],
+        });
+
+        let now = at("2024-05-01T00:30:00Z");
+        let configs = refresher.take_due(now);
+        assert!(matches!(&configs[..], [ConfigBundle::ReplaceContact { contacts, .. }] if contacts.len() == 4));
+        assert_eq!(refresher.next_refresh(), Some(at("2024-05-01T02:30:00Z")));
+        assert!(refresher.take_due(at("2024-05-01T02:29:59Z")).is_empty());
+        assert_eq!(refresher.take_due(at("2024-05-01T02:30:00Z")).len(), 1);
+    }
+
+    #[test]
+    fn overflow_refused() {
+        let huge = "18446744073709551615s";
+        let invalid = RecurrenceError::Time(TimeParseError::InvalidDuration(huge.to_owned()));
+        assert_eq!(format!("every {}", huge).parse::<Recurrence>(), Err(invalid.clone()));
+        assert_eq!(format!("every 1h for {}", huge).parse::<RecurringWindow>(), Err(invalid));
+
+        // Starts past the latest representable time end the expansion
+        let now = at("2024-05-01T00:00:00Z");
+        let hour = Duration::from_secs(3600);
+        let recurrence = Recurrence::Every { first: now, period: Duration::MAX };
+        assert_eq!(recurrence.starts(now, now + hour), vec![now]);
+
+        let window = RecurringWindow {
+            recurrence: Recurrence::Every { first: now, period: hour },
+            duration: Duration::from_secs(i64::MAX as u64),
+            data_rate: ContactDataRate::Unlimited,
+        };
+        assert!(window.contacts(now, now + hour).is_empty());
+
+        let mut refresher = ContactRefresher::new(Duration::MAX).with_lead(hour);
+        refresher.add(RecurringContact {
+            eid: "dtn://bus.dtn/".into(),
+            cla_address: None,
+            reliability: None,
+            reaches_eid: Vec::new(),
+            windows: vec!["every 1h for 10m".parse().unwrap()],
+        });
+        let configs = refresher.take_due(now);
+        assert!(matches!(&configs[..], [ConfigBundle::ReplaceContact { contacts, .. }] if contacts.len() == MAX_OCCURRENCES));
+        assert!(refresher.take_due(now).is_empty());
+        assert!(refresher.next_refresh().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn failed_refresh_retried() {
+        use crate::{testing::{Fault, MockNode}, Agent};
+
+        let mut refresher = ContactRefresher::new(Duration::from_secs(4 * 3600));
+        refresher.add(RecurringContact {
+            eid: "dtn://bus.dtn/".into(),
+            cla_address: None,
+            reliability: None,
+            reaches_eid: Vec::new(),
+            windows: vec!["every 1h for 10m".parse().unwrap()],
+        });
+
+        let node = MockNode::new("dtn://a.dtn/");
+        let mut agent = Agent::new(node.connect()).unwrap().register("planner".into()).unwrap();
+        let now = at("2024-05-01T00:30:00Z");
+
+        node.inject(Fault::Nack);
+        assert!(refresher.refresh(&mut agent, now).is_err());
+        assert_eq!(refresher.next_refresh(), Some(SystemTime::UNIX_EPOCH));
+
+        assert_eq!(refresher.refresh(&mut agent, now).unwrap(), 1);
+        assert_eq!(node.config_bundles().len(), 1);
+        assert_eq!(refresher.refresh(&mut agent, now).unwrap(), 0);
+    }
+}
@@ -0,0 +1,361 @@
+//! Incremental synchronization of contact plans
+//!
+//! [NodeConfig] tracks the peers configured on a node by the config bundles applied to it.
+//! [NodeConfig::diff] computes the commands turning one configuration into another, and [PlanSync] remembers
+//! the configuration last applied to each node on disk so only the difference is sent when a plan changes.
+//!
+//! Config commands are assumed to follow ud3tn semantics: [ConfigBundle::AddContact] merges reachable EIDs
+//! and contacts into an existing peer, [ConfigBundle::ReplaceContact] replaces them, and contacts are dropped
+//! by the node once they ended.
+//!
+//! Relative plan windows are resolved at each synchronization, so they move and are sent again every time.
+//! Plans meant for repeated synchronization should use absolute times.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::SystemTime};
+//! use ud3tn_aap::{Agent, plan::{ContactPlan, sync::PlanSync}};
+//!
+//! let mut agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("planner".to_owned()).unwrap();
+//! let sync = PlanSync::new(Path::new("/var/lib/planner"));
+//!
+//! let plan = ContactPlan::load(Path::new("contacts.toml")).unwrap();
+//! let sent = sync.sync(&mut agent, &plan, SystemTime::now()).unwrap();
+//! println!("{} config commands sent", sent.len());
+//! ```
+
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, time::SystemTime};
+
+use crate::{config::{ClaAddress, ConfigBatch, ConfigBundle, Contact}, AapStream, BaseAgent, RegisteredAgent};
+
+use super::{ContactPlan, PlanError};
+
+/// Configuration of a peer on a node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerConfig {
+    /// CLA address of peer
+    pub cla_address: ClaAddress,
+
+    /// Expected likelihood of future contacts
+    pub reliability: Option<i32>,
+
+    /// EIDs reachable through peer, sorted
+    pub reaches_eid: Vec<String>,
+
+    /// Contacts with peer, sorted by start then end
+    pub contacts: Vec<Contact>,
+}
+
+impl PeerConfig {
+    fn normalize(&mut self) {
+        self.reaches_eid.sort();
+        self.reaches_eid.dedup();
+        self.contacts.sort_by_key(|it| (it.start, it.end));
+        self.contacts.dedup();
+    }
+}
+
+/// Peers configured on a node, by EID
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeConfig {
+    /// Configured peers
+    pub peers: BTreeMap<String, PeerConfig>,
+}
+
+impl NodeConfig {
+    /// Configuration resulting from applying config bundles in order on an empty node
+    pub fn from_config_bundles<'a>(configs: impl IntoIterator<Item = &'a ConfigBundle>) -> Self {
+        let mut result = Self::default();
+        for config in configs {
+            result.apply(config);
+        }
+        result
+    }
+
+    /// Update configuration as the node does when receiving `config`
+    pub fn apply(&mut self, config: &ConfigBundle) {
+        match config {
+            ConfigBundle::AddContact { eid, reliability, cla_address, reaches_eid, contacts } => {
+                let peer = self.peers.entry(eid.clone()).or_insert_with(|| PeerConfig {
+                    cla_address: cla_address.clone(),
+                    reliability: None,
+                    reaches_eid: Vec::new(),
+                    contacts: Vec::new(),
+                });
+                peer.cla_address = cla_address.clone();
+                peer.reliability = reliability.or(peer.reliability);
+                peer.reaches_eid.extend(reaches_eid.iter().cloned());
+                peer.contacts.extend(contacts.iter().cloned());
+                peer.normalize();
+            }
+            ConfigBundle::ReplaceContact { eid, reliability, cla_address, reaches_eid, contacts } => {
+                let peer = match (self.peers.get_mut(eid), cla_address) {
+                    (Some(peer), _) => peer,
+                    (None, Some(cla_address)) => self.peers.entry(eid.clone()).or_insert_with(|| PeerConfig {
+                        cla_address: cla_address.clone(),
+                        reliability: None,
+                        reaches_eid: Vec::new(),
+                        contacts: Vec::new(),
+                    }),
+                    (None, None) => return,
+                };
+                if let Some(cla_address) = cla_address {
+                    peer.cla_address = cla_address.clone();
+                }
+                peer.reliability = reliability.or(peer.reliability);
+                peer.reaches_eid = reaches_eid.clone();
+                peer.contacts = contacts.clone();
+                peer.normalize();
+            }
+            ConfigBundle::DeleteContact(eid) => {
+                self.peers.remove(eid);
+            }
+        }
+    }
+
+    /// Remove contacts ended at `now`, as the node does
+    pub fn prune(&mut self, now: SystemTime) {
+        for peer in self.peers.values_mut() {
+            peer.contacts.retain(|it| it.end > now);
+        }
+    }
+
+    /// One [ConfigBundle::AddContact] per peer, recreating this configuration on an empty node
+    pub fn to_config_bundles(&self) -> Vec<ConfigBundle> {
+        self.peers.iter()
+            .map(|(eid, peer)| ConfigBundle::AddContact {
+                eid: eid.clone(),
+                reliability: peer.reliability,
+                cla_address: peer.cla_address.clone(),
+                reaches_eid: peer.reaches_eid.clone(),
+                contacts: peer.contacts.clone(),
+            })
+            .collect()
+    }
+
+    /// Commands turning this configuration into `target`, one per changed peer
+    ///
+    /// Deletions come first, then changes and additions, by peer EID.
+    /// A peer only gaining reachable EIDs or contacts is sent an [ConfigBundle::AddContact] with the new ones only,
+    /// other changes are sent as [ConfigBundle::ReplaceContact].
+    ///
+    /// As reliability can't be unset, a peer losing its reliability is deleted then added again. The node
+    /// forgets everything it knew about the peer in between, including bundles already routed through its
+    /// contacts, which are rescheduled or dropped by the node. Keep a reliability to avoid this.
+    pub fn diff(&self, target: &NodeConfig) -> Vec<ConfigBundle> {
+        let mut deletions = Vec::new();
+        let mut changes = Vec::new();
+
+        for eid in self.peers.keys().filter(|it| !target.peers.contains_key(*it)) {
+            deletions.push(ConfigBundle::DeleteContact(eid.clone()));
+        }
+
+        for (eid, new) in &target.peers {
+            let add_all = || ConfigBundle::AddContact {
+                eid: eid.clone(),
+                reliability: new.reliability,
+                cla_address: new.cla_address.clone(),
+                reaches_eid: new.reaches_eid.clone(),
+                contacts: new.contacts.clone(),
+            };
+
+            let Some(old) = self.peers.get(eid) else {
+                changes.push(add_all());
+                continue;
+            };
+            if old == new {
+                continue;
+            }
+
+            if old.reliability.is_some() && new.reliability.is_none() {
+                deletions.push(ConfigBundle::DeleteContact(eid.clone()));
+                changes.push(add_all());
+            } else if old.cla_address == new.cla_address
+                && old.reliability == new.reliability
+                && old.reaches_eid.iter().all(|it| new.reaches_eid.contains(it))
+                && old.contacts.iter().all(|it| new.contacts.contains(it))
+            {
+                changes.push(ConfigBundle::AddContact {
+                    eid: eid.clone(),
+                    reliability: None,
+                    cla_address: new.cla_address.clone(),
+                    reaches_eid: new.reaches_eid.iter().filter(|it| !old.reaches_eid.contains(it)).cloned().collect(),
+                    contacts: new.contacts.iter().filter(|it| !old.contacts.contains(it)).cloned().collect(),
+                });
+            } else {
+                changes.push(ConfigBundle::ReplaceContact {
+                    eid: eid.clone(),
+                    reliability: if old.reliability != new.reliability { new.reliability } else { None },
+                    cla_address: if old.cla_address != new.cla_address { Some(new.cla_address.clone()) } else { None },
+                    reaches_eid: new.reaches_eid.clone(),
+                    contacts: new.contacts.clone(),
+                });
+            }
+        }
+
+        deletions.extend(changes);
+        deletions
+    }
+}
+
+/// Last applied configuration of nodes, stored as ud3tn config commands in a directory
+#[derive(Debug, Clone)]
+pub struct PlanSync {
+    state_dir: PathBuf,
+}
+
+impl PlanSync {
+    /// Store applied configurations in `state_dir`
+    pub fn new(state_dir: &Path) -> Self {
+        Self { state_dir: state_dir.to_owned() }
+    }
+
+    fn state_path(&self, node_eid: &str) -> PathBuf {
+        let name: String = node_eid.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect();
+        self.state_dir.join(format!("{}.config", name))
+    }
+
+    /// Configuration last applied to a node, empty if it was never synchronized
+    pub fn applied(&self, node_eid: &str) -> Result<NodeConfig, PlanError> {
+        match fs::read_to_string(self.state_path(node_eid)) {
+            Ok(content) => Ok(NodeConfig::from_config_bundles(&ConfigBundle::parse_all(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(NodeConfig::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record configuration applied to a node
+    pub fn store(&self, node_eid: &str, config: &NodeConfig) -> Result<(), PlanError> {
+        fs::create_dir_all(&self.state_dir)?;
+        let content: String = config.to_config_bundles().iter().map(|it| it.to_string() + "\n").collect();
+
+        let path = self.state_path(node_eid);
+        let temporary_path = path.with_extension("config.tmp");
+        fs::write(&temporary_path, content)?;
+        fs::rename(&temporary_path, &path)?;
+        Ok(())
+    }
+
+    /// Send the commands turning the configuration last applied to the node of `agent` into `plan` applied at `now`
+    ///
+    /// Contacts ended at `now` are ignored on both sides, the node already dropped them.
+    /// Commands are sent in [ConfigBatch] bundles. Stored configuration is updated after each bundle,
+    /// so an interrupted synchronization resumes where it stopped.
+    /// Returns commands sent
+    pub fn sync<S: AapStream>(
+        &self,
+        agent: &mut RegisteredAgent<S>,
+        plan: &ContactPlan,
+        now: SystemTime,
+    ) -> Result<Vec<ConfigBundle>, PlanError> {
+        let node_eid = agent.node_id().to_owned();
+        let mut target = NodeConfig::from_config_bundles(&plan.to_config_bundles(&node_eid, now)?);
+        let mut applied = self.applied(&node_eid)?;
+        target.prune(now);
+        applied.prune(now);
+
+        let batch: ConfigBatch = applied.diff(&target).into_iter().collect();
+        for chunk in batch.chunks()? {
+            agent.send_config_batch(chunk)?;
+            chunk.iter().for_each(|it| applied.apply(it));
+            self.store(&node_eid, &applied)?;
+        }
+
+        Ok(batch.commands().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::{ContactDataRate, HostPort};
+
+    use super::*;
+
+    fn contact(start: u64) -> Contact {
+        Contact::from_during(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + start),
+            Duration::from_secs(60),
+            ContactDataRate::Unlimited,
+        )
+    }
+
+    fn add(eid: &str, reliability: Option<i32>, port: u16, contacts: Vec<Contact>) -> ConfigBundle {
+        ConfigBundle::AddContact {
+            eid: eid.into(),
+            reliability,
+            cla_address: ClaAddress::Mtcp(HostPort::new("10.0.0.1", port)),
+            reaches_eid: Vec::new(),
+            contacts,
+        }
+    }
+
+    #[test]
+    fn minimal_commands() {
+        let old = NodeConfig::from_config_bundles(&[
+            add("dtn://kept.dtn/", None, 4224, vec![contact(0)]),
+            add("dtn://extended.dtn/", Some(500), 4224, vec![contact(0)]),
+            add("dtn://moved.dtn/", None, 4224, vec![contact(0), contact(100)]),
+            add("dtn://unreliable.dtn/", Some(500), 4224, Vec::new()),
+            add("dtn://removed.dtn/", None, 4224, Vec::new()),
+        ]);
+        let new = NodeConfig::from_config_bundles(&[
+            add("dtn://kept.dtn/", None, 4224, vec![contact(0)]),
+            add("dtn://extended.dtn/", Some(500), 4224, vec![contact(200), contact(0)]),
+            add("dtn://moved.dtn/", None, 4225, vec![contact(100)]),
+            add("dtn://unreliable.dtn/", None, 4224, Vec::new()),
+            add("dtn://added.dtn/", None, 4224, vec![contact(300)]),
+        ]);
+
+        let commands = old.diff(&new);
+        assert_eq!(commands, vec![
+            ConfigBundle::DeleteContact("dtn://removed.dtn/".into()),
+            ConfigBundle::DeleteContact("dtn://unreliable.dtn/".into()),
+            add("dtn://added.dtn/", None, 4224, vec![contact(300)]),
+            add("dtn://extended.dtn/", None, 4224, vec![contact(200)]),
+            ConfigBundle::ReplaceContact {
+                eid: "dtn://moved.dtn/".into(),
+                reliability: None,
+                cla_address: Some(ClaAddress::Mtcp(HostPort::new("10.0.0.1", 4225))),
+                reaches_eid: Vec::new(),
+                contacts: vec![contact(100)],
+            },
+            add("dtn://unreliable.dtn/", None, 4224, Vec::new()),
+        ]);
+
+        let mut applied = old.clone();
+        commands.iter().for_each(|it| applied.apply(it));
+        assert_eq!(applied, new);
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn ended_contacts_ignored() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_170);
+        let mut old = NodeConfig::from_config_bundles(&[add("dtn://peer.dtn/", None, 4224, vec![contact(0), contact(200)])]);
+        let mut new = NodeConfig::from_config_bundles(&[add("dtn://peer.dtn/", None, 4224, vec![contact(100), contact(200)])]);
+        assert_eq!(old.diff(&new).len(), 1);
+
+        old.prune(now);
+        new.prune(now);
+        assert!(old.diff(&new).is_empty());
+        assert_eq!(new.peers["dtn://peer.dtn/"].contacts, vec![contact(200)]);
+    }
+
+    #[test]
+    fn stored_configuration() {
+        let dir = std::env::temp_dir().join(format!("ud3tn-aap-plan-sync-{}", std::process::id()));
+        let sync = PlanSync::new(&dir);
+        assert_eq!(sync.applied("dtn://node.dtn/").unwrap(), NodeConfig::default());
+
+        let config = NodeConfig::from_config_bundles(&[add("dtn://peer.dtn/", Some(900), 4224, vec![contact(0)])]);
+        sync.store("dtn://node.dtn/", &config).unwrap();
+        assert_eq!(sync.applied("dtn://node.dtn/").unwrap(), config);
+        assert_eq!(sync.applied("dtn://other.dtn/").unwrap(), NodeConfig::default());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
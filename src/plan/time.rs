@@ -0,0 +1,354 @@
+//! Human-friendly durations, times and contact windows
+//!
+//! ```text
+//! duration: 1d2h30m10s, 90s, 5m
+//! time:     +10m (relative to plan application) or 2024-05-01T10:00:00Z (absolute, UTC or ±HH:MM offset)
+//! window:   <time> for <duration> [at <bytes per second>]
+//!           <time> until <time> [at <bytes per second>]
+//! ```
+
+use std::{fmt, str::FromStr, time::{Duration, SystemTime}};
+
+use thiserror::Error;
+
+use crate::config::{Contact, ContactDataRate};
+
+/// An error parsing a duration, time or window expression
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TimeParseError {
+    /// Duration isn't a sequence of `<number><d|h|m|s>`
+    #[error("Invalid duration {0:?}")]
+    InvalidDuration(String),
+
+    /// Time is neither `+<duration>` nor an RFC 3339 date and time
+    #[error("Invalid time {0:?}")]
+    InvalidTime(String),
+
+    /// Window isn't `<time> for <duration>` or `<time> until <time>`
+    #[error("Invalid window {0:?}")]
+    InvalidWindow(String),
+
+    /// Data rate isn't a number of bytes per second
+    #[error("Invalid data rate {0:?}")]
+    InvalidDataRate(String),
+}
+
+/// Parse a duration such as `1h30m`
+pub fn parse_duration(s: &str) -> Result<Duration, TimeParseError> {
+    let error = || TimeParseError::InvalidDuration(s.to_owned());
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(error());
+    }
+
+    let mut result = Duration::ZERO;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(error)?;
+        let value: u64 = rest[..digits].parse().map_err(|_| error())?;
+        let unit = rest[digits..].chars().next().ok_or_else(error)?;
+        let seconds = match unit {
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(error()),
+        };
+        result = result.checked_add(Duration::from_secs(value.checked_mul(seconds).ok_or_else(error)?)).ok_or_else(error)?;
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+
+    Ok(result)
+}
+
+/// Format a duration with whole seconds, as parsed by [parse_duration]
+pub fn format_duration(duration: Duration) -> String {
+    let mut seconds = duration.as_secs();
+    if seconds == 0 {
+        return "0s".to_owned();
+    }
+
+    let mut result = String::new();
+    for (unit, name) in [(24 * 60 * 60, 'd'), (60 * 60, 'h'), (60, 'm'), (1, 's')] {
+        if seconds >= unit {
+            result += &format!("{}{}", seconds / unit, name);
+            seconds %= unit;
+        }
+    }
+    result
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Proleptic Gregorian date of a number of days since 1970-01-01
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn parse_number<T: FromStr>(s: &str, digits: usize) -> Option<T> {
+    if s.len() != digits || !s.bytes().all(|it| it.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Parse an RFC 3339 date and time with second precision, e.g. `2024-05-01T10:00:00Z`
+pub fn parse_datetime(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut date = date.split('-');
+    let year: i64 = parse_number(date.next()?, 4)?;
+    let month: u32 = parse_number(date.next()?, 2)?;
+    let day: u32 = parse_number(date.next()?, 2)?;
+    if date.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else {
+        let split = time.rfind(['+', '-'])?;
+        let (clock, offset) = time.split_at(split);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        let hours: i64 = parse_number(hours, 2)?;
+        let minutes: i64 = parse_number(minutes, 2)?;
+        (clock, sign * (hours * 3600 + minutes * 60))
+    };
+
+    let clock = clock.split('.').next()?;
+    let mut clock = clock.split(':');
+    let hour: i64 = parse_number(clock.next()?, 2)?;
+    let minute: i64 = parse_number(clock.next()?, 2)?;
+    let second: i64 = parse_number(clock.next()?, 2)?;
+    if clock.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(seconds).ok().map(|it| SystemTime::UNIX_EPOCH + Duration::from_secs(it))
+}
+
+/// Format a time as RFC 3339 in UTC with second precision
+pub fn format_datetime(time: SystemTime) -> String {
+    let seconds = time.duration_since(SystemTime::UNIX_EPOCH).map(|it| it.as_secs()).unwrap_or(0) as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let second_of_day = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, second_of_day / 3600, second_of_day % 3600 / 60, second_of_day % 60
+    )
+}
+
+/// A point in time of a contact plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeExpr {
+    /// Delay after the time the plan is applied, written `+10m`
+    Relative(Duration),
+
+    /// Fixed time, written as RFC 3339 date and time
+    Absolute(SystemTime),
+}
+
+impl TimeExpr {
+    /// Actual time when plan is applied at `now`
+    ///
+    /// Fails with [TimeParseError::InvalidTime] if it is beyond what [SystemTime] can represent
+    pub fn resolve(&self, now: SystemTime) -> Result<SystemTime, TimeParseError> {
+        match self {
+            TimeExpr::Relative(delay) => now.checked_add(*delay).ok_or_else(|| TimeParseError::InvalidTime(self.to_string())),
+            TimeExpr::Absolute(time) => Ok(*time),
+        }
+    }
+}
+
+impl fmt::Display for TimeExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeExpr::Relative(delay) => write!(f, "+{}", format_duration(*delay)),
+            TimeExpr::Absolute(time) => write!(f, "{}", format_datetime(*time)),
+        }
+    }
+}
+
+impl FromStr for TimeExpr {
+    type Err = TimeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(delay) = s.strip_prefix('+') {
+            return parse_duration(delay)
+                .map(TimeExpr::Relative)
+                .map_err(|_| TimeParseError::InvalidTime(s.to_owned()));
+        }
+
+        parse_datetime(s).map(TimeExpr::Absolute).ok_or_else(|| TimeParseError::InvalidTime(s.to_owned()))
+    }
+}
+
+/// End of a contact window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WindowEnd {
+    /// Window lasts a duration, written `for 5m`
+    For(Duration),
+
+    /// Window ends at a time, written `until +1h`
+    Until(TimeExpr),
+}
+
+/// A contact window of a plan, written `+10m for 5m at 1200`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(try_from = "String", into = "String"))]
+pub struct Window {
+    /// When contact starts
+    pub start: TimeExpr,
+
+    /// When contact ends
+    pub end: WindowEnd,
+
+    /// Expected transmission rate, unlimited if not written
+    pub data_rate: ContactDataRate,
+}
+
+impl Window {
+    /// Contact of this window when plan is applied at `now`
+    ///
+    /// Fails with [TimeParseError::InvalidWindow] if it ends beyond what [SystemTime] can represent
+    pub fn resolve(&self, now: SystemTime) -> Result<Contact, TimeParseError> {
+        let error = || TimeParseError::InvalidWindow(self.to_string());
+        let start = self.start.resolve(now).map_err(|_| error())?;
+        let end = match self.end {
+            WindowEnd::For(duration) => start.checked_add(duration).ok_or_else(error)?,
+            WindowEnd::Until(end) => end.resolve(now).map_err(|_| error())?,
+        };
+        Ok(Contact { start, end, data_rate: self.data_rate.clone() })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.start)?;
+        match self.end {
+            WindowEnd::For(duration) => write!(f, " for {}", format_duration(duration))?,
+            WindowEnd::Until(end) => write!(f, " until {}", end)?,
+        }
+        match self.data_rate {
+            ContactDataRate::Limited(rate) => write!(f, " at {}", rate),
+            ContactDataRate::Unlimited => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = TimeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || TimeParseError::InvalidWindow(s.to_owned());
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        let (start, end, rate) = match words.as_slice() {
+            [start, keyword, end] => (*start, (*keyword, *end), None),
+            [start, keyword, end, "at", rate] => (*start, (*keyword, *end), Some(*rate)),
+            _ => return Err(error()),
+        };
+
+        let end = match end {
+            ("for", duration) => WindowEnd::For(parse_duration(duration)?),
+            ("until", time) => WindowEnd::Until(time.parse()?),
+            _ => return Err(error()),
+        };
+
+        let data_rate = match rate {
+            None | Some("unlimited") => ContactDataRate::Unlimited,
+            Some(rate) => ContactDataRate::Limited(rate.parse().map_err(|_| TimeParseError::InvalidDataRate(rate.to_owned()))?),
+        };
+
+        Ok(Self { start: start.parse()?, end, data_rate })
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = TimeParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Window> for String {
+    fn from(value: Window) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1d10s"), Ok(Duration::from_secs(86410)));
+        assert_eq!(format_duration(Duration::from_secs(86410)), "1d10s");
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("5µs").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+    }
+
+    #[test]
+    fn datetimes() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        assert_eq!(parse_datetime("2024-05-01T10:00:00Z"), Some(time));
+        assert_eq!(parse_datetime("2024-05-01T12:00:00+02:00"), Some(time));
+        assert_eq!(format_datetime(time), "2024-05-01T10:00:00Z");
+        assert_eq!(parse_datetime("2000-01-01T00:00:00Z"), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(946684800)));
+        assert_eq!(parse_datetime("2024-13-01T10:00:00Z"), None);
+        assert_eq!(parse_datetime("yesterday"), None);
+
+        for days in [-1, 0, 59, 11016, 19844, 100000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let window: Window = "+10m for 5m at 1200".parse().unwrap();
+        assert_eq!(window.resolve(now).unwrap(), Contact {
+            start: now + Duration::from_secs(600),
+            end: now + Duration::from_secs(900),
+            data_rate: ContactDataRate::Limited(1200),
+        });
+        assert_eq!(window.to_string(), "+10m for 5m at 1200");
+
+        let window: Window = "2024-05-01T10:00:00Z until +1h".parse().unwrap();
+        assert_eq!(window.resolve(now).unwrap(), Contact::from_during(now, Duration::from_secs(3600), ContactDataRate::Unlimited));
+        assert_eq!(window.to_string(), "2024-05-01T10:00:00Z until +1h");
+
+        let window: Window = "+213503982334601d for 1s".parse().unwrap();
+        assert_eq!(window.resolve(now), Err(TimeParseError::InvalidWindow("+213503982334601d for 1s".into())));
+
+        assert_eq!("+10m during 5m".parse::<Window>(), Err(TimeParseError::InvalidWindow("+10m during 5m".into())));
+        assert_eq!("+10m for 5m at fast".parse::<Window>(), Err(TimeParseError::InvalidDataRate("fast".into())));
+    }
+}
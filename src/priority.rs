@@ -0,0 +1,263 @@
+//! Prioritized sending of outgoing bundles
+//!
+//! AAP v1 bundles carry no priority, the node forwards them in the order they are handed over.
+//! [PrioritySender] queues outgoing bundles by [Priority] and hands the most urgent one to the node first.
+//! A bundle waiting longer than the aging delay is promoted one class per delay elapsed, up to [Priority::Normal],
+//! so bulk traffic is not starved by a steady flow of normal bundles while expedited ones are always handed over first.
+//! Queued bundles can be cancelled or their payload replaced until they are dispatched.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::Duration};
+//! use ud3tn_aap::{Agent, priority::{Priority, PrioritySender}};
+//!
+//! let agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("telemetry".to_owned()).unwrap();
+//! let mut sender = PrioritySender::new(agent).with_aging(Duration::from_secs(300));
+//!
+//! let telemetry = sender.enqueue("dtn://ground.dtn/telemetry".into(), b"bulk samples".to_vec(), Priority::Bulk);
+//! sender.enqueue("dtn://ground.dtn/alerts".into(), b"battery low".to_vec(), Priority::Expedited);
+//! sender.replace(telemetry, b"newer bulk samples".to_vec());
+//!
+//! // Alert is handed to the node first
+//! sender.dispatch_all().unwrap();
+//! ```
+
+use std::{collections::BTreeMap, time::{Duration, SystemTime}};
+
+use crate::{AapStream, BundleIdentifier, Error, RegisteredAgent};
+
+/// Default delay after which a waiting bundle is promoted one class
+pub const DEFAULT_AGING: Duration = Duration::from_secs(60);
+
+/// Class of service of a queued bundle, from least to most urgent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Sent when nothing else is waiting
+    Bulk,
+    /// Default class
+    Normal,
+    /// Sent before anything else
+    Expedited,
+}
+
+/// Identifier of a queued bundle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ticket(pub u64);
+
+/// A bundle waiting to be dispatched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedBundle {
+    /// Destination endpoint ID
+    pub destination: String,
+
+    /// Content of bundle
+    pub payload: Vec<u8>,
+
+    /// Requested class of service
+    pub priority: Priority,
+
+    /// When bundle was queued
+    pub queued_at: SystemTime,
+}
+
+/// Outgoing bundles ordered by priority, aged to avoid starvation
+#[derive(Debug, Clone)]
+pub struct PriorityQueue {
+    bundles: BTreeMap<Ticket, QueuedBundle>,
+    aging: Duration,
+    next_ticket: u64,
+}
+
+impl PriorityQueue {
+    /// Empty queue promoting waiting bundles every `aging`
+    pub fn new(aging: Duration) -> Self {
+        Self { bundles: BTreeMap::new(), aging, next_ticket: 0 }
+    }
+
+    /// Queue a bundle
+    pub fn push(&mut self, destination: String, payload: Vec<u8>, priority: Priority, now: SystemTime) -> Ticket {
+        let ticket = Ticket(self.next_ticket);
+        self.next_ticket += 1;
+        self.bundles.insert(ticket, QueuedBundle { destination, payload, priority, queued_at: now });
+        ticket
+    }
+
+    /// Remove a queued bundle, [None] if it was already dispatched or cancelled
+    pub fn cancel(&mut self, ticket: Ticket) -> Option<QueuedBundle> {
+        self.bundles.remove(&ticket)
+    }
+
+    /// Replace payload of a queued bundle, keeping its place in queue
+    ///
+    /// Returns false if it was already dispatched or cancelled
+    pub fn replace(&mut self, ticket: Ticket, payload: Vec<u8>) -> bool {
+        match self.bundles.get_mut(&ticket) {
+            Some(bundle) => {
+                bundle.payload = payload;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queued bundle by ticket
+    pub fn get(&self, ticket: Ticket) -> Option<&QueuedBundle> {
+        self.bundles.get(&ticket)
+    }
+
+    /// Priority of a bundle at `now`, including classes gained by waiting
+    ///
+    /// Waiting never promotes a bundle over [Priority::Normal]
+    pub fn effective_priority(&self, bundle: &QueuedBundle, now: SystemTime) -> u64 {
+        let waited = now.duration_since(bundle.queued_at).unwrap_or(Duration::ZERO);
+        let promotions = match self.aging.as_nanos() {
+            0 => 0,
+            aging => u64::try_from(waited.as_nanos() / aging).unwrap_or(u64::MAX),
+        };
+        let aged = (bundle.priority as u64).saturating_add(promotions).min(Priority::Normal as u64);
+        aged.max(bundle.priority as u64)
+    }
+
+    /// Ticket of next bundle to dispatch, oldest first among equal priorities
+    pub fn peek(&self, now: SystemTime) -> Option<Ticket> {
+        self.bundles.iter()
+            .max_by(|(a_ticket, a), (b_ticket, b)| {
+                self.effective_priority(a, now).cmp(&self.effective_priority(b, now))
+                    .then(b_ticket.cmp(a_ticket))
+            })
+            .map(|(ticket, _)| *ticket)
+    }
+
+    /// Remove next bundle to dispatch
+    pub fn pop(&mut self, now: SystemTime) -> Option<(Ticket, QueuedBundle)> {
+        let ticket = self.peek(now)?;
+        self.bundles.remove(&ticket).map(|bundle| (ticket, bundle))
+    }
+
+    /// Put back a bundle whose dispatch failed, keeping its ticket
+    fn restore(&mut self, ticket: Ticket, bundle: QueuedBundle) {
+        self.bundles.insert(ticket, bundle);
+    }
+
+    /// Number of queued bundles
+    pub fn len(&self) -> usize {
+        self.bundles.len()
+    }
+
+    /// Whether no bundle is queued
+    pub fn is_empty(&self) -> bool {
+        self.bundles.is_empty()
+    }
+}
+
+/// A registered agent sending queued bundles by priority
+pub struct PrioritySender<S: AapStream> {
+    agent: RegisteredAgent<S>,
+    queue: PriorityQueue,
+}
+
+impl<S: AapStream> PrioritySender<S> {
+    /// Wrap a registered agent using [DEFAULT_AGING]
+    pub fn new(agent: RegisteredAgent<S>) -> Self {
+        Self { agent, queue: PriorityQueue::new(DEFAULT_AGING) }
+    }
+
+    /// Promote waiting bundles one class every `aging`, [Duration::ZERO] disables promotion
+    pub fn with_aging(mut self, aging: Duration) -> Self {
+        self.queue.aging = aging;
+        self
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// Unwrap the underlying registered agent, dropping queued bundles
+    pub fn into_inner(self) -> RegisteredAgent<S> {
+        self.agent
+    }
+
+    /// Bundles waiting to be dispatched
+    pub fn queue(&self) -> &PriorityQueue {
+        &self.queue
+    }
+
+    /// Queue a bundle
+    pub fn enqueue(&mut self, destination_eid: String, payload: Vec<u8>, priority: Priority) -> Ticket {
+        self.queue.push(destination_eid, payload, priority, SystemTime::now())
+    }
+
+    /// Remove a queued bundle, [None] if it was already dispatched or cancelled
+    pub fn cancel(&mut self, ticket: Ticket) -> Option<QueuedBundle> {
+        self.queue.cancel(ticket)
+    }
+
+    /// Replace payload of a queued bundle, false if it was already dispatched or cancelled
+    pub fn replace(&mut self, ticket: Ticket, payload: Vec<u8>) -> bool {
+        self.queue.replace(ticket, payload)
+    }
+
+    /// Send next bundle by priority, [None] if queue is empty
+    ///
+    /// On error, bundle stays queued
+    pub fn dispatch_next(&mut self) -> Result<Option<(Ticket, BundleIdentifier)>, Error> {
+        let Some((ticket, bundle)) = self.queue.pop(SystemTime::now()) else { return Ok(None) };
+        match self.agent.send_bundle(bundle.destination.clone(), &bundle.payload) {
+            Ok(bundle_id) => Ok(Some((ticket, bundle_id))),
+            Err(e) => {
+                self.queue.restore(ticket, bundle);
+                Err(e)
+            }
+        }
+    }
+
+    /// Send all queued bundles by priority
+    pub fn dispatch_all(&mut self) -> Result<Vec<(Ticket, BundleIdentifier)>, Error> {
+        let mut result = Vec::new();
+        while let Some(dispatched) = self.dispatch_next()? {
+            result.push(dispatched);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highest_priority_first() {
+        let now = SystemTime::now();
+        let mut queue = PriorityQueue::new(Duration::from_secs(60));
+        let bulk = queue.push("dtn://a/bulk".into(), b"1".to_vec(), Priority::Bulk, now);
+        let first = queue.push("dtn://a/normal".into(), b"2".to_vec(), Priority::Normal, now);
+        let alert = queue.push("dtn://a/alert".into(), b"3".to_vec(), Priority::Expedited, now);
+        let second = queue.push("dtn://a/normal".into(), b"4".to_vec(), Priority::Normal, now);
+        let cancelled = queue.push("dtn://a/normal".into(), b"5".to_vec(), Priority::Expedited, now);
+
+        assert!(queue.cancel(cancelled).is_some());
+        assert!(queue.replace(bulk, b"newer".to_vec()));
+        assert!(!queue.replace(cancelled, b"newer".to_vec()));
+
+        let order: Vec<Ticket> = std::iter::from_fn(|| queue.pop(now).map(|(ticket, _)| ticket)).collect();
+        assert_eq!(order, vec![alert, first, second, bulk]);
+    }
+
+    #[test]
+    fn waiting_bundles_are_promoted() {
+        let start = SystemTime::now();
+        let mut queue = PriorityQueue::new(Duration::from_secs(60));
+        let bulk = queue.push("dtn://a/bulk".into(), b"1".to_vec(), Priority::Bulk, start);
+        let later = start + Duration::from_secs(150);
+        let normal = queue.push("dtn://a/normal".into(), b"2".to_vec(), Priority::Normal, later);
+        let alert = queue.push("dtn://a/alert".into(), b"3".to_vec(), Priority::Expedited, later);
+
+        // Aging is capped below expedited class, however long bulk bundle waited
+        let much_later = start + Duration::from_secs(365 * 24 * 60 * 60);
+        assert_eq!(queue.effective_priority(queue.get(bulk).unwrap(), much_later), Priority::Normal as u64);
+
+        let order: Vec<Ticket> = std::iter::from_fn(|| queue.pop(later).map(|(ticket, _)| ticket)).collect();
+        assert_eq!(order, vec![alert, bulk, normal]);
+    }
+}
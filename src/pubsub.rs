@@ -0,0 +1,517 @@
+//! Publish/subscribe topics over DTN endpoints
+//!
+//! A topic published by a node is served by a [Publisher] registered as agent `pubsub/<topic>`,
+//! i.e. reachable at `dtn://node/pubsub/<topic>`. On `ipn` nodes the publisher uses a service number derived from
+//! the topic instead, see [topic_agent_id].
+//! [Subscriber]s send subscription requests to this endpoint, the publisher keeps the list of subscribers
+//! and fans each publication out to them.
+//! Subscriptions are leased and must be renewed with [Subscriber::renew] before they expire.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::Duration};
+//! use ud3tn_aap::{Agent, pubsub::{Publisher, Subscriber}};
+//!
+//! let socket = Path::new("/run/archipel-core/archipel-core.socket");
+//!
+//! let mut publisher = Publisher::register(Agent::connect_unix(socket).unwrap(), "weather").unwrap();
+//! publisher.process_next().unwrap(); // Wait for a subscription
+//! publisher.publish(b"sunny").unwrap();
+//!
+//! let agent = Agent::connect_unix(socket).unwrap().register("weather-display".to_owned()).unwrap();
+//! let mut subscriber = Subscriber::new(agent);
+//! subscriber.subscribe("dtn://station.dtn/", "weather", Duration::from_secs(24 * 60 * 60)).unwrap();
+//! let publication = subscriber.recv().unwrap();
+//! ```
+
+use std::{collections::{HashMap, HashSet, VecDeque}, time::{Duration, SystemTime}};
+
+use thiserror::Error;
+
+use crate::{endpoint_id, message::ParseError, wire, Agent, AapStream, BaseAgent, RegisteredAgent};
+
+/// First byte of every pub/sub bundle
+const MAGIC: u8 = 0xB7;
+
+const KIND_SUBSCRIBE: u8 = 0;
+const KIND_UNSUBSCRIBE: u8 = 1;
+const KIND_PUBLISH: u8 = 2;
+
+/// Default number of remembered message identifiers used for duplicate suppression
+pub const DEFAULT_DUPLICATE_WINDOW: usize = 4096;
+
+/// Longest lease granted by a [Publisher], longer requested leases are shortened
+pub const MAX_LEASE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Agent id of the publisher of a topic on node `node_eid`
+///
+/// `pubsub/<topic>` on `dtn` nodes. As `ipn` service numbers are numeric, `ipn` nodes use a service number above
+/// 2^32 derived from the topic. Two topics of the same node get the same number with a probability of 2^-32.
+pub fn topic_agent_id(node_eid: &str, topic: &str) -> String {
+    if !node_eid.starts_with("ipn:") {
+        return format!("pubsub/{}", topic);
+    }
+
+    // 32-bit FNV-1a
+    let hash = topic.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+    (1u64 << 32 | hash as u64).to_string()
+}
+
+/// Endpoint of the publisher of a topic on a node
+///
+/// `node_eid` is the node EID, e.g. `dtn://node/` or `ipn:7.0`, see [endpoint_id]
+pub fn topic_eid(node_eid: &str, topic: &str) -> String {
+    endpoint_id(node_eid, &topic_agent_id(node_eid, topic))
+}
+
+/// Payload of a pub/sub bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Subscription request, also used to renew a subscription
+    Subscribe {
+        /// Subscribed topic
+        topic: String,
+        /// EID publications are sent to
+        subscriber: String,
+        /// Duration of subscription in seconds
+        lease: u64,
+    },
+
+    /// Subscription cancellation
+    Unsubscribe {
+        /// Unsubscribed topic
+        topic: String,
+        /// EID publications were sent to
+        subscriber: String,
+    },
+
+    /// A publication sent to subscribers
+    Publish {
+        /// Topic of publication
+        topic: String,
+        /// Identifier chosen by publisher, used for duplicate suppression
+        message_id: u64,
+        /// Published content
+        payload: Vec<u8>,
+    },
+}
+
+impl Packet {
+    /// Serialize this packet to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![MAGIC];
+        match self {
+            Packet::Subscribe { topic, subscriber, lease } => {
+                result.push(KIND_SUBSCRIBE);
+                wire::put_string(&mut result, topic);
+                wire::put_string(&mut result, subscriber);
+                wire::put_u64(&mut result, *lease);
+            }
+            Packet::Unsubscribe { topic, subscriber } => {
+                result.push(KIND_UNSUBSCRIBE);
+                wire::put_string(&mut result, topic);
+                wire::put_string(&mut result, subscriber);
+            }
+            Packet::Publish { topic, message_id, payload } => {
+                result.push(KIND_PUBLISH);
+                wire::put_string(&mut result, topic);
+                wire::put_u64(&mut result, *message_id);
+                result.extend_from_slice(payload);
+            }
+        }
+        result
+    }
+
+    /// Parse a packet from a bundle payload
+    pub fn parse(bytes: &[u8]) -> Result<Self, PubSubError> {
+        let mut reader = wire::Reader::new(bytes);
+        if reader.u8()? != MAGIC {
+            return Err(PubSubError::NotAPubSubMessage);
+        }
+
+        match reader.u8()? {
+            KIND_SUBSCRIBE => Ok(Packet::Subscribe {
+                topic: reader.string()?,
+                subscriber: reader.string()?,
+                lease: reader.u64()?,
+            }),
+            KIND_UNSUBSCRIBE => Ok(Packet::Unsubscribe {
+                topic: reader.string()?,
+                subscriber: reader.string()?,
+            }),
+            KIND_PUBLISH => Ok(Packet::Publish {
+                topic: reader.string()?,
+                message_id: reader.u64()?,
+                payload: reader.rest().to_vec(),
+            }),
+            _ => Err(PubSubError::NotAPubSubMessage),
+        }
+    }
+}
+
+/// Remembers recently seen message identifiers
+///
+/// Only the last `capacity` identifiers are remembered.
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    capacity: usize,
+    order: VecDeque<(String, u64)>,
+    seen: HashSet<(String, u64)>,
+}
+
+impl DuplicateFilter {
+    /// Create a filter remembering `capacity` identifiers
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, order: VecDeque::new(), seen: HashSet::new() }
+    }
+
+    /// Record a message identifier from a publisher
+    ///
+    /// Returns false if it was already seen
+    pub fn insert(&mut self, publisher: &str, message_id: u64) -> bool {
+        let key = (publisher.to_owned(), message_id);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+impl Default for DuplicateFilter {
+    fn default() -> Self {
+        Self::new(DEFAULT_DUPLICATE_WINDOW)
+    }
+}
+
+/// Subscribers of a topic and the expiration of their lease
+#[derive(Debug, Default)]
+pub struct SubscriberTable {
+    subscribers: HashMap<String, SystemTime>,
+}
+
+impl SubscriberTable {
+    /// Add or renew a subscriber
+    pub fn subscribe(&mut self, subscriber: String, until: SystemTime) {
+        self.subscribers.insert(subscriber, until);
+    }
+
+    /// Remove a subscriber
+    pub fn unsubscribe(&mut self, subscriber: &str) -> bool {
+        self.subscribers.remove(subscriber).is_some()
+    }
+
+    /// Subscribers whose lease is still valid, sorted by EID
+    ///
+    /// Expired subscribers are removed
+    pub fn active(&mut self, now: SystemTime) -> Vec<String> {
+        self.subscribers.retain(|_, until| now < *until);
+        let mut result: Vec<String> = self.subscribers.keys().cloned().collect();
+        result.sort();
+        result
+    }
+}
+
+/// Agent publishing a topic to its subscribers
+pub struct Publisher<S: AapStream> {
+    agent: RegisteredAgent<S>,
+    topic: String,
+    subscribers: SubscriberTable,
+}
+
+impl<S: AapStream> Publisher<S> {
+    /// Register an agent as the publisher of `topic`, see [topic_agent_id]
+    pub fn register(agent: Agent<S>, topic: &str) -> Result<Self, PubSubError> {
+        let agent_id = topic_agent_id(agent.node_id(), topic);
+        let agent = agent.register(agent_id)?;
+        Ok(Self { agent, topic: topic.to_owned(), subscribers: SubscriberTable::default() })
+    }
+
+    /// Published topic
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// Current subscribers of topic
+    pub fn subscribers(&mut self) -> Vec<String> {
+        self.subscribers.active(SystemTime::now())
+    }
+
+    /// Send a publication to every subscriber
+    ///
+    /// Returns the message identifier of publication
+    pub fn publish(&mut self, payload: &[u8]) -> Result<u64, PubSubError> {
+        let message_id = wire::new_message_id();
+        self.send_publication(message_id, payload)?;
+        Ok(message_id)
+    }
+
+    /// Send again a publication with a known message identifier, e.g. after a restart
+    ///
+    /// Subscribers that already received it drop it.
+    pub fn send_publication(&mut self, message_id: u64, payload: &[u8]) -> Result<(), PubSubError> {
+        let packet = Packet::Publish { topic: self.topic.clone(), message_id, payload: payload.to_vec() }.to_bytes();
+        for subscriber in self.subscribers.active(SystemTime::now()) {
+            self.agent.send_bundle(subscriber, &packet)?;
+        }
+        Ok(())
+    }
+
+    /// Block until a subscription management bundle is received and process it
+    ///
+    /// Leases are granted for at most [MAX_LEASE]. Agents can only subscribe or unsubscribe themselves, requests
+    /// whose subscriber isn't the bundle source are refused with [PubSubError::SubscriberMismatch].
+    pub fn process_next(&mut self) -> Result<(), PubSubError> {
+        let bundle = self.agent.recv_bundle()?;
+        let packet = Packet::parse(&bundle.payload)?;
+        if let Packet::Subscribe { subscriber, .. } | Packet::Unsubscribe { subscriber, .. } = &packet {
+            if bundle.source.as_ref() != Some(subscriber) {
+                return Err(PubSubError::SubscriberMismatch { subscriber: subscriber.clone(), sender: bundle.source });
+            }
+        }
+
+        match packet {
+            Packet::Subscribe { topic, subscriber, lease } if topic == self.topic => {
+                let lease = Duration::from_secs(lease).min(MAX_LEASE);
+                let until = SystemTime::now().checked_add(lease).ok_or(PubSubError::UnexpectedPacket)?;
+                self.subscribers.subscribe(subscriber, until);
+                Ok(())
+            }
+            Packet::Unsubscribe { topic, subscriber } if topic == self.topic => {
+                self.subscribers.unsubscribe(&subscriber);
+                Ok(())
+            }
+            _ => Err(PubSubError::UnexpectedPacket),
+        }
+    }
+}
+
+/// A received publication
+#[derive(Debug, Clone)]
+pub struct Publication {
+    /// Topic of publication
+    pub topic: String,
+
+    /// Source endpoint ID of publication bundle
+    pub publisher: Option<String>,
+
+    /// Identifier chosen by publisher
+    pub message_id: u64,
+
+    /// Published content
+    pub payload: Vec<u8>,
+}
+
+/// Agent subscribing to topics of publishers
+pub struct Subscriber<S: AapStream> {
+    agent: RegisteredAgent<S>,
+
+    /// Subscriptions as (publisher endpoint, topic, lease)
+    subscriptions: Vec<(String, String, Duration)>,
+    duplicates: DuplicateFilter,
+}
+
+impl<S: AapStream> Subscriber<S> {
+    /// Wrap a registered agent, publications are sent to its EID
+    pub fn new(agent: RegisteredAgent<S>) -> Self {
+        Self { agent, subscriptions: Vec::new(), duplicates: DuplicateFilter::default() }
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// EID of this agent, used as subscriber EID
+    pub fn eid(&self) -> String {
+        self.agent.endpoint_id()
+    }
+
+    /// Current subscriptions as (publisher endpoint, topic)
+    pub fn subscriptions(&self) -> Vec<(&str, &str)> {
+        self.subscriptions.iter().map(|(publisher, topic, _)| (publisher.as_str(), topic.as_str())).collect()
+    }
+
+    /// Subscribe to `topic` published by node `node_eid` for `lease`
+    pub fn subscribe(&mut self, node_eid: &str, topic: &str, lease: Duration) -> Result<(), PubSubError> {
+        let publisher = topic_eid(node_eid, topic);
+        self.send_subscribe(&publisher, topic, lease)?;
+
+        self.subscriptions.retain(|(it, _, _)| *it != publisher);
+        self.subscriptions.push((publisher, topic.to_owned(), lease));
+        Ok(())
+    }
+
+    /// Unsubscribe from `topic` published by node `node_eid`
+    pub fn unsubscribe(&mut self, node_eid: &str, topic: &str) -> Result<(), PubSubError> {
+        let publisher = topic_eid(node_eid, topic);
+        let packet = Packet::Unsubscribe { topic: topic.to_owned(), subscriber: self.eid() };
+        self.agent.send_bundle(publisher.clone(), &packet.to_bytes())?;
+
+        self.subscriptions.retain(|(it, _, _)| *it != publisher);
+        Ok(())
+    }
+
+    /// Send again every subscription request to renew their lease
+    pub fn renew(&mut self) -> Result<(), PubSubError> {
+        for (publisher, topic, lease) in self.subscriptions.clone() {
+            self.send_subscribe(&publisher, &topic, lease)?;
+        }
+        Ok(())
+    }
+
+    fn send_subscribe(&mut self, publisher: &str, topic: &str, lease: Duration) -> Result<(), PubSubError> {
+        let packet = Packet::Subscribe { topic: topic.to_owned(), subscriber: self.eid(), lease: lease.as_secs() };
+        self.agent.send_bundle(publisher.to_owned(), &packet.to_bytes())?;
+        Ok(())
+    }
+
+    /// Block until a new publication is received
+    ///
+    /// Duplicated publications are dropped
+    pub fn recv(&mut self) -> Result<Publication, PubSubError> {
+        loop {
+            let bundle = self.agent.recv_bundle()?;
+            let Packet::Publish { topic, message_id, payload } = Packet::parse(&bundle.payload)? else {
+                return Err(PubSubError::UnexpectedPacket);
+            };
+
+            let publisher = bundle.source.clone().unwrap_or_default();
+            if self.duplicates.insert(&publisher, message_id) {
+                return Ok(Publication { topic, publisher: bundle.source, message_id, payload });
+            }
+        }
+    }
+}
+
+/// An error during pub/sub operations
+#[derive(Debug, Error)]
+pub enum PubSubError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Received bundle doesn't start with a pub/sub header
+    #[error("Bundle is not a pub/sub message")]
+    NotAPubSubMessage,
+
+    /// Received header is truncated
+    #[error("Malformed pub/sub message")]
+    Malformed(#[from] ParseError),
+
+    /// Received packet isn't handled by this side or targets another topic
+    #[error("Unexpected pub/sub packet")]
+    UnexpectedPacket,
+
+    /// Subscription request for another endpoint than the one sending it
+    #[error("Subscription of {subscriber} sent by {sender:?}")]
+    SubscriberMismatch {
+        /// Subscriber EID in request
+        subscriber: String,
+        /// Source EID of request bundle
+        sender: Option<String>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn naming() {
+        assert_eq!(topic_agent_id("dtn://node/", "sensors/temp"), "pubsub/sensors/temp");
+        assert_eq!(topic_eid("dtn://node/", "bulletin"), "dtn://node/pubsub/bulletin");
+        assert_eq!(topic_agent_id("ipn:7.0", "bulletin"), "4543269370");
+        assert_eq!(topic_eid("ipn:7.0", "bulletin"), "ipn:7.4543269370");
+        assert_ne!(topic_agent_id("ipn:7.0", "bulletin"), topic_agent_id("ipn:7.0", "weather"));
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn ipn_subscription() {
+        use crate::testing::MockNode;
+
+        let node = MockNode::new("ipn:7.0");
+        let connect = || {
+            let mut stream = node.connect();
+            stream.set_read_timeout(Some(Duration::from_secs(5)));
+            Agent::new(stream).unwrap()
+        };
+        let mut publisher = Publisher::register(connect(), "bulletin").unwrap();
+        assert_eq!(node.registered_agents(), vec!["4543269370".to_owned()]);
+        let mut subscriber = Subscriber::new(connect().register("1".into()).unwrap());
+        assert_eq!(subscriber.eid(), "ipn:7.1");
+
+        subscriber.subscribe("ipn:7.0", "bulletin", Duration::from_secs(60)).unwrap();
+        publisher.process_next().unwrap();
+        assert_eq!(publisher.subscribers(), vec!["ipn:7.1".to_owned()]);
+
+        publisher.publish(b"news").unwrap();
+        assert_eq!(subscriber.recv().unwrap().payload, b"news");
+
+        subscriber.subscribe("ipn:7.0", "bulletin", Duration::MAX).unwrap();
+        publisher.process_next().unwrap();
+        assert_eq!(publisher.subscribers(), vec!["ipn:7.1".to_owned()]);
+
+        // Third parties can't be subscribed by someone else
+        let mut other = connect().register("2".into()).unwrap();
+        let packet = Packet::Subscribe { topic: "bulletin".into(), subscriber: "ipn:9.1".into(), lease: 60 };
+        other.send_bundle(topic_eid("ipn:7.0", "bulletin"), &packet.to_bytes()).unwrap();
+        assert!(matches!(
+            publisher.process_next(),
+            Err(PubSubError::SubscriberMismatch { subscriber, sender: Some(sender) }) if subscriber == "ipn:9.1" && sender == "ipn:7.2"
+        ));
+        let packet = Packet::Unsubscribe { topic: "bulletin".into(), subscriber: "ipn:7.1".into() };
+        other.send_bundle(topic_eid("ipn:7.0", "bulletin"), &packet.to_bytes()).unwrap();
+        assert!(matches!(publisher.process_next(), Err(PubSubError::SubscriberMismatch { .. })));
+        assert_eq!(publisher.subscribers(), vec!["ipn:7.1".to_owned()]);
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        let packets = [
+            Packet::Subscribe { topic: "bulletin".into(), subscriber: "dtn://a/app".into(), lease: 3600 },
+            Packet::Unsubscribe { topic: "bulletin".into(), subscriber: "dtn://a/app".into() },
+            Packet::Publish { topic: "bulletin".into(), message_id: 99, payload: b"news".to_vec() },
+        ];
+        for packet in packets {
+            assert_eq!(Packet::parse(&packet.to_bytes()).unwrap(), packet);
+        }
+        assert!(matches!(Packet::parse(&[MAGIC, 9]), Err(PubSubError::NotAPubSubMessage)));
+    }
+
+    #[test]
+    fn subscriber_leases() {
+        let now = SystemTime::now();
+        let mut table = SubscriberTable::default();
+        table.subscribe("dtn://b/app".into(), now + Duration::from_secs(10));
+        table.subscribe("dtn://a/app".into(), now + Duration::from_secs(20));
+        assert_eq!(table.active(now), vec!["dtn://a/app", "dtn://b/app"]);
+
+        table.subscribe("dtn://b/app".into(), now + Duration::from_secs(30));
+        assert_eq!(table.active(now + Duration::from_secs(25)), vec!["dtn://b/app"]);
+        assert!(table.unsubscribe("dtn://b/app"));
+        assert!(table.active(now).is_empty());
+    }
+
+    #[test]
+    fn duplicate_window() {
+        let mut filter = DuplicateFilter::new(2);
+        assert!(filter.insert("dtn://a/", 1));
+        assert!(!filter.insert("dtn://a/", 1));
+        assert!(filter.insert("dtn://b/", 1));
+        assert!(filter.insert("dtn://a/", 2));
+        // Oldest identifier fell out of window
+        assert!(filter.insert("dtn://a/", 1));
+    }
+}
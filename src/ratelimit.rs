@@ -0,0 +1,356 @@
+//! Client-side rate limiting and volume budgeting per destination
+//!
+//! [RateLimitedAgent] meters bundles sent to destinations matching a [Destination] rule with a token bucket:
+//! a [Limit] refills `bytes_per_second` up to `burst` bytes, and optionally caps the total `volume` sent.
+//! When the most specific rule for a destination has no budget left, sending either waits for the bucket to
+//! refill ([Mode::Block]) or fails ([Mode::Error]). Destinations without matching rule are not limited.
+//!
+//! ```rust,no_run
+//! use std::path::Path;
+//! use ud3tn_aap::{Agent, ratelimit::{Destination, Limit, Mode, RateLimitedAgent}};
+//!
+//! let agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("telemetry".to_owned()).unwrap();
+//! let mut agent = RateLimitedAgent::new(agent, Mode::Block)
+//!     .with_limit(Destination::Prefix("dtn://ground.dtn/".into()), Limit::new(1024, 64 * 1024))
+//!     .with_limit(Destination::Exact("dtn://ground.dtn/bulk".into()), Limit::new(128, 4096).with_volume(10_000_000));
+//!
+//! agent.send_bundle("dtn://ground.dtn/bulk".into(), b"measurements").unwrap();
+//! println!("{:?}", agent.remaining("dtn://ground.dtn/bulk"));
+//! ```
+
+use std::{thread, time::{Duration, SystemTime}};
+
+use thiserror::Error;
+
+use crate::{AapStream, BundleIdentifier, RegisteredAgent};
+
+/// Destinations a [Limit] applies to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    /// A single endpoint ID
+    Exact(String),
+
+    /// All endpoint IDs starting with prefix, sharing one budget
+    Prefix(String),
+}
+
+impl Destination {
+    /// Whether `eid` is covered by this rule
+    pub fn matches(&self, eid: &str) -> bool {
+        match self {
+            Destination::Exact(it) => it == eid,
+            Destination::Prefix(prefix) => eid.starts_with(prefix.as_str()),
+        }
+    }
+
+    /// Rule precedence, exact rules first then longest prefix
+    fn specificity(&self) -> usize {
+        match self {
+            Destination::Exact(_) => usize::MAX,
+            Destination::Prefix(prefix) => prefix.len(),
+        }
+    }
+}
+
+/// Sending budget of a destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    /// Refill rate of bucket
+    pub bytes_per_second: u64,
+
+    /// Capacity of bucket, maximum bytes sent at once
+    pub burst: u64,
+
+    /// Total bytes allowed, never refilled
+    pub volume: Option<u64>,
+}
+
+impl Limit {
+    /// Limit refilling `bytes_per_second` up to `burst` bytes
+    pub fn new(bytes_per_second: u64, burst: u64) -> Self {
+        Self { bytes_per_second, burst, volume: None }
+    }
+
+    /// Cap total bytes sent
+    pub fn with_volume(mut self, volume: u64) -> Self {
+        self.volume = Some(volume);
+        self
+    }
+}
+
+/// Budget left for a destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    /// Bytes that can be sent right now
+    pub available: u64,
+
+    /// Bytes left in total volume, [None] if volume is unlimited
+    pub volume_remaining: Option<u64>,
+}
+
+/// Behaviour when a bundle exceeds budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Wait until enough budget is refilled
+    Block,
+
+    /// Fail with [RateLimitError::RateExceeded]
+    Error,
+}
+
+/// Token bucket of a [Limit]
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    volume_used: u64,
+    last_refill: SystemTime,
+}
+
+impl TokenBucket {
+    /// Full bucket
+    pub fn new(limit: Limit, now: SystemTime) -> Self {
+        Self { limit, tokens: limit.burst as f64, volume_used: 0, last_refill: now }
+    }
+
+    fn refill(&mut self, now: SystemTime) {
+        let elapsed = now.duration_since(self.last_refill).unwrap_or(Duration::ZERO);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.bytes_per_second as f64)
+            .min(self.limit.burst as f64);
+        self.last_refill = self.last_refill.max(now);
+    }
+
+    /// Budget left at `now`
+    pub fn remaining(&mut self, now: SystemTime) -> Budget {
+        self.refill(now);
+        Budget {
+            available: self.tokens as u64,
+            volume_remaining: self.limit.volume.map(|it| it.saturating_sub(self.volume_used)),
+        }
+    }
+
+    /// Take `size` bytes from budget
+    ///
+    /// On [Exceeded::Rate], budget is left untouched
+    pub fn take(&mut self, size: u64, now: SystemTime) -> Result<(), Exceeded> {
+        if size > self.limit.burst {
+            return Err(Exceeded::TooLarge);
+        }
+        if let Some(volume) = self.limit.volume {
+            if self.volume_used.saturating_add(size) > volume {
+                return Err(Exceeded::Volume);
+            }
+        }
+
+        self.refill(now);
+        if self.tokens < size as f64 {
+            if self.limit.bytes_per_second == 0 {
+                return Err(Exceeded::Rate(Duration::MAX));
+            }
+            let missing = size as f64 - self.tokens;
+            return Err(Exceeded::Rate(Duration::from_secs_f64(missing / self.limit.bytes_per_second as f64)));
+        }
+
+        self.tokens -= size as f64;
+        self.volume_used += size;
+        Ok(())
+    }
+
+    /// Forget bytes sent so far toward total volume
+    pub fn reset_volume(&mut self) {
+        self.volume_used = 0;
+    }
+}
+
+/// Reason a [TokenBucket] refused bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+    /// Bucket will hold enough tokens after this delay, [Duration::MAX] if it is never refilled
+    Rate(Duration),
+
+    /// Total volume is exhausted
+    Volume,
+
+    /// Bytes exceed burst size and can never be sent
+    TooLarge,
+}
+
+/// Limits by destination rule
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    rules: Vec<(Destination, TokenBucket)>,
+}
+
+impl RateLimits {
+    /// No limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set limit of a rule, replacing its previous limit and budget
+    pub fn set(&mut self, destination: Destination, limit: Limit, now: SystemTime) {
+        self.rules.retain(|(it, _)| *it != destination);
+        self.rules.push((destination, TokenBucket::new(limit, now)));
+    }
+
+    /// Remove limit of a rule
+    pub fn remove(&mut self, destination: &Destination) {
+        self.rules.retain(|(it, _)| it != destination);
+    }
+
+    /// Most specific rule matching `eid` and its bucket
+    pub fn bucket_mut(&mut self, eid: &str) -> Option<(&Destination, &mut TokenBucket)> {
+        self.rules.iter_mut()
+            .filter(|(destination, _)| destination.matches(eid))
+            .max_by_key(|(destination, _)| destination.specificity())
+            .map(|(destination, bucket)| (&*destination, bucket))
+    }
+
+    /// Take `size` bytes from budget of `eid`, always succeeding for unlimited destinations
+    pub fn take(&mut self, eid: &str, size: u64, now: SystemTime) -> Result<(), Exceeded> {
+        match self.bucket_mut(eid) {
+            Some((_, bucket)) => bucket.take(size, now),
+            None => Ok(()),
+        }
+    }
+
+    /// Budget left for `eid`, [None] for unlimited destinations
+    pub fn remaining(&mut self, eid: &str, now: SystemTime) -> Option<Budget> {
+        self.bucket_mut(eid).map(|(_, bucket)| bucket.remaining(now))
+    }
+}
+
+/// A registered agent limiting bytes sent per destination
+pub struct RateLimitedAgent<S: AapStream> {
+    agent: RegisteredAgent<S>,
+    limits: RateLimits,
+    mode: Mode,
+}
+
+impl<S: AapStream> RateLimitedAgent<S> {
+    /// Wrap a registered agent, without any limit
+    pub fn new(agent: RegisteredAgent<S>, mode: Mode) -> Self {
+        Self { agent, limits: RateLimits::new(), mode }
+    }
+
+    /// Limit bytes sent to destinations matching `destination`
+    pub fn with_limit(mut self, destination: Destination, limit: Limit) -> Self {
+        self.set_limit(destination, limit);
+        self
+    }
+
+    /// Set limit of a rule, replacing its previous limit and budget
+    pub fn set_limit(&mut self, destination: Destination, limit: Limit) {
+        self.limits.set(destination, limit, SystemTime::now());
+    }
+
+    /// Limits by destination rule
+    pub fn limits(&mut self) -> &mut RateLimits {
+        &mut self.limits
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// Unwrap the underlying registered agent
+    pub fn into_inner(self) -> RegisteredAgent<S> {
+        self.agent
+    }
+
+    /// Budget left for `destination_eid`, [None] if it is not limited
+    pub fn remaining(&mut self, destination_eid: &str) -> Option<Budget> {
+        self.limits.remaining(destination_eid, SystemTime::now())
+    }
+
+    /// Send a bundle once budget allows it
+    ///
+    /// Fails with [RateLimitError::RateExceeded] even in [Mode::Block] if the bucket is never refilled
+    pub fn send_bundle(&mut self, destination_eid: String, payload: &[u8]) -> Result<BundleIdentifier, RateLimitError> {
+        loop {
+            match self.limits.take(&destination_eid, payload.len() as u64, SystemTime::now()) {
+                Ok(()) => break,
+                Err(Exceeded::Rate(delay)) if self.mode == Mode::Block && delay != Duration::MAX => thread::sleep(delay),
+                Err(Exceeded::Rate(delay)) => {
+                    return Err(RateLimitError::RateExceeded { destination: destination_eid, retry_after: delay })
+                }
+                Err(Exceeded::Volume) => return Err(RateLimitError::VolumeExhausted(destination_eid)),
+                Err(Exceeded::TooLarge) => return Err(RateLimitError::TooLarge(payload.len())),
+            }
+        }
+
+        Ok(self.agent.send_bundle(destination_eid, payload)?)
+    }
+}
+
+/// An error of rate limited sending
+#[derive(Debug, Error)]
+pub enum RateLimitError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Not enough budget to send bundle now
+    #[error("Rate limit exceeded for {destination}, retry after {retry_after:?}")]
+    RateExceeded {
+        /// Destination of refused bundle
+        destination: String,
+        /// Delay until enough budget is refilled, [Duration::MAX] if it never is
+        retry_after: Duration,
+    },
+
+    /// Total volume allowed for destination is exhausted
+    #[error("Volume exhausted for {0}")]
+    VolumeExhausted(String),
+
+    /// Payload is larger than burst size of its limit
+    #[error("Payload of {0} bytes exceeds burst size")]
+    TooLarge(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let now = SystemTime::now();
+        let mut bucket = TokenBucket::new(Limit::new(100, 1000).with_volume(1500), now);
+
+        assert_eq!(bucket.take(800, now), Ok(()));
+        assert_eq!(bucket.take(400, now), Err(Exceeded::Rate(Duration::from_secs(2))));
+        assert_eq!(bucket.remaining(now), Budget { available: 200, volume_remaining: Some(700) });
+
+        assert_eq!(bucket.take(400, now + Duration::from_secs(2)), Ok(()));
+        assert_eq!(bucket.take(2000, now + Duration::from_secs(100)), Err(Exceeded::TooLarge));
+        assert_eq!(bucket.take(500, now + Duration::from_secs(100)), Err(Exceeded::Volume));
+        bucket.reset_volume();
+        assert_eq!(bucket.take(500, now + Duration::from_secs(100)), Ok(()));
+
+        let mut bucket = TokenBucket::new(Limit::new(0, 100), now);
+        assert_eq!(bucket.take(100, now), Ok(()));
+        assert_eq!(bucket.take(1, now + Duration::from_secs(100)), Err(Exceeded::Rate(Duration::MAX)));
+
+        let mut bucket = TokenBucket::new(Limit::new(0, u64::MAX).with_volume(u64::MAX - 1), now);
+        assert_eq!(bucket.take(100, now), Ok(()));
+        assert_eq!(bucket.take(u64::MAX, now), Err(Exceeded::Volume));
+    }
+
+    #[test]
+    fn most_specific_rule_applies() {
+        let now = SystemTime::now();
+        let mut limits = RateLimits::new();
+        limits.set(Destination::Prefix("dtn://ground.dtn/".into()), Limit::new(10, 100), now);
+        limits.set(Destination::Prefix("dtn://ground.dtn/bulk/".into()), Limit::new(10, 50), now);
+        limits.set(Destination::Exact("dtn://ground.dtn/alerts".into()), Limit::new(10, 1000), now);
+
+        assert_eq!(limits.take("dtn://ground.dtn/bulk/a", 50, now), Ok(()));
+        assert_eq!(limits.remaining("dtn://ground.dtn/bulk/b", now).unwrap().available, 0);
+        assert_eq!(limits.remaining("dtn://ground.dtn/other", now).unwrap().available, 100);
+        assert_eq!(limits.take("dtn://ground.dtn/alerts", 500, now), Ok(()));
+        assert_eq!(limits.take("dtn://elsewhere.dtn/app", 1_000_000, now), Ok(()));
+        assert_eq!(limits.remaining("dtn://elsewhere.dtn/app", now), None);
+    }
+}
@@ -0,0 +1,432 @@
+//! End-to-end acknowledged messaging
+//!
+//! [RegisteredAgent::send_bundle] only confirms the local node accepted a bundle.
+//! [ReliableAgent] prefixes each message with an identifier, the receiving [ReliableAgent] answers with a small
+//! acknowledgement bundle sent back to [ReceivedBundle::source].
+//! Unacknowledged messages are retransmitted until they are acknowledged or expire.
+//!
+//! Retransmissions happen in [ReliableAgent::tick], also called by [ReliableAgent::recv].
+//! Use a read timeout on the underlying stream so that [ReliableAgent::recv] regularly returns.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::Duration};
+//! use ud3tn_aap::{Agent, reliable::{ReliableAgent, Outcome}};
+//!
+//! let agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("reports".to_owned()).unwrap();
+//! let mut agent = ReliableAgent::new(agent)
+//!     .with_retransmit_after(Duration::from_secs(600));
+//!
+//! let id = agent.send("dtn://hq.dtn/reports".into(), b"field report").unwrap();
+//! loop {
+//!     let _ = agent.recv();
+//!     for outcome in agent.take_outcomes() {
+//!         if let Outcome::Delivered { message_id, .. } = outcome {
+//!             if message_id == id { return }
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::{collections::{HashMap, VecDeque}, time::{Duration, SystemTime}};
+
+use thiserror::Error;
+
+use crate::{message::ParseError, wire, AapStream, BundleIdentifier, ReceivedBundle, RegisteredAgent};
+
+/// First byte of every reliable messaging bundle
+const MAGIC: u8 = 0xA7;
+
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+
+/// Default delay before an unacknowledged message is sent again
+pub const DEFAULT_RETRANSMIT_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Default delay after which an unacknowledged message is given up
+pub const DEFAULT_EXPIRE_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Payload of a reliable messaging bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Application message awaiting acknowledgement
+    Data {
+        /// Identifier chosen by sender
+        message_id: u64,
+        /// Application payload
+        payload: Vec<u8>,
+    },
+
+    /// Acknowledgement of a [Packet::Data]
+    Ack {
+        /// Identifier of acknowledged message
+        message_id: u64,
+    },
+}
+
+impl Packet {
+    /// Serialize this packet to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![MAGIC];
+        match self {
+            Packet::Data { message_id, payload } => {
+                result.push(KIND_DATA);
+                wire::put_u64(&mut result, *message_id);
+                result.extend_from_slice(payload);
+            }
+            Packet::Ack { message_id } => {
+                result.push(KIND_ACK);
+                wire::put_u64(&mut result, *message_id);
+            }
+        }
+        result
+    }
+
+    /// Parse a packet from a bundle payload
+    pub fn parse(bytes: &[u8]) -> Result<Self, ReliableError> {
+        let mut reader = wire::Reader::new(bytes);
+        if reader.u8()? != MAGIC {
+            return Err(ReliableError::NotAReliableMessage);
+        }
+
+        match reader.u8()? {
+            KIND_DATA => Ok(Packet::Data {
+                message_id: reader.u64()?,
+                payload: reader.rest().to_vec(),
+            }),
+            KIND_ACK => Ok(Packet::Ack { message_id: reader.u64()? }),
+            _ => Err(ReliableError::NotAReliableMessage),
+        }
+    }
+}
+
+/// Final state of a sent message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Destination acknowledged the message
+    Delivered {
+        /// Identifier returned by [ReliableAgent::send]
+        message_id: u64,
+        /// Destination of message
+        destination: String,
+    },
+
+    /// No acknowledgement was received before expiration
+    Expired {
+        /// Identifier returned by [ReliableAgent::send]
+        message_id: u64,
+        /// Destination of message
+        destination: String,
+    },
+}
+
+/// A sent message awaiting acknowledgement
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    /// Destination of message
+    pub destination: String,
+
+    /// Application payload
+    pub payload: Vec<u8>,
+
+    /// When message was first sent
+    pub first_sent: SystemTime,
+
+    /// When message was last sent
+    pub last_sent: SystemTime,
+
+    /// Number of times message was sent
+    pub attempts: u32,
+
+    /// Identifier of the last bundle carrying this message
+    pub bundle_id: Option<BundleIdentifier>,
+}
+
+/// Messages awaiting acknowledgement, indexed by message identifier
+#[derive(Debug)]
+pub struct PendingMessages {
+    retransmit_after: Duration,
+    expire_after: Duration,
+    messages: HashMap<u64, PendingMessage>,
+}
+
+impl PendingMessages {
+    /// Create an empty set of pending messages
+    pub fn new(retransmit_after: Duration, expire_after: Duration) -> Self {
+        Self { retransmit_after, expire_after, messages: HashMap::new() }
+    }
+
+    /// Track a message sent for the first time
+    pub fn insert(&mut self, message_id: u64, destination: String, payload: Vec<u8>, now: SystemTime) {
+        self.messages.insert(message_id, PendingMessage {
+            destination,
+            payload,
+            first_sent: now,
+            last_sent: now,
+            attempts: 1,
+            bundle_id: None,
+        });
+    }
+
+    /// Stop tracking an acknowledged message
+    pub fn acknowledge(&mut self, message_id: u64) -> Option<PendingMessage> {
+        self.messages.remove(&message_id)
+    }
+
+    /// Get a pending message
+    pub fn get_mut(&mut self, message_id: u64) -> Option<&mut PendingMessage> {
+        self.messages.get_mut(&message_id)
+    }
+
+    /// Remove expired messages and list messages due for retransmission
+    ///
+    /// Returns a tuple of (Identifiers to retransmit, Expired messages with their identifiers)
+    pub fn due(&mut self, now: SystemTime) -> (Vec<u64>, Vec<(u64, PendingMessage)>) {
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or(Duration::ZERO);
+
+        let expired_ids: Vec<u64> = self.messages.iter()
+            .filter(|(_, it)| elapsed(it.first_sent) >= self.expire_after)
+            .map(|(id, _)| *id)
+            .collect();
+        let expired = expired_ids.into_iter()
+            .filter_map(|id| self.messages.remove(&id).map(|it| (id, it)))
+            .collect();
+
+        let mut retransmit: Vec<u64> = self.messages.iter()
+            .filter(|(_, it)| elapsed(it.last_sent) >= self.retransmit_after)
+            .map(|(id, _)| *id)
+            .collect();
+        retransmit.sort_by_key(|id| self.messages[id].first_sent);
+
+        (retransmit, expired)
+    }
+
+    /// Number of messages awaiting acknowledgement
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// No message is awaiting acknowledgement
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// A registered agent acknowledging received messages and retransmitting unacknowledged sent ones
+pub struct ReliableAgent<S: AapStream> {
+    agent: RegisteredAgent<S>,
+    pending: PendingMessages,
+    outcomes: VecDeque<Outcome>,
+
+    /// Received messages by (source, identifier), kept until `expire_after` to suppress retransmitted duplicates
+    received: HashMap<(String, u64), SystemTime>,
+    expire_after: Duration,
+}
+
+impl<S: AapStream> ReliableAgent<S> {
+    /// Wrap a registered agent using [DEFAULT_RETRANSMIT_AFTER] and [DEFAULT_EXPIRE_AFTER]
+    pub fn new(agent: RegisteredAgent<S>) -> Self {
+        Self {
+            agent,
+            pending: PendingMessages::new(DEFAULT_RETRANSMIT_AFTER, DEFAULT_EXPIRE_AFTER),
+            outcomes: VecDeque::new(),
+            received: HashMap::new(),
+            expire_after: DEFAULT_EXPIRE_AFTER,
+        }
+    }
+
+    /// Retransmit unacknowledged messages after `delay`
+    pub fn with_retransmit_after(mut self, delay: Duration) -> Self {
+        self.pending.retransmit_after = delay;
+        self
+    }
+
+    /// Give up unacknowledged messages after `delay`
+    pub fn with_expire_after(mut self, delay: Duration) -> Self {
+        self.pending.expire_after = delay;
+        self.expire_after = delay;
+        self
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// Unwrap the underlying registered agent, forgetting pending messages
+    pub fn into_inner(self) -> RegisteredAgent<S> {
+        self.agent
+    }
+
+    /// Messages awaiting acknowledgement
+    pub fn pending(&self) -> &PendingMessages {
+        &self.pending
+    }
+
+    /// Send a message that will be retransmitted until acknowledged or expired
+    ///
+    /// Returns message identifier, reported back in [Outcome]
+    pub fn send(&mut self, destination_eid: String, payload: &[u8]) -> Result<u64, ReliableError> {
+        let message_id = wire::new_message_id();
+        let packet = Packet::Data { message_id, payload: payload.to_vec() };
+        let bundle_id = self.agent.send_bundle(destination_eid.clone(), &packet.to_bytes())?;
+
+        self.pending.insert(message_id, destination_eid, payload.to_vec(), SystemTime::now());
+        if let Some(message) = self.pending.get_mut(message_id) {
+            message.bundle_id = Some(bundle_id);
+        }
+        Ok(message_id)
+    }
+
+    /// Retransmit messages due for retransmission and expire old ones
+    pub fn tick(&mut self, now: SystemTime) -> Result<(), ReliableError> {
+        let (retransmit, expired) = self.pending.due(now);
+
+        for (message_id, message) in expired {
+            self.outcomes.push_back(Outcome::Expired { message_id, destination: message.destination });
+        }
+
+        for message_id in retransmit {
+            let Some(message) = self.pending.get_mut(message_id) else { continue };
+            let packet = Packet::Data { message_id, payload: message.payload.clone() };
+            let bundle_id = self.agent.send_bundle(message.destination.clone(), &packet.to_bytes())?;
+
+            if let Some(message) = self.pending.get_mut(message_id) {
+                message.bundle_id = Some(bundle_id);
+                message.last_sent = now;
+                message.attempts += 1;
+            }
+        }
+
+        let expire_after = self.expire_after;
+        self.received.retain(|_, at| now.duration_since(*at).unwrap_or(Duration::ZERO) < expire_after);
+        Ok(())
+    }
+
+    /// Block until a new message is received
+    ///
+    /// Received messages are acknowledged, duplicates are dropped.
+    /// Received acknowledgements are reported in [ReliableAgent::take_outcomes].
+    pub fn recv(&mut self) -> Result<ReceivedBundle, ReliableError> {
+        loop {
+            self.tick(SystemTime::now())?;
+            let bundle = self.agent.recv_bundle()?;
+
+            match Packet::parse(&bundle.payload)? {
+                Packet::Ack { message_id } => {
+                    if let Some(message) = self.pending.acknowledge(message_id) {
+                        self.outcomes.push_back(Outcome::Delivered { message_id, destination: message.destination });
+                    }
+                }
+                Packet::Data { message_id, payload } => {
+                    let Some(source) = bundle.source else {
+                        return Ok(ReceivedBundle { source: None, payload });
+                    };
+
+                    self.agent.send_bundle(source.clone(), &Packet::Ack { message_id }.to_bytes())?;
+
+                    if self.received.insert((source.clone(), message_id), SystemTime::now()).is_none() {
+                        return Ok(ReceivedBundle { source: Some(source), payload });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take outcomes of sent messages reported since last call
+    pub fn take_outcomes(&mut self) -> Vec<Outcome> {
+        self.outcomes.drain(..).collect()
+    }
+}
+
+/// An error during reliable messaging
+#[derive(Debug, Error)]
+pub enum ReliableError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Received bundle doesn't start with a reliable messaging header
+    #[error("Bundle is not a reliable message")]
+    NotAReliableMessage,
+
+    /// Received header is truncated
+    #[error("Malformed reliable message")]
+    Malformed(#[from] ParseError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_roundtrip() {
+        let data = Packet::Data { message_id: 0x0102030405060708, payload: b"report".to_vec() };
+        let bytes = data.to_bytes();
+        assert_eq!(&bytes[..10], &[MAGIC, KIND_DATA, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(Packet::parse(&bytes).unwrap(), data);
+
+        let ack = Packet::Ack { message_id: 12 };
+        assert_eq!(Packet::parse(&ack.to_bytes()).unwrap(), ack);
+
+        assert!(matches!(Packet::parse(b"plain"), Err(ReliableError::NotAReliableMessage)));
+        assert!(matches!(Packet::parse(&[MAGIC, KIND_ACK, 0]), Err(ReliableError::Malformed(_))));
+    }
+
+    #[test]
+    fn retransmit_and_expire() {
+        let start = SystemTime::now();
+        let mut pending = PendingMessages::new(Duration::from_secs(10), Duration::from_secs(25));
+        pending.insert(1, "dtn://a/".into(), vec![1], start);
+        pending.insert(2, "dtn://b/".into(), vec![2], start + Duration::from_secs(5));
+
+        let (retransmit, expired) = pending.due(start + Duration::from_secs(5));
+        assert!(retransmit.is_empty() && expired.is_empty());
+
+        let (retransmit, expired) = pending.due(start + Duration::from_secs(15));
+        assert_eq!(retransmit, vec![1, 2]);
+        assert!(expired.is_empty());
+        pending.get_mut(1).unwrap().last_sent = start + Duration::from_secs(15);
+
+        assert_eq!(pending.acknowledge(2).unwrap().destination, "dtn://b/");
+        assert!(pending.acknowledge(2).is_none());
+
+        let (retransmit, expired) = pending.due(start + Duration::from_secs(25));
+        assert!(retransmit.is_empty());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, 1);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn acknowledged_end_to_end() {
+        use crate::{testing::MockNode, Agent};
+
+        let node = MockNode::new("dtn://a.dtn/");
+        let connect = |agent_id: &str| {
+            let mut stream = node.connect();
+            stream.set_read_timeout(Some(Duration::from_millis(200)));
+            ReliableAgent::new(Agent::new(stream).unwrap().register(agent_id.into()).unwrap())
+                .with_retransmit_after(Duration::from_secs(60 * 60))
+        };
+        let mut sender = connect("sender");
+        let mut receiver = connect("receiver");
+
+        let id = sender.send("dtn://a.dtn/receiver".into(), b"report").unwrap();
+        sender.tick(SystemTime::now() + Duration::from_secs(2 * 60 * 60)).unwrap();
+        assert_eq!(node.sent_bundles().len(), 2);
+        assert_eq!(sender.pending().len(), 1);
+
+        // Retransmitted copy is acknowledged again but not returned twice
+        let received = receiver.recv().unwrap();
+        assert_eq!((received.source.as_deref(), received.payload.as_slice()), (Some("dtn://a.dtn/sender"), &b"report"[..]));
+        assert!(matches!(receiver.recv(), Err(ReliableError::Agent(_))));
+        let acks = node.sent_bundles().iter().filter(|it| it.destination == "dtn://a.dtn/sender").count();
+        assert_eq!(acks, 2);
+
+        assert!(matches!(sender.recv(), Err(ReliableError::Agent(_))));
+        assert_eq!(sender.take_outcomes(), vec![Outcome::Delivered { message_id: id, destination: "dtn://a.dtn/receiver".into() }]);
+        assert!(sender.pending().is_empty());
+    }
+}
@@ -0,0 +1,398 @@
+//! Contact graph routing over contact plans
+//!
+//! [ContactGraph] holds the contacts of a [ContactPlan] as the nodes would be configured with them, one contact
+//! per window from a node to a peer. Routes are searched as in contact graph routing: a Dijkstra search over
+//! contacts, minimizing the arrival time of a bundle of a given size, accounting for transmission time and for
+//! volume already booked by previous bundles with [ContactGraph::consume].
+//!
+//! Peers listing the destination in their reachable EIDs are considered to deliver it on arrival.
+//! Propagation delay is neglected.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::{Duration, SystemTime}};
+//! use ud3tn_aap::{Agent, BaseAgent, plan::ContactPlan, routing::{ContactGraph, RouteQuery}};
+//!
+//! let mut agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("telemetry".to_owned()).unwrap();
+//! let graph = ContactGraph::from_plan(&ContactPlan::load(Path::new("contacts.toml")).unwrap(), SystemTime::now());
+//!
+//! let payload = b"samples";
+//! let query = RouteQuery::new(agent.node_id(), "dtn://ground.dtn/telemetry", payload.len() as u64, SystemTime::now())
+//!     .with_expiry(SystemTime::now() + Duration::from_secs(3600));
+//! match graph.best_route(&query) {
+//!     Some(route) => println!("Delivered through {} at {:?}", route.next_hop(), route.arrival),
+//!     None => eprintln!("Warning: bundle can't reach destination before it expires"),
+//! }
+//! agent.send_bundle("dtn://ground.dtn/telemetry".into(), payload).unwrap();
+//! ```
+
+use std::{cmp::Reverse, collections::BinaryHeap, time::{Duration, SystemTime}};
+
+use crate::{config::ContactDataRate, plan::ContactPlan, schedule::node_id};
+
+/// A contact from a node to a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphContact {
+    /// Node ID of sending node
+    pub from: String,
+
+    /// Node ID of receiving node
+    pub to: String,
+
+    /// When contact starts
+    pub start: SystemTime,
+
+    /// When contact ends
+    pub end: SystemTime,
+
+    /// Expected transmission rate
+    pub data_rate: ContactDataRate,
+
+    /// Node IDs reachable through receiving node
+    pub reaches: Vec<String>,
+}
+
+impl GraphContact {
+    /// Bytes transmitted during whole contact, [None] if unlimited
+    pub fn volume(&self) -> Option<u64> {
+        match self.data_rate {
+            ContactDataRate::Limited(rate) => {
+                let duration = self.end.duration_since(self.start).unwrap_or(Duration::ZERO);
+                Some((rate.max(0) as u64).saturating_mul(duration.as_secs()))
+            }
+            ContactDataRate::Unlimited => None,
+        }
+    }
+
+    fn transmission_time(&self, size: u64) -> Option<Duration> {
+        match self.data_rate {
+            ContactDataRate::Unlimited => Some(Duration::ZERO),
+            _ if size == 0 => Some(Duration::ZERO),
+            ContactDataRate::Limited(rate) if rate > 0 => Some(Duration::from_secs_f64(size as f64 / rate as f64)),
+            ContactDataRate::Limited(_) => None,
+        }
+    }
+}
+
+/// Bundle to route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteQuery {
+    /// Node ID of sending node
+    pub source: String,
+
+    /// Destination node ID
+    pub destination: String,
+
+    /// Bundle size in bytes
+    pub size: u64,
+
+    /// When bundle is available at source
+    pub creation: SystemTime,
+
+    /// Latest arrival time, [None] if bundle never expires
+    pub expiry: Option<SystemTime>,
+}
+
+impl RouteQuery {
+    /// Query for a bundle without expiry, source and destination may be any EID of their nodes
+    pub fn new(source: &str, destination: &str, size: u64, creation: SystemTime) -> Self {
+        Self { source: node_id(source), destination: node_id(destination), size, creation, expiry: None }
+    }
+
+    /// Set latest arrival time
+    pub fn with_expiry(mut self, expiry: SystemTime) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+}
+
+/// A contact used by a route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hop {
+    /// Index of contact in [ContactGraph::contacts]
+    pub contact: usize,
+
+    /// When transmission starts
+    pub departure: SystemTime,
+
+    /// When bundle is fully received
+    pub arrival: SystemTime,
+}
+
+/// A sequence of contacts from source to destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Contacts in order
+    pub hops: Vec<Hop>,
+
+    /// When bundle reaches destination
+    pub arrival: SystemTime,
+
+    /// Bytes still available on the route, limited by its most booked contact, [None] if unlimited
+    pub volume: Option<u64>,
+
+    next_hop: String,
+    limiting_contact: usize,
+}
+
+impl Route {
+    /// Node ID of first receiving node
+    pub fn next_hop(&self) -> &str {
+        &self.next_hop
+    }
+
+    /// Index of contact ending first, suppressed to find alternative routes
+    pub fn limiting_contact(&self) -> usize {
+        self.limiting_contact
+    }
+}
+
+/// Contacts between nodes, with the volume still available for each
+#[derive(Debug, Clone, Default)]
+pub struct ContactGraph {
+    contacts: Vec<GraphContact>,
+    residual: Vec<Option<u64>>,
+}
+
+impl ContactGraph {
+    /// Graph of contacts
+    pub fn new(contacts: Vec<GraphContact>) -> Self {
+        let residual = contacts.iter().map(GraphContact::volume).collect();
+        Self { contacts, residual }
+    }
+
+    /// Graph of contacts of all nodes of a plan applied at `now`
+    pub fn from_plan(plan: &ContactPlan, now: SystemTime) -> Self {
+        let mut contacts = Vec::new();
+        for node in &plan.nodes {
+            for peer in &node.peers {
+                for contact in peer.resolvable_contacts(now) {
+                    contacts.push(GraphContact {
+                        from: node_id(&node.eid),
+                        to: node_id(&peer.eid),
+                        start: contact.start,
+                        end: contact.end,
+                        data_rate: contact.data_rate,
+                        reaches: peer.reaches.iter().map(|it| node_id(it)).collect(),
+                    });
+                }
+            }
+        }
+        Self::new(contacts)
+    }
+
+    /// All contacts
+    pub fn contacts(&self) -> &[GraphContact] {
+        &self.contacts
+    }
+
+    /// Bytes still available during a contact, [None] if unlimited
+    pub fn residual_volume(&self, contact: usize) -> Option<u64> {
+        self.residual[contact]
+    }
+
+    /// Book volume of a bundle of `size` bytes sent along a route
+    pub fn consume(&mut self, route: &Route, size: u64) {
+        for hop in &route.hops {
+            self.book(hop.contact, size);
+        }
+    }
+
+    /// Book volume of a bundle of `size` bytes sent during a contact
+    pub fn book(&mut self, contact: usize, size: u64) {
+        if let Some(residual) = &mut self.residual[contact] {
+            *residual = residual.saturating_sub(size);
+        }
+    }
+
+    /// Departure and arrival of a bundle ready at `ready` sent during a contact, after booked volume
+    fn transmit(&self, contact: usize, ready: SystemTime, size: u64) -> Option<(SystemTime, SystemTime)> {
+        let graph_contact = &self.contacts[contact];
+        let transmission = graph_contact.transmission_time(size)?;
+
+        let mut departure = ready.max(graph_contact.start);
+        if let (Some(volume), Some(residual)) = (graph_contact.volume(), self.residual[contact]) {
+            if residual < size {
+                return None;
+            }
+            departure = departure.max(graph_contact.start + graph_contact.transmission_time(volume - residual)?);
+        }
+
+        let arrival = departure + transmission;
+        (arrival <= graph_contact.end).then_some((departure, arrival))
+    }
+
+    fn delivers(&self, contact: usize, destination: &str) -> bool {
+        let graph_contact = &self.contacts[contact];
+        graph_contact.to == destination || graph_contact.reaches.iter().any(|it| it == destination)
+    }
+
+    fn search(&self, query: &RouteQuery, suppressed: &[bool]) -> Option<Route> {
+        let count = self.contacts.len();
+        let mut best: Vec<Option<(SystemTime, SystemTime)>> = vec![None; count];
+        let mut predecessor: Vec<Option<usize>> = vec![None; count];
+        let mut visited = vec![false; count];
+        let mut queue = BinaryHeap::new();
+
+        let in_time = |arrival: SystemTime| query.expiry.is_none_or(|expiry| arrival <= expiry);
+
+        for index in 0..count {
+            if suppressed[index] || self.contacts[index].from != query.source {
+                continue;
+            }
+            if let Some((departure, arrival)) = self.transmit(index, query.creation, query.size).filter(|it| in_time(it.1)) {
+                best[index] = Some((departure, arrival));
+                queue.push(Reverse((arrival, index)));
+            }
+        }
+
+        while let Some(Reverse((arrival, current))) = queue.pop() {
+            if visited[current] {
+                continue;
+            }
+            visited[current] = true;
+
+            if self.delivers(current, &query.destination) {
+                return Some(self.route(current, &best, &predecessor));
+            }
+
+            let node = &self.contacts[current].to;
+            for next in 0..count {
+                let contact = &self.contacts[next];
+                if suppressed[next] || visited[next] || contact.from != *node || contact.end <= arrival {
+                    continue;
+                }
+                // Don't go back to a node already on path
+                let mut path = Some(current);
+                let mut looping = contact.to == query.source;
+                while let (Some(hop), false) = (path, looping) {
+                    looping = self.contacts[hop].to == contact.to;
+                    path = predecessor[hop];
+                }
+                if looping {
+                    continue;
+                }
+
+                let Some((departure, next_arrival)) = self.transmit(next, arrival, query.size) else { continue };
+                if in_time(next_arrival) && best[next].is_none_or(|(_, it)| next_arrival < it) {
+                    best[next] = Some((departure, next_arrival));
+                    predecessor[next] = Some(current);
+                    queue.push(Reverse((next_arrival, next)));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn route(&self, last: usize, best: &[Option<(SystemTime, SystemTime)>], predecessor: &[Option<usize>]) -> Route {
+        let mut hops = Vec::new();
+        let mut current = Some(last);
+        while let Some(contact) = current {
+            let (departure, arrival) = best[contact].expect("contact on path was reached");
+            hops.push(Hop { contact, departure, arrival });
+            current = predecessor[contact];
+        }
+        hops.reverse();
+
+        let limiting_contact = hops.iter().map(|it| it.contact).min_by_key(|it| self.contacts[*it].end).expect("route has hops");
+        Route {
+            arrival: hops[hops.len() - 1].arrival,
+            volume: hops.iter().filter_map(|it| self.residual[it.contact]).min(),
+            next_hop: self.contacts[hops[0].contact].to.clone(),
+            limiting_contact,
+            hops,
+        }
+    }
+
+    /// Route with earliest arrival, [None] if bundle can't reach destination before expiry
+    pub fn best_route(&self, query: &RouteQuery) -> Option<Route> {
+        self.search(query, &vec![false; self.contacts.len()])
+    }
+
+    /// Earliest arrival time at destination
+    pub fn earliest_arrival(&self, query: &RouteQuery) -> Option<SystemTime> {
+        self.best_route(query).map(|it| it.arrival)
+    }
+
+    /// Up to `k` routes by arrival time, each found after suppressing the limiting contact of the previous one
+    pub fn routes(&self, query: &RouteQuery, k: usize) -> Vec<Route> {
+        let mut suppressed = vec![false; self.contacts.len()];
+        let mut result = Vec::new();
+        while result.len() < k {
+            let Some(route) = self.search(query, &suppressed) else { break };
+            suppressed[route.limiting_contact] = true;
+            result.push(route);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::{ClaAddress, HostPort}, plan::{Peer, PlanNode}};
+
+    use super::*;
+
+    fn node(eid: &str, peers: &[(&str, &[&str])]) -> PlanNode {
+        PlanNode {
+            eid: eid.into(),
+            peers: peers.iter().map(|(peer, windows)| Peer {
+                eid: peer.to_string(),
+                cla: ClaAddress::Mtcp(HostPort::new("10.0.0.1", 4224)),
+                reliability: None,
+                reaches: Vec::new(),
+                windows: windows.iter().map(|it| it.parse().unwrap()).collect(),
+            }).collect(),
+        }
+    }
+
+    fn plan() -> ContactPlan {
+        ContactPlan {
+            nodes: vec![
+                node("dtn://a.dtn/", &[
+                    ("dtn://b.dtn/", &["+10s for 100s at 10"]),
+                    ("dtn://c.dtn/", &["+0s for 100s at 100"]),
+                ]),
+                node("dtn://b.dtn/", &[("dtn://d.dtn/", &["+200s for 100s at 10"])]),
+                node("dtn://c.dtn/", &[("dtn://d.dtn/", &["+500s for 100s at 100"])]),
+            ],
+        }
+    }
+
+    #[test]
+    fn earliest_route_found() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let mut graph = ContactGraph::from_plan(&plan(), now);
+        let query = RouteQuery::new("dtn://a.dtn/sensor", "dtn://d.dtn/sink", 500, now);
+
+        let route = graph.best_route(&query).unwrap();
+        assert_eq!(route.next_hop(), "dtn://b.dtn/");
+        assert_eq!(route.arrival, now + Duration::from_secs(250));
+        assert_eq!(route.volume, Some(1000));
+        assert_eq!(route.hops[0].departure, now + Duration::from_secs(10));
+
+        // Second bundle waits for the first one on the same contacts
+        graph.consume(&route, 500);
+        assert_eq!(graph.earliest_arrival(&query), Some(now + Duration::from_secs(300)));
+
+        // No room left through b
+        graph.consume(&route, 500);
+        assert_eq!(graph.best_route(&query).unwrap().next_hop(), "dtn://c.dtn/");
+
+        assert_eq!(graph.best_route(&query.clone().with_expiry(now + Duration::from_secs(500))), None);
+        assert_eq!(graph.best_route(&RouteQuery::new("dtn://d.dtn/", "dtn://a.dtn/", 1, now)), None);
+    }
+
+    #[test]
+    fn alternative_routes_found() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+        let graph = ContactGraph::from_plan(&plan(), now);
+        let query = RouteQuery::new("dtn://a.dtn/", "dtn://d.dtn/", 500, now);
+
+        let routes = graph.routes(&query, 3);
+        let next_hops: Vec<&str> = routes.iter().map(Route::next_hop).collect();
+        assert_eq!(next_hops, vec!["dtn://b.dtn/", "dtn://c.dtn/"]);
+        assert_eq!(routes[1].arrival, now + Duration::from_secs(505));
+    }
+}
@@ -0,0 +1,475 @@
+//! Request/response calls between agents
+//!
+//! Requests carry a correlation identifier and the EID replies must be sent to.
+//! The server side registers a handler per method name, the client side gets a [Call] handle
+//! completed when the matching reply arrives or its deadline expires.
+//! Several calls can be outstanding on the same agent.
+//!
+//! ```rust,no_run
+//! use std::{path::Path, time::{Duration, SystemTime}};
+//! use ud3tn_aap::{Agent, rpc::RpcAgent};
+//!
+//! let agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("control".to_owned()).unwrap();
+//! let mut agent = RpcAgent::new(agent);
+//! agent.register_handler("status", |_request| Ok(b"running".to_vec()));
+//!
+//! let call = agent.call(
+//!     "dtn://sensor.dtn/control".into(), "status", &[],
+//!     SystemTime::now() + Duration::from_secs(6 * 60 * 60)
+//! ).unwrap();
+//! let status = agent.wait(&call).unwrap();
+//! ```
+
+use std::{collections::{HashMap, VecDeque}, io::ErrorKind, time::SystemTime};
+
+use thiserror::Error;
+
+use crate::{message::ParseError, wire, AapStream, ReceivedBundle, RegisteredAgent};
+
+/// First byte of every RPC bundle
+const MAGIC: u8 = 0xC7;
+
+const KIND_REQUEST: u8 = 0;
+const KIND_RESPONSE: u8 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_METHOD: u8 = 1;
+const STATUS_FAILED: u8 = 2;
+
+/// Maximum number of received bundles kept when they aren't RPC messages, oldest are dropped first
+pub const MAX_UNHANDLED_BUNDLES: usize = 1024;
+
+/// Payload of a RPC bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// A method call
+    Request {
+        /// Identifier of call, copied in response
+        correlation_id: u64,
+        /// EID the response must be sent to
+        reply_to: String,
+        /// Name of called method
+        method: String,
+        /// Method argument
+        body: Vec<u8>,
+    },
+
+    /// Result of a method call
+    Response {
+        /// Identifier of answered call
+        correlation_id: u64,
+        /// Method result
+        result: Result<Vec<u8>, RemoteError>,
+    },
+}
+
+impl Packet {
+    /// Serialize this packet to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![MAGIC];
+        match self {
+            Packet::Request { correlation_id, reply_to, method, body } => {
+                result.push(KIND_REQUEST);
+                wire::put_u64(&mut result, *correlation_id);
+                wire::put_string(&mut result, reply_to);
+                wire::put_string(&mut result, method);
+                result.extend_from_slice(body);
+            }
+            Packet::Response { correlation_id, result: response } => {
+                result.push(KIND_RESPONSE);
+                wire::put_u64(&mut result, *correlation_id);
+                match response {
+                    Ok(body) => {
+                        result.push(STATUS_OK);
+                        result.extend_from_slice(body);
+                    }
+                    Err(RemoteError::UnknownMethod) => result.push(STATUS_UNKNOWN_METHOD),
+                    Err(RemoteError::Failed(message)) => {
+                        result.push(STATUS_FAILED);
+                        result.extend_from_slice(message.as_bytes());
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Parse a packet from a bundle payload
+    pub fn parse(bytes: &[u8]) -> Result<Self, RpcError> {
+        let mut reader = wire::Reader::new(bytes);
+        if reader.u8()? != MAGIC {
+            return Err(RpcError::NotARpcMessage);
+        }
+
+        match reader.u8()? {
+            KIND_REQUEST => Ok(Packet::Request {
+                correlation_id: reader.u64()?,
+                reply_to: reader.string()?,
+                method: reader.string()?,
+                body: reader.rest().to_vec(),
+            }),
+            KIND_RESPONSE => {
+                let correlation_id = reader.u64()?;
+                let result = match reader.u8()? {
+                    STATUS_OK => Ok(reader.rest().to_vec()),
+                    STATUS_UNKNOWN_METHOD => Err(RemoteError::UnknownMethod),
+                    STATUS_FAILED => Err(RemoteError::Failed(String::from_utf8_lossy(reader.rest()).into_owned())),
+                    _ => return Err(RpcError::NotARpcMessage),
+                };
+                Ok(Packet::Response { correlation_id, result })
+            }
+            _ => Err(RpcError::NotARpcMessage),
+        }
+    }
+}
+
+/// A request received by a handler
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// Source endpoint ID of request bundle
+    pub source: Option<String>,
+
+    /// EID the response is sent to
+    pub reply_to: String,
+
+    /// Identifier of call
+    pub correlation_id: u64,
+
+    /// Name of called method
+    pub method: String,
+
+    /// Method argument
+    pub body: Vec<u8>,
+}
+
+/// Method handler, returns response body or an error message sent back to caller
+pub type Handler = Box<dyn FnMut(&Request) -> Result<Vec<u8>, String> + Send>;
+
+/// Handle of an outstanding call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    correlation_id: u64,
+    deadline: SystemTime,
+}
+
+impl Call {
+    /// Identifier of this call
+    pub fn correlation_id(&self) -> u64 {
+        self.correlation_id
+    }
+
+    /// Time after which this call fails with [RpcError::DeadlineExpired]
+    pub fn deadline(&self) -> SystemTime {
+        self.deadline
+    }
+}
+
+/// Outstanding calls and their results
+///
+/// Results not taken before their call deadline are dropped by [CallTable::expire].
+#[derive(Debug, Default)]
+pub struct CallTable {
+    outstanding: HashMap<u64, SystemTime>,
+    completed: HashMap<u64, (SystemTime, Result<Vec<u8>, RemoteError>)>,
+}
+
+impl CallTable {
+    /// Track a new outstanding call
+    pub fn insert(&mut self, correlation_id: u64, deadline: SystemTime) {
+        self.outstanding.insert(correlation_id, deadline);
+    }
+
+    /// Store the result of a call
+    ///
+    /// Returns false if call is unknown, already completed or expired
+    pub fn complete(&mut self, correlation_id: u64, result: Result<Vec<u8>, RemoteError>) -> bool {
+        let Some(deadline) = self.outstanding.remove(&correlation_id) else {
+            return false;
+        };
+        self.completed.insert(correlation_id, (deadline, result));
+        true
+    }
+
+    /// Take the result of a call
+    ///
+    /// [None] if call is still outstanding
+    pub fn take(&mut self, call: &Call, now: SystemTime) -> Option<Result<Vec<u8>, RpcError>> {
+        if let Some((_, result)) = self.completed.remove(&call.correlation_id) {
+            return Some(result.map_err(RpcError::Remote));
+        }
+
+        if now >= call.deadline {
+            self.outstanding.remove(&call.correlation_id);
+            return Some(Err(RpcError::DeadlineExpired));
+        }
+
+        None
+    }
+
+    /// Forget calls and results whose deadline expired without being waited for
+    pub fn expire(&mut self, now: SystemTime) {
+        self.outstanding.retain(|_, deadline| now < *deadline);
+        self.completed.retain(|_, (deadline, _)| now < *deadline);
+    }
+
+    /// Number of calls awaiting a response
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+}
+
+/// A registered agent sending and serving calls
+pub struct RpcAgent<S: AapStream> {
+    agent: RegisteredAgent<S>,
+    handlers: HashMap<String, Handler>,
+    calls: CallTable,
+    unhandled: VecDeque<ReceivedBundle>,
+}
+
+impl<S: AapStream> RpcAgent<S> {
+    /// Wrap a registered agent
+    pub fn new(agent: RegisteredAgent<S>) -> Self {
+        Self { agent, handlers: HashMap::new(), calls: CallTable::default(), unhandled: VecDeque::new() }
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// Unwrap the underlying registered agent, forgetting outstanding calls
+    pub fn into_inner(self) -> RegisteredAgent<S> {
+        self.agent
+    }
+
+    /// EID of this agent, used as reply-to EID of calls
+    pub fn eid(&self) -> String {
+        self.agent.endpoint_id()
+    }
+
+    /// Register the handler of a method, replacing any previous one
+    pub fn register_handler<F>(&mut self, method: &str, handler: F)
+        where F: FnMut(&Request) -> Result<Vec<u8>, String> + Send + 'static
+    {
+        self.handlers.insert(method.to_owned(), Box::new(handler));
+    }
+
+    /// Call a method of a remote agent
+    ///
+    /// Use [RpcAgent::wait] or [RpcAgent::try_result] to get its result
+    pub fn call(&mut self, destination_eid: String, method: &str, body: &[u8], deadline: SystemTime) -> Result<Call, RpcError> {
+        let correlation_id = wire::new_message_id();
+        let packet = Packet::Request {
+            correlation_id,
+            reply_to: self.eid(),
+            method: method.to_owned(),
+            body: body.to_vec(),
+        };
+        self.agent.send_bundle(destination_eid, &packet.to_bytes())?;
+
+        self.calls.insert(correlation_id, deadline);
+        Ok(Call { correlation_id, deadline })
+    }
+
+    /// Get result of a call if available, without blocking
+    pub fn try_result(&mut self, call: &Call) -> Option<Result<Vec<u8>, RpcError>> {
+        self.calls.take(call, SystemTime::now())
+    }
+
+    /// Block until call result is received or its deadline expires
+    ///
+    /// Requests received meanwhile are served, other bundles are kept for [RpcAgent::take_unhandled].
+    /// Deadline is only checked when a bundle is received or a read times out, set a read timeout on the
+    /// underlying stream to return on time.
+    pub fn wait(&mut self, call: &Call) -> Result<Vec<u8>, RpcError> {
+        loop {
+            if let Some(result) = self.try_result(call) {
+                return result;
+            }
+
+            match self.process_next() {
+                Ok(()) => {}
+                Err(RpcError::Agent(crate::Error::IOError(e)))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Block until a request or response is received and process it
+    ///
+    /// Requests are answered with registered handlers, responses complete their call.
+    /// Bundles that aren't RPC messages are kept for [RpcAgent::take_unhandled], up to [MAX_UNHANDLED_BUNDLES].
+    pub fn process_next(&mut self) -> Result<(), RpcError> {
+        let bundle = self.agent.recv_bundle()?;
+        self.calls.expire(SystemTime::now());
+
+        let packet = match Packet::parse(&bundle.payload) {
+            Ok(packet) => packet,
+            Err(RpcError::NotARpcMessage | RpcError::Malformed(_)) => {
+                if self.unhandled.len() >= MAX_UNHANDLED_BUNDLES {
+                    self.unhandled.pop_front();
+                }
+                self.unhandled.push_back(bundle);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        match packet {
+            Packet::Response { correlation_id, result } => {
+                self.calls.complete(correlation_id, result);
+            }
+            Packet::Request { correlation_id, reply_to, method, body } => {
+                let request = Request { source: bundle.source, reply_to, correlation_id, method, body };
+                let result = match self.handlers.get_mut(&request.method) {
+                    Some(handler) => handler(&request).map_err(RemoteError::Failed),
+                    None => Err(RemoteError::UnknownMethod),
+                };
+
+                let response = Packet::Response { correlation_id, result };
+                self.agent.send_bundle(request.reply_to, &response.to_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take the oldest received bundle that wasn't a RPC message
+    pub fn take_unhandled(&mut self) -> Option<ReceivedBundle> {
+        self.unhandled.pop_front()
+    }
+
+    /// Serve requests forever
+    pub fn serve(&mut self) -> Result<(), RpcError> {
+        loop {
+            self.process_next()?;
+        }
+    }
+}
+
+/// An error reported by the called agent
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum RemoteError {
+    /// No handler is registered for called method
+    #[error("Unknown method")]
+    UnknownMethod,
+
+    /// Handler returned an error
+    #[error("Call failed: {0}")]
+    Failed(String),
+}
+
+/// An error during a call
+#[derive(Debug, Error)]
+pub enum RpcError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Received bundle doesn't start with a RPC header
+    #[error("Bundle is not a RPC message")]
+    NotARpcMessage,
+
+    /// Received header is truncated
+    #[error("Malformed RPC message")]
+    Malformed(#[from] ParseError),
+
+    /// Called agent answered with an error
+    #[error("Remote error")]
+    Remote(#[from] RemoteError),
+
+    /// No response was received before call deadline
+    #[error("Call deadline expired")]
+    DeadlineExpired,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn packet_roundtrip() {
+        let packets = [
+            Packet::Request {
+                correlation_id: 5,
+                reply_to: "dtn://a.dtn/ctl".into(),
+                method: "reboot".into(),
+                body: vec![1, 2],
+            },
+            Packet::Response { correlation_id: 5, result: Ok(b"done".to_vec()) },
+            Packet::Response { correlation_id: 6, result: Err(RemoteError::UnknownMethod) },
+            Packet::Response { correlation_id: 7, result: Err(RemoteError::Failed("disk full".into())) },
+        ];
+
+        for packet in packets {
+            assert_eq!(Packet::parse(&packet.to_bytes()).unwrap(), packet);
+        }
+
+        assert!(matches!(Packet::parse(b"hello"), Err(RpcError::NotARpcMessage)));
+        assert!(matches!(Packet::parse(&[MAGIC, KIND_REQUEST, 0]), Err(RpcError::Malformed(_))));
+    }
+
+    #[test]
+    fn concurrent_calls() {
+        let now = SystemTime::now();
+        let first = Call { correlation_id: 1, deadline: now + Duration::from_secs(60) };
+        let second = Call { correlation_id: 2, deadline: now + Duration::from_secs(120) };
+
+        let mut table = CallTable::default();
+        table.insert(1, first.deadline);
+        table.insert(2, second.deadline);
+        assert!(table.take(&first, now).is_none());
+
+        assert!(table.complete(2, Err(RemoteError::UnknownMethod)));
+        assert!(!table.complete(2, Ok(Vec::new())));
+        assert!(!table.complete(3, Ok(Vec::new())));
+        assert!(matches!(table.take(&second, now), Some(Err(RpcError::Remote(RemoteError::UnknownMethod)))));
+
+        assert!(matches!(table.take(&first, now + Duration::from_secs(60)), Some(Err(RpcError::DeadlineExpired))));
+        assert!(!table.complete(1, Ok(Vec::new())));
+        assert_eq!(table.outstanding(), 0);
+    }
+
+    #[test]
+    fn unclaimed_results_expire() {
+        let now = SystemTime::now();
+        let call = Call { correlation_id: 1, deadline: now + Duration::from_secs(60) };
+
+        let mut table = CallTable::default();
+        table.insert(1, call.deadline);
+        assert!(table.complete(1, Ok(b"late".to_vec())));
+        table.expire(now + Duration::from_secs(30));
+        assert_eq!(table.completed.len(), 1);
+        table.expire(call.deadline);
+        assert!(table.completed.is_empty());
+        assert!(matches!(table.take(&call, call.deadline), Some(Err(RpcError::DeadlineExpired))));
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn reply_to_ipn_endpoint() {
+        use crate::{testing::MockNode, Agent};
+
+        let node = MockNode::new("ipn:7.0");
+        let connect = |agent_id: &str| {
+            let mut stream = node.connect();
+            stream.set_read_timeout(Some(Duration::from_secs(5)));
+            RpcAgent::new(Agent::new(stream).unwrap().register(agent_id.into()).unwrap())
+        };
+        let mut client = connect("1");
+        let mut server = connect("2");
+        server.register_handler("status", |_| Ok(b"running".to_vec()));
+        assert_eq!(client.eid(), "ipn:7.1");
+
+        let call = client.call("ipn:7.2".into(), "status", &[], SystemTime::now() + Duration::from_secs(5)).unwrap();
+        assert!(node.deliver("ipn:7.1", "ipn:9.1", b"plain"));
+        server.process_next().unwrap();
+        assert_eq!(client.wait(&call).unwrap(), b"running");
+        assert_eq!(client.take_unhandled().unwrap().payload, b"plain");
+        assert!(client.take_unhandled().is_none());
+    }
+}
@@ -0,0 +1,287 @@
+//! Deferred sending at a given time or during known contacts
+//!
+//! Bundles handed to the node while no contact is available wait in node storage and may expire there.
+//! [Scheduler] keeps bundles until their `not_before` time and, when contacts with the destination node
+//! are known, until the next contact opens. Bundles whose `not_after` time passes before they could be sent are
+//! given up and reported as [Dispatch::Expired].
+//!
+//! ```rust,no_run
+//! use std::{path::Path, thread, time::{Duration, SystemTime}};
+//! use ud3tn_aap::{Agent, config::{Contact, ContactDataRate}, schedule::Scheduler};
+//!
+//! let mut agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("telemetry".to_owned()).unwrap();
+//!
+//! let mut scheduler = Scheduler::new();
+//! scheduler.set_contacts("dtn://ground.dtn/", vec![
+//!     Contact::from_during(SystemTime::now() + Duration::from_secs(3600), Duration::from_secs(600), ContactDataRate::Unlimited)
+//! ]);
+//! scheduler.schedule("dtn://ground.dtn/telemetry".into(), b"samples".to_vec(), SystemTime::now(), None);
+//!
+//! loop {
+//!     scheduler.dispatch_due(&mut agent, SystemTime::now()).unwrap();
+//!     thread::sleep(Duration::from_secs(1));
+//! }
+//! ```
+
+use std::{collections::{BTreeMap, HashMap}, time::SystemTime};
+
+use crate::{config::Contact, AapStream, BundleIdentifier, Error, RegisteredAgent};
+
+/// Identifier of a scheduled bundle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScheduleId(pub u64);
+
+/// A bundle waiting for its sending time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledBundle {
+    /// Destination endpoint ID
+    pub destination: String,
+
+    /// Content of bundle
+    pub payload: Vec<u8>,
+
+    /// Bundle isn't sent before this time
+    pub not_before: SystemTime,
+
+    /// Bundle is given up if not sent before this time
+    pub not_after: Option<SystemTime>,
+}
+
+/// Result of dispatching a scheduled bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Bundle was handed to the node
+    Sent {
+        /// Schedule identifier of bundle
+        id: ScheduleId,
+        /// Identifier given by node
+        bundle_id: BundleIdentifier,
+    },
+
+    /// Bundle couldn't be sent before its `not_after` time
+    Expired {
+        /// Schedule identifier of bundle
+        id: ScheduleId,
+        /// Bundle given up
+        bundle: ScheduledBundle,
+    },
+}
+
+/// Node part of an endpoint ID, `dtn://node/` or `ipn:node.0`
+pub fn node_id(eid: &str) -> String {
+    if let Some(rest) = eid.strip_prefix("dtn://") {
+        let node = rest.split('/').next().unwrap_or(rest);
+        return format!("dtn://{}/", node);
+    }
+    if let Some(rest) = eid.strip_prefix("ipn:") {
+        let node = rest.split('.').next().unwrap_or(rest);
+        return format!("ipn:{}.0", node);
+    }
+    eid.to_owned()
+}
+
+/// Earliest time from `not_before` at which a bundle can be sent during one of `contacts`
+///
+/// Without any contact, `not_before` is returned. [None] if no contact opens before `not_after`
+pub fn send_time(not_before: SystemTime, not_after: Option<SystemTime>, contacts: &[Contact]) -> Option<SystemTime> {
+    let earliest = if contacts.is_empty() {
+        Some(not_before)
+    } else {
+        contacts.iter()
+            .filter(|contact| contact.end > not_before)
+            .map(|contact| contact.start.max(not_before))
+            .min()
+    }?;
+
+    match not_after {
+        Some(not_after) if earliest > not_after => None,
+        _ => Some(earliest),
+    }
+}
+
+/// Scheduled bundles by identifier
+pub type ScheduledBundles = Vec<(ScheduleId, ScheduledBundle)>;
+
+/// Bundles waiting for their sending time
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    bundles: BTreeMap<ScheduleId, ScheduledBundle>,
+
+    /// Known contacts by node ID
+    contacts: HashMap<String, Vec<Contact>>,
+    next_id: u64,
+}
+
+impl Scheduler {
+    /// Empty scheduler without any known contact
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set known contacts with a node, replacing previous ones
+    ///
+    /// Bundles to any endpoint of this node are then sent only during these contacts
+    pub fn set_contacts(&mut self, node_eid: &str, contacts: Vec<Contact>) {
+        self.contacts.insert(node_id(node_eid), contacts);
+    }
+
+    /// Forget contacts with a node, its bundles are then sent at their `not_before` time
+    pub fn remove_contacts(&mut self, node_eid: &str) {
+        self.contacts.remove(&node_id(node_eid));
+    }
+
+    /// Schedule a bundle
+    ///
+    /// Times are [SystemTime] or [crate::DtnTime]
+    pub fn schedule(
+        &mut self,
+        destination_eid: String,
+        payload: Vec<u8>,
+        not_before: impl Into<SystemTime>,
+        not_after: Option<SystemTime>,
+    ) -> ScheduleId {
+        let id = ScheduleId(self.next_id);
+        self.next_id += 1;
+        self.bundles.insert(id, ScheduledBundle {
+            destination: destination_eid,
+            payload,
+            not_before: not_before.into(),
+            not_after,
+        });
+        id
+    }
+
+    /// Remove a scheduled bundle, [None] if it was already dispatched
+    pub fn cancel(&mut self, id: ScheduleId) -> Option<ScheduledBundle> {
+        self.bundles.remove(&id)
+    }
+
+    /// Scheduled bundle by identifier
+    pub fn get(&self, id: ScheduleId) -> Option<&ScheduledBundle> {
+        self.bundles.get(&id)
+    }
+
+    /// Number of scheduled bundles
+    pub fn len(&self) -> usize {
+        self.bundles.len()
+    }
+
+    /// Whether no bundle is scheduled
+    pub fn is_empty(&self) -> bool {
+        self.bundles.is_empty()
+    }
+
+    /// When a scheduled bundle will be sent if still waiting at `now`, [None] if it will expire unsent
+    pub fn send_time_of(&self, bundle: &ScheduledBundle, now: SystemTime) -> Option<SystemTime> {
+        let contacts = self.contacts.get(&node_id(&bundle.destination)).map(Vec::as_slice).unwrap_or(&[]);
+        send_time(bundle.not_before.max(now), bundle.not_after, contacts)
+    }
+
+    /// Earliest time a bundle is due after `now`, useful to sleep until then
+    pub fn next_due(&self, now: SystemTime) -> Option<SystemTime> {
+        self.bundles.values().filter_map(|bundle| self.send_time_of(bundle, now)).min()
+    }
+
+    /// Remove bundles due at `now`, and bundles which can't be sent anymore
+    ///
+    /// Returns (due bundles, expired bundles)
+    pub fn take_due(&mut self, now: SystemTime) -> (ScheduledBundles, ScheduledBundles) {
+        let mut due = Vec::new();
+        let mut expired = Vec::new();
+
+        let ids: Vec<ScheduleId> = self.bundles.keys().copied().collect();
+        for id in ids {
+            let bundle = &self.bundles[&id];
+            match self.send_time_of(bundle, now) {
+                None => expired.push((id, self.bundles.remove(&id).expect("bundle exists"))),
+                Some(time) if time <= now => due.push((id, self.bundles.remove(&id).expect("bundle exists"))),
+                Some(_) => {}
+            }
+        }
+
+        (due, expired)
+    }
+
+    /// Send bundles due at `now` through `agent`
+    ///
+    /// On error, bundles not sent yet are scheduled again
+    pub fn dispatch_due<S: AapStream>(&mut self, agent: &mut RegisteredAgent<S>, now: SystemTime) -> Result<Vec<Dispatch>, Error> {
+        let (due, expired) = self.take_due(now);
+        let mut result: Vec<Dispatch> = expired.into_iter()
+            .map(|(id, bundle)| Dispatch::Expired { id, bundle })
+            .collect();
+
+        let mut due = due.into_iter();
+        while let Some((id, bundle)) = due.next() {
+            match agent.send_bundle(bundle.destination.clone(), &bundle.payload) {
+                Ok(bundle_id) => result.push(Dispatch::Sent { id, bundle_id }),
+                Err(e) => {
+                    self.bundles.insert(id, bundle);
+                    self.bundles.extend(due);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::ContactDataRate;
+
+    use super::*;
+
+    #[test]
+    fn send_time_follows_contacts() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let minutes = |m: u64| t0 + Duration::from_secs(m * 60);
+        let contacts = vec![
+            Contact::from_during(minutes(10), Duration::from_secs(300), ContactDataRate::Unlimited),
+            Contact::from_during(minutes(60), Duration::from_secs(300), ContactDataRate::Limited(1000)),
+        ];
+
+        assert_eq!(send_time(t0, None, &[]), Some(t0));
+        assert_eq!(send_time(t0, None, &contacts), Some(minutes(10)));
+        assert_eq!(send_time(minutes(12), None, &contacts), Some(minutes(12)));
+        assert_eq!(send_time(minutes(20), None, &contacts), Some(minutes(60)));
+        assert_eq!(send_time(minutes(20), Some(minutes(30)), &contacts), None);
+        assert_eq!(send_time(minutes(70), None, &contacts), None);
+    }
+
+    #[test]
+    fn due_and_expired_bundles() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut scheduler = Scheduler::new();
+        scheduler.set_contacts("dtn://ground.dtn/", vec![
+            Contact::from_during(t0 + Duration::from_secs(600), Duration::from_secs(300), ContactDataRate::Unlimited),
+        ]);
+
+        let direct = scheduler.schedule("dtn://relay.dtn/app".into(), b"1".to_vec(), t0, None);
+        let ground = scheduler.schedule("dtn://ground.dtn/app".into(), b"2".to_vec(), t0, None);
+        let missed = scheduler.schedule("dtn://ground.dtn/app".into(), b"3".to_vec(), t0, Some(t0 + Duration::from_secs(60)));
+        let cancelled = scheduler.schedule("ipn:2.1".into(), b"4".to_vec(), t0, None);
+        assert!(scheduler.cancel(cancelled).is_some());
+        assert_eq!(scheduler.next_due(t0), Some(t0));
+
+        let (due, expired) = scheduler.take_due(t0);
+        assert_eq!(due.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![direct]);
+        assert_eq!(expired.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![missed]);
+        assert_eq!(scheduler.next_due(t0), Some(t0 + Duration::from_secs(600)));
+
+        let (due, _) = scheduler.take_due(t0 + Duration::from_secs(700));
+        assert_eq!(due.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![ground]);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn node_of_endpoint() {
+        assert_eq!(node_id("dtn://ground.dtn/telemetry/raw"), "dtn://ground.dtn/");
+        assert_eq!(node_id("dtn://ground.dtn"), "dtn://ground.dtn/");
+        assert_eq!(node_id("ipn:42.7"), "ipn:42.0");
+    }
+}
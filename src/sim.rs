@@ -0,0 +1,722 @@
+//! Discrete-event simulation of DTN nodes for testing agents without a real network
+//!
+//! A [Simulation] models nodes, the contacts between them and bundles stored and forwarded along
+//! [routing](crate::routing) routes in simulated time. [Simulation::connect] returns an in-process [SimStream]
+//! speaking AAP, so unmodified [Agent](crate::Agent) and [RegisteredAgent](crate::RegisteredAgent) code runs against
+//! simulated nodes. Config bundles sent by agents update the contacts of their node.
+//!
+//! Time only moves with [Simulation::advance] and [Simulation::run_until]. Reads on a [SimStream] block until a
+//! bundle is delivered, so receive after advancing time, or set a read timeout.
+//! Bundles are lost when their lifetime ends before they reach their destination, see [SimReport].
+//!
+//! ```rust
+//! use std::time::{Duration, SystemTime};
+//! use ud3tn_aap::{Agent, config::{Contact, ContactDataRate}, sim::Simulation};
+//!
+//! let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600);
+//! let mut sim = Simulation::new(start);
+//! sim.add_contact("dtn://a.dtn/", "dtn://b.dtn/", Contact::from_during(
+//!     start + Duration::from_secs(60), Duration::from_secs(60), ContactDataRate::Limited(1000)
+//! ));
+//!
+//! let mut sender = Agent::new(sim.connect("dtn://a.dtn/")).unwrap().register("sensor".into()).unwrap();
+//! let mut receiver = Agent::new(sim.connect("dtn://b.dtn/")).unwrap().register("sink".into()).unwrap();
+//!
+//! sender.send_bundle("dtn://b.dtn/sink".into(), b"hello").unwrap();
+//! sim.advance(Duration::from_secs(120));
+//! assert_eq!(receiver.recv_bundle().unwrap().payload, b"hello");
+//!
+//! println!("{}", sim.report());
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    fmt,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    config::{ConfigBundle, Contact},
+    duplex::Pipe,
+    message::{DtnTime, ParseError},
+    plan::{format_duration, ContactPlan},
+    routing::{ContactGraph, GraphContact, RouteQuery},
+    schedule::node_id,
+    endpoint_id, BundleIdentifier, Message,
+};
+
+/// Lifetime of bundles sent by agents unless set with [Simulation::with_lifetime]
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What happened to a bundle so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleStatus {
+    /// Being transmitted to next node
+    InTransit,
+
+    /// Waiting in node storage for a route or for the destination agent
+    Stored,
+
+    /// Handed to destination agent at a time
+    Delivered(SystemTime),
+
+    /// Lifetime ended before delivery
+    Expired(SystemTime),
+
+    /// Cancelled by sending agent
+    Cancelled,
+}
+
+/// A bundle sent by an agent during the simulation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleRecord {
+    /// Identifier returned to sending agent
+    pub id: BundleIdentifier,
+
+    /// Source endpoint ID
+    pub source: String,
+
+    /// Destination endpoint ID
+    pub destination: String,
+
+    /// Payload size in bytes
+    pub size: usize,
+
+    /// When bundle was sent
+    pub created: SystemTime,
+
+    /// When bundle expires
+    pub expiry: SystemTime,
+
+    /// Nodes reached, starting with source node
+    pub path: Vec<String>,
+
+    /// What happened to bundle
+    pub status: BundleStatus,
+}
+
+impl BundleRecord {
+    /// Time from sending to delivery, [None] if not delivered
+    pub fn latency(&self) -> Option<Duration> {
+        match self.status {
+            BundleStatus::Delivered(time) => time.duration_since(self.created).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Delivery latency and loss of the bundles sent during a simulation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimReport {
+    /// All bundles, in sending order
+    pub bundles: Vec<BundleRecord>,
+}
+
+impl SimReport {
+    fn count(&self, status: impl Fn(&BundleStatus) -> bool) -> usize {
+        self.bundles.iter().filter(|it| status(&it.status)).count()
+    }
+
+    /// Number of bundles delivered
+    pub fn delivered(&self) -> usize {
+        self.count(|it| matches!(it, BundleStatus::Delivered(_)))
+    }
+
+    /// Number of bundles lost because they expired
+    pub fn lost(&self) -> usize {
+        self.count(|it| matches!(it, BundleStatus::Expired(_)))
+    }
+
+    /// Number of bundles neither delivered, lost nor cancelled yet
+    pub fn pending(&self) -> usize {
+        self.count(|it| matches!(it, BundleStatus::InTransit | BundleStatus::Stored))
+    }
+
+    /// Latencies of delivered bundles
+    pub fn latencies(&self) -> Vec<Duration> {
+        self.bundles.iter().filter_map(BundleRecord::latency).collect()
+    }
+
+    /// Mean latency of delivered bundles
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let latencies = self.latencies();
+        let count = u32::try_from(latencies.len()).ok().filter(|it| *it > 0)?;
+        Some(latencies.iter().sum::<Duration>() / count)
+    }
+
+    /// Highest latency of delivered bundles
+    pub fn max_latency(&self) -> Option<Duration> {
+        self.latencies().into_iter().max()
+    }
+
+    /// Share of bundles lost among delivered and lost bundles
+    pub fn loss_ratio(&self) -> f64 {
+        let (delivered, lost) = (self.delivered(), self.lost());
+        if delivered + lost == 0 {
+            0.0
+        } else {
+            lost as f64 / (delivered + lost) as f64
+        }
+    }
+}
+
+impl fmt::Display for SimReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bundles: {} delivered, {} lost ({:.1}%), {} pending",
+            self.bundles.len(), self.delivered(), self.lost(), self.loss_ratio() * 100.0, self.pending()
+        )?;
+        if let (Some(mean), Some(max)) = (self.mean_latency(), self.max_latency()) {
+            write!(f, ", latency mean {} max {}", format_duration(mean), format_duration(max))?;
+        }
+        Ok(())
+    }
+}
+
+/// Contacts from a node to a peer
+#[derive(Debug, Clone, Default)]
+struct Link {
+    reaches: Vec<String>,
+    contacts: Vec<Contact>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    links: BTreeMap<String, Link>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    node: String,
+    agent_id: Option<String>,
+    inbound: Arc<Pipe>,
+}
+
+impl Endpoint {
+    fn eid(&self) -> Option<String> {
+        self.agent_id.as_ref().map(|it| endpoint_id(&self.node, it))
+    }
+
+    fn send(&self, message: Message<'_>) {
+        // Ignored when agent side is gone
+        let _ = self.inbound.push(&message.to_bytes());
+    }
+}
+
+#[derive(Debug)]
+struct SimBundle {
+    record: BundleRecord,
+    payload: Vec<u8>,
+}
+
+/// Bundle arrival at a node, ordered by time then scheduling order
+type Event = Reverse<(SystemTime, u64, usize, String)>;
+
+#[derive(Debug)]
+struct SimState {
+    now: SystemTime,
+    lifetime: Duration,
+    nodes: BTreeMap<String, Node>,
+    endpoints: Vec<Option<Endpoint>>,
+    bundles: Vec<SimBundle>,
+    events: BinaryHeap<Event>,
+    next_event: u64,
+    next_sequence: u16,
+
+    graph: Option<ContactGraph>,
+    /// Bytes booked per contact, kept when graph is rebuilt
+    booked: HashMap<(String, String, SystemTime, SystemTime), u64>,
+}
+
+impl SimState {
+    fn node(&mut self, eid: &str) -> &mut Node {
+        self.graph = None;
+        self.nodes.entry(node_id(eid)).or_default()
+    }
+
+    fn graph(&mut self) -> &mut ContactGraph {
+        let (nodes, booked) = (&self.nodes, &self.booked);
+        self.graph.get_or_insert_with(|| {
+            let contacts = nodes.iter()
+                .flat_map(|(node, it)| it.links.iter().map(move |(peer, link)| (node, peer, link)))
+                .flat_map(|(node, peer, link)| link.contacts.iter().map(move |contact| GraphContact {
+                    from: node.clone(),
+                    to: peer.clone(),
+                    start: contact.start,
+                    end: contact.end,
+                    data_rate: contact.data_rate.clone(),
+                    reaches: link.reaches.iter().map(|it| node_id(it)).collect(),
+                }))
+                .collect();
+
+            let mut graph = ContactGraph::new(contacts);
+            for index in 0..graph.contacts().len() {
+                let contact = &graph.contacts()[index];
+                if let Some(size) = booked.get(&(contact.from.clone(), contact.to.clone(), contact.start, contact.end)) {
+                    graph.book(index, *size);
+                }
+            }
+            graph
+        })
+    }
+
+    fn schedule(&mut self, time: SystemTime, bundle: usize, node: String) {
+        self.events.push(Reverse((time, self.next_event, bundle, node)));
+        self.next_event += 1;
+    }
+
+    /// Apply a config command received by a node
+    fn configure(&mut self, node: &str, config: &ConfigBundle) {
+        let (ConfigBundle::AddContact { eid: peer, .. }
+            | ConfigBundle::ReplaceContact { eid: peer, .. }
+            | ConfigBundle::DeleteContact(peer)) = config;
+        self.node(peer);
+
+        let node = self.node(node);
+        match config {
+            ConfigBundle::AddContact { eid, reaches_eid, contacts, .. } => {
+                let link = node.links.entry(node_id(eid)).or_default();
+                link.reaches.extend(reaches_eid.iter().cloned());
+                link.contacts.extend(contacts.iter().cloned());
+            }
+            ConfigBundle::ReplaceContact { eid, reaches_eid, contacts, .. } => {
+                node.links.insert(node_id(eid), Link { reaches: reaches_eid.clone(), contacts: contacts.clone() });
+            }
+            ConfigBundle::DeleteContact(eid) => {
+                node.links.remove(&node_id(eid));
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let now = self.now;
+        for bundle in &mut self.bundles {
+            if bundle.record.status == BundleStatus::Stored && bundle.record.expiry <= now {
+                bundle.record.status = BundleStatus::Expired(bundle.record.expiry);
+            }
+        }
+    }
+
+    /// Route stored bundles again after contacts changed
+    fn retry_stored(&mut self) {
+        self.expire();
+        let stored: Vec<(usize, String)> = self.bundles.iter().enumerate()
+            .filter(|(_, it)| it.record.status == BundleStatus::Stored)
+            .map(|(index, it)| (index, it.record.path.last().cloned().expect("bundle has a location")))
+            .filter(|(index, node)| *node != node_id(&self.bundles[*index].record.destination))
+            .collect();
+        for (bundle, node) in stored {
+            self.forward(bundle, &node);
+        }
+    }
+
+    fn arrive(&mut self, bundle: usize, node: String) {
+        if self.bundles[bundle].record.status != BundleStatus::InTransit {
+            return;
+        }
+        self.bundles[bundle].record.path.push(node.clone());
+        if node == node_id(&self.bundles[bundle].record.destination) {
+            self.bundles[bundle].record.status = BundleStatus::Stored;
+            self.deliver(bundle);
+        } else {
+            self.forward(bundle, &node);
+        }
+    }
+
+    fn forward(&mut self, bundle: usize, node: &str) {
+        let now = self.now;
+        let record = &self.bundles[bundle].record;
+        let query = RouteQuery::new(node, &record.destination, record.size as u64, now).with_expiry(record.expiry);
+        let destination = query.destination.clone();
+
+        let graph = self.graph();
+        let Some(route) = graph.best_route(&query) else {
+            self.bundles[bundle].record.status = BundleStatus::Stored;
+            return;
+        };
+
+        let hop = route.hops[0];
+        graph.book(hop.contact, query.size);
+        let contact = &graph.contacts()[hop.contact];
+        let key = (contact.from.clone(), contact.to.clone(), contact.start, contact.end);
+        // Peers reaching destination deliver it on arrival
+        let next = if route.hops.len() == 1 { destination } else { contact.to.clone() };
+
+        *self.booked.entry(key).or_default() += query.size;
+        self.bundles[bundle].record.status = BundleStatus::InTransit;
+        self.schedule(hop.arrival, bundle, next);
+    }
+
+    /// Hand a bundle stored at its destination node to the destination agent if registered
+    fn deliver(&mut self, bundle: usize) {
+        let record = &self.bundles[bundle].record;
+        if record.status != BundleStatus::Stored || record.expiry < self.now {
+            return;
+        }
+        let Some(endpoint) = self.endpoints.iter().flatten().find(|it| it.eid().as_ref() == Some(&record.destination)) else {
+            return;
+        };
+
+        let payload = std::borrow::Cow::Borrowed(self.bundles[bundle].payload.as_slice());
+        endpoint.send(Message::RecvBundle(record.source.clone(), payload));
+        self.bundles[bundle].record.status = BundleStatus::Delivered(self.now);
+    }
+
+    fn bundle_id(&mut self) -> BundleIdentifier {
+        let dtn_epoch = SystemTime::from(DtnTime::from(0));
+        let millis = self.now.duration_since(dtn_epoch).map(|it| it.as_millis() as u64).unwrap_or(0);
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        BundleIdentifier::from(0b11 << 62 | (millis & 0x3fff_ffff_ffff) << 16 | self.next_sequence as u64)
+    }
+
+    fn reply(&self, endpoint: usize, message: Message<'_>) {
+        if let Some(endpoint) = &self.endpoints[endpoint] {
+            endpoint.send(message);
+        }
+    }
+
+    fn handle(&mut self, endpoint: usize, message: Message<'_>) {
+        let Some(current) = &self.endpoints[endpoint] else { return };
+        let (node, source) = (current.node.clone(), current.eid());
+
+        match message {
+            Message::Ping => self.reply(endpoint, Message::Ack),
+            Message::Register(agent_id) => {
+                let taken = self.endpoints.iter().flatten()
+                    .any(|it| it.node == node && it.agent_id.as_ref() == Some(&agent_id));
+                if taken {
+                    return self.reply(endpoint, Message::Nack);
+                }
+
+                self.endpoints[endpoint].as_mut().expect("endpoint exists").agent_id = Some(agent_id);
+                self.reply(endpoint, Message::Ack);
+                for bundle in 0..self.bundles.len() {
+                    self.deliver(bundle);
+                }
+            }
+            Message::SendBundle(destination, payload) => {
+                let Some(source) = source else {
+                    return self.reply(endpoint, Message::Nack);
+                };
+                let id = self.bundle_id();
+                self.reply(endpoint, Message::SendConfirm(id));
+
+                if destination == format!("{}config", node) {
+                    let configs = std::str::from_utf8(&payload).ok().and_then(|it| ConfigBundle::parse_all(it).ok());
+                    for config in configs.unwrap_or_default() {
+                        self.configure(&node, &config);
+                    }
+                    self.retry_stored();
+                    return;
+                }
+
+                self.bundles.push(SimBundle {
+                    record: BundleRecord {
+                        id,
+                        source,
+                        destination,
+                        size: payload.len(),
+                        created: self.now,
+                        expiry: self.now + self.lifetime,
+                        path: Vec::new(),
+                        status: BundleStatus::InTransit,
+                    },
+                    payload: payload.into_owned(),
+                });
+                self.arrive(self.bundles.len() - 1, node);
+            }
+            Message::CancelBundle(id) => {
+                let bundle = self.bundles.iter_mut()
+                    .find(|it| it.record.id == id && matches!(it.record.status, BundleStatus::InTransit | BundleStatus::Stored));
+                let reply = match bundle {
+                    Some(bundle) => {
+                        bundle.record.status = BundleStatus::Cancelled;
+                        Message::Ack
+                    }
+                    None => Message::Nack,
+                };
+                self.reply(endpoint, reply);
+            }
+            _ => self.reply(endpoint, Message::Nack),
+        }
+    }
+}
+
+/// A network of simulated nodes
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Simulation {
+    /// Simulation without nodes starting at `start`
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimState {
+                now: start,
+                lifetime: DEFAULT_LIFETIME,
+                nodes: BTreeMap::new(),
+                endpoints: Vec::new(),
+                bundles: Vec::new(),
+                events: BinaryHeap::new(),
+                next_event: 0,
+                next_sequence: 0,
+                graph: None,
+                booked: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Set lifetime of bundles sent afterwards
+    pub fn with_lifetime(self, lifetime: Duration) -> Self {
+        self.lock().lifetime = lifetime;
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Add a node without contacts
+    pub fn add_node(&mut self, eid: &str) {
+        self.lock().node(eid);
+    }
+
+    /// Add a contact from a node to a peer, nodes are added if unknown
+    pub fn add_contact(&mut self, from: &str, to: &str, contact: Contact) {
+        let mut state = self.lock();
+        state.node(to);
+        state.node(from).links.entry(node_id(to)).or_default().contacts.push(contact);
+        state.retry_stored();
+    }
+
+    /// Apply a config command to a node as if an agent sent it
+    pub fn configure(&mut self, node: &str, config: &ConfigBundle) {
+        let mut state = self.lock();
+        state.configure(node, config);
+        state.retry_stored();
+    }
+
+    /// Add nodes and contacts of a plan applied at current simulated time
+    pub fn load_plan(&mut self, plan: &ContactPlan) {
+        let mut state = self.lock();
+        let now = state.now;
+        for node in &plan.nodes {
+            state.node(&node.eid);
+            for peer in &node.peers {
+                state.configure(&node.eid, &ConfigBundle::AddContact {
+                    eid: peer.eid.clone(),
+                    reliability: peer.reliability,
+                    cla_address: peer.cla.clone(),
+                    reaches_eid: peer.reaches.clone(),
+                    contacts: peer.resolvable_contacts(now),
+                });
+            }
+        }
+        state.retry_stored();
+    }
+
+    /// EIDs of nodes
+    pub fn nodes(&self) -> Vec<String> {
+        self.lock().nodes.keys().cloned().collect()
+    }
+
+    /// Current simulated time
+    pub fn now(&self) -> SystemTime {
+        self.lock().now
+    }
+
+    /// Connect an agent to a node, node is added if unknown
+    ///
+    /// The node sends [Message::Welcome] right away, so the stream can be given to [Agent::new](crate::Agent::new)
+    pub fn connect(&self, node: &str) -> SimStream {
+        let mut state = self.lock();
+        state.node(node);
+        let endpoint = Endpoint { node: node_id(node), agent_id: None, inbound: Arc::new(Pipe::default()) };
+        endpoint.send(Message::Welcome(endpoint.node.clone()));
+
+        let inbound = endpoint.inbound.clone();
+        state.endpoints.push(Some(endpoint));
+        SimStream {
+            state: self.state.clone(),
+            endpoint: state.endpoints.len() - 1,
+            inbound,
+            write_buffer: Vec::new(),
+            read_timeout: None,
+        }
+    }
+
+    /// Process bundle arrivals until `time`, then expire stored bundles
+    pub fn run_until(&mut self, time: SystemTime) {
+        let mut state = self.lock();
+        while let Some(Reverse((event_time, _, bundle, node))) = state.events.peek().cloned() {
+            if event_time > time {
+                break;
+            }
+            state.events.pop();
+            state.now = state.now.max(event_time);
+            state.arrive(bundle, node);
+        }
+        state.now = state.now.max(time);
+        state.expire();
+    }
+
+    /// Move simulated time forward
+    pub fn advance(&mut self, duration: Duration) {
+        let time = self.now() + duration;
+        self.run_until(time);
+    }
+
+    /// Run until every bundle is delivered, lost or waiting for its destination agent
+    pub fn run_to_completion(&mut self) {
+        let last = {
+            let state = self.lock();
+            let last_event = state.events.iter().map(|Reverse((time, ..))| *time).max();
+            let last_expiry = state.bundles.iter()
+                .filter(|it| matches!(it.record.status, BundleStatus::InTransit | BundleStatus::Stored))
+                .map(|it| it.record.expiry)
+                .max();
+            last_event.max(last_expiry)
+        };
+        if let Some(last) = last {
+            self.run_until(last);
+        }
+    }
+
+    /// Report of all bundles sent so far
+    pub fn report(&self) -> SimReport {
+        SimReport { bundles: self.lock().bundles.iter().map(|it| it.record.clone()).collect() }
+    }
+}
+
+/// In-process AAP connection to a simulated node
+#[derive(Debug)]
+pub struct SimStream {
+    state: Arc<Mutex<SimState>>,
+    endpoint: usize,
+    inbound: Arc<Pipe>,
+    write_buffer: Vec<u8>,
+    read_timeout: Option<Duration>,
+}
+
+impl SimStream {
+    /// Fail reads with [io::ErrorKind::TimedOut] when nothing is received within `timeout`, real time
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.read(buf, self.read_timeout)
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buffer.extend_from_slice(buf);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while !self.write_buffer.is_empty() {
+            match Message::parse_buffer(&self.write_buffer) {
+                Ok((message, consumed)) => {
+                    state.handle(self.endpoint, message);
+                    self.write_buffer.drain(..consumed);
+                }
+                Err(ParseError::UnexpectedEnd) => break,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        self.inbound.close();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.endpoints[self.endpoint] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::{ClaAddress, ContactDataRate, HostPort}, Agent};
+
+    use super::*;
+
+    fn start() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1714557600)
+    }
+
+    fn contact(from: u64, duration: u64, rate: i32) -> Contact {
+        Contact::from_during(start() + Duration::from_secs(from), Duration::from_secs(duration), ContactDataRate::Limited(rate))
+    }
+
+    #[test]
+    fn bundles_stored_and_forwarded() {
+        let mut sim = Simulation::new(start());
+        sim.add_contact("dtn://a.dtn/", "dtn://b.dtn/", contact(10, 100, 100));
+        sim.add_contact("dtn://b.dtn/", "dtn://c.dtn/", contact(300, 100, 100));
+
+        let mut sender = Agent::new(sim.connect("dtn://a.dtn/")).unwrap().register("sensor".into()).unwrap();
+        let mut stream = sim.connect("dtn://c.dtn/");
+        stream.set_read_timeout(Some(Duration::from_millis(10)));
+        let mut receiver = Agent::new(stream).unwrap().register("sink".into()).unwrap();
+        assert!(Agent::new(sim.connect("dtn://c.dtn/")).unwrap().register("sink".into()).is_err());
+
+        let first = sender.send_bundle("dtn://c.dtn/sink".into(), &[1; 1000]).unwrap();
+        let second = sender.send_bundle("dtn://c.dtn/sink".into(), &[2; 1000]).unwrap();
+        assert_eq!(first.sequence_number().map(|it| it + 1), second.sequence_number());
+
+        sim.advance(Duration::from_secs(200));
+        assert!(receiver.recv_bundle().is_err());
+
+        sim.advance(Duration::from_secs(200));
+        let bundle = receiver.recv_bundle().unwrap();
+        assert_eq!(bundle.source.as_deref(), Some("dtn://a.dtn/sensor"));
+        assert_eq!(bundle.payload, vec![1; 1000]);
+        assert_eq!(receiver.recv_bundle().unwrap().payload, vec![2; 1000]);
+
+        let report = sim.report();
+        assert_eq!(report.delivered(), 2);
+        assert_eq!(report.bundles[0].path, vec!["dtn://a.dtn/", "dtn://b.dtn/", "dtn://c.dtn/"]);
+        // Second bundle waits for the first on both contacts
+        assert_eq!(report.latencies(), vec![Duration::from_secs(310), Duration::from_secs(320)]);
+    }
+
+    #[test]
+    fn bundles_lost_or_routed_after_config() {
+        let mut sim = Simulation::new(start()).with_lifetime(Duration::from_secs(600));
+        let mut planner = Agent::new(sim.connect("dtn://a.dtn/")).unwrap().register("planner".into()).unwrap();
+        let mut receiver = Agent::new(sim.connect("dtn://b.dtn/")).unwrap().register("sink".into()).unwrap();
+
+        planner.send_bundle("dtn://b.dtn/sink".into(), b"stored").unwrap();
+        planner.send_bundle("dtn://z.dtn/sink".into(), b"lost").unwrap();
+        sim.advance(Duration::from_secs(60));
+        assert_eq!(sim.report().pending(), 2);
+
+        planner.send_config(ConfigBundle::AddContact {
+            eid: "dtn://b.dtn/".into(),
+            reliability: None,
+            cla_address: ClaAddress::Mtcp(HostPort::new("10.0.0.2", 4224)),
+            reaches_eid: Vec::new(),
+            contacts: vec![contact(120, 60, 100)],
+        }).unwrap();
+
+        sim.run_to_completion();
+        assert_eq!(receiver.recv_bundle().unwrap().payload, b"stored");
+
+        let report = sim.report();
+        assert_eq!((report.delivered(), report.lost(), report.pending()), (1, 1, 0));
+        assert_eq!(report.bundles[1].status, BundleStatus::Expired(start() + Duration::from_secs(600)));
+        assert_eq!(report.to_string(), "2 bundles: 1 delivered, 1 lost (50.0%), 0 pending, latency mean 2m max 2m");
+    }
+}
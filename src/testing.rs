@@ -0,0 +1,372 @@
+//! Mock ud3tn node to unit-test code built on [RegisteredAgent](crate::RegisteredAgent)
+//!
+//! [MockNode] speaks AAP over an in-memory [DuplexStream] or a [UnixStream] pair, each connection being served by
+//! its own thread. It sends [Message::Welcome], acknowledges registrations, confirms sent bundles with
+//! incrementing [BundleIdentifier]s, loops bundles back to agents registered on it and records config bundles.
+//! Replies are queued per connection, so an agent that doesn't read never blocks the others.
+//! [MockNode::inject] makes it answer the next requests with a [Fault].
+//!
+//! ```rust
+//! use ud3tn_aap::{Agent, BaseAgent, config::ConfigBundle, testing::{Fault, MockNode}};
+//!
+//! let node = MockNode::new("dtn://mock.dtn/");
+//! let mut agent = Agent::new(node.connect()).unwrap().register("app".into()).unwrap();
+//! let mut peer = Agent::new(node.connect()).unwrap().register("peer".into()).unwrap();
+//!
+//! agent.send_bundle("dtn://mock.dtn/peer".into(), b"hello").unwrap();
+//! assert_eq!(peer.recv_bundle().unwrap().payload, b"hello");
+//!
+//! agent.send_config(ConfigBundle::DeleteContact("dtn://other.dtn/".into())).unwrap();
+//! assert_eq!(node.config_bundles(), vec![ConfigBundle::DeleteContact("dtn://other.dtn/".into())]);
+//!
+//! node.inject(Fault::Nack);
+//! assert!(agent.ping().is_err());
+//! ```
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    net::Shutdown,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+use crate::{
+    config::ConfigBundle,
+    duplex::Pipe,
+    message::ParseError,
+    endpoint_id, BundleIdentifier, Message,
+};
+
+pub use crate::duplex::DuplexStream;
+
+/// Misbehavior of the node for one request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Answer with [Message::Nack] without processing request
+    Nack,
+
+    /// Answer with a message instead of the expected one, without processing request
+    Reply(Message<'static>),
+
+    /// Answer with raw bytes, e.g. a malformed message, without processing request
+    Garbage(Vec<u8>),
+
+    /// Close connection without answering
+    Disconnect,
+}
+
+/// A bundle an agent sent to the node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentBundle {
+    /// Identifier returned to agent
+    pub id: BundleIdentifier,
+
+    /// Endpoint ID of sending agent
+    pub source: String,
+
+    /// Destination endpoint ID
+    pub destination: String,
+
+    /// Bundle content
+    pub payload: Vec<u8>,
+}
+
+/// A config bundle the node couldn't parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigFailure {
+    /// Bundle content
+    pub payload: Vec<u8>,
+
+    /// Why parsing failed
+    pub error: String,
+}
+
+#[derive(Debug)]
+struct Connection {
+    agent_id: Option<String>,
+    outbound: Arc<Pipe>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    node_eid: String,
+    connections: Vec<Option<Connection>>,
+    next_sequence: u64,
+    sent: Vec<SentBundle>,
+    configs: Vec<ConfigBundle>,
+    config_failures: Vec<ConfigFailure>,
+    faults: VecDeque<Fault>,
+}
+
+impl MockState {
+    fn send(&mut self, connection: usize, bytes: &[u8]) {
+        if let Some(connection) = &self.connections[connection] {
+            // Ignored when agent side is gone
+            let _ = connection.outbound.push(bytes);
+        }
+    }
+
+    fn deliver(&mut self, destination: &str, source: &str, payload: &[u8]) -> bool {
+        let node_eid = &self.node_eid;
+        let target = self.connections.iter()
+            .position(|it| it.as_ref().and_then(|it| it.agent_id.as_deref()).map(|it| endpoint_id(node_eid, it)).as_deref() == Some(destination));
+        match target {
+            Some(target) => {
+                self.send(target, &Message::RecvBundle(source.to_owned(), Cow::Borrowed(payload)).to_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Process a request, returns false when connection must be closed
+    fn handle(&mut self, connection: usize, message: Message<'_>) -> bool {
+        match self.faults.pop_front() {
+            Some(Fault::Nack) => self.send(connection, &Message::Nack.to_bytes()),
+            Some(Fault::Reply(reply)) => self.send(connection, &reply.to_bytes()),
+            Some(Fault::Garbage(bytes)) => self.send(connection, &bytes),
+            Some(Fault::Disconnect) => return false,
+            None => {
+                let reply = self.process(connection, message);
+                self.send(connection, &reply.to_bytes());
+            }
+        }
+        true
+    }
+
+    fn process(&mut self, connection: usize, message: Message<'_>) -> Message<'static> {
+        let agent_id = self.connections[connection].as_ref().and_then(|it| it.agent_id.clone());
+        match message {
+            Message::Ping => Message::Ack,
+            Message::Register(agent_id) => {
+                let taken = self.connections.iter().flatten().any(|it| it.agent_id.as_ref() == Some(&agent_id));
+                if taken {
+                    return Message::Nack;
+                }
+                if let Some(connection) = &mut self.connections[connection] {
+                    connection.agent_id = Some(agent_id);
+                }
+                Message::Ack
+            }
+            Message::SendBundle(destination, payload) => {
+                let Some(agent_id) = agent_id else { return Message::Nack };
+                let source = endpoint_id(&self.node_eid, &agent_id);
+
+                self.next_sequence += 1;
+                let id = BundleIdentifier::from(1 << 63 | self.next_sequence);
+
+                if destination == format!("{}config", self.node_eid) {
+                    let configs = std::str::from_utf8(&payload)
+                        .map_err(|e| e.to_string())
+                        .and_then(|it| ConfigBundle::parse_all(it).map_err(|e| e.to_string()));
+                    match configs {
+                        Ok(configs) => self.configs.extend(configs),
+                        Err(error) => self.config_failures.push(ConfigFailure { payload: payload.to_vec(), error }),
+                    }
+                } else {
+                    self.deliver(&destination, &source, &payload);
+                }
+
+                self.sent.push(SentBundle { id, source, destination, payload: payload.into_owned() });
+                Message::SendConfirm(id)
+            }
+            Message::CancelBundle(_) => Message::Ack,
+            _ => Message::Nack,
+        }
+    }
+}
+
+/// A fake node accepting any number of agent connections
+#[derive(Debug, Clone)]
+pub struct MockNode {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockNode {
+    /// Node with an EID, such as `dtn://mock.dtn/`
+    pub fn new(node_eid: &str) -> Self {
+        Self { state: Arc::new(Mutex::new(MockState { node_eid: node_eid.to_owned(), ..Default::default() })) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// EID of node
+    pub fn node_eid(&self) -> String {
+        self.lock().node_eid.clone()
+    }
+
+    /// Connect through an in-memory stream
+    pub fn connect(&self) -> DuplexStream {
+        let (client, server) = DuplexStream::pair();
+        let outbound = server.outbound();
+        self.serve(server, outbound);
+        client
+    }
+
+    /// Connect through a unix socket pair
+    pub fn connect_unix(&self) -> io::Result<UnixStream> {
+        let (client, server) = UnixStream::pair()?;
+        let mut writer = server.try_clone()?;
+        let outbound = Arc::new(Pipe::default());
+        let queued = outbound.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 64 * 1024];
+            while let Ok(count @ 1..) = queued.read(&mut buffer, None) {
+                if writer.write_all(&buffer[..count]).is_err() {
+                    queued.close();
+                    break;
+                }
+            }
+            let _ = writer.shutdown(Shutdown::Both);
+        });
+        self.serve(server, outbound);
+        Ok(client)
+    }
+
+    /// Serve requests read from `reader`, replying through `outbound` which is closed when connection ends
+    fn serve<R: Read + Send + 'static>(&self, mut reader: R, outbound: Arc<Pipe>) {
+        let connection = {
+            let mut state = self.lock();
+            state.connections.push(Some(Connection { agent_id: None, outbound: outbound.clone() }));
+            let connection = state.connections.len() - 1;
+            let welcome = Message::Welcome(state.node_eid.clone()).to_bytes();
+            state.send(connection, &welcome);
+            connection
+        };
+
+        let state = self.state.clone();
+        thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            'connection: while let Ok(count @ 1..) = reader.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..count]);
+                while !received.is_empty() {
+                    let (message, consumed) = match Message::parse_buffer(&received) {
+                        Ok(it) => it,
+                        Err(ParseError::UnexpectedEnd) => break,
+                        Err(_) => break 'connection,
+                    };
+                    let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                    if !state.handle(connection, message) {
+                        break 'connection;
+                    }
+                    received.drain(..consumed);
+                }
+            }
+
+            state.lock().unwrap_or_else(PoisonError::into_inner).connections[connection] = None;
+            outbound.close();
+        });
+    }
+
+    /// Answer the next request of any connection with a fault, faults are used in injection order
+    pub fn inject(&self, fault: Fault) {
+        self.lock().faults.push_back(fault);
+    }
+
+    /// Send a bundle to a registered agent, false if no agent is registered with `destination` endpoint ID
+    pub fn deliver(&self, destination: &str, source: &str, payload: &[u8]) -> bool {
+        self.lock().deliver(destination, source, payload)
+    }
+
+    /// Agent IDs currently registered
+    pub fn registered_agents(&self) -> Vec<String> {
+        self.lock().connections.iter().flatten().filter_map(|it| it.agent_id.clone()).collect()
+    }
+
+    /// Bundles sent by agents, including config bundles
+    pub fn sent_bundles(&self) -> Vec<SentBundle> {
+        self.lock().sent.clone()
+    }
+
+    /// Config commands sent by agents, in order
+    pub fn config_bundles(&self) -> Vec<ConfigBundle> {
+        self.lock().configs.clone()
+    }
+
+    /// Config bundles that couldn't be parsed, in order, none of their commands is in [MockNode::config_bundles]
+    pub fn config_failures(&self) -> Vec<ConfigFailure> {
+        self.lock().config_failures.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Agent, BaseAgent, Error};
+
+    use super::*;
+
+    #[test]
+    fn bundles_looped_back() {
+        let node = MockNode::new("ipn:7.0");
+        let mut agent = Agent::new(node.connect_unix().unwrap()).unwrap().register("1".into()).unwrap();
+        let mut peer = Agent::new(node.connect()).unwrap().register("2".into()).unwrap();
+        assert_eq!(agent.node_id(), "ipn:7.0");
+        assert!(Agent::new(node.connect()).unwrap().register("2".into()).is_err());
+
+        let first = agent.send_bundle("ipn:7.2".into(), b"first").unwrap();
+        let second = peer.send_bundle("ipn:7.1".into(), b"second").unwrap();
+        assert_eq!(first.sequence_number().map(|it| it + 1), second.sequence_number());
+
+        assert_eq!(peer.recv_bundle().unwrap().payload, b"first");
+        let received = agent.recv_bundle().unwrap();
+        assert_eq!((received.source.as_deref(), received.payload.as_slice()), (Some("ipn:7.2"), &b"second"[..]));
+
+        assert!(node.deliver("ipn:7.1", "ipn:9.1", b"injected"));
+        assert!(!node.deliver("ipn:7.3", "ipn:9.1", b"nobody"));
+        assert_eq!(agent.recv_bundle().unwrap().payload, b"injected");
+
+        assert_eq!(node.registered_agents(), vec!["1".to_owned(), "2".to_owned()]);
+        assert_eq!(node.sent_bundles().len(), 2);
+    }
+
+    #[test]
+    fn faults_injected() {
+        let node = MockNode::new("dtn://mock.dtn/");
+        let mut agent = Agent::new(node.connect()).unwrap().register("app".into()).unwrap();
+
+        node.inject(Fault::Nack);
+        node.inject(Fault::Reply(Message::Ack));
+        node.inject(Fault::Disconnect);
+
+        assert!(matches!(agent.send_bundle("dtn://other.dtn/app".into(), b"x"), Err(Error::UnexpectedMessage)));
+        assert!(matches!(agent.send_bundle("dtn://other.dtn/app".into(), b"x"), Err(Error::UnexpectedMessage)));
+        assert!(matches!(agent.ping(), Err(Error::UnexpectedEnd)));
+        assert!(node.sent_bundles().is_empty());
+
+        let mut agent = Agent::new(node.connect()).unwrap();
+        node.inject(Fault::Garbage(vec![0xff]));
+        assert!(matches!(agent.ping(), Err(Error::MalformedMessage(_))));
+    }
+
+    #[test]
+    fn large_bundles_over_unix() {
+        let node = MockNode::new("dtn://mock.dtn/");
+        let mut agent = Agent::new(node.connect_unix().unwrap()).unwrap().register("app".into()).unwrap();
+        let mut peer = Agent::new(node.connect_unix().unwrap()).unwrap().register("peer".into()).unwrap();
+
+        let payload = vec![7; 4 * 1024 * 1024];
+        agent.send_bundle("dtn://mock.dtn/peer".into(), &payload).unwrap();
+        agent.ping().unwrap();
+        assert_eq!(peer.recv_bundle().unwrap().payload, payload);
+    }
+
+    #[test]
+    fn malformed_configs_recorded() {
+        let node = MockNode::new("dtn://mock.dtn/");
+        let mut agent = Agent::new(node.connect()).unwrap().register("app".into()).unwrap();
+
+        agent.send_bundle("dtn://mock.dtn/config".into(), b"9(nonsense);").unwrap();
+        agent.send_bundle("dtn://mock.dtn/config".into(), &[0xff]).unwrap();
+        assert!(node.config_bundles().is_empty());
+
+        let failures = node.config_failures();
+        assert_eq!(failures.iter().map(|it| it.payload.as_slice()).collect::<Vec<_>>(), vec![&b"9(nonsense);"[..], &[0xff]]);
+        assert!(failures.iter().all(|it| !it.error.is_empty()));
+    }
+}
@@ -0,0 +1,967 @@
+//! Reliable file transfer with resume
+//!
+//! The sender announces a file with a [Packet::Manifest] (name, size, SHA-256 hash) then sends it in chunks.
+//! The receiver writes chunks in a temporary file, asks for missing chunks with [Packet::Request],
+//! verifies the checksum and moves the file in its output directory before confirming with [Packet::Complete].
+//! Existing files are never overwritten, a number is appended to the name of received files instead.
+//!
+//! Both sides persist their state in a state directory, so transfers resume across process and node restarts:
+//! [TransferAgent::resume] announces unfinished outgoing transfers again and asks for missing incoming chunks.
+//!
+//! ```rust,no_run
+//! use std::path::Path;
+//! use ud3tn_aap::{Agent, transfer::TransferAgent};
+//!
+//! let agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("files".to_owned()).unwrap();
+//! let mut agent = TransferAgent::new(agent, Path::new("/var/lib/files/state"), Path::new("/var/lib/files/inbox")).unwrap();
+//! agent.on_progress(|progress| println!("{}: {}/{}", progress.name, progress.done_bytes, progress.total_bytes));
+//!
+//! agent.resume().unwrap();
+//! agent.send_file("dtn://bob.dtn/files".into(), Path::new("report.pdf"), 64 * 1024).unwrap();
+//! loop {
+//!     agent.process_next().unwrap();
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{message::ParseError, wire, AapStream, RegisteredAgent};
+
+/// First byte of every file transfer bundle
+const MAGIC: u8 = 0xD7;
+
+const KIND_MANIFEST: u8 = 0;
+const KIND_CHUNK: u8 = 1;
+const KIND_REQUEST: u8 = 2;
+const KIND_COMPLETE: u8 = 3;
+
+/// Maximum number of chunks kept in memory while their manifest hasn't arrived
+const MAX_ORPHAN_CHUNKS: usize = 1024;
+
+/// Default maximum size of a received file
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Maximum number of chunks of a transferred file, larger files need larger chunks
+pub const MAX_CHUNKS: u32 = 1 << 20;
+
+/// Description of a transferred file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Identifier chosen by sender
+    pub transfer_id: u64,
+
+    /// File name, without directories
+    pub name: String,
+
+    /// File size in bytes
+    pub size: u64,
+
+    /// Size of every chunk but the last one
+    pub chunk_size: u32,
+
+    /// SHA-256 hash of file content
+    pub sha256: [u8; 32],
+
+    /// EID of the sender, receiving requests and confirmation
+    pub reply_to: String,
+}
+
+impl Manifest {
+    /// Number of chunks of the file
+    ///
+    /// Saturates at [u32::MAX], manifests with more than [MAX_CHUNKS] chunks are refused,
+    /// see [Manifest::checked_chunk_count]
+    pub fn chunk_count(&self) -> u32 {
+        u32::try_from(self.size.div_ceil(self.chunk_size.max(1) as u64)).unwrap_or(u32::MAX)
+    }
+
+    /// Number of chunks of the file, [None] if there are more than [MAX_CHUNKS]
+    pub fn checked_chunk_count(&self) -> Option<u32> {
+        Some(self.chunk_count()).filter(|it| *it <= MAX_CHUNKS)
+    }
+
+    /// Size of a chunk
+    pub fn chunk_length(&self, index: u32) -> usize {
+        let start = index as u64 * self.chunk_size as u64;
+        self.size.saturating_sub(start).min(self.chunk_size as u64) as usize
+    }
+}
+
+/// Payload of a file transfer bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// File announcement
+    Manifest(Manifest),
+
+    /// Part of a file
+    Chunk {
+        /// Identifier of transfer
+        transfer_id: u64,
+        /// Position of chunk in file, starting at 0
+        index: u32,
+        /// Chunk content
+        data: Vec<u8>,
+    },
+
+    /// Request of missing chunks sent by receiver
+    Request {
+        /// Identifier of transfer
+        transfer_id: u64,
+        /// Missing chunks as ranges of (first index, last index excluded)
+        missing: Vec<(u32, u32)>,
+    },
+
+    /// Confirmation of a verified file sent by receiver
+    Complete {
+        /// Identifier of transfer
+        transfer_id: u64,
+    },
+}
+
+impl Packet {
+    /// Serialize this packet to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![MAGIC];
+        match self {
+            Packet::Manifest(manifest) => {
+                result.push(KIND_MANIFEST);
+                wire::put_u64(&mut result, manifest.transfer_id);
+                wire::put_string(&mut result, &manifest.name);
+                wire::put_u64(&mut result, manifest.size);
+                wire::put_u32(&mut result, manifest.chunk_size);
+                result.extend_from_slice(&manifest.sha256);
+                wire::put_string(&mut result, &manifest.reply_to);
+            }
+            Packet::Chunk { transfer_id, index, data } => {
+                result.push(KIND_CHUNK);
+                wire::put_u64(&mut result, *transfer_id);
+                wire::put_u32(&mut result, *index);
+                result.extend_from_slice(data);
+            }
+            Packet::Request { transfer_id, missing } => {
+                result.push(KIND_REQUEST);
+                wire::put_u64(&mut result, *transfer_id);
+                wire::put_u32(&mut result, missing.len() as u32);
+                for (start, end) in missing {
+                    wire::put_u32(&mut result, *start);
+                    wire::put_u32(&mut result, *end);
+                }
+            }
+            Packet::Complete { transfer_id } => {
+                result.push(KIND_COMPLETE);
+                wire::put_u64(&mut result, *transfer_id);
+            }
+        }
+        result
+    }
+
+    /// Parse a packet from a bundle payload
+    pub fn parse(bytes: &[u8]) -> Result<Self, TransferError> {
+        let mut reader = wire::Reader::new(bytes);
+        if reader.u8()? != MAGIC {
+            return Err(TransferError::NotATransferMessage);
+        }
+
+        match reader.u8()? {
+            KIND_MANIFEST => Ok(Packet::Manifest(Manifest {
+                transfer_id: reader.u64()?,
+                name: reader.string()?,
+                size: reader.u64()?,
+                chunk_size: reader.u32()?,
+                sha256: reader.take(32)?.try_into().map_err(ParseError::from)?,
+                reply_to: reader.string()?,
+            })),
+            KIND_CHUNK => Ok(Packet::Chunk {
+                transfer_id: reader.u64()?,
+                index: reader.u32()?,
+                data: reader.rest().to_vec(),
+            }),
+            KIND_REQUEST => {
+                let transfer_id = reader.u64()?;
+                let count = reader.u32()?;
+                let mut missing = Vec::new();
+                for _ in 0..count {
+                    missing.push((reader.u32()?, reader.u32()?));
+                }
+                Ok(Packet::Request { transfer_id, missing })
+            }
+            KIND_COMPLETE => Ok(Packet::Complete { transfer_id: reader.u64()? }),
+            _ => Err(TransferError::NotATransferMessage),
+        }
+    }
+}
+
+/// Direction of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// File sent by this agent
+    Sending,
+
+    /// File received by this agent
+    Receiving,
+}
+
+/// Progress of a transfer, reported to the progress callback
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Identifier of transfer
+    pub transfer_id: u64,
+
+    /// Direction of transfer
+    pub direction: Direction,
+
+    /// Name of transferred file
+    pub name: String,
+
+    /// Bytes sent (including retransmissions) or received
+    pub done_bytes: u64,
+
+    /// File size in bytes
+    pub total_bytes: u64,
+}
+
+/// Callback called each time a transfer progresses
+pub type ProgressCallback = Box<dyn FnMut(&Progress) + Send>;
+
+/// A notable change of a transfer returned by [TransferAgent::process_next]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    /// A new incoming transfer was announced
+    Announced(Manifest),
+
+    /// An incoming file was verified and moved to its final path
+    Received {
+        /// Identifier of transfer
+        transfer_id: u64,
+        /// Path of received file
+        path: PathBuf,
+    },
+
+    /// An incoming file doesn't match its checksum and will be received again
+    ChecksumMismatch {
+        /// Identifier of transfer
+        transfer_id: u64,
+    },
+
+    /// Receiver confirmed an outgoing file
+    Delivered {
+        /// Identifier of transfer
+        transfer_id: u64,
+    },
+}
+
+/// An outgoing transfer waiting for confirmation
+#[derive(Debug, Clone)]
+struct Outgoing {
+    manifest: Manifest,
+    destination: String,
+    path: PathBuf,
+    sent_bytes: u64,
+}
+
+/// Received chunks of a transfer, one bit per chunk as stored in its `chunks` state file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChunkSet {
+    bits: Vec<u8>,
+    count: u32,
+    received: u32,
+}
+
+impl ChunkSet {
+    /// No chunk received out of `count`
+    fn new(count: u32) -> Self {
+        Self { bits: vec![0; count.div_ceil(8) as usize], count, received: 0 }
+    }
+
+    /// Chunks recorded in a state file, bits past `count` are ignored
+    fn from_bytes(mut bits: Vec<u8>, count: u32) -> Self {
+        bits.resize(count.div_ceil(8) as usize, 0);
+        if !count.is_multiple_of(8) {
+            if let Some(last) = bits.last_mut() {
+                *last &= (1 << (count % 8)) - 1;
+            }
+        }
+        let received = bits.iter().map(|it| it.count_ones()).sum();
+        Self { bits, count, received }
+    }
+
+    fn contains(&self, index: u32) -> bool {
+        self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    /// Mark a chunk received, returns position and new value of its byte in state file
+    fn insert(&mut self, index: u32) -> (u64, u8) {
+        if !self.contains(index) {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+            self.received += 1;
+        }
+        ((index / 8) as u64, self.bits[(index / 8) as usize])
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.count
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+/// An incoming transfer being received
+#[derive(Debug)]
+struct Incoming {
+    manifest: Manifest,
+    received: ChunkSet,
+    received_bytes: u64,
+}
+
+impl Incoming {
+    fn missing(&self) -> Vec<(u32, u32)> {
+        let mut result: Vec<(u32, u32)> = Vec::new();
+        for index in 0..self.received.count {
+            if self.received.contains(index) {
+                continue;
+            }
+            match result.last_mut() {
+                Some((_, end)) if *end == index => *end += 1,
+                _ => result.push((index, index + 1)),
+            }
+        }
+        result
+    }
+}
+
+/// A registered agent sending and receiving files
+pub struct TransferAgent<S: AapStream> {
+    agent: RegisteredAgent<S>,
+    state_dir: PathBuf,
+    output_dir: PathBuf,
+    outgoing: HashMap<u64, Outgoing>,
+    incoming: HashMap<u64, Incoming>,
+    orphans: Vec<(u64, u32, Vec<u8>)>,
+    progress: Option<ProgressCallback>,
+    max_file_size: u64,
+}
+
+impl<S: AapStream> TransferAgent<S> {
+    /// Wrap a registered agent
+    ///
+    /// Transfer state is kept in `state_dir`, received files are written in `output_dir`.
+    /// Unfinished transfers found in `state_dir` are loaded.
+    pub fn new(agent: RegisteredAgent<S>, state_dir: &Path, output_dir: &Path) -> Result<Self, TransferError> {
+        fs::create_dir_all(state_dir.join("outgoing"))?;
+        fs::create_dir_all(state_dir.join("incoming"))?;
+        fs::create_dir_all(output_dir)?;
+
+        let mut new_self = Self {
+            agent,
+            state_dir: state_dir.to_owned(),
+            output_dir: output_dir.to_owned(),
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            orphans: Vec::new(),
+            progress: None,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        };
+        new_self.load_state()?;
+        Ok(new_self)
+    }
+
+    /// Refuse incoming files bigger than `max_file_size` bytes instead of [DEFAULT_MAX_FILE_SIZE]
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Underlying registered agent
+    pub fn agent(&mut self) -> &mut RegisteredAgent<S> {
+        &mut self.agent
+    }
+
+    /// Call `callback` each time a transfer progresses
+    pub fn on_progress<F: FnMut(&Progress) + Send + 'static>(&mut self, callback: F) {
+        self.progress = Some(Box::new(callback));
+    }
+
+    /// Identifiers of outgoing transfers not yet confirmed by their receiver
+    pub fn outgoing_transfers(&self) -> Vec<u64> {
+        self.outgoing.keys().cloned().collect()
+    }
+
+    /// Identifiers of incoming transfers not yet completed
+    pub fn incoming_transfers(&self) -> Vec<u64> {
+        self.incoming.keys().cloned().collect()
+    }
+
+    /// Start sending a file in chunks of `chunk_size` bytes
+    ///
+    /// Returns transfer identifier
+    pub fn send_file(&mut self, destination_eid: String, path: &Path, chunk_size: u32) -> Result<u64, TransferError> {
+        let path = path.canonicalize()?;
+        let name = path.file_name()
+            .map(|it| it.to_string_lossy().into_owned())
+            .ok_or(TransferError::InvalidName)?;
+
+        let mut file = File::open(&path)?;
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut file, &mut hasher)?;
+
+        let manifest = Manifest {
+            transfer_id: wire::new_message_id(),
+            name,
+            size,
+            chunk_size: chunk_size.max(1),
+            sha256: hasher.finalize().into(),
+            reply_to: self.agent.endpoint_id(),
+        };
+        if manifest.checked_chunk_count().is_none() {
+            return Err(TransferError::TooManyChunks);
+        }
+        let transfer = Outgoing { manifest: manifest.clone(), destination: destination_eid, path, sent_bytes: 0 };
+        self.save_outgoing(&transfer)?;
+        self.outgoing.insert(manifest.transfer_id, transfer);
+
+        self.send_manifest(manifest.transfer_id)?;
+        self.send_chunks(manifest.transfer_id, &[(0, manifest.chunk_count())])?;
+        Ok(manifest.transfer_id)
+    }
+
+    /// Resume transfers after a restart
+    ///
+    /// Manifests of unconfirmed outgoing transfers are sent again so receivers ask for their missing chunks,
+    /// missing chunks of incoming transfers are requested to their sender.
+    pub fn resume(&mut self) -> Result<(), TransferError> {
+        let outgoing: Vec<u64> = self.outgoing.keys().cloned().collect();
+        for transfer_id in outgoing {
+            self.send_manifest(transfer_id)?;
+        }
+
+        let incoming: Vec<u64> = self.incoming.keys().cloned().collect();
+        for transfer_id in incoming {
+            self.request_missing(transfer_id)?;
+        }
+        Ok(())
+    }
+
+    /// Block until a file transfer bundle is received and process it
+    pub fn process_next(&mut self) -> Result<Option<TransferEvent>, TransferError> {
+        let bundle = self.agent.recv_bundle()?;
+        match Packet::parse(&bundle.payload)? {
+            Packet::Manifest(manifest) => self.handle_manifest(manifest),
+            Packet::Chunk { transfer_id, index, data } => self.handle_chunk(transfer_id, index, data),
+            Packet::Request { transfer_id, missing } => {
+                if self.outgoing.contains_key(&transfer_id) {
+                    self.send_chunks(transfer_id, &missing)?;
+                }
+                Ok(None)
+            }
+            Packet::Complete { transfer_id } => {
+                if self.outgoing.remove(&transfer_id).is_none() {
+                    return Ok(None);
+                }
+                remove_if_exists(&self.outgoing_state_path(transfer_id))?;
+                Ok(Some(TransferEvent::Delivered { transfer_id }))
+            }
+        }
+    }
+
+    fn send_manifest(&mut self, transfer_id: u64) -> Result<(), TransferError> {
+        let transfer = &self.outgoing[&transfer_id];
+        let packet = Packet::Manifest(transfer.manifest.clone());
+        self.agent.send_bundle(transfer.destination.clone(), &packet.to_bytes())?;
+        Ok(())
+    }
+
+    fn send_chunks(&mut self, transfer_id: u64, ranges: &[(u32, u32)]) -> Result<(), TransferError> {
+        let Some(transfer) = self.outgoing.get(&transfer_id) else { return Ok(()) };
+        let manifest = transfer.manifest.clone();
+        let destination = transfer.destination.clone();
+        let mut file = File::open(&transfer.path)?;
+
+        for (start, end) in ranges {
+            for index in *start..(*end).min(manifest.chunk_count()) {
+                let mut data = vec![0; manifest.chunk_length(index)];
+                file.seek(SeekFrom::Start(index as u64 * manifest.chunk_size as u64))?;
+                file.read_exact(&mut data)?;
+
+                let length = data.len() as u64;
+                self.agent.send_bundle(destination.clone(), &Packet::Chunk { transfer_id, index, data }.to_bytes())?;
+
+                let transfer = self.outgoing.get_mut(&transfer_id).expect("outgoing transfer exists");
+                transfer.sent_bytes += length;
+                let progress = Progress {
+                    transfer_id,
+                    direction: Direction::Sending,
+                    name: manifest.name.clone(),
+                    done_bytes: transfer.sent_bytes,
+                    total_bytes: manifest.size,
+                };
+                self.report(&progress);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_manifest(&mut self, manifest: Manifest) -> Result<Option<TransferEvent>, TransferError> {
+        let transfer_id = manifest.transfer_id;
+
+        if Path::new(&manifest.name).file_name().is_none_or(|it| *it != *manifest.name) {
+            return Err(TransferError::InvalidName);
+        }
+        if manifest.size > self.max_file_size {
+            return Err(TransferError::FileTooLarge { size: manifest.size, max: self.max_file_size });
+        }
+        if manifest.checked_chunk_count().is_none() {
+            return Err(TransferError::TooManyChunks);
+        }
+
+        if self.incoming.contains_key(&transfer_id) {
+            self.request_missing(transfer_id)?;
+            return Ok(None);
+        }
+
+        if self.completed_marker_path(transfer_id).exists() {
+            self.agent.send_bundle(manifest.reply_to.clone(), &Packet::Complete { transfer_id }.to_bytes())?;
+            return Ok(None);
+        }
+
+        fs::write(self.incoming_state_path(transfer_id, "manifest"), Packet::Manifest(manifest.clone()).to_bytes())?;
+        File::create(self.incoming_state_path(transfer_id, "part"))?.set_len(manifest.size)?;
+        let received = ChunkSet::new(manifest.chunk_count());
+        fs::write(self.incoming_state_path(transfer_id, "chunks"), received.as_bytes())?;
+
+        self.incoming.insert(transfer_id, Incoming {
+            received,
+            received_bytes: 0,
+            manifest: manifest.clone(),
+        });
+
+        let mut event = Some(TransferEvent::Announced(manifest));
+        let orphans: Vec<(u64, u32, Vec<u8>)> = self.orphans.iter().filter(|it| it.0 == transfer_id).cloned().collect();
+        self.orphans.retain(|it| it.0 != transfer_id);
+        for (_, index, data) in orphans {
+            if let Some(completion) = self.handle_chunk(transfer_id, index, data)? {
+                event = Some(completion);
+            }
+        }
+
+        if self.incoming.get(&transfer_id).is_some_and(|it| it.manifest.size == 0) {
+            return self.complete(transfer_id);
+        }
+        Ok(event)
+    }
+
+    fn handle_chunk(&mut self, transfer_id: u64, index: u32, data: Vec<u8>) -> Result<Option<TransferEvent>, TransferError> {
+        let Some(transfer) = self.incoming.get(&transfer_id) else {
+            if !self.completed_marker_path(transfer_id).exists() && self.orphans.len() < MAX_ORPHAN_CHUNKS {
+                self.orphans.push((transfer_id, index, data));
+            }
+            return Ok(None);
+        };
+
+        let manifest = &transfer.manifest;
+        if index >= manifest.chunk_count() || data.len() != manifest.chunk_length(index) {
+            return Err(TransferError::InvalidChunk { transfer_id, index });
+        }
+        if transfer.received.contains(index) {
+            return Ok(None);
+        }
+
+        let mut part = OpenOptions::new().write(true).open(self.incoming_state_path(transfer_id, "part"))?;
+        part.seek(SeekFrom::Start(index as u64 * manifest.chunk_size as u64))?;
+        part.write_all(&data)?;
+        part.sync_data()?;
+
+        let mut chunks = OpenOptions::new().write(true).open(self.incoming_state_path(transfer_id, "chunks"))?;
+        let transfer = self.incoming.get_mut(&transfer_id).expect("incoming transfer exists");
+        let (position, bits) = transfer.received.insert(index);
+        transfer.received_bytes += data.len() as u64;
+
+        chunks.seek(SeekFrom::Start(position))?;
+        chunks.write_all(&[bits])?;
+
+        let progress = Progress {
+            transfer_id,
+            direction: Direction::Receiving,
+            name: transfer.manifest.name.clone(),
+            done_bytes: transfer.received_bytes,
+            total_bytes: transfer.manifest.size,
+        };
+        let done = transfer.received.is_complete();
+        self.report(&progress);
+
+        if done {
+            self.complete(transfer_id)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Verify a fully received file and move it to output directory
+    fn complete(&mut self, transfer_id: u64) -> Result<Option<TransferEvent>, TransferError> {
+        let part_path = self.incoming_state_path(transfer_id, "part");
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(&part_path)?, &mut hasher)?;
+        let hash: [u8; 32] = hasher.finalize().into();
+
+        let chunks_path = self.incoming_state_path(transfer_id, "chunks");
+        let transfer = self.incoming.get_mut(&transfer_id).expect("incoming transfer exists");
+        if hash != transfer.manifest.sha256 {
+            transfer.received = ChunkSet::new(transfer.manifest.chunk_count());
+            transfer.received_bytes = 0;
+            fs::write(chunks_path, transfer.received.as_bytes())?;
+            self.request_missing(transfer_id)?;
+            return Ok(Some(TransferEvent::ChecksumMismatch { transfer_id }));
+        }
+
+        let transfer = self.incoming.remove(&transfer_id).expect("incoming transfer exists");
+        let path = unused_path(&self.output_dir, &transfer.manifest.name);
+        let mut marker = File::create(self.completed_marker_path(transfer_id))?;
+        marker.write_all(path.as_os_str().as_bytes())?;
+        marker.sync_all()?;
+        self.finish_completed(transfer_id)?;
+
+        self.agent.send_bundle(transfer.manifest.reply_to, &Packet::Complete { transfer_id }.to_bytes())?;
+        Ok(Some(TransferEvent::Received { transfer_id, path }))
+    }
+
+    /// Move a verified file to the path recorded in its completion marker and remove its state
+    ///
+    /// Can be called again when interrupted, so completions are finished when state is loaded after a crash
+    fn finish_completed(&self, transfer_id: u64) -> Result<PathBuf, TransferError> {
+        let path = PathBuf::from(OsString::from_vec(fs::read(self.completed_marker_path(transfer_id))?));
+        let part_path = self.incoming_state_path(transfer_id, "part");
+        if part_path.exists() {
+            move_file(&part_path, &path)?;
+        }
+        remove_if_exists(&self.incoming_state_path(transfer_id, "chunks"))?;
+        remove_if_exists(&self.incoming_state_path(transfer_id, "manifest"))?;
+        Ok(path)
+    }
+
+    fn request_missing(&mut self, transfer_id: u64) -> Result<(), TransferError> {
+        let transfer = &self.incoming[&transfer_id];
+        let packet = Packet::Request { transfer_id, missing: transfer.missing() };
+        self.agent.send_bundle(transfer.manifest.reply_to.clone(), &packet.to_bytes())?;
+        Ok(())
+    }
+
+    fn report(&mut self, progress: &Progress) {
+        if let Some(callback) = self.progress.as_mut() {
+            callback(progress);
+        }
+    }
+
+    fn outgoing_state_path(&self, transfer_id: u64) -> PathBuf {
+        self.state_dir.join("outgoing").join(format!("{:016x}", transfer_id))
+    }
+
+    fn incoming_state_path(&self, transfer_id: u64, extension: &str) -> PathBuf {
+        self.state_dir.join("incoming").join(format!("{:016x}.{}", transfer_id, extension))
+    }
+
+    fn completed_marker_path(&self, transfer_id: u64) -> PathBuf {
+        self.incoming_state_path(transfer_id, "done")
+    }
+
+    /// Outgoing state file: destination and path lines followed by the manifest packet
+    fn save_outgoing(&self, transfer: &Outgoing) -> Result<(), TransferError> {
+        let mut content = format!("{}\n{}\n", transfer.destination, transfer.path.display()).into_bytes();
+        content.extend(Packet::Manifest(transfer.manifest.clone()).to_bytes());
+        fs::write(self.outgoing_state_path(transfer.manifest.transfer_id), content)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self) -> Result<(), TransferError> {
+        for entry in fs::read_dir(self.state_dir.join("outgoing"))? {
+            let content = fs::read(entry?.path())?;
+            let mut lines = content.splitn(3, |it| *it == b'\n');
+            let (Some(destination), Some(path), Some(manifest)) = (lines.next(), lines.next(), lines.next()) else {
+                return Err(TransferError::CorruptedState);
+            };
+            let Packet::Manifest(manifest) = Packet::parse(manifest)? else {
+                return Err(TransferError::CorruptedState);
+            };
+            self.outgoing.insert(manifest.transfer_id, Outgoing {
+                manifest,
+                destination: String::from_utf8_lossy(destination).into_owned(),
+                path: PathBuf::from(String::from_utf8_lossy(path).into_owned()),
+                sent_bytes: 0,
+            });
+        }
+
+        for entry in fs::read_dir(self.state_dir.join("incoming"))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|it| it != "manifest") {
+                continue;
+            }
+            let Packet::Manifest(manifest) = Packet::parse(&fs::read(&path)?)? else {
+                return Err(TransferError::CorruptedState);
+            };
+            if self.completed_marker_path(manifest.transfer_id).exists() {
+                self.finish_completed(manifest.transfer_id)?;
+                continue;
+            }
+
+            if manifest.checked_chunk_count().is_none() {
+                return Err(TransferError::CorruptedState);
+            }
+            let received = ChunkSet::from_bytes(fs::read(path.with_extension("chunks"))?, manifest.chunk_count());
+            let received_bytes = (0..received.count)
+                .filter(|it| received.contains(*it))
+                .map(|it| manifest.chunk_length(it) as u64)
+                .sum();
+
+            self.incoming.insert(manifest.transfer_id, Incoming { manifest, received, received_bytes });
+        }
+        Ok(())
+    }
+}
+
+/// `name` in `dir`, with a number appended if a file with this name already exists
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name.extension().map(|it| format!(".{}", it.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|number| dir.join(format!("{} ({}){}", stem, number, extension)))
+        .find(|it| !it.exists())
+        .expect("an unused name exists")
+}
+
+/// Rename a file, copying it when `to` is on another filesystem
+fn move_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let mut target = File::create(to)?;
+            io::copy(&mut File::open(from)?, &mut target)?;
+            target.sync_all()?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), io::Error> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// An error during a file transfer
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Error reading or writing files
+    #[error("io Error")]
+    IOError(#[from] io::Error),
+
+    /// Received bundle doesn't start with a file transfer header
+    #[error("Bundle is not a file transfer message")]
+    NotATransferMessage,
+
+    /// Received header is truncated
+    #[error("Malformed file transfer message")]
+    Malformed(#[from] ParseError),
+
+    /// Sent path doesn't have a file name or received name isn't a plain file name
+    #[error("Invalid file name")]
+    InvalidName,
+
+    /// Received chunk doesn't match its manifest
+    #[error("Invalid chunk {index} of transfer {transfer_id:016x}")]
+    InvalidChunk {
+        /// Identifier of transfer
+        transfer_id: u64,
+        /// Index of chunk
+        index: u32,
+    },
+
+    /// File would need more than [MAX_CHUNKS] chunks
+    #[error("Too many chunks")]
+    TooManyChunks,
+
+    /// Announced file is bigger than allowed by [TransferAgent::with_max_file_size]
+    #[error("File of {size} bytes bigger than {max} bytes")]
+    FileTooLarge {
+        /// Announced file size
+        size: u64,
+        /// Maximum file size
+        max: u64,
+    },
+
+    /// A state file couldn't be read
+    #[error("Corrupted transfer state")]
+    CorruptedState,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            transfer_id: 0xABCD,
+            name: "photo.jpg".into(),
+            size: 2500,
+            chunk_size: 1000,
+            sha256: [7; 32],
+            reply_to: "dtn://a.dtn/files".into(),
+        }
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let manifest = manifest();
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(manifest.chunk_length(1), 1000);
+        assert_eq!(manifest.chunk_length(2), 500);
+        assert_eq!(Manifest { size: 0, ..manifest.clone() }.chunk_count(), 0);
+        assert_eq!(Manifest { size: u64::MAX, chunk_size: 1, ..manifest }.checked_chunk_count(), None);
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        let packets = [
+            Packet::Manifest(manifest()),
+            Packet::Chunk { transfer_id: 1, index: 4, data: vec![1, 2, 3] },
+            Packet::Request { transfer_id: 1, missing: vec![(0, 2), (5, 6)] },
+            Packet::Complete { transfer_id: 1 },
+        ];
+        for packet in packets {
+            assert_eq!(Packet::parse(&packet.to_bytes()).unwrap(), packet);
+        }
+        assert!(matches!(Packet::parse(b"data"), Err(TransferError::NotATransferMessage)));
+    }
+
+    #[test]
+    fn missing_ranges() {
+        let incoming = Incoming {
+            manifest: Manifest { size: 7000, ..manifest() },
+            received: ChunkSet::from_bytes(vec![0b1010_1001], 7),
+            received_bytes: 3000,
+        };
+        assert_eq!(incoming.missing(), vec![(1, 3), (4, 5), (6, 7)]);
+        assert_eq!(incoming.received.received, 3);
+
+        let mut received = ChunkSet::new(10);
+        assert_eq!(received.insert(9), (1, 0b10));
+        assert_eq!(received.insert(0), (0, 0b1));
+        assert!(!received.is_complete());
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn ipn_transfer() {
+        use std::time::Duration;
+        use crate::{testing::MockNode, Agent};
+
+        let dir = std::env::temp_dir().join(format!("ud3tn-aap-transfer-ipn-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("report.txt");
+        fs::write(&source, b"hello over ipn").unwrap();
+
+        let node = MockNode::new("ipn:7.0");
+        let connect = |agent_id: &str, name: &str| {
+            let mut stream = node.connect();
+            stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let agent = Agent::new(stream).unwrap().register(agent_id.into()).unwrap();
+            TransferAgent::new(agent, &dir.join(name).join("state"), &dir.join(name).join("output")).unwrap()
+        };
+        let mut sender = connect("1", "sender");
+        let mut receiver = connect("2", "receiver");
+
+        let transfer_id = sender.send_file("ipn:7.2".into(), &source, 4).unwrap();
+        let received = loop {
+            if let Some(TransferEvent::Received { path, .. }) = receiver.process_next().unwrap() {
+                break path;
+            }
+        };
+        assert_eq!(fs::read(received).unwrap(), b"hello over ipn");
+        assert!(matches!(sender.process_next().unwrap(), Some(TransferEvent::Delivered { transfer_id: id }) if id == transfer_id));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn interrupted_completion_finished() {
+        use crate::{testing::MockNode, Agent};
+
+        let dir = std::env::temp_dir().join(format!("ud3tn-aap-transfer-completion-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (incoming, output) = (dir.join("state").join("incoming"), dir.join("output"));
+        fs::create_dir_all(&incoming).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        fs::write(output.join("photo.jpg"), b"existing").unwrap();
+        assert_eq!(unused_path(&output, "photo.jpg"), output.join("photo (1).jpg"));
+
+        // Crash after the completion marker was written, before the file was moved
+        let manifest = manifest();
+        let target = output.join("photo (1).jpg");
+        fs::write(incoming.join("000000000000abcd.manifest"), Packet::Manifest(manifest).to_bytes()).unwrap();
+        fs::write(incoming.join("000000000000abcd.chunks"), [0b111]).unwrap();
+        fs::write(incoming.join("000000000000abcd.part"), b"received").unwrap();
+        fs::write(incoming.join("000000000000abcd.done"), target.as_os_str().as_bytes()).unwrap();
+
+        let node = MockNode::new("dtn://b.dtn/");
+        let agent = Agent::new(node.connect()).unwrap().register("files".into()).unwrap();
+        let receiver = TransferAgent::new(agent, &dir.join("state"), &output).unwrap();
+        assert!(receiver.incoming_transfers().is_empty());
+        assert_eq!(fs::read(target).unwrap(), b"received");
+        assert_eq!(fs::read(output.join("photo.jpg")).unwrap(), b"existing");
+        assert_eq!(fs::read_dir(&incoming).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn oversized_manifest_refused() {
+        use crate::{testing::MockNode, Agent};
+
+        let dir = std::env::temp_dir().join(format!("ud3tn-aap-transfer-oversized-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let node = MockNode::new("dtn://b.dtn/");
+        let agent = Agent::new(node.connect()).unwrap().register("files".into()).unwrap();
+        let mut receiver = TransferAgent::new(agent, &dir.join("state"), &dir.join("output")).unwrap().with_max_file_size(2000);
+
+        for (size, chunk_size) in [(2500, 1000), (u64::MAX, 1)] {
+            let manifest = Packet::Manifest(Manifest { size, chunk_size, ..manifest() });
+            assert!(node.deliver("dtn://b.dtn/files", "dtn://a.dtn/files", &manifest.to_bytes()));
+            assert!(matches!(receiver.process_next(), Err(TransferError::FileTooLarge { max: 2000, .. })));
+        }
+
+        let mut receiver = receiver.with_max_file_size(u64::MAX);
+        for size in [u64::MAX, DEFAULT_MAX_FILE_SIZE - 1, MAX_CHUNKS as u64 + 1] {
+            let manifest = Packet::Manifest(Manifest { size, chunk_size: 1, ..manifest() });
+            assert!(node.deliver("dtn://b.dtn/files", "dtn://a.dtn/files", &manifest.to_bytes()));
+            assert!(matches!(receiver.process_next(), Err(TransferError::TooManyChunks)));
+        }
+
+        assert_eq!(fs::read_dir(dir.join("state").join("incoming")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+//! Typed payloads serialized with serde
+//!
+//! Values are wrapped in a small self-describing [Envelope] carrying the serialization [Format],
+//! the content type and the schema version of the payload.
+//! Formats are enabled with the `json`, `cbor` and `postcard` features.
+//!
+//! ```rust,no_run
+//! use std::path::Path;
+//! use serde::{Serialize, Deserialize};
+//! use ud3tn_aap::{Agent, typed::{Format, TypedPayload}};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Report {
+//!     temperature: f32,
+//! }
+//!
+//! impl TypedPayload for Report {
+//!     const CONTENT_TYPE: &'static str = "org.example.report";
+//! }
+//!
+//! let mut agent = Agent::connect_unix(Path::new("/run/archipel-core/archipel-core.socket")).unwrap()
+//!     .register("reports".to_owned()).unwrap();
+//!
+//! agent.send_message("dtn://hq.dtn/reports".into(), &Report { temperature: 21.5 }, Format::Cbor).unwrap();
+//! let report = agent.recv_message::<Report>().unwrap();
+//! ```
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::{message::ParseError, wire, AapStream, BundleIdentifier, ReceivedBundle, RegisteredAgent};
+
+/// First byte of every envelope
+const MAGIC: u8 = 0xE7;
+
+/// A type that can be sent in an [Envelope]
+pub trait TypedPayload {
+    /// Name identifying this type of payload, e.g. `org.example.report`
+    const CONTENT_TYPE: &'static str;
+
+    /// Version of this payload schema
+    ///
+    /// Envelopes with a greater schema version are refused on decoding
+    const SCHEMA_VERSION: u16 = 1;
+}
+
+/// Serialization format of an envelope body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// JSON, requires `json` feature
+    Json,
+
+    /// CBOR, requires `cbor` feature
+    Cbor,
+
+    /// Postcard, requires `postcard` feature
+    Postcard,
+}
+
+impl Format {
+    fn code(&self) -> u8 {
+        match self {
+            Format::Json => 1,
+            Format::Cbor => 2,
+            Format::Postcard => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, TypedError> {
+        match code {
+            1 => Ok(Format::Json),
+            2 => Ok(Format::Cbor),
+            3 => Ok(Format::Postcard),
+            _ => Err(TypedError::UnknownFormat(code)),
+        }
+    }
+
+    /// Serialize a value in this format
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, TypedError> {
+        match self {
+            #[cfg(feature = "json")]
+            Format::Json => serde_json::to_vec(value).map_err(|e| TypedError::Encode(e.to_string())),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut result = Vec::new();
+                ciborium::into_writer(value, &mut result).map_err(|e| TypedError::Encode(e.to_string()))?;
+                Ok(result)
+            }
+            #[cfg(feature = "postcard")]
+            Format::Postcard => postcard::to_allocvec(value).map_err(|e| TypedError::Encode(e.to_string())),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = value;
+                Err(TypedError::FormatNotEnabled(*self))
+            }
+        }
+    }
+
+    /// Deserialize a value from this format
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, TypedError> {
+        match self {
+            #[cfg(feature = "json")]
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| TypedError::Decode(e.to_string())),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|e| TypedError::Decode(e.to_string())),
+            #[cfg(feature = "postcard")]
+            Format::Postcard => postcard::from_bytes(bytes).map_err(|e| TypedError::Decode(e.to_string())),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = bytes;
+                Err(TypedError::FormatNotEnabled(*self))
+            }
+        }
+    }
+}
+
+/// A serialized value with its description
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    /// Serialization format of body
+    pub format: Format,
+
+    /// Content type of body, see [TypedPayload::CONTENT_TYPE]
+    pub content_type: String,
+
+    /// Schema version of body, see [TypedPayload::SCHEMA_VERSION]
+    pub schema_version: u16,
+
+    /// Serialized value
+    pub body: Vec<u8>,
+}
+
+impl Envelope {
+    /// Serialize a value in an envelope
+    pub fn encode<T: Serialize + TypedPayload>(value: &T, format: Format) -> Result<Self, TypedError> {
+        Ok(Self {
+            format,
+            content_type: T::CONTENT_TYPE.to_owned(),
+            schema_version: T::SCHEMA_VERSION,
+            body: format.serialize(value)?,
+        })
+    }
+
+    /// Deserialize the value of this envelope
+    ///
+    /// Fails if content type doesn't match or schema version is greater than [TypedPayload::SCHEMA_VERSION]
+    pub fn decode<T: DeserializeOwned + TypedPayload>(&self) -> Result<T, TypedError> {
+        if self.content_type != T::CONTENT_TYPE {
+            return Err(TypedError::ContentTypeMismatch {
+                expected: T::CONTENT_TYPE,
+                found: self.content_type.clone(),
+            });
+        }
+
+        if self.schema_version > T::SCHEMA_VERSION {
+            return Err(TypedError::UnsupportedSchemaVersion {
+                supported: T::SCHEMA_VERSION,
+                found: self.schema_version,
+            });
+        }
+
+        self.format.deserialize(&self.body)
+    }
+
+    /// Serialize this envelope to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![MAGIC, self.format.code()];
+        wire::put_string(&mut result, &self.content_type);
+        wire::put_u16(&mut result, self.schema_version);
+        result.extend_from_slice(&self.body);
+        result
+    }
+
+    /// Parse an envelope from a bundle payload
+    pub fn parse(bytes: &[u8]) -> Result<Self, TypedError> {
+        let mut reader = wire::Reader::new(bytes);
+        if reader.u8()? != MAGIC {
+            return Err(TypedError::NotAnEnvelope);
+        }
+
+        Ok(Self {
+            format: Format::from_code(reader.u8()?)?,
+            content_type: reader.string()?,
+            schema_version: reader.u16()?,
+            body: reader.rest().to_vec(),
+        })
+    }
+}
+
+/// A decoded message and its source
+#[derive(Debug, Clone)]
+pub struct TypedBundle<T> {
+    /// Source endpoint ID of this bundle
+    pub source: Option<String>,
+
+    /// Decoded message
+    pub message: T,
+}
+
+impl ReceivedBundle {
+    /// Parse the envelope carried by this bundle
+    pub fn envelope(&self) -> Result<Envelope, TypedError> {
+        Envelope::parse(&self.payload)
+    }
+
+    /// Decode the typed value carried by this bundle
+    pub fn decode<T: DeserializeOwned + TypedPayload>(&self) -> Result<T, TypedError> {
+        self.envelope()?.decode()
+    }
+}
+
+impl<S: AapStream> RegisteredAgent<S> {
+    /// Send a typed value in an [Envelope]
+    pub fn send_message<T: Serialize + TypedPayload>(&mut self, destination_eid: String, message: &T, format: Format) -> Result<BundleIdentifier, TypedError> {
+        let envelope = Envelope::encode(message, format)?;
+        Ok(self.send_bundle(destination_eid, &envelope.to_bytes())?)
+    }
+
+    /// Block until a bundle is received and decode its typed value
+    pub fn recv_message<T: DeserializeOwned + TypedPayload>(&mut self) -> Result<TypedBundle<T>, TypedError> {
+        let bundle = self.recv_bundle()?;
+        Ok(TypedBundle {
+            message: bundle.decode()?,
+            source: bundle.source,
+        })
+    }
+}
+
+/// An error during typed payload encoding or decoding
+#[derive(Debug, Error)]
+pub enum TypedError {
+    /// Error communicating with ud3tn node
+    #[error("Agent error")]
+    Agent(#[from] crate::Error),
+
+    /// Bundle payload doesn't start with an envelope header
+    #[error("Bundle is not a typed envelope")]
+    NotAnEnvelope,
+
+    /// Envelope header is truncated
+    #[error("Malformed envelope")]
+    Malformed(#[from] ParseError),
+
+    /// Envelope format code is unknown
+    #[error("Unknown format {0}")]
+    UnknownFormat(u8),
+
+    /// Format feature isn't enabled in this build
+    #[error("Format {0:?} not enabled")]
+    FormatNotEnabled(Format),
+
+    /// Envelope carries another type of payload
+    #[error("Expected content type {expected}, found {found}")]
+    ContentTypeMismatch {
+        /// Content type of requested type
+        expected: &'static str,
+        /// Content type of envelope
+        found: String,
+    },
+
+    /// Envelope schema version is newer than requested type
+    #[error("Schema version {found} not supported, latest supported is {supported}")]
+    UnsupportedSchemaVersion {
+        /// Schema version of requested type
+        supported: u16,
+        /// Schema version of envelope
+        found: u16,
+    },
+
+    /// Value couldn't be serialized
+    #[error("Encoding failed: {0}")]
+    Encode(String),
+
+    /// Envelope body couldn't be deserialized
+    #[error("Decoding failed: {0}")]
+    Decode(String),
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "json", all(feature = "cbor", feature = "postcard")))]
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[cfg(any(feature = "json", all(feature = "cbor", feature = "postcard")))]
+    struct Report {
+        station: String,
+        temperature: f32,
+    }
+
+    #[cfg(any(feature = "json", all(feature = "cbor", feature = "postcard")))]
+    impl TypedPayload for Report {
+        const CONTENT_TYPE: &'static str = "test.report";
+        const SCHEMA_VERSION: u16 = 2;
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[cfg(feature = "json")]
+    struct Other;
+
+    #[cfg(feature = "json")]
+    impl TypedPayload for Other {
+        const CONTENT_TYPE: &'static str = "test.other";
+    }
+
+    #[cfg(any(feature = "json", all(feature = "cbor", feature = "postcard")))]
+    fn report() -> Report {
+        Report { station: "north".into(), temperature: 12.5 }
+    }
+
+    #[test]
+    fn envelope_roundtrip() {
+        let envelope = Envelope {
+            format: Format::Cbor,
+            content_type: "test.report".into(),
+            schema_version: 2,
+            body: vec![1, 2, 3],
+        };
+        let bytes = envelope.to_bytes();
+        assert_eq!(&bytes[..10], &[MAGIC, 2, 0, 0, 0, 0, 0, 0, 0, 11]);
+        assert_eq!(Envelope::parse(&bytes).unwrap(), envelope);
+        assert!(matches!(Envelope::parse(b"raw"), Err(TypedError::NotAnEnvelope)));
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json_payload() {
+        let envelope = Envelope::encode(&report(), Format::Json).unwrap();
+        assert_eq!(envelope.body, br#"{"station":"north","temperature":12.5}"#);
+
+        let bundle = ReceivedBundle { source: None, payload: envelope.to_bytes() };
+        assert_eq!(bundle.decode::<Report>().unwrap(), report());
+        assert!(matches!(bundle.decode::<Other>(), Err(TypedError::ContentTypeMismatch { .. })));
+    }
+
+    #[test]
+    #[cfg(all(feature = "cbor", feature = "postcard"))]
+    fn binary_payloads() {
+        for format in [Format::Cbor, Format::Postcard] {
+            let mut envelope = Envelope::encode(&report(), format).unwrap();
+            assert_eq!(Envelope::parse(&envelope.to_bytes()).unwrap().decode::<Report>().unwrap(), report());
+
+            envelope.schema_version = 3;
+            assert!(matches!(envelope.decode::<Report>(), Err(TypedError::UnsupportedSchemaVersion { supported: 2, found: 3 })));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Binary encoding helpers shared by application-level protocols
+
+use crate::message::ParseError;
+
+/// Append a big endian [u16] to a buffer
+#[cfg(feature = "serde")]
+pub(crate) fn put_u16(target: &mut Vec<u8>, value: u16) {
+    target.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Append a big endian [u32] to a buffer
+pub(crate) fn put_u32(target: &mut Vec<u8>, value: u32) {
+    target.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Append a big endian [u64] to a buffer
+pub(crate) fn put_u64(target: &mut Vec<u8>, value: u64) {
+    target.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Append a string to a buffer including its length as [u64] before it
+pub(crate) fn put_string(target: &mut Vec<u8>, value: &str) {
+    put_bytes(target, value.as_bytes());
+}
+
+/// Append a byte array to a buffer including its length as [u64] before it
+pub(crate) fn put_bytes(target: &mut Vec<u8>, value: &[u8]) {
+    put_u64(target, value.len() as u64);
+    target.extend_from_slice(value);
+}
+
+/// Cursor reading values written with `put_*` functions
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    pub(crate) fn take(&mut self, length: usize) -> Result<&'a [u8], ParseError> {
+        let end = self.offset.checked_add(length).ok_or(ParseError::UnexpectedEnd)?;
+        let slice = self.bytes.get(self.offset..end).ok_or(ParseError::UnexpectedEnd)?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn u16(&mut self) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, ParseError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, ParseError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, ParseError> {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], ParseError> {
+        let length = self.u64()?;
+        self.take(usize::try_from(length).map_err(|_| ParseError::UnexpectedEnd)?)
+    }
+
+    /// Remaining unread bytes
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.offset..];
+        self.offset = self.bytes.len();
+        rest
+    }
+}
+
+/// Generate a message identifier unlikely to collide with identifiers of other processes
+pub(crate) fn new_message_id() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::SystemTime;
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|it| it.as_nanos() as u64)
+        .unwrap_or(0);
+    let pid = std::process::id() as u64;
+
+    nanos.rotate_left(16) ^ (pid << 48) ^ COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_string_roundtrip() {
+        let value = "a".repeat(70_000);
+        let mut buffer = Vec::new();
+        put_string(&mut buffer, &value);
+        put_string(&mut buffer, "end");
+
+        let mut reader = Reader::new(&buffer);
+        assert_eq!(reader.string().unwrap(), value);
+        assert_eq!(reader.string().unwrap(), "end");
+        assert!(reader.rest().is_empty());
+    }
+}